@@ -1,5 +1,7 @@
 // Exports common ABI types and constants for use by userspace programs.
-pub use crate::file::{CONSOLE, OpenFlag};
-pub use crate::fs::{DIRSIZE, Directory, InodeType, Stat};
+pub use crate::file::{At, CONSOLE, FULL, Fcntl, LockFlag, NULL, OpenFlag, RANDOM, ZERO};
+pub use crate::fs::{BSIZE, DirRecord, Directory, InodeType, MAXNAME, Stat};
 pub use crate::param::MAXPATH;
+pub use crate::socket::{AddressFamily, SocketType};
 pub use crate::syscall::{SysError, Syscall};
+pub use crate::vfs::StatFs;
@@ -1,9 +1,15 @@
-use core::mem::{self, MaybeUninit};
+use alloc::collections::TryReserveError;
+use alloc::vec;
+use alloc::vec::Vec;
 
 use crate::fs::BSIZE;
-use crate::param::NBUF;
+use crate::kalloc;
+use crate::param::{BCACHEPCT, BFLUSHTICKS, NBUF};
+use crate::proc::{self, Channel};
 use crate::sleeplock::{SleepLock, SleepLockGuard};
 use crate::spinlock::SpinLock;
+use crate::sync::OnceLock;
+use crate::trap::TICKS;
 use crate::virtio_disk;
 
 /// Buffer metadata, protected by `BCache`'s `SpinLock`.
@@ -11,12 +17,18 @@ use crate::virtio_disk;
 pub struct BufMeta {
     pub valid: bool,
     pub disk: bool,
+    /// Holds committed changes that are not written to the home location yet
+    pub dirty: bool,
+    /// Part of the transaction the log is building, so must not be written to the home location
+    pub pinned: bool,
     pub dev: u32,
     pub block_no: u32,
     pub ref_count: u32,
     // LRU linked list using indices
     pub prev: usize,
     pub next: usize,
+    /// Next buffer in the same hash bucket
+    pub hash_next: Option<usize>,
 }
 
 impl BufMeta {
@@ -24,11 +36,14 @@ impl BufMeta {
         Self {
             valid: false,
             disk: false,
+            dirty: false,
+            pinned: false,
             dev: 0,
             block_no: 0,
             ref_count: 0,
             prev: 0,
             next: 0,
+            hash_next: None,
         }
     }
 }
@@ -66,8 +81,118 @@ impl Buf<'_> {
 /// Meta data of the buffer cache, protected by `SpinLock`.
 #[derive(Debug)]
 pub struct BCacheInner {
-    pub meta: [BufMeta; NBUF],
+    pub meta: Vec<BufMeta>,
     pub head: usize,
+    /// Hash table of cached blocks, keyed by device and block number.
+    /// Each bucket chains buffers through `BufMeta::hash_next`.
+    buckets: Vec<Option<usize>>,
+    hits: usize,
+    misses: usize,
+}
+
+impl BCacheInner {
+    fn bucket(&self, dev: u32, block_no: u32) -> usize {
+        (dev as usize)
+            .wrapping_mul(31)
+            .wrapping_add(block_no as usize)
+            % self.buckets.len()
+    }
+
+    /// Returns the buffer caching the block, if any.
+    fn lookup(&self, dev: u32, block_no: u32) -> Option<usize> {
+        let mut next = self.buckets[self.bucket(dev, block_no)];
+
+        while let Some(i) = next {
+            let meta = &self.meta[i];
+            if meta.dev == dev && meta.block_no == block_no {
+                return Some(i);
+            }
+            next = meta.hash_next;
+        }
+
+        None
+    }
+
+    /// Recycles the least recently used (LRU) unused clean buffer for the block.
+    /// The returned buffer is not valid and has no references.
+    fn recycle(&mut self, dev: u32, block_no: u32) -> Option<usize> {
+        // start from the tail
+        let mut i = self.meta[self.head].prev;
+
+        while i != self.head {
+            if self.meta[i].ref_count == 0 && !self.meta[i].dirty {
+                self.unhash(i);
+
+                let meta = &mut self.meta[i];
+                meta.dev = dev;
+                meta.block_no = block_no;
+                meta.valid = false;
+
+                let bucket = self.bucket(dev, block_no);
+                self.meta[i].hash_next = self.buckets[bucket].replace(i);
+
+                return Some(i);
+            }
+
+            i = self.meta[i].prev;
+        }
+
+        None
+    }
+
+    /// Returns the least recently used (LRU) unused dirty buffer.
+    fn lru_dirty(&self) -> Option<usize> {
+        let mut i = self.meta[self.head].prev;
+
+        while i != self.head {
+            if self.meta[i].ref_count == 0 && self.meta[i].dirty {
+                return Some(i);
+            }
+
+            i = self.meta[i].prev;
+        }
+
+        None
+    }
+
+    /// Removes buffer `id` from its hash bucket.
+    fn unhash(&mut self, id: usize) {
+        let bucket = self.bucket(self.meta[id].dev, self.meta[id].block_no);
+        let next = self.meta[id].hash_next.take();
+
+        if self.buckets[bucket] == Some(id) {
+            self.buckets[bucket] = next;
+            return;
+        }
+
+        let mut prev = self.buckets[bucket];
+        while let Some(i) = prev {
+            if self.meta[i].hash_next == Some(id) {
+                self.meta[i].hash_next = next;
+                return;
+            }
+            prev = self.meta[i].hash_next;
+        }
+    }
+
+    /// Moves buffer `id` to the head of the most-recently-used list.
+    fn touch(&mut self, id: usize) {
+        // from: prev -> current -> next
+        // to:   prev -> next
+        let next = self.meta[id].next;
+        let prev = self.meta[id].prev;
+        self.meta[next].prev = self.meta[id].prev;
+        self.meta[prev].next = self.meta[id].next;
+
+        // from: head -> first
+        // to:   head -> current -> first
+        let head = self.head;
+        let first = self.meta[head].next;
+        self.meta[id].next = first;
+        self.meta[id].prev = head;
+        self.meta[first].prev = id;
+        self.meta[head].next = id;
+    }
 }
 
 pub static BCACHE: BCache = BCache::new();
@@ -76,7 +201,8 @@ pub static BCACHE: BCache = BCache::new();
 ///
 /// The buffer cache is a linked list of buf structures holding cached copies of disk block
 /// contents. Caching disk blocks in memory reduces the number of disk reads and also provides a
-/// synchronization point for disk blocks used by multiple processes.
+/// synchronization point for disk blocks used by multiple processes. The number of buffers is
+/// picked at boot from the amount of free memory, and a hash table finds the buffer of a block.
 ///
 /// Interface:
 /// * To get a buffer for a particular disk block, call `read()`.
@@ -84,50 +210,50 @@ pub static BCACHE: BCache = BCache::new();
 /// * When done with the buffer, call `release()`.
 /// * Do not use the buffer after calling `release()`.
 /// * Only one process at a time can use a buffer, so do not keep them longer than necessary.
+/// * To have a block read in the background before it is needed, call `read_ahead()`.
+///
+/// The log does not write committed blocks to their home locations right away, but marks their
+/// buffers dirty with `mark_dirty()`. Dirty buffers are written back by `flush()`, which the
+/// `bflush` kernel thread calls periodically, and by `get()` when no clean buffer is left.
 #[derive(Debug)]
 pub struct BCache {
     /// `SpinLock` protects metadata lookups and LRU manipulations.
     pub inner: SpinLock<BCacheInner>,
     /// Each buffer's data is protected by its own `SleepLock`.
-    pub bufs: [SleepLock<BufData>; NBUF],
+    bufs: OnceLock<Vec<SleepLock<BufData>>>,
 }
 
 impl BCache {
     const fn new() -> Self {
-        let bufs = {
-            let mut array: [MaybeUninit<SleepLock<BufData>>; NBUF] =
-                unsafe { MaybeUninit::uninit().assume_init() };
-
-            let mut i = 0;
-            while i < NBUF {
-                array[i] = MaybeUninit::new(SleepLock::new(BufData::new(), "buffer"));
-                i += 1;
-            }
-
-            unsafe {
-                mem::transmute::<[MaybeUninit<SleepLock<BufData>>; 30], [SleepLock<BufData>; 30]>(
-                    array,
-                )
-            }
-        };
-
-        let meta = {
-            let mut array: [MaybeUninit<BufMeta>; NBUF] =
-                unsafe { MaybeUninit::uninit().assume_init() };
+        Self {
+            inner: SpinLock::new(
+                BCacheInner {
+                    meta: Vec::new(),
+                    head: 0,
+                    buckets: Vec::new(),
+                    hits: 0,
+                    misses: 0,
+                },
+                "bcache",
+            ),
+            bufs: OnceLock::new(),
+        }
+    }
 
-            let mut i = 0;
-            while i < NBUF {
-                array[i] = MaybeUninit::new(BufMeta::new());
-                i += 1;
-            }
+    fn bufs(&self) -> &[SleepLock<BufData>] {
+        self.bufs.get().expect("bcache to be init")
+    }
 
-            unsafe { mem::transmute::<[MaybeUninit<BufMeta>; 30], [BufMeta; 30]>(array) }
-        };
+    /// Returns the number of buffers.
+    pub fn len(&self) -> usize {
+        // buffer 0 is the head of the LRU list
+        self.bufs().len() - 1
+    }
 
-        Self {
-            inner: SpinLock::new(BCacheInner { meta, head: 0 }, "bcache"),
-            bufs,
-        }
+    /// Returns the number of lookups that found the block cached and those that did not.
+    pub fn stats(&self) -> (usize, usize) {
+        let inner = self.inner.lock();
+        (inner.hits, inner.misses)
     }
 
     /// Looks through buffer cache for block on device `dev`.
@@ -136,41 +262,37 @@ impl BCache {
     pub fn get(&self, dev: u32, block_no: u32) -> Buf<'_> {
         let mut inner = self.inner.lock();
 
-        // is the block already cached?
-        for i in 0..NBUF {
-            let meta = &mut inner.meta[i];
-            if meta.dev == dev && meta.block_no == block_no {
-                meta.ref_count += 1;
-                drop(inner);
+        let id = loop {
+            // is the block already cached?
+            if let Some(id) = inner.lookup(dev, block_no) {
+                inner.hits += 1;
+                break id;
+            }
 
-                let guard = self.bufs[i].lock();
-                return Buf { id: i, guard };
+            if let Some(id) = inner.recycle(dev, block_no) {
+                inner.misses += 1;
+                break id;
             }
-        }
 
-        // not cached
-        // recycle the least recently used (LRU) unused buffer
-        // start from the tail
-        let mut i = inner.meta[inner.head].prev;
-        loop {
-            if i == inner.head {
+            // every unused buffer is dirty, write the least recently used one back and look again,
+            // since the lock is released meanwhile
+            let Some(id) = inner.lru_dirty() else {
                 panic!("bcache get no buffers");
-            }
+            };
 
-            let meta = &mut inner.meta[i];
-            if meta.ref_count == 0 {
-                meta.dev = dev;
-                meta.block_no = block_no;
-                meta.valid = false;
-                meta.ref_count += 1;
-                drop(inner);
+            inner.meta[id].ref_count += 1;
+            drop(inner);
 
-                let guard = self.bufs[i].lock();
-                return Buf { id: i, guard };
-            }
+            self.write_back(id);
 
-            i = inner.meta[i].prev;
-        }
+            inner = self.inner.lock();
+        };
+
+        inner.meta[id].ref_count += 1;
+        drop(inner);
+
+        let guard = self.bufs()[id].lock();
+        Buf { id, guard }
     }
 
     /// Returns a locked buf with the contents of the indicated block.
@@ -178,8 +300,14 @@ impl BCache {
         let mut buf = self.get(dev, block_no);
 
         let valid = {
-            let lock = self.inner.lock();
-            lock.meta[buf.id].valid
+            let mut inner = self.inner.lock();
+
+            // a read-ahead may still be filling the buffer
+            while inner.meta[buf.id].disk {
+                inner = proc::sleep(Channel::Buffer(buf.id), inner);
+            }
+
+            inner.meta[buf.id].valid
         };
 
         if !valid {
@@ -192,6 +320,36 @@ impl BCache {
         buf
     }
 
+    /// Starts reading the indicated block, and up to `count - 1` blocks after it, into the cache
+    /// without waiting for them. Consecutive blocks cost a single disk request.
+    /// Stops at a block that is already cached, and does nothing if the disk is busy.
+    pub fn read_ahead(&self, dev: u32, block_no: u32, count: usize) {
+        virtio_disk::read_ahead(dev, block_no, count);
+    }
+
+    /// Claims a buffer for a read-ahead of the indicated block.
+    /// Returns the buffer's index and data, or `None` if the block is already cached.
+    /// The buffer is marked as being under disk I/O, and holds a reference that the disk driver
+    /// drops once the read is done.
+    pub fn claim(&self, dev: u32, block_no: u32) -> Option<(usize, *mut u8)> {
+        let mut inner = self.inner.lock();
+
+        if inner.lookup(dev, block_no).is_some() {
+            return None;
+        }
+
+        let id = inner.recycle(dev, block_no)?;
+        inner.meta[id].ref_count += 1;
+        inner.meta[id].disk = true;
+        // we are about to use it, do not recycle it first
+        inner.touch(id);
+
+        // # Safety: no one else can lock the buffer until `disk` is cleared
+        let data = unsafe { self.bufs()[id].get_mut_unchecked().data.as_mut_ptr() };
+
+        Some((id, data))
+    }
+
     /// Writes `buf`'s contents to disk.
     pub fn write(&self, buf: &mut Buf<'_>) {
         // buf must be locked since it holds the sleep lock guard
@@ -213,52 +371,147 @@ impl BCache {
         inner.meta[id].ref_count -= 1;
         if inner.meta[id].ref_count == 0 {
             // no one is waiting for it
-
-            // from: prev -> current -> next
-            // to:   prev -> next
-            let next = inner.meta[id].next;
-            let prev = inner.meta[id].prev;
-            inner.meta[next].prev = inner.meta[id].prev;
-            inner.meta[prev].next = inner.meta[id].next;
-
-            // from: head -> first
-            // to:   head -> current -> first
-            let head = inner.head;
-            let first = inner.meta[head].next;
-            inner.meta[id].next = first;
-            inner.meta[id].prev = head;
-            inner.meta[first].prev = id;
-            inner.meta[head].next = id;
+            inner.touch(id);
         }
     }
 
-    /// Artificially increments the reference count for the buffer so that it is not recycled.
+    /// Artificially increments the reference count for the buffer so that it is not recycled, and
+    /// keeps it from being written back until it is unpinned.
     pub fn pin(&self, buf: &Buf<'_>) {
         let mut inner = self.inner.lock();
         inner.meta[buf.id].ref_count += 1;
+        inner.meta[buf.id].pinned = true;
     }
 
     /// Artificially decrements the reference count for the buffer.
     pub fn unpin(&self, buf: &Buf<'_>) {
         let mut inner = self.inner.lock();
         inner.meta[buf.id].ref_count -= 1;
+        inner.meta[buf.id].pinned = false;
+    }
+
+    /// Marks `buf` as holding committed changes to be written back later.
+    pub fn mark_dirty(&self, buf: &Buf<'_>) {
+        let mut inner = self.inner.lock();
+        inner.meta[buf.id].dirty = true;
+    }
+
+    /// Writes buffer `id` to disk if it is dirty and not pinned, then drops the caller's reference.
+    /// The buffer stays where it is in the LRU list.
+    /// Returns whether the buffer was written.
+    fn write_back(&self, id: usize) -> bool {
+        let mut buf = Buf {
+            id,
+            guard: self.bufs()[id].lock(),
+        };
+
+        // the buffer can only be pinned or written back while locked. the flag is cleared before
+        // the write, so an update installed once the buffer is unlocked marks it dirty again
+        let dirty = {
+            let mut inner = self.inner.lock();
+            let meta = &mut inner.meta[id];
+            let dirty = meta.dirty && !meta.pinned;
+            if dirty {
+                meta.dirty = false;
+            }
+            dirty
+        };
+
+        if dirty {
+            virtio_disk::rw(&mut buf, true);
+        }
+
+        drop(buf);
+
+        self.inner.lock().meta[id].ref_count -= 1;
+
+        dirty
+    }
+
+    /// Writes all dirty buffers that are not pinned to disk.
+    /// Returns the number of buffers written.
+    pub fn flush(&self) -> usize {
+        let mut written = 0;
+
+        for id in 1..self.bufs().len() {
+            let dirty = {
+                let mut inner = self.inner.lock();
+                let meta = &mut inner.meta[id];
+
+                if meta.dirty && !meta.pinned {
+                    meta.ref_count += 1;
+                }
+
+                meta.dirty && !meta.pinned
+            };
+
+            if dirty && self.write_back(id) {
+                written += 1;
+            }
+        }
+
+        written
     }
 }
 
 /// Initialize the buffer cache.
 ///
 /// # Safety
-/// This function must be called only once during kernel initialization.
+/// This function must be called only once during kernel initialization, after `kalloc::init()`.
 pub unsafe fn init() {
+    // take a share of free memory, rounded down to a power of two so that the buddy allocator does
+    // not waste the rest of the block
+    let budget = kalloc::available() * BCACHEPCT / 100;
+    let nbuf = ((1 << budget.ilog2()) / size_of::<SleepLock<BufData>>()).max(NBUF + 1);
+
+    BCACHE.bufs.initialize(|| {
+        let mut bufs = Vec::new();
+        try_log!(bufs.try_reserve_exact(nbuf));
+        bufs.extend((0..nbuf).map(|_| SleepLock::new(BufData::new(), "buffer")));
+        Ok::<_, TryReserveError>(bufs)
+    });
+
     let mut inner = BCACHE.inner.lock();
 
+    inner.meta = (0..nbuf).map(|_| BufMeta::new()).collect();
+    inner.buckets = vec![None; nbuf];
+
     // create a circular doubly-linked list
-    // head -> 0 -> 1 -> ... -> NBUF - 1 -> head
+    // head -> 1 -> 2 -> ... -> nbuf - 1 -> head
     inner.head = 0;
-    for i in 0..NBUF {
-        inner.meta[i].prev = if i == 0 { NBUF - 1 } else { i - 1 };
-        inner.meta[i].next = if i == NBUF - 1 { 0 } else { i + 1 };
+    for i in 0..nbuf {
+        inner.meta[i].prev = if i == 0 { nbuf - 1 } else { i - 1 };
+        inner.meta[i].next = if i == nbuf - 1 { 0 } else { i + 1 };
+    }
+
+    println!("buf  init ({} buffers)", nbuf - 1);
+}
+
+/// Body of the `bflush` kernel thread.
+/// Writes dirty buffers back every `BFLUSHTICKS` ticks, so that log checkpoints and buffer
+/// recycling rarely have to wait for the disk.
+pub fn flusher() -> ! {
+    loop {
+        {
+            let mut ticks = TICKS.lock();
+            let ticks0 = *ticks;
+            while *ticks - ticks0 < BFLUSHTICKS {
+                ticks = proc::sleep(Channel::Ticks, ticks);
+            }
+        }
+
+        BCACHE.flush();
     }
+}
 
-    println!("buf  init");
+/// Prints buffer cache statistics to the console.
+/// For debugging only.
+pub fn dump() {
+    let (hits, misses) = BCACHE.stats();
+    println!(
+        "bcache: {} buffers, {} hits, {} misses",
+        BCACHE.len(),
+        hits,
+        misses
+    );
 }
@@ -1,3 +1,4 @@
+use crate::buf;
 use crate::proc::{self, Channel, PROC_TABLE};
 use crate::spinlock::SpinLock;
 use crate::syscall::SysError;
@@ -73,7 +74,8 @@ impl Console {
 
     /// User `read()`s from the console are handled here.
     /// Currently only handles user addresses.
-    pub fn read(dst: VA, mut len: usize) -> Result<usize, SysError> {
+    /// If `nonblock` is set, returns what has been typed so far instead of waiting for a line.
+    pub fn read(dst: VA, mut len: usize, nonblock: bool) -> Result<usize, SysError> {
         let mut console = CONSOLE.lock();
 
         let mut dst = dst;
@@ -81,6 +83,13 @@ impl Console {
 
         while len > 0 {
             // wait until interrupt handler has put some input into `buf`.
+            if console.r == console.w && nonblock {
+                if len == target {
+                    return Err(SysError::ResourceUnavailable);
+                }
+                break;
+            }
+
             while console.r == console.w {
                 if proc::current_proc().is_killed() {
                     return Err(SysError::Interrupted);
@@ -139,6 +148,7 @@ impl Console {
 
             c if c == ctrl(b'P') => {
                 unsafe { PROC_TABLE.dump() };
+                buf::dump();
             }
 
             // normal character
@@ -0,0 +1,33 @@
+// CRC32C (Castagnoli), the checksum of file system metadata blocks.
+//
+// Table driven, one byte at a time. The table is built at compile time.
+
+/// Reversed Castagnoli polynomial
+const POLY: u32 = 0x82F6_3B78;
+
+static TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Returns the CRC32C of `data`.
+pub fn crc32c(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &b| {
+        TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
@@ -0,0 +1,194 @@
+// Device file system.
+//
+// A devfs holds one device node for every driver registered in `DEVICES`, named after the driver,
+// so device nodes do not have to be made with `mknod`. Opening a node goes through the driver like
+// any other device node. The tree is fixed: nodes cannot be created, linked or removed.
+//
+// The root is inode 1, and the node of the device with major number `m` is inode `m + 1`.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::file::DEVICES;
+use crate::fs::{FsError, InodeType, Stat};
+use crate::vfs::{self, DirEntry, FileOps, FileSystem, NodeId, StatFs, VnodeOps};
+
+/// Inode number of the root directory
+const ROOTINO: u32 = 1;
+
+/// A file system of device nodes
+#[derive(Debug)]
+pub struct DevFs {
+    dev: u32,
+    /// References to any of the nodes
+    refs: AtomicUsize,
+}
+
+impl DevFs {
+    /// Creates a devfs. `dev` is ignored, since a devfs has no disk; it gets a device number of its
+    /// own.
+    pub fn mount(_dev: u32) -> Result<Arc<dyn FileSystem>, FsError> {
+        Ok(Arc::new(DevFs {
+            dev: vfs::alloc_dev(),
+            refs: AtomicUsize::new(0),
+        }))
+    }
+
+    /// Takes a reference to the node with inode number `ino`.
+    fn get(&self, ino: u32) -> NodeId {
+        self.refs.fetch_add(1, Ordering::Relaxed);
+        NodeId {
+            id: ino as usize,
+            ino,
+        }
+    }
+
+    /// Returns the major number of the device node `node`, or None for the root.
+    fn major(node: NodeId) -> Option<usize> {
+        (node.ino != ROOTINO).then(|| (node.ino - ROOTINO) as usize)
+    }
+}
+
+impl FileSystem for DevFs {
+    fn dev(&self) -> u32 {
+        self.dev
+    }
+
+    fn root(&self) -> Result<NodeId, FsError> {
+        Ok(self.get(ROOTINO))
+    }
+
+    fn statfs(&self) -> StatFs {
+        // nothing is stored
+        StatFs::default()
+    }
+
+    fn busy(&self) -> bool {
+        self.refs.load(Ordering::Relaxed) > 0
+    }
+
+    fn unmount(&self) {
+        // nothing is kept
+    }
+}
+
+impl VnodeOps for DevFs {
+    fn dup(&self, node: NodeId) {
+        self.get(node.ino);
+    }
+
+    fn put(&self, _node: NodeId) {
+        let refs = self.refs.fetch_sub(1, Ordering::Relaxed);
+        assert!(refs >= 1, "devfs put");
+    }
+
+    fn stat(&self, node: NodeId) -> Result<Stat, FsError> {
+        let (r#type, major) = match Self::major(node) {
+            Some(major) => (InodeType::Device, major as u16),
+            None => (InodeType::Directory, 0),
+        };
+
+        Ok(Stat {
+            dev: self.dev,
+            ino: node.ino,
+            r#type,
+            major,
+            minor: 0,
+            nlink: 1,
+            size: 0,
+        })
+    }
+
+    fn lookup(&self, dir: NodeId, name: &str) -> Result<Option<NodeId>, FsError> {
+        if Self::major(dir).is_some() {
+            err!(FsError::NotDirectory);
+        }
+
+        let ino = match name {
+            "." | ".." => Some(ROOTINO),
+            _ => DEVICES
+                .iter()
+                .position(|dev| dev.is_some_and(|dev| dev.name == name))
+                .map(|major| ROOTINO + major as u32),
+        };
+
+        Ok(ino.map(|ino| self.get(ino)))
+    }
+
+    fn create(
+        &self,
+        _dir: NodeId,
+        _name: &str,
+        _type: InodeType,
+        _major: u16,
+        _minor: u16,
+        _exclusive: bool,
+    ) -> Result<NodeId, FsError> {
+        err!(FsError::NotPermitted)
+    }
+
+    fn link(&self, _dir: NodeId, _name: &str, _node: NodeId) -> Result<(), FsError> {
+        err!(FsError::NotPermitted)
+    }
+
+    fn unlink(&self, _dir: NodeId, _name: &str) -> Result<(), FsError> {
+        err!(FsError::NotPermitted)
+    }
+
+    fn readdir(&self, dir: NodeId, offset: u32) -> Result<Option<(DirEntry, u32)>, FsError> {
+        if Self::major(dir).is_some() {
+            err!(FsError::NotDirectory);
+        }
+
+        // offsets past `.` and `..` are major numbers, skipping those without a driver
+        let entry = match offset {
+            0 => Some((String::from("."), ROOTINO, 1)),
+            1 => Some((String::from(".."), ROOTINO, 2)),
+            _ => DEVICES
+                .iter()
+                .enumerate()
+                .skip(offset as usize - 2)
+                .find_map(|(major, dev)| dev.map(|dev| (major, dev)))
+                .map(|(major, dev)| {
+                    let major = major as u32;
+                    (String::from(dev.name), ROOTINO + major, major + 3)
+                }),
+        };
+
+        Ok(entry.map(|(name, ino, next)| (DirEntry { ino, name }, next)))
+    }
+}
+
+impl FileOps for DevFs {
+    fn read(
+        &self,
+        _node: NodeId,
+        _offset: u32,
+        _dst: &mut [u8],
+        _user: bool,
+    ) -> Result<u32, FsError> {
+        // device nodes are read through their driver
+        err!(FsError::Type)
+    }
+
+    fn write(
+        &self,
+        _node: NodeId,
+        _offset: &mut u32,
+        _src: &[u8],
+        _user: bool,
+        _append: bool,
+    ) -> Result<u32, FsError> {
+        err!(FsError::Type)
+    }
+
+    fn truncate(&self, _node: NodeId, _size: usize) -> Result<(), FsError> {
+        err!(FsError::NotPermitted)
+    }
+
+    fn sync(&self, _node: NodeId) -> Result<(), FsError> {
+        // nothing to write back
+        Ok(())
+    }
+}
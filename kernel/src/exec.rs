@@ -1,11 +1,12 @@
 use core::fmt::Display;
+use core::mem;
 use core::slice;
 
-use crate::fs::Path;
 use crate::log::Operation;
 use crate::param::{MAXARG, USERSTACK};
 use crate::proc::current_proc;
 use crate::riscv::{PGSIZE, PTE_W, PTE_X, pg_round_up};
+use crate::vfs::Path;
 use crate::vm::VA;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -105,18 +106,16 @@ pub fn exec(path: &Path, argv: &[&str]) -> Result<usize, ExecError> {
     let _op = Operation::begin();
 
     // open the executable file
-    let Ok(mut inode) = log!(path.resolve()) else {
+    let Ok(vnode) = log!(path.resolve()) else {
         err!(ExecError::Read);
     };
 
-    let mut inner = inode.lock();
-
     // read the elf header
     let mut elf_buf = [0u8; ElfHeader::SIZE];
-    match log!(inode.read(&mut inner, 0, &mut elf_buf, false)) {
+    match log!(vnode.read(0, &mut elf_buf, false)) {
         Ok(read) if read as usize == elf_buf.len() => {}
         _ => {
-            inode.unlock_put(inner);
+            vnode.put();
             err!(ExecError::Read);
         }
     }
@@ -125,13 +124,13 @@ pub fn exec(path: &Path, argv: &[&str]) -> Result<usize, ExecError> {
 
     // make sure it's a valid elf file
     if elf.magic != ELF_MAGIC {
-        inode.unlock_put(inner);
+        vnode.put();
         err!(ExecError::Elf);
     }
 
     // create a new pagetable
     let Ok(mut pagetable) = log!(proc.create_pagetable()) else {
-        inode.unlock_put(inner);
+        vnode.put();
         err!(ExecError::Alloc);
     };
 
@@ -140,10 +139,10 @@ pub fn exec(path: &Path, argv: &[&str]) -> Result<usize, ExecError> {
     let mut offset = elf.phoff;
 
     for _ in 0..elf.phnum {
-        match log!(inode.read(&mut inner, offset as u32, &mut ph_buf, false)) {
+        match log!(vnode.read(offset as u32, &mut ph_buf, false)) {
             Ok(read) if read as usize == ph_buf.len() => {}
             _ => {
-                inode.unlock_put(inner);
+                vnode.put();
                 err!(ExecError::Memory);
             }
         }
@@ -160,7 +159,7 @@ pub fn exec(path: &Path, argv: &[&str]) -> Result<usize, ExecError> {
             || !ph.vaddr.is_multiple_of(PGSIZE as u64)
         {
             pagetable.proc_free(size);
-            inode.unlock_put(inner);
+            vnode.put();
             err!(ExecError::Header);
         }
 
@@ -168,14 +167,13 @@ pub fn exec(path: &Path, argv: &[&str]) -> Result<usize, ExecError> {
             Ok(new_size) => new_size,
             Err(_) => {
                 pagetable.proc_free(size);
-                inode.unlock_put(inner);
+                vnode.put();
                 err!(ExecError::Alloc);
             }
         };
 
         if log!(pagetable.load_elf_segment(
-            &mut inode,
-            &mut inner,
+            &vnode,
             VA::from(ph.vaddr as usize),
             ph.offset as u32,
             ph.filesz as usize,
@@ -183,12 +181,12 @@ pub fn exec(path: &Path, argv: &[&str]) -> Result<usize, ExecError> {
         .is_err()
         {
             pagetable.proc_free(size);
-            inode.unlock_put(inner);
+            vnode.put();
             err!(ExecError::Memory);
         }
     }
 
-    inode.unlock_put(inner);
+    vnode.put();
     drop(_op);
 
     let old_size = proc.data().size;
@@ -271,6 +269,9 @@ pub fn exec(path: &Path, argv: &[&str]) -> Result<usize, ExecError> {
             .1,
     );
 
+    // save the arguments for `/proc`
+    data.cmdline = argv.join(" ");
+
     // commit to the user image
     let old_pagetable = data.pagetable.replace(pagetable).unwrap();
     data.size = size;
@@ -286,5 +287,14 @@ pub fn exec(path: &Path, argv: &[&str]) -> Result<usize, ExecError> {
 
     old_pagetable.proc_free(old_size);
 
+    // close the descriptors opened with `O_CLOEXEC`
+    for (file, cloexec) in data.open_files.iter_mut().zip(&mut data.close_on_exec) {
+        if mem::take(cloexec)
+            && let Some(mut file) = file.take()
+        {
+            file.close();
+        }
+    }
+
     Ok(argc) // this end up in a0, the first argument to main(argc, argv)
 }
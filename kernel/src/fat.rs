@@ -0,0 +1,717 @@
+// FAT32 file system, read-only.
+//
+// Lets the kernel read disk images made on the host, e.g. with mtools. Files and directories are
+// read through the buffer cache like any other disk, but nothing is ever written, so there is no
+// log and every call that would change the tree fails with `ReadOnly`.
+//
+// The boot sector gives the layout: reserved sectors, then the file allocation tables, then the
+// data area, which is split into clusters. The FAT maps each cluster of a file to the next one,
+// and a directory is a file of 32-byte entries. Long file names are stored in extra entries in
+// front of the 8.3 entry they belong to, 13 UTF-16 units each, last part first.
+//
+// FAT has no inode numbers, so a node is numbered by its first cluster, which no other file
+// shares. Empty files have no cluster and are numbered by the position of their directory entry,
+// with the top bit set. Nodes are kept in a table while they are referenced.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::char;
+
+use crate::buf::BCACHE;
+use crate::fs::{BSIZE, FsError, InodeType, MAXNAME, Stat};
+use crate::spinlock::SpinLock;
+use crate::vfs::{self, DirEntry, FileOps, FileSystem, NodeId, StatFs, VnodeOps};
+use crate::virtio_disk;
+
+/// Size of a directory entry
+const DIRENT_SIZE: u32 = 32;
+
+// directory entry attributes
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_LONG_NAME: u8 = 0x0F;
+
+/// First byte of a deleted entry
+const DELETED: u8 = 0xE5;
+
+/// Bit of the sequence number that marks the last part of a long name
+const LAST_LONG_ENTRY: u8 = 0x40;
+
+/// FAT entries at or above this end a cluster chain
+const END_OF_CHAIN: u32 = 0x0FFF_FFF8;
+
+/// Bit of the inode number of an empty file
+const EMPTY_INO: u32 = 1 << 31;
+
+/// Layout of the file system, from the boot sector
+#[derive(Debug)]
+struct Layout {
+    bytes_per_sector: u32,
+    sectors_per_cluster: u32,
+    /// First sector of the first FAT
+    fat_start: u32,
+    /// First sector of cluster 2
+    data_start: u32,
+    /// Number of clusters in the data area
+    clusters: u32,
+    root_cluster: u32,
+}
+
+impl Layout {
+    /// Parses the boot sector `sector` of a FAT32 file system.
+    fn parse(sector: &[u8; 512]) -> Result<Self, FsError> {
+        let u16_at = |i: usize| u16::from_le_bytes([sector[i], sector[i + 1]]) as u32;
+        let u32_at = |i: usize| u32::from_le_bytes(sector[i..i + 4].try_into().unwrap());
+
+        let bytes_per_sector = u16_at(11);
+        let sectors_per_cluster = sector[13] as u32;
+        let reserved = u16_at(14);
+        let nfats = sector[16] as u32;
+        let root_entries = u16_at(17);
+        let total = match u16_at(19) {
+            0 => u32_at(32),
+            total => total,
+        };
+        let fat_size = match u16_at(22) {
+            0 => u32_at(36),
+            size => size,
+        };
+        let root_cluster = u32_at(44);
+
+        if sector[510..512] != [0x55, 0xAA]
+            || !bytes_per_sector.is_power_of_two()
+            || !(512..=4096).contains(&bytes_per_sector)
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved == 0
+            || nfats == 0
+        {
+            err!(FsError::BadSuperBlock);
+        }
+
+        // FAT12 and FAT16 keep the root directory outside of the data area
+        if root_entries != 0 || u16_at(22) != 0 {
+            err!(FsError::BadSuperBlock);
+        }
+
+        let data_start = reserved + nfats * fat_size;
+        if total <= data_start {
+            err!(FsError::BadSuperBlock);
+        }
+
+        let clusters = (total - data_start) / sectors_per_cluster;
+        if root_cluster < 2 || root_cluster >= clusters + 2 {
+            err!(FsError::BadSuperBlock);
+        }
+
+        Ok(Self {
+            bytes_per_sector,
+            sectors_per_cluster,
+            fat_start: reserved,
+            data_start,
+            clusters,
+            root_cluster,
+        })
+    }
+
+    /// Counts the free clusters in the first FAT of disk `dev`.
+    fn count_free(&self, dev: u32) -> Result<u32, FsError> {
+        let start = self.fat_start as u64 * self.bytes_per_sector as u64;
+        let end = self.clusters + 2;
+        let per_chunk = (BSIZE / 4) as u32;
+
+        // the kernel stack is too small for a block
+        let mut chunk = vec![0u8; BSIZE];
+        let mut free = 0;
+
+        for first in (0..end).step_by(per_chunk as usize) {
+            let n = (end - first).min(per_chunk) as usize * 4;
+            try_log!(read_disk(
+                dev,
+                start + first as u64 * 4,
+                &mut chunk[..n],
+                false
+            ));
+
+            free += chunk[..n]
+                .as_chunks::<4>()
+                .0
+                .iter()
+                .zip(first..)
+                .filter(|&(&entry, cluster)| {
+                    cluster >= 2 && u32::from_le_bytes(entry) & 0x0FFF_FFFF == 0
+                })
+                .count() as u32;
+        }
+
+        Ok(free)
+    }
+
+    fn cluster_size(&self) -> u32 {
+        self.sectors_per_cluster * self.bytes_per_sector
+    }
+
+    /// Returns the byte position of `cluster` on the disk.
+    fn cluster_pos(&self, cluster: u32) -> u64 {
+        (self.data_start as u64 + (cluster as u64 - 2) * self.sectors_per_cluster as u64)
+            * self.bytes_per_sector as u64
+    }
+}
+
+/// A directory entry, with its long name if it has one
+#[derive(Debug, Clone)]
+struct Entry {
+    name: String,
+    /// First cluster, 0 for an empty file
+    cluster: u32,
+    size: u32,
+    dir: bool,
+    /// Byte position of the 8.3 entry on the disk
+    pos: u64,
+}
+
+impl Entry {
+    fn ino(&self) -> u32 {
+        if self.cluster != 0 {
+            self.cluster
+        } else {
+            EMPTY_INO | (self.pos / DIRENT_SIZE as u64) as u32
+        }
+    }
+}
+
+/// A long name being put together from its entries
+#[derive(Debug)]
+struct LongName {
+    /// Checksum of the 8.3 name it belongs to
+    checksum: u8,
+    /// Sequence number of the next entry expected, the parts come last first
+    next: u8,
+    units: Vec<u16>,
+}
+
+impl LongName {
+    /// Adds the part in long name entry `raw`. Returns None if it does not continue `long`.
+    fn add(long: Option<Self>, raw: &[u8; 32]) -> Option<Self> {
+        let seq = raw[0] & !LAST_LONG_ENTRY;
+
+        let mut long = if raw[0] & LAST_LONG_ENTRY != 0 {
+            LongName {
+                checksum: raw[13],
+                next: seq,
+                units: Vec::new(),
+            }
+        } else {
+            long.filter(|long| long.checksum == raw[13])?
+        };
+
+        if seq == 0 || seq != long.next {
+            return None;
+        }
+
+        let mut part: Vec<u16> = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30]
+            .iter()
+            .map(|&i| u16::from_le_bytes([raw[i], raw[i + 1]]))
+            .take_while(|&unit| unit != 0 && unit != 0xFFFF)
+            .collect();
+
+        // parts come last first
+        part.append(&mut long.units);
+        long.units = part;
+        long.next -= 1;
+
+        Some(long)
+    }
+
+    /// Returns the name if all of its parts were found for the 8.3 name with `checksum`.
+    fn finish(self, checksum: u8) -> Option<String> {
+        if self.next != 0 || self.checksum != checksum {
+            return None;
+        }
+
+        let name: String = char::decode_utf16(self.units.iter().copied())
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect();
+
+        (!name.is_empty() && name.len() <= MAXNAME).then_some(name)
+    }
+}
+
+/// Returns the checksum of an 8.3 name, which its long name entries carry.
+fn checksum(short: &[u8]) -> u8 {
+    short
+        .iter()
+        .fold(0u8, |sum, &c| sum.rotate_right(1).wrapping_add(c))
+}
+
+/// Returns the 8.3 name of entry `raw` as `NAME.EXT`, lowercasing the parts flagged as lowercase.
+fn short_name(raw: &[u8; 32]) -> String {
+    let mut base = raw[..8].to_vec();
+    if base[0] == 0x05 {
+        base[0] = DELETED;
+    }
+
+    let part = |bytes: &[u8], lower: bool| -> String {
+        bytes
+            .iter()
+            .map(|&c| char::from(c))
+            .map(|c| if lower { c.to_ascii_lowercase() } else { c })
+            .collect::<String>()
+            .trim_end()
+            .into()
+    };
+
+    let mut name = part(&base, raw[12] & 0x08 != 0);
+    let ext = part(&raw[8..11], raw[12] & 0x10 != 0);
+    if !ext.is_empty() {
+        name.push('.');
+        name.push_str(&ext);
+    }
+
+    name
+}
+
+/// A node of the table
+#[derive(Debug, Clone, Copy)]
+struct FatNode {
+    ino: u32,
+    cluster: u32,
+    size: u32,
+    dir: bool,
+    refs: u32,
+}
+
+/// A read-only FAT32 file system on a disk
+#[derive(Debug)]
+pub struct FatFs {
+    dev: u32,
+    layout: Layout,
+    /// Free clusters, counted at mount since nothing is allocated afterwards
+    free: u32,
+    /// Nodes in use, indexed by `NodeId::id`. Slots with no references are reused.
+    nodes: SpinLock<Vec<FatNode>>,
+}
+
+impl FatFs {
+    /// Mounts the FAT32 file system on disk `dev`.
+    pub fn mount(dev: u32) -> Result<Arc<dyn FileSystem>, FsError> {
+        if !virtio_disk::present(dev) {
+            err!(FsError::NoDevice);
+        }
+
+        let mut sector = [0u8; 512];
+        try_log!(read_disk(dev, 0, &mut sector, false));
+        let layout = try_log!(Layout::parse(&sector));
+        let free = try_log!(layout.count_free(dev));
+
+        Ok(Arc::new(FatFs {
+            dev,
+            layout,
+            free,
+            nodes: SpinLock::new(Vec::new(), "fat"),
+        }))
+    }
+
+    /// Takes a reference to node `ino`, adding it to the table unless it is there.
+    fn get(&self, ino: u32, cluster: u32, size: u32, dir: bool) -> NodeId {
+        let mut nodes = self.nodes.lock();
+
+        let id = match nodes.iter().position(|n| n.refs > 0 && n.ino == ino) {
+            Some(id) => id,
+            None => {
+                let node = FatNode {
+                    ino,
+                    cluster,
+                    size,
+                    dir,
+                    refs: 0,
+                };
+
+                match nodes.iter().position(|n| n.refs == 0) {
+                    Some(id) => {
+                        nodes[id] = node;
+                        id
+                    }
+                    None => {
+                        nodes.push(node);
+                        nodes.len() - 1
+                    }
+                }
+            }
+        };
+
+        nodes[id].refs += 1;
+        NodeId { id, ino }
+    }
+
+    fn get_entry(&self, entry: &Entry) -> NodeId {
+        self.get(entry.ino(), entry.cluster, entry.size, entry.dir)
+    }
+
+    fn get_dir(&self, cluster: u32) -> NodeId {
+        self.get(cluster, cluster, 0, true)
+    }
+
+    fn node(&self, node: NodeId) -> FatNode {
+        self.nodes.lock()[node.id]
+    }
+
+    /// Returns the cluster after `cluster` in its chain, or None at the end.
+    fn next_cluster(&self, cluster: u32) -> Result<Option<u32>, FsError> {
+        let layout = &self.layout;
+
+        let pos = layout.fat_start as u64 * layout.bytes_per_sector as u64 + cluster as u64 * 4;
+        let mut entry = [0u8; 4];
+        try_log!(read_disk(self.dev, pos, &mut entry, false));
+
+        match u32::from_le_bytes(entry) & 0x0FFF_FFFF {
+            next if next >= END_OF_CHAIN => Ok(None),
+            // free or bad clusters do not belong in a chain
+            next if next < 2 || next >= layout.clusters + 2 => err!(FsError::Read),
+            next => Ok(Some(next)),
+        }
+    }
+
+    /// Follows the chain from `cluster` for `n` clusters.
+    fn skip_clusters(&self, mut cluster: u32, n: u32) -> Result<Option<u32>, FsError> {
+        // no chain is longer than the data area, so a longer walk is going around a cycle
+        if n >= self.layout.clusters {
+            err!(FsError::Read);
+        }
+
+        for _ in 0..n {
+            match try_log!(self.next_cluster(cluster)) {
+                Some(next) => cluster = next,
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(cluster))
+    }
+
+    /// Returns the first cluster of the parent of directory `cluster`, from its `..` entry.
+    fn parent(&self, cluster: u32) -> Result<u32, FsError> {
+        if cluster == self.layout.root_cluster {
+            return Ok(cluster);
+        }
+
+        let mut raw = [0u8; 32];
+        let pos = self.layout.cluster_pos(cluster) + DIRENT_SIZE as u64;
+        try_log!(read_disk(self.dev, pos, &mut raw, false));
+
+        if raw[..11] != *b"..         " {
+            err!(FsError::Read);
+        }
+
+        // the root is cluster 0 in `..`
+        match cluster_of(&raw) {
+            0 => Ok(self.layout.root_cluster),
+            parent => Ok(parent),
+        }
+    }
+
+    /// Calls `f` with the entries of directory `cluster`, starting at entry index `start`, and the
+    /// index of the entry after each, until it returns Some. `.` and `..` are left out.
+    fn scan<T>(
+        &self,
+        cluster: u32,
+        start: u32,
+        mut f: impl FnMut(Entry, u32) -> Option<T>,
+    ) -> Result<Option<T>, FsError> {
+        if cluster < 2 || cluster >= self.layout.clusters + 2 {
+            err!(FsError::Read);
+        }
+
+        let per_cluster = self.layout.cluster_size() / DIRENT_SIZE;
+
+        let mut followed = start / per_cluster;
+        let Some(mut cluster) = try_log!(self.skip_clusters(cluster, followed)) else {
+            return Ok(None);
+        };
+
+        let mut index = start;
+        let mut long: Option<LongName> = None;
+
+        loop {
+            let pos =
+                self.layout.cluster_pos(cluster) + ((index % per_cluster) * DIRENT_SIZE) as u64;
+            let mut raw = [0u8; 32];
+            try_log!(read_disk(self.dev, pos, &mut raw, false));
+            index += 1;
+
+            let attr = raw[11];
+
+            match raw[0] {
+                // no entries past this one
+                0 => return Ok(None),
+                DELETED => long = None,
+                _ if attr & 0x3F == ATTR_LONG_NAME => long = LongName::add(long.take(), &raw),
+                _ if attr & ATTR_VOLUME_ID != 0 || raw[0] == b'.' => long = None,
+                _ => {
+                    let name = long
+                        .take()
+                        .and_then(|long| long.finish(checksum(&raw[..11])))
+                        .unwrap_or_else(|| short_name(&raw));
+
+                    let entry = Entry {
+                        name,
+                        cluster: cluster_of(&raw),
+                        size: u32::from_le_bytes(raw[28..32].try_into().unwrap()),
+                        dir: attr & ATTR_DIRECTORY != 0,
+                        pos,
+                    };
+
+                    if let Some(result) = f(entry, index) {
+                        return Ok(Some(result));
+                    }
+                }
+            }
+
+            if index.is_multiple_of(per_cluster) {
+                followed += 1;
+                if followed >= self.layout.clusters {
+                    err!(FsError::Read);
+                }
+
+                match try_log!(self.next_cluster(cluster)) {
+                    Some(next) => cluster = next,
+                    None => return Ok(None),
+                }
+            }
+        }
+    }
+}
+
+/// Returns the first cluster of directory entry `raw`.
+fn cluster_of(raw: &[u8; 32]) -> u32 {
+    let high = u16::from_le_bytes([raw[20], raw[21]]) as u32;
+    let low = u16::from_le_bytes([raw[26], raw[27]]) as u32;
+    high << 16 | low
+}
+
+/// Reads `dst.len()` bytes at byte position `pos` of disk `dev` through the buffer cache, into
+/// user space if `user`.
+fn read_disk(dev: u32, pos: u64, dst: &mut [u8], user: bool) -> Result<(), FsError> {
+    let mut done = 0;
+
+    while done < dst.len() {
+        let pos = pos + done as u64;
+        let Ok(block) = u32::try_from(pos / BSIZE as u64) else {
+            err!(FsError::OutOfRange);
+        };
+        let start = (pos % BSIZE as u64) as usize;
+        let n = (BSIZE - start).min(dst.len() - done);
+
+        let buf = BCACHE.read(dev, block);
+        let result = vfs::copy_out(
+            &buf.data()[start..start + n],
+            &mut dst[done..done + n],
+            user,
+        );
+        BCACHE.release(buf);
+
+        try_log!(result);
+        done += n;
+    }
+
+    Ok(())
+}
+
+impl FileSystem for FatFs {
+    fn dev(&self) -> u32 {
+        self.dev
+    }
+
+    fn root(&self) -> Result<NodeId, FsError> {
+        Ok(self.get_dir(self.layout.root_cluster))
+    }
+
+    fn statfs(&self) -> StatFs {
+        StatFs {
+            bsize: self.layout.cluster_size(),
+            blocks: self.layout.clusters,
+            bfree: self.free,
+            ..Default::default()
+        }
+    }
+
+    fn busy(&self) -> bool {
+        self.nodes.lock().iter().any(|n| n.refs > 0)
+    }
+
+    fn unmount(&self) {
+        // nothing to write back
+    }
+}
+
+impl VnodeOps for FatFs {
+    fn dup(&self, node: NodeId) {
+        self.nodes.lock()[node.id].refs += 1;
+    }
+
+    fn put(&self, node: NodeId) {
+        let mut nodes = self.nodes.lock();
+        assert!(nodes[node.id].refs >= 1, "fat put");
+        nodes[node.id].refs -= 1;
+    }
+
+    fn stat(&self, node: NodeId) -> Result<Stat, FsError> {
+        let fat = self.node(node);
+
+        Ok(Stat {
+            dev: self.dev,
+            ino: node.ino,
+            r#type: if fat.dir {
+                InodeType::Directory
+            } else {
+                InodeType::File
+            },
+            major: 0,
+            minor: 0,
+            nlink: 1,
+            size: fat.size as u64,
+        })
+    }
+
+    fn lookup(&self, dir: NodeId, name: &str) -> Result<Option<NodeId>, FsError> {
+        let dir = self.node(dir);
+
+        if !dir.dir {
+            err!(FsError::NotDirectory);
+        }
+
+        match name {
+            "." => return Ok(Some(self.get_dir(dir.cluster))),
+            ".." => return Ok(Some(self.get_dir(try_log!(self.parent(dir.cluster))))),
+            _ => {}
+        }
+
+        // names are not case sensitive
+        let entry = try_log!(self.scan(dir.cluster, 0, |entry, _| {
+            entry.name.eq_ignore_ascii_case(name).then_some(entry)
+        }));
+
+        Ok(entry.map(|entry| self.get_entry(&entry)))
+    }
+
+    fn create(
+        &self,
+        _dir: NodeId,
+        _name: &str,
+        _type: InodeType,
+        _major: u16,
+        _minor: u16,
+        _exclusive: bool,
+    ) -> Result<NodeId, FsError> {
+        err!(FsError::ReadOnly)
+    }
+
+    fn link(&self, _dir: NodeId, _name: &str, _node: NodeId) -> Result<(), FsError> {
+        err!(FsError::ReadOnly)
+    }
+
+    fn unlink(&self, _dir: NodeId, _name: &str) -> Result<(), FsError> {
+        err!(FsError::ReadOnly)
+    }
+
+    fn readdir(&self, dir: NodeId, offset: u32) -> Result<Option<(DirEntry, u32)>, FsError> {
+        let dir = self.node(dir);
+
+        if !dir.dir {
+            err!(FsError::NotDirectory);
+        }
+
+        // offsets past `.` and `..` are entry indexes, shifted by 2
+        let (name, ino, next) = match offset {
+            0 => (String::from("."), dir.cluster, 1),
+            1 => (String::from(".."), try_log!(self.parent(dir.cluster)), 2),
+            _ => {
+                let found = try_log!(self.scan(dir.cluster, offset - 2, |entry, next| {
+                    Some((entry, next))
+                }));
+
+                match found {
+                    Some((entry, next)) => (entry.name.clone(), entry.ino(), next + 2),
+                    None => return Ok(None),
+                }
+            }
+        };
+
+        Ok(Some((DirEntry { ino, name }, next)))
+    }
+}
+
+impl FileOps for FatFs {
+    fn read(&self, node: NodeId, offset: u32, dst: &mut [u8], user: bool) -> Result<u32, FsError> {
+        let fat = self.node(node);
+
+        if fat.dir {
+            err!(FsError::Type);
+        }
+
+        if offset >= fat.size {
+            return Ok(0);
+        }
+
+        if fat.cluster < 2 || fat.cluster >= self.layout.clusters + 2 {
+            err!(FsError::Read);
+        }
+
+        let n = dst.len().min((fat.size - offset) as usize);
+        let cluster_size = self.layout.cluster_size();
+
+        let mut followed = offset / cluster_size;
+        let Some(mut cluster) = try_log!(self.skip_clusters(fat.cluster, followed)) else {
+            err!(FsError::Read);
+        };
+
+        let mut done = 0;
+        let mut start = offset % cluster_size;
+
+        loop {
+            let chunk = ((cluster_size - start) as usize).min(n - done);
+            let pos = self.layout.cluster_pos(cluster) + start as u64;
+            try_log!(read_disk(self.dev, pos, &mut dst[done..done + chunk], user));
+
+            done += chunk;
+            start = 0;
+
+            if done == n {
+                break;
+            }
+
+            followed += 1;
+            if followed >= self.layout.clusters {
+                err!(FsError::Read);
+            }
+
+            match try_log!(self.next_cluster(cluster)) {
+                Some(next) => cluster = next,
+                // the chain is shorter than the size says
+                None => err!(FsError::Read),
+            }
+        }
+
+        Ok(n as u32)
+    }
+
+    fn write(
+        &self,
+        _node: NodeId,
+        _offset: &mut u32,
+        _src: &[u8],
+        _user: bool,
+        _append: bool,
+    ) -> Result<u32, FsError> {
+        err!(FsError::ReadOnly)
+    }
+
+    fn truncate(&self, _node: NodeId, _size: usize) -> Result<(), FsError> {
+        err!(FsError::ReadOnly)
+    }
+
+    fn sync(&self, _node: NodeId) -> Result<(), FsError> {
+        // nothing to write back
+        Ok(())
+    }
+}
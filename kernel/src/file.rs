@@ -4,28 +4,58 @@ use core::slice;
 use alloc::sync::Arc;
 
 use crate::console::Console;
-use crate::fs::{BSIZE, FsError, Inode, Stat};
+use crate::fs::{FsError, InodeType, Stat};
 use crate::log::Operation;
-use crate::param::{MAXOPBLOCKS, NDEV, NFILE};
+use crate::memdev::{Full, Null, Random, Zero};
+use crate::param::{NDEV, NFILE};
 use crate::pipe::Pipe;
-use crate::proc;
+use crate::proc::{self, Channel};
 use crate::sleeplock::SleepLock;
+use crate::socket::Socket;
 use crate::spinlock::SpinLock;
 use crate::syscall::SysError;
+use crate::vfs::Vnode;
 use crate::vm::VA;
 
 #[derive(Debug, Clone)]
 pub enum FileType {
     None,
-    Pipe { pipe: Arc<Pipe> },
-    Inode { inode: Inode },
-    Device { inode: Inode, major: u16 },
+    Pipe {
+        pipe: Arc<Pipe>,
+    },
+    Vnode {
+        vnode: Vnode,
+    },
+    Device {
+        vnode: Vnode,
+        major: u16,
+    },
+    /// An open named pipe, which holds the FIFO's vnode while its data goes through `pipe`
+    Fifo {
+        vnode: Vnode,
+        pipe: Arc<Pipe>,
+    },
+    /// A Unix domain socket, see socket.rs
+    Socket {
+        socket: Arc<Socket>,
+    },
 }
 
 /// File metadata protected by table-wide spinlock
 #[derive(Debug, Clone)]
 pub struct FileMeta {
     pub ref_count: usize,
+    /// Advisory lock held through this file
+    pub flock: Option<FileLock>,
+}
+
+/// Advisory lock taken with `flock`
+#[derive(Debug, Clone, Copy)]
+pub struct FileLock {
+    /// Device and inode number of the locked file
+    pub dev: u32,
+    pub ino: u32,
+    pub exclusive: bool,
 }
 
 /// Per-file mutable state protected by per-file sleeplock
@@ -36,6 +66,10 @@ pub struct FileInner {
     pub writeable: bool,
     pub r#type: FileType,
     pub offset: u32,
+    /// Writes go to the end of the file (`O_APPEND`)
+    pub append: bool,
+    /// Reads and writes fail instead of waiting (`O_NONBLOCK`)
+    pub nonblock: bool,
 }
 
 pub static FILE_TABLE: FileTable = FileTable::new();
@@ -57,7 +91,10 @@ impl FileTable {
 
             let mut i = 0;
             while i < NFILE {
-                array[i] = MaybeUninit::new(FileMeta { ref_count: 0 });
+                array[i] = MaybeUninit::new(FileMeta {
+                    ref_count: 0,
+                    flock: None,
+                });
                 i += 1;
             }
 
@@ -81,6 +118,8 @@ impl FileTable {
                         writeable: false,
                         r#type: FileType::None,
                         offset: 0,
+                        append: false,
+                        nonblock: false,
                     },
                     "file",
                 ));
@@ -144,6 +183,10 @@ impl File {
             return;
         }
 
+        if let Some(lock) = meta.flock.take() {
+            proc::wakeup(Channel::Flock(lock.ino as usize));
+        }
+
         let inner_copy = {
             let mut inner = FILE_TABLE.inner[self.id].lock();
             // copy inner before resetting fields
@@ -161,10 +204,16 @@ impl File {
             FileType::Pipe { pipe } => {
                 pipe.close(inner_copy.writeable);
             }
-            FileType::Inode { inode } | FileType::Device { inode, .. } => {
+            FileType::Fifo { vnode, pipe } => {
+                Pipe::close_fifo(&pipe, inner_copy.readable, inner_copy.writeable);
+                let _op = Operation::begin();
+                vnode.put();
+            }
+            FileType::Vnode { vnode } | FileType::Device { vnode, .. } => {
                 let _op = Operation::begin();
-                inode.put();
+                vnode.put();
             }
+            FileType::Socket { socket } => socket.close(),
         }
     }
 
@@ -173,10 +222,10 @@ impl File {
         let file_inner = FILE_TABLE.inner[self.id].lock();
 
         match &file_inner.r#type {
-            FileType::Inode { inode } | FileType::Device { inode, .. } => {
-                let inode_inner = inode.lock();
-                let stat = inode.stat(&inode_inner);
-                inode.unlock(inode_inner);
+            FileType::Vnode { vnode }
+            | FileType::Device { vnode, .. }
+            | FileType::Fifo { vnode, .. } => {
+                let stat = try_log!(vnode.stat());
 
                 let src = unsafe {
                     slice::from_raw_parts(&stat as *const _ as *const u8, size_of::<Stat>())
@@ -191,6 +240,125 @@ impl File {
         }
     }
 
+    /// Takes or releases an advisory lock on the file's inode, as `op` from `LockFlag` says.
+    /// Locks belong to the open file, so they are shared by duplicated descriptors and released
+    /// by the last close.
+    pub fn flock(&self, op: usize) -> Result<(), SysError> {
+        let (dev, ino) = match &FILE_TABLE.inner[self.id].lock().r#type {
+            FileType::Vnode { vnode }
+            | FileType::Device { vnode, .. }
+            | FileType::Fifo { vnode, .. } => (vnode.dev(), vnode.ino()),
+            _ => err!(SysError::InvalidArgument),
+        };
+
+        let exclusive = match op & !LockFlag::NONBLOCK {
+            LockFlag::SHARED => false,
+            LockFlag::EXCLUSIVE => true,
+            LockFlag::UNLOCK => {
+                if FILE_TABLE.meta.lock()[self.id].flock.take().is_some() {
+                    proc::wakeup(Channel::Flock(ino as usize));
+                }
+                return Ok(());
+            }
+            _ => err!(SysError::InvalidArgument),
+        };
+
+        let mut meta = FILE_TABLE.meta.lock();
+
+        // converting a lock releases it first, so two processes upgrading at once cannot deadlock
+        if meta[self.id].flock.take().is_some() {
+            proc::wakeup(Channel::Flock(ino as usize));
+        }
+
+        loop {
+            let conflict = meta.iter().any(|other| {
+                other.flock.is_some_and(|lock| {
+                    lock.dev == dev && lock.ino == ino && (exclusive || lock.exclusive)
+                })
+            });
+
+            if !conflict {
+                break;
+            }
+
+            if op & LockFlag::NONBLOCK != 0 {
+                err!(SysError::ResourceUnavailable);
+            }
+
+            if proc::current_proc().is_killed() {
+                err!(SysError::Interrupted);
+            }
+
+            meta = proc::sleep(Channel::Flock(ino as usize), meta);
+        }
+
+        meta[self.id].flock = Some(FileLock {
+            dev,
+            ino,
+            exclusive,
+        });
+        Ok(())
+    }
+
+    /// Waits until all changes made to the file are on disk.
+    pub fn sync(&self) -> Result<(), SysError> {
+        let file_inner = FILE_TABLE.inner[self.id].lock();
+
+        match &file_inner.r#type {
+            FileType::Vnode { vnode } | FileType::Device { vnode, .. } => {
+                let vnode = vnode.clone();
+                drop(file_inner);
+                try_log!(vnode.sync());
+                Ok(())
+            }
+            _ => Err(SysError::InvalidArgument),
+        }
+    }
+
+    /// Sets the size of the file to `size` bytes.
+    pub fn truncate(&self, size: usize) -> Result<(), SysError> {
+        let file_inner = FILE_TABLE.inner[self.id].lock();
+
+        if !file_inner.writeable {
+            err!(SysError::InvalidArgument);
+        }
+
+        match &file_inner.r#type {
+            FileType::Vnode { vnode } => {
+                try_log!(vnode.truncate(size));
+                Ok(())
+            }
+            _ => Err(SysError::InvalidArgument),
+        }
+    }
+
+    /// Returns the access mode and the `APPEND` and `NONBLOCK` open flags of the file.
+    pub fn flags(&self) -> usize {
+        let file_inner = FILE_TABLE.inner[self.id].lock();
+
+        let mut flags = match (file_inner.readable, file_inner.writeable) {
+            (true, true) => OpenFlag::READ_WRITE,
+            (false, true) => OpenFlag::WRITE_ONLY,
+            _ => OpenFlag::READ_ONLY,
+        };
+
+        if file_inner.append {
+            flags |= OpenFlag::APPEND;
+        }
+        if file_inner.nonblock {
+            flags |= OpenFlag::NONBLOCK;
+        }
+
+        flags
+    }
+
+    /// Sets the `APPEND` and `NONBLOCK` flags of the file, ignoring the rest of `flags`.
+    pub fn set_flags(&self, flags: usize) {
+        let mut file_inner = FILE_TABLE.inner[self.id].lock();
+        file_inner.append = flags & OpenFlag::APPEND != 0;
+        file_inner.nonblock = flags & OpenFlag::NONBLOCK != 0;
+    }
+
     /// Reads from file.
     pub fn read(&self, addr: VA, n: usize) -> Result<usize, SysError> {
         let mut file_inner = FILE_TABLE.inner[self.id].lock();
@@ -199,33 +367,44 @@ impl File {
             err!(SysError::BadDescriptor);
         }
 
+        let nonblock = file_inner.nonblock;
+
         match &mut file_inner.r#type {
             FileType::None => panic!("fileread"),
 
-            FileType::Pipe { pipe } => pipe.read(addr, n),
+            FileType::Pipe { pipe } | FileType::Fifo { pipe, .. } => pipe.read(addr, n, nonblock),
 
-            FileType::Inode { inode } => {
-                let inode = inode.clone();
-                let mut inode_inner = inode.lock();
+            // both ends of a connection can be used through the same file at once
+            FileType::Socket { socket } => {
+                let socket = Arc::clone(socket);
+                drop(file_inner);
+                socket.read(addr, n, nonblock)
+            }
 
+            FileType::Vnode { vnode } => {
+                let vnode = vnode.clone();
                 let dst = unsafe { slice::from_raw_parts_mut(addr.as_mut_ptr(), n) };
-                let read = log!(inode.read(&mut inode_inner, file_inner.offset, dst, true));
-
-                if let Ok(read) = read {
-                    file_inner.offset += read;
-                }
-
-                inode.unlock(inode_inner);
 
-                if let Ok(read) = read {
-                    Ok(read as usize)
+                // directories read as blocks of records, whatever file system they are on
+                let read = if vnode.r#type() == Ok(InodeType::Directory) {
+                    log!(vnode.read_dir(&mut file_inner.offset, dst))
                 } else {
-                    err!(SysError::IoError);
+                    let read = log!(vnode.read(file_inner.offset, dst, true));
+                    if let Ok(read) = read {
+                        file_inner.offset += read;
+                    }
+                    read
+                };
+
+                match read {
+                    Ok(read) => Ok(read as usize),
+                    Err(FsError::OutOfRange) => err!(SysError::InvalidArgument),
+                    Err(_) => err!(SysError::IoError),
                 }
             }
 
-            FileType::Device { inode: _, major } => match &DEVICES[*major as usize] {
-                Some(dev) => (dev.read)(addr, n),
+            FileType::Device { vnode: _, major } => match &DEVICES[*major as usize] {
+                Some(dev) => (dev.read)(addr, n, nonblock),
                 None => err!(SysError::NoEntry),
             },
         }
@@ -239,52 +418,31 @@ impl File {
             err!(SysError::BadDescriptor);
         }
 
+        let nonblock = file_inner.nonblock;
+
         match &mut file_inner.r#type {
             FileType::None => panic!("filewrite"),
 
-            FileType::Pipe { pipe } => pipe.write(addr, n),
-
-            FileType::Inode { inode } => {
-                let inode = inode.clone();
+            FileType::Pipe { pipe } | FileType::Fifo { pipe, .. } => pipe.write(addr, n, nonblock),
 
-                // write a few block at a time to avoid exceeding the maximum log transaction size,
-                // including inode, indirect block, allocation blocks, and 2 block of slop for
-                // non-aligned writes.
-                let max = ((MAXOPBLOCKS - 1 - 1 - 2) / 2) * BSIZE;
-                let mut i = 0;
-
-                while i < n {
-                    let n1 = (n - i).min(max);
-
-                    let _op = Operation::begin();
-                    let mut inode_inner = inode.lock();
-
-                    let src =
-                        unsafe { slice::from_raw_parts((addr.as_usize() + i) as *const u8, n1) };
-                    let write = log!(inode.write(&mut inode_inner, file_inner.offset, src, true));
-
-                    if let Ok(w) = write {
-                        file_inner.offset += w;
-                    }
-
-                    inode.unlock(inode_inner);
-                    drop(_op);
+            FileType::Socket { socket } => {
+                let socket = Arc::clone(socket);
+                drop(file_inner);
+                socket.write(addr, n, nonblock)
+            }
 
-                    if write.is_err() {
-                        break;
-                    }
+            FileType::Vnode { vnode } => {
+                let vnode = vnode.clone();
+                let append = file_inner.append;
+                let src = unsafe { slice::from_raw_parts(addr.as_usize() as *const u8, n) };
 
-                    i += write.unwrap() as usize;
-                }
-
-                if i == n {
-                    Ok(n)
-                } else {
-                    err!(SysError::IoError);
+                match log!(vnode.write(&mut file_inner.offset, src, true, append)) {
+                    Ok(_) => Ok(n),
+                    Err(_) => err!(SysError::IoError),
                 }
             }
 
-            FileType::Device { inode: _, major } => match &DEVICES[*major as usize] {
+            FileType::Device { vnode: _, major } => match &DEVICES[*major as usize] {
                 Some(dev) => (dev.write)(addr, n),
                 None => err!(SysError::NoEntry),
             },
@@ -301,24 +459,108 @@ impl OpenFlag {
     pub const READ_WRITE: usize = 0x002;
     pub const CREATE: usize = 0x200;
     pub const TRUNCATE: usize = 0x400;
+    /// Every write appends to the end of the file
+    pub const APPEND: usize = 0x800;
+    /// With `CREATE`, fail if the file already exists
+    pub const EXCLUSIVE: usize = 0x1000;
+    /// Fail with `ResourceUnavailable` instead of waiting on pipes and the console
+    pub const NONBLOCK: usize = 0x2000;
+    /// Fail unless the path is a directory
+    pub const DIRECTORY: usize = 0x4000;
+    /// Close the descriptor on `exec`
+    pub const CLOEXEC: usize = 0x8000;
+}
+
+/// `flock` operations
+pub struct LockFlag;
+
+impl LockFlag {
+    /// Take a shared lock
+    pub const SHARED: usize = 0x1;
+    /// Take an exclusive lock
+    pub const EXCLUSIVE: usize = 0x2;
+    /// Fail with `ResourceUnavailable` instead of waiting for a conflicting lock
+    pub const NONBLOCK: usize = 0x4;
+    /// Release the lock
+    pub const UNLOCK: usize = 0x8;
+}
+
+/// `fcntl` commands
+pub struct Fcntl;
+
+impl Fcntl {
+    /// Duplicate into the lowest free descriptor not below the argument
+    pub const DUPFD: usize = 0;
+    /// Get the descriptor flags
+    pub const GETFD: usize = 1;
+    /// Set the descriptor flags
+    pub const SETFD: usize = 2;
+    /// Get the open flags
+    pub const GETFL: usize = 3;
+    /// Set the `APPEND` and `NONBLOCK` open flags
+    pub const SETFL: usize = 4;
+
+    /// Descriptor flag: close on `exec`
+    pub const FD_CLOEXEC: usize = 1;
+}
+
+/// Directory descriptor and flags of the `*at` syscalls
+pub struct At;
+
+impl At {
+    /// Resolve relative paths from the current directory, in place of a directory descriptor
+    pub const FDCWD: usize = -100isize as usize;
+    /// `unlinkat`: remove a directory
+    pub const REMOVEDIR: usize = 0x200;
 }
 
 /// Device interface
 #[derive(Debug, Clone, Copy)]
 pub struct Device {
-    pub read: fn(addr: VA, n: usize) -> Result<usize, SysError>,
+    /// Name of the device in `/dev`
+    pub name: &'static str,
+    pub read: fn(addr: VA, n: usize, nonblock: bool) -> Result<usize, SysError>,
     pub write: fn(addr: VA, n: usize) -> Result<usize, SysError>,
 }
 
 /// Console device major number
 pub const CONSOLE: usize = 1;
+/// Null device major number
+pub const NULL: usize = 2;
+/// Zero device major number
+pub const ZERO: usize = 3;
+/// Full device major number
+pub const FULL: usize = 4;
+/// Random device major number
+pub const RANDOM: usize = 5;
 
 /// Device table
 pub static DEVICES: [Option<Device>; NDEV] = {
     let mut devices = [None; NDEV];
     devices[CONSOLE] = Some(Device {
+        name: "console",
         read: Console::read,
         write: Console::write,
     });
+    devices[NULL] = Some(Device {
+        name: "null",
+        read: Null::read,
+        write: Null::write,
+    });
+    devices[ZERO] = Some(Device {
+        name: "zero",
+        read: Zero::read,
+        write: Zero::write,
+    });
+    devices[FULL] = Some(Device {
+        name: "full",
+        read: Full::read,
+        write: Full::write,
+    });
+    devices[RANDOM] = Some(Device {
+        name: "random",
+        read: Random::read,
+        write: Random::write,
+    });
     devices
 };
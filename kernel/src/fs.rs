@@ -1,19 +1,34 @@
+use core::cmp::Reverse;
 use core::fmt::Display;
 use core::mem::{self, MaybeUninit};
 use core::ptr;
 use core::slice;
 
-use crate::buf::{BCACHE, Buf};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::buf::{self, BCACHE, Buf};
+use crate::crc;
 use crate::log::{self, Operation};
-use crate::param::{NINODE, ROOTDEV};
+use crate::mount;
+use crate::param::{MAXOPBLOCKS, NDISK, NINODE, READAHEAD, ROOTDEV};
 use crate::proc;
 use crate::sleeplock::{SleepLock, SleepLockGuard};
 use crate::spinlock::SpinLock;
 use crate::sync::OnceLock;
+use crate::vfs::{DirEntry, FileOps, FileSystem, NodeId, StatFs, VnodeOps};
+use crate::virtio_disk;
 use crate::vm::VA;
 
 /// File system magic number
 pub const FSMAGIC: u32 = 0x10203040;
+/// Feature flag: metadata blocks end with a checksum, see `Checksum`
+pub const FEATURE_CSUM: u32 = 0x1;
+/// Features this kernel understands
+const FEATURES: u32 = FEATURE_CSUM;
+/// Bytes at the end of a metadata block holding its checksum
+pub const CSUMSIZE: usize = size_of::<u32>();
 
 /// Root inode number
 pub const ROOTINO: u32 = 1;
@@ -30,8 +45,20 @@ pub const MAXFILE: usize = NDIRECT + NINDIRECT;
 pub const IPB: u32 = (BSIZE / size_of::<DiskInode>()) as u32;
 /// Bitmap bits per block
 pub const BPB: u32 = BSIZE as u32 * 8;
-/// Directory entry name size
-pub const DIRSIZE: usize = 14;
+/// Maximum length of a directory entry name
+pub const MAXNAME: usize = 255;
+/// Directory index magic number
+pub const DXMAGIC: u32 = 0x64786964;
+
+/// Number of slots in the orphan block
+const NORPHAN: usize = BSIZE / size_of::<u32>();
+/// Free map blocks a truncation may touch inside the caller's operation
+const TRUNCINLINE: usize = 2;
+/// Free map blocks each follow-up truncation operation may touch, leaving room for the inode,
+/// indirect, and orphan blocks
+const TRUNCSTEP: usize = MAXOPBLOCKS - 3;
+/// Source of the zeros read from holes in sparse files
+static ZEROS: [u8; BSIZE] = [0; BSIZE];
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FsError {
@@ -47,6 +74,16 @@ pub enum FsError {
     Resolve,
     Type,
     Copy,
+    NameTooLong,
+    NoDevice,
+    BadSuperBlock,
+    Busy,
+    NotDirectory,
+    NotEmpty,
+    NotPermitted,
+    CrossDevice,
+    NotMounted,
+    ReadOnly,
 }
 
 impl Display for FsError {
@@ -64,11 +101,27 @@ impl Display for FsError {
             FsError::Resolve => write!(f, "resolve error"),
             FsError::Type => write!(f, "type error"),
             FsError::Copy => write!(f, "copy error"),
+            FsError::NameTooLong => write!(f, "name too long"),
+            FsError::NoDevice => write!(f, "no such device"),
+            FsError::BadSuperBlock => write!(f, "bad superblock"),
+            FsError::Busy => write!(f, "busy"),
+            FsError::NotDirectory => write!(f, "not a directory"),
+            FsError::NotEmpty => write!(f, "directory not empty"),
+            FsError::NotPermitted => write!(f, "not permitted"),
+            FsError::CrossDevice => write!(f, "cross-device link"),
+            FsError::NotMounted => write!(f, "not mounted"),
+            FsError::ReadOnly => write!(f, "read-only file system"),
         }
     }
 }
 
-pub static SB: OnceLock<SuperBlock> = OnceLock::new();
+/// Superblock of each disk, read when it is first mounted
+static SB: [OnceLock<SuperBlock>; NDISK] = [const { OnceLock::new() }; NDISK];
+
+/// Returns the superblock of mounted device `dev`.
+pub fn superblock(dev: u32) -> &'static SuperBlock {
+    SB[dev as usize - 1].get().expect("superblock")
+}
 
 /// On-disk superblock (read at boot)
 #[repr(C)]
@@ -90,26 +143,264 @@ pub struct SuperBlock {
     pub inodestart: u32,
     /// Block number of first free map block
     pub bmapstart: u32,
+    /// Block number of the orphan block
+    pub orphan: u32,
+    /// Optional format features (`FEATURE_*`)
+    pub features: u32,
+    /// CRC32C of the fields above, if `FEATURE_CSUM` is set
+    pub checksum: u32,
 }
 
 impl SuperBlock {
-    /// Reads the superblock from disk and initializes the global `SB`.
-    fn initialize(dev: u32) {
+    /// Returns whether metadata blocks carry checksums.
+    pub fn checksummed(&self) -> bool {
+        self.features & FEATURE_CSUM != 0
+    }
+
+    /// Reads the superblock of device `dev` from disk into `SB`, unless it is already there.
+    fn initialize(dev: u32) -> Result<(), FsError> {
+        let sb = &SB[dev as usize - 1];
+        if sb.get().is_some() {
+            return Ok(());
+        }
+
         let buf = BCACHE.read(dev, 1); // superblock is at block 1
-        let sb = unsafe { ptr::read_unaligned(buf.data().as_ptr() as *const SuperBlock) };
+        let disk = unsafe { ptr::read_unaligned(buf.data().as_ptr() as *const SuperBlock) };
+        let sum = crc::crc32c(&buf.data()[..mem::offset_of!(SuperBlock, checksum)]);
         BCACHE.release(buf);
 
-        assert_eq!(sb.magic, FSMAGIC, "invalid file system");
+        if disk.magic != FSMAGIC || disk.features & !FEATURES != 0 {
+            err!(FsError::BadSuperBlock);
+        }
+
+        if disk.features & FEATURE_CSUM != 0 && disk.checksum != sum {
+            err!(FsError::BadSuperBlock);
+        }
+
+        // any disk can be mounted, so a bad layout must fail here rather than panic later.
+        // the log needs its header and room for an operation.
+        let ninodeblocks = disk.ninodes / IPB + 1;
+        let nbitmap = disk.size / BPB + 1;
+        if disk.logstart != 2
+            || (disk.nlogs as usize) <= MAXOPBLOCKS
+            || disk.logstart.checked_add(disk.nlogs) != Some(disk.orphan)
+            || disk.orphan.checked_add(1) != Some(disk.inodestart)
+            || disk.inodestart.checked_add(ninodeblocks) != Some(disk.bmapstart)
+            || disk.nblocks > disk.size
+            || disk.bmapstart.checked_add(nbitmap) != Some(disk.size - disk.nblocks)
+            || disk.ninodes <= ROOTINO
+        {
+            err!(FsError::BadSuperBlock);
+        }
+
+        sb.initialize(|| Ok::<_, ()>(disk));
+        Ok(())
+    }
+}
+
+/// Checksums of metadata blocks.
+///
+/// On a file system with `FEATURE_CSUM`, the last `CSUMSIZE` bytes of every inode, bitmap and
+/// directory block hold the CRC32C of the rest of the block. So the last inode of every inode block
+/// and the last bits of every bitmap block are never used, and directory records stop short of the
+/// checksum. A block is checked whenever it is read, and sealed with a new checksum whenever it is
+/// logged, so the copies in the log and at the home location always match their checksum.
+pub struct Checksum;
+
+impl Checksum {
+    /// Returns the checksum of a block with contents `data`.
+    pub fn compute(data: &[u8; BSIZE]) -> u32 {
+        crc::crc32c(&data[..BSIZE - CSUMSIZE])
+    }
+
+    /// Returns the checksum stored at the end of a block with contents `data`.
+    pub fn stored(data: &[u8; BSIZE]) -> u32 {
+        u32::from_le_bytes(data[BSIZE - CSUMSIZE..].try_into().unwrap())
+    }
+
+    /// Checks the checksum of metadata block `buf` of device `dev`, if the file system keeps
+    /// them, so that a damaged block is not acted on.
+    pub fn verify(dev: u32, buf: &Buf<'_>) -> Result<(), FsError> {
+        if superblock(dev).checksummed() && Self::stored(buf.data()) != Self::compute(buf.data()) {
+            let block_no = BCACHE.inner.lock().meta[buf.id].block_no;
+            println!("fs: bad checksum in block {} of disk {}", block_no, dev);
+            err!(FsError::Read);
+        }
+
+        Ok(())
+    }
+
+    /// Seals metadata block `buf` of device `dev` with its checksum and logs it.
+    /// Must be called inside a transaction, instead of `log::write()`.
+    pub fn write(dev: u32, buf: &mut Buf<'_>) {
+        if superblock(dev).checksummed() {
+            let sum = Self::compute(buf.data());
+            buf.data_mut()[BSIZE - CSUMSIZE..].copy_from_slice(&sum.to_le_bytes());
+        }
+
+        log::write(buf);
+    }
+
+    /// Returns the bytes at the start of a metadata block of device `dev` that are free for its
+    /// contents.
+    pub fn space(dev: u32) -> usize {
+        if superblock(dev).checksummed() {
+            BSIZE - CSUMSIZE
+        } else {
+            BSIZE
+        }
+    }
+}
+
+/// Allocation state of each disk, kept in memory while it is mounted
+static USAGE: [SpinLock<Usage>; NDISK] = [const { SpinLock::new(Usage::new(), "usage") }; NDISK];
+
+/// Usable and free data blocks and inodes of a disk, for `statfs` and the allocators.
+///
+/// Counting them means reading the whole bitmap and every inode block, so it is done once when the
+/// disk is mounted, and the allocators keep the counts up to date from then on. Free blocks are
+/// counted per group, the blocks described by one bitmap block, and free inodes per inode block,
+/// so that the allocators skip full ones without reading them.
+#[derive(Debug)]
+struct Usage {
+    /// Usable data blocks
+    blocks: u32,
+    /// Usable inodes
+    inodes: u32,
+    /// Free data blocks in each group
+    bfree: Vec<u32>,
+    /// Free inodes in each inode block
+    ifree: Vec<u32>,
+}
+
+impl Usage {
+    const fn new() -> Self {
+        Self {
+            blocks: 0,
+            inodes: 0,
+            bfree: Vec::new(),
+            ifree: Vec::new(),
+        }
+    }
+
+    fn of(dev: u32) -> &'static SpinLock<Self> {
+        &USAGE[dev as usize - 1]
+    }
+
+    /// Counts the data blocks and inodes of device `dev` and how many of them are free.
+    /// Called at mount, before anything is allocated or freed. Blocks and inodes described by a
+    /// damaged metadata block count as in use, since the allocators will not hand them out.
+    fn count(dev: u32) {
+        let sb = superblock(dev);
+        let bits = Checksum::space(dev) as u32 * 8;
+        let data_start = sb.size - sb.nblocks;
+        let mut usage = Self::new();
+
+        for b in (0..sb.size).step_by(BPB as usize) {
+            let buf = BCACHE.read(dev, sb.bmapstart + (b / BPB));
+            let valid = log!(Checksum::verify(dev, &buf)).is_ok();
+            let mut free = 0;
+
+            for bi in (0..bits).take_while(|bi| b + bi < sb.size) {
+                if b + bi < data_start {
+                    continue;
+                }
+
+                usage.blocks += 1;
+                if valid && buf.data()[bi as usize / 8] & (1 << (bi % 8)) == 0 {
+                    free += 1;
+                }
+            }
+
+            BCACHE.release(buf);
+            usage.bfree.push(free);
+        }
+
+        for block in 0..sb.ninodes.div_ceil(IPB) {
+            let mut buf = BCACHE.read(dev, sb.inodestart + block);
+            let valid = log!(Checksum::verify(dev, &buf)).is_ok();
+            let mut free = 0;
+
+            for inum in (block * IPB).max(1)..((block + 1) * IPB).min(sb.ninodes) {
+                if Inode::reserved(dev, inum) {
+                    continue;
+                }
+
+                usage.inodes += 1;
+                if valid && unsafe { DiskInode::from_buf(&mut buf, inum) }.r#type == InodeType::Free
+                {
+                    free += 1;
+                }
+            }
+
+            BCACHE.release(buf);
+            usage.ifree.push(free);
+        }
+
+        *Self::of(dev).lock() = usage;
+    }
+
+    fn statfs(dev: u32) -> StatFs {
+        let usage = Self::of(dev).lock();
+        StatFs {
+            bsize: BSIZE as u32,
+            blocks: usage.blocks,
+            bfree: usage.bfree.iter().sum(),
+            files: usage.inodes,
+            ffree: usage.ifree.iter().sum(),
+        }
+    }
+}
+
+/// Returns the first clear bit of bitmap `map` in `from..to`.
+fn first_clear(map: &[u8], from: u32, to: u32) -> Option<u32> {
+    let mut bi = from;
 
-        SB.initialize(|| Ok::<_, ()>(sb));
+    while bi < to {
+        let byte = map[bi as usize / 8];
+
+        if bi % 8 == 0 && byte == 0xFF {
+            // skip a full byte at once
+            bi += 8;
+        } else if byte & (1 << (bi % 8)) == 0 {
+            return Some(bi);
+        } else {
+            bi += 1;
+        }
     }
+
+    None
 }
 
 /// Initialize the file system.
-pub fn init(dev: u32) {
-    SuperBlock::initialize(dev);
-    log::init(dev, SB.get().unwrap());
+pub fn init() {
+    log!(proc::spawn("bflush", buf::flusher)).expect("buffer flusher");
+    log!(proc::spawn("logd", log::flusher)).expect("log flusher");
+    let root = log!(DiskFs::mount(ROOTDEV)).expect("invalid file system");
+    mount::init(root);
+}
+
+/// Starts using the file system on disk `dev`: reads its superblock, recovers its log, and
+/// finishes the truncations and frees a crash interrupted.
+/// The caller makes it reachable through the mount table.
+pub fn mount(dev: u32) -> Result<(), FsError> {
+    if !virtio_disk::present(dev) {
+        err!(FsError::NoDevice);
+    }
+
+    try_log!(SuperBlock::initialize(dev));
+    log::init(dev, superblock(dev));
+    Usage::count(dev);
+    Orphans::recover(dev);
     Inode::reclaim(dev);
+
+    Ok(())
+}
+
+/// Stops using the file system on disk `dev`, writing everything in its log back.
+/// The caller makes sure no inode of the device is in use.
+pub fn unmount(dev: u32) {
+    log::stop(dev);
 }
 
 /// A disk block.
@@ -126,30 +417,50 @@ impl Block {
         BCACHE.release(buf);
     }
 
-    /// Allocates a zeroed disk block.
-    pub fn alloc(dev: u32) -> Result<Self, FsError> {
-        let sb = SB.get().unwrap();
+    /// Allocates a zeroed disk block, the first free one from block `goal` on in its group, or
+    /// else in the next group with any free. Groups without free blocks are skipped without
+    /// reading their bitmap block.
+    pub fn alloc(dev: u32, goal: u32) -> Result<Self, FsError> {
+        let sb = superblock(dev);
+        // bits overlapping the checksum do not stand for blocks
+        let bits = Checksum::space(dev) as u32 * 8;
+        let data_start = sb.size - sb.nblocks;
+        let goal = if (data_start..sb.size).contains(&goal) {
+            goal
+        } else {
+            data_start
+        };
 
-        for b in (0..sb.size).step_by(BPB as usize) {
-            let mut buf = BCACHE.read(dev, sb.bmapstart + (b / BPB));
+        let groups = sb.size.div_ceil(BPB);
+        let first = goal / BPB;
 
-            for bi in 0..BPB {
-                if b + bi >= sb.size {
-                    break;
-                }
+        for group in (first..groups).chain(0..first) {
+            if Usage::of(dev).lock().bfree[group as usize] == 0 {
+                continue;
+            }
 
-                let m = 1u8 << (bi % 8);
-                if buf.data()[bi as usize / 8] & m == 0 {
-                    // block is free, mark it as in use
-                    buf.data_mut()[bi as usize / 8] |= m;
-                    log::write(&buf);
-                    BCACHE.release(buf);
+            let mut buf = BCACHE.read(dev, sb.bmapstart + group);
+            if let Err(e) = log!(Checksum::verify(dev, &buf)) {
+                BCACHE.release(buf);
+                return Err(e);
+            }
 
-                    let mut block = Self(b + bi);
-                    block.zero(dev);
+            let end = bits.min(sb.size - group * BPB);
+            let start = if group == first { goal % BPB } else { 0 };
+            let free = first_clear(buf.data(), start, end)
+                .or_else(|| first_clear(buf.data(), 0, start.min(end)));
 
-                    return Ok(block);
-                }
+            if let Some(bi) = free {
+                // block is free, mark it as in use
+                buf.data_mut()[bi as usize / 8] |= 1 << (bi % 8);
+                Checksum::write(dev, &mut buf);
+                BCACHE.release(buf);
+                Usage::of(dev).lock().bfree[group as usize] -= 1;
+
+                let mut block = Self(group * BPB + bi);
+                block.zero(dev);
+
+                return Ok(block);
             }
 
             BCACHE.release(buf);
@@ -160,8 +471,15 @@ impl Block {
 
     /// Frees a disk block.
     pub fn free(self, dev: u32) {
-        let sb = SB.get().unwrap();
+        let sb = superblock(dev);
         let mut buf = BCACHE.read(dev, sb.bmapstart + (self.0 / BPB));
+
+        // leak the block rather than write back a bitmap that cannot be trusted
+        if log!(Checksum::verify(dev, &buf)).is_err() {
+            BCACHE.release(buf);
+            return;
+        }
+
         let bi = self.0 % BPB;
         let m = 1u8 << (bi % 8);
 
@@ -170,9 +488,97 @@ impl Block {
         }
 
         buf.data_mut()[bi as usize / 8] &= !m;
-        log::write(&buf);
+        Checksum::write(dev, &mut buf);
+        BCACHE.release(buf);
+        Usage::of(dev).lock().bfree[(self.0 / BPB) as usize] += 1;
+    }
+}
+
+/// Inodes that still have blocks past their end to free.
+///
+/// Freeing the blocks of a large file may write more blocks than fit in one operation, so only the
+/// first few are freed in the operation that truncates or unlinks the file. The inode is then
+/// listed in the on-disk orphan block, in the same operation, and the rest of its blocks are freed
+/// by `Inode::reap_orphans()` in follow-up operations. After a crash, the inodes still listed are
+/// finished at mount.
+///
+/// The orphan block is an array of inode numbers, where 0 is a free slot.
+pub struct Orphans {
+    /// Listed inodes, each holding a reference until its blocks are freed
+    pending: Vec<Inode>,
+    /// A process is running `Inode::reap_orphans()`
+    reaping: bool,
+}
+
+static ORPHANS: SpinLock<Orphans> = SpinLock::new(
+    Orphans {
+        pending: Vec::new(),
+        reaping: false,
+    },
+    "orphans",
+);
+
+// every listed inode holds an inode table entry, so the orphan block never fills up
+const _: () = assert!(NINODE <= NORPHAN);
+
+impl Orphans {
+    /// Lists `inum` in the orphan block of device `dev`.
+    /// Must be called inside a transaction.
+    fn add(dev: u32, inum: u32) {
+        let sb = superblock(dev);
+        let mut buf = BCACHE.read(dev, sb.orphan);
+        let slots =
+            unsafe { slice::from_raw_parts_mut(buf.data_mut().as_mut_ptr() as *mut u32, NORPHAN) };
+
+        if !slots.contains(&inum) {
+            let Some(slot) = slots.iter_mut().find(|slot| **slot == 0) else {
+                panic!("orphan block full");
+            };
+
+            *slot = inum;
+            log::write(&buf);
+        }
+
+        BCACHE.release(buf);
+    }
+
+    /// Removes `inum` from the orphan block of device `dev`.
+    /// Must be called inside a transaction.
+    fn remove(dev: u32, inum: u32) {
+        let sb = superblock(dev);
+        let mut buf = BCACHE.read(dev, sb.orphan);
+        let slots =
+            unsafe { slice::from_raw_parts_mut(buf.data_mut().as_mut_ptr() as *mut u32, NORPHAN) };
+
+        if let Some(slot) = slots.iter_mut().find(|slot| **slot == inum) {
+            *slot = 0;
+            log::write(&buf);
+        }
+
         BCACHE.release(buf);
     }
+
+    /// Finishes freeing the blocks of the inodes left in the orphan block of device `dev` by a
+    /// crash. Called at file system initialization.
+    fn recover(dev: u32) {
+        let sb = superblock(dev);
+        let buf = BCACHE.read(dev, sb.orphan);
+        let slots = unsafe { slice::from_raw_parts(buf.data().as_ptr() as *const u32, NORPHAN) };
+        let inums: Vec<u32> = slots.iter().copied().filter(|inum| *inum != 0).collect();
+        BCACHE.release(buf);
+
+        for inum in inums {
+            println!("orphan: finishing inode {}", inum);
+
+            match log!(Inode::get(dev, inum)) {
+                Ok(inode) => {
+                    ORPHANS.lock().pending.push(inode);
+                    Inode::reap_orphans();
+                }
+                Err(_) => panic!("orphan: no inode"),
+            }
+        }
+    }
 }
 
 /// Inode types
@@ -184,6 +590,10 @@ pub enum InodeType {
     Directory = 1,
     File = 2,
     Device = 3,
+    /// Named pipe, whose data only lives in memory, see `Pipe::open_fifo()`
+    Fifo = 4,
+    /// Name of a bound Unix domain socket, see socket.rs
+    Socket = 5,
 }
 
 /// On-disk inode structure
@@ -225,6 +635,8 @@ pub struct Stat {
     pub dev: u32,
     pub ino: u32,
     pub r#type: InodeType,
+    pub major: u16,
+    pub minor: u16,
     pub nlink: u16,
     pub size: u64,
 }
@@ -240,6 +652,8 @@ pub struct InodeInner {
     pub nlink: u16,
     pub size: u32,
     pub addrs: [u32; NDIRECT + 1],
+    /// Offset where the last read ended, to detect sequential reads for read-ahead
+    pub read_end: u32,
 }
 
 impl InodeInner {
@@ -253,6 +667,7 @@ impl InodeInner {
             nlink: 0,
             size: 0,
             addrs: [0; NDIRECT + 1],
+            read_end: 0,
         }
     }
 }
@@ -347,21 +762,50 @@ impl InodeTable {
 }
 
 impl Inode {
-    /// Allocates an inode on device `dev`.
+    /// Allocates an inode on device `dev` for a file in directory `parent`.
     /// Marks it allocated by giving it type `type`.
+    /// Files are placed next to their directory, and directories in the inode block with the most
+    /// free inodes, so that each starts a neighbourhood of its own. Since the data blocks of a file
+    /// are placed by its inode number, see `home()`, its blocks end up near those of its siblings.
     /// Returns an unlocked but allocated and referenced inode or error.
-    pub fn alloc(dev: u32, r#type: InodeType) -> Result<Self, FsError> {
-        let sb = SB.get().unwrap();
+    pub fn alloc(dev: u32, r#type: InodeType, parent: u32) -> Result<Self, FsError> {
+        let sb = superblock(dev);
+        let blocks = sb.ninodes.div_ceil(IPB);
+
+        let first = if r#type == InodeType::Directory {
+            let usage = Usage::of(dev).lock();
+            (0..blocks)
+                .max_by_key(|&block| (usage.ifree[block as usize], Reverse(block)))
+                .unwrap_or(0)
+        } else {
+            (parent / IPB).min(blocks - 1)
+        };
 
-        for inum in 1..sb.ninodes {
-            let mut buf = BCACHE.read(dev, sb.inodestart + inum / IPB);
-            let dinode = unsafe { DiskInode::from_buf(&mut buf, inum) };
+        for block in (first..blocks).chain(0..first) {
+            if Usage::of(dev).lock().ifree[block as usize] == 0 {
+                continue;
+            }
 
-            if dinode.r#type == InodeType::Free {
-                dinode.r#type = r#type;
-                log::write(&buf);
+            let mut buf = BCACHE.read(dev, sb.inodestart + block);
+            if let Err(e) = log!(Checksum::verify(dev, &buf)) {
                 BCACHE.release(buf);
-                return log!(Self::get(dev, inum));
+                return Err(e);
+            }
+
+            for inum in (block * IPB).max(1)..((block + 1) * IPB).min(sb.ninodes) {
+                if Self::reserved(dev, inum) {
+                    continue;
+                }
+
+                let dinode = unsafe { DiskInode::from_buf(&mut buf, inum) };
+
+                if dinode.r#type == InodeType::Free {
+                    dinode.r#type = r#type;
+                    Checksum::write(dev, &mut buf);
+                    BCACHE.release(buf);
+                    Usage::of(dev).lock().ifree[block as usize] -= 1;
+                    return log!(Self::get(dev, inum));
+                }
             }
 
             BCACHE.release(buf);
@@ -370,6 +814,21 @@ impl Inode {
         err!(FsError::OutOfInode);
     }
 
+    /// Returns the data block the blocks of the inode are placed from. The data blocks are shared
+    /// out among the inodes in order, so that files whose inodes are close together have their
+    /// blocks close together too, and apart from the rest.
+    fn home(&self) -> u32 {
+        let sb = superblock(self.dev);
+        let share = self.inum as u64 * sb.nblocks as u64 / sb.ninodes as u64;
+        sb.size - sb.nblocks + share as u32
+    }
+
+    /// Returns whether inode number `inum` of device `dev` is never used, since its slot overlaps
+    /// the checksum of its block.
+    fn reserved(dev: u32, inum: u32) -> bool {
+        superblock(dev).checksummed() && inum % IPB == IPB - 1
+    }
+
     /// Finds the inode with number `inum` on device `dev` and returns the in-memory copy.
     /// Does not lock the inode and does not read it from disk.
     pub fn get(dev: u32, inum: u32) -> Result<Self, FsError> {
@@ -407,9 +866,16 @@ impl Inode {
     /// Copies a modified in-memory inode to disk.
     /// Must be called after every change to an `Inode` field that lives on disk.
     pub fn update(&self, inner: &SleepLockGuard<'_, InodeInner>) {
-        let sb = SB.get().unwrap();
+        let sb = superblock(self.dev);
 
         let mut buf = BCACHE.read(self.dev, sb.inodestart + (self.inum / IPB));
+
+        // sealing a damaged block would make it look valid, so leave it for fsck
+        if log!(Checksum::verify(self.dev, &buf)).is_err() {
+            BCACHE.release(buf);
+            return;
+        }
+
         let dinode = unsafe { DiskInode::from_buf(&mut buf, self.inum) };
 
         dinode.r#type = inner.r#type;
@@ -419,7 +885,7 @@ impl Inode {
         dinode.size = inner.size;
         dinode.addrs.copy_from_slice(&inner.addrs);
 
-        log::write(&buf);
+        Checksum::write(self.dev, &mut buf);
         BCACHE.release(buf);
     }
 
@@ -432,14 +898,23 @@ impl Inode {
     }
 
     /// Locks the given `inode`. The lifetime of the lock is static since it comes from the table.
-    /// Reads the inode from disk if necessary.
-    pub fn lock(&self) -> SleepLockGuard<'static, InodeInner> {
-        let sb = SB.get().unwrap();
+    /// Reads the inode from disk if necessary, failing if its block is damaged.
+    pub fn lock(&self) -> Result<SleepLockGuard<'static, InodeInner>, FsError> {
+        let sb = superblock(self.dev);
 
         let mut inner = INODE_TABLE.inner[self.id].lock();
 
         if !inner.valid {
+            if Self::reserved(self.dev, self.inum) {
+                err!(FsError::Read);
+            }
+
             let mut buf = BCACHE.read(self.dev, sb.inodestart + (self.inum / IPB));
+            if let Err(e) = log!(Checksum::verify(self.dev, &buf)) {
+                BCACHE.release(buf);
+                return Err(e);
+            }
+
             let dinode = unsafe { DiskInode::from_buf(&mut buf, self.inum) };
 
             inner.r#type = dinode.r#type;
@@ -455,7 +930,7 @@ impl Inode {
             assert_ne!(inner.r#type, InodeType::Free, "ilock: no type");
         }
 
-        inner
+        Ok(inner)
     }
 
     /// Unlocks the given `inode`.
@@ -468,7 +943,7 @@ impl Inode {
     /// If that was the last reference and the inode has no links to it, free the inode (and its
     /// content) on disk.
     /// All calls to `iput()` must be inside a transaction in case it has to free the inode.
-    pub fn put(mut self) {
+    pub fn put(self) {
         let mut meta = INODE_TABLE.meta.lock();
 
         if meta[self.id].r#ref == 1 {
@@ -482,10 +957,19 @@ impl Inode {
 
                 drop(meta);
 
-                self.trunc(&mut inner);
+                inner.size = 0;
+                if !self.trim(&mut inner) {
+                    // the orphan list keeps this reference until the blocks are freed, and puts
+                    // it again then
+                    drop(inner);
+                    ORPHANS.lock().pending.push(self);
+                    return;
+                }
+
                 inner.r#type = InodeType::Free;
                 self.update(&inner);
                 inner.valid = false;
+                Usage::of(self.dev).lock().ifree[(self.inum / IPB) as usize] += 1;
 
                 drop(inner);
 
@@ -497,6 +981,18 @@ impl Inode {
         meta[self.id].r#ref -= 1;
     }
 
+    /// Returns whether any inode of device `dev` is referenced.
+    pub fn in_use(dev: u32) -> bool {
+        let meta = INODE_TABLE.meta.lock();
+        meta.iter().any(|inode| inode.r#ref > 0 && inode.dev == dev)
+    }
+
+    /// Returns the number of inode table entries in use.
+    pub fn active() -> usize {
+        let meta = INODE_TABLE.meta.lock();
+        meta.iter().filter(|inode| inode.r#ref > 0).count()
+    }
+
     /// Common idiom: `unlock()`, then `put()`
     pub fn unlock_put(self, guard: SleepLockGuard<'static, InodeInner>) {
         self.unlock(guard);
@@ -506,10 +1002,19 @@ impl Inode {
     /// Reclaims orphaned inodes on device `dev`.
     /// Called at file system initialization.
     pub fn reclaim(dev: u32) {
-        let sb = SB.get().unwrap();
+        let sb = superblock(dev);
 
         for inum in 1..sb.ninodes {
+            if Self::reserved(dev, inum) {
+                continue;
+            }
+
             let mut buf = BCACHE.read(dev, sb.inodestart + (inum / IPB));
+            if log!(Checksum::verify(dev, &buf)).is_err() {
+                BCACHE.release(buf);
+                continue;
+            }
+
             let dinode = unsafe { DiskInode::from_buf(&mut buf, inum) };
 
             let mut inode = None;
@@ -524,158 +1029,450 @@ impl Inode {
 
             if let Some(Ok(inode)) = inode {
                 let _op = Operation::begin();
-                let guard = inode.lock();
-                inode.unlock(guard);
+                if let Ok(guard) = log!(inode.lock()) {
+                    inode.unlock(guard);
+                }
                 inode.put();
             }
         }
     }
 
-    /// Truncates inode (discard contents).
-    pub fn trunc(&mut self, inner: &mut SleepLockGuard<'_, InodeInner>) {
-        for i in 0..NDIRECT {
-            if inner.addrs[i] != 0 {
-                let block = Block(inner.addrs[i]);
-                block.free(self.dev);
-                inner.addrs[i] = 0;
+    /// Sets the size of the inode to `size` bytes. Shrinking discards the contents past it;
+    /// growing leaves a hole that reads as zeros without allocating blocks.
+    /// Must be called inside a transaction. Blocks that do not fit in it are freed once it ends.
+    pub fn truncate(&self, inner: &mut SleepLockGuard<'_, InodeInner>, size: u32) {
+        if size >= inner.size {
+            if size > inner.size {
+                inner.size = size;
+                self.update(inner);
             }
+            return;
         }
 
-        if inner.addrs[NDIRECT] != 0 {
-            let buf = BCACHE.read(self.dev, inner.addrs[NDIRECT]);
-            let array =
-                unsafe { slice::from_raw_parts(buf.data().as_ptr() as *const u32, NINDIRECT) };
-
-            for block in array {
-                if *block != 0 {
-                    let b = Block(*block);
-                    b.free(self.dev);
-                }
-            }
-
+        // bytes past the end of the last block must read as zeros if the file grows again
+        let tail = size as usize % BSIZE;
+        if tail != 0
+            && let Some(addr) = self.mapped(inner, size / BSIZE as u32)
+        {
+            let mut buf = BCACHE.read(self.dev, addr);
+            buf.data_mut()[tail..].fill(0);
+            log::write(&buf);
             BCACHE.release(buf);
-            let block = Block(inner.addrs[NDIRECT]);
-            block.free(self.dev);
-            inner.addrs[NDIRECT] = 0;
         }
 
-        inner.size = 0;
+        inner.size = size;
         self.update(inner);
+
+        if !self.trim(inner) {
+            ORPHANS.lock().pending.push(self.dup());
+        }
     }
 
-    /// Returns the disk block address of the nth block in `inode`.
-    /// If there is no such block, allocates one.
-    pub fn map(
+    /// Sets the size of a regular file to `size` bytes, for `truncate` and `ftruncate`.
+    /// Must be called inside a transaction.
+    pub fn resize(
         &self,
         inner: &mut SleepLockGuard<'_, InodeInner>,
-        block_no: u32,
-    ) -> Result<u32, FsError> {
-        let mut block_no = block_no as usize;
+        size: usize,
+    ) -> Result<(), FsError> {
+        if inner.r#type != InodeType::File {
+            err!(FsError::Type);
+        }
 
-        if block_no < NDIRECT {
-            let addr = &mut inner.addrs[block_no];
+        if size > MAXFILE * BSIZE {
+            err!(FsError::OutOfRange);
+        }
 
-            if *addr == 0 {
-                let block = try_log!(Block::alloc(self.dev));
-                *addr = block.0;
-            }
+        self.truncate(inner, size as u32);
+        Ok(())
+    }
 
-            return Ok(*addr);
+    /// Frees the blocks past the end of the inode, as many as the current operation allows.
+    /// If some are left, lists the inode in the orphan block and returns false. The caller must
+    /// then queue a reference to it for `reap_orphans()`.
+    fn trim(&self, inner: &mut SleepLockGuard<'_, InodeInner>) -> bool {
+        if self.trunc_step(inner, TRUNCINLINE) {
+            return true;
         }
 
-        block_no -= NDIRECT;
+        Orphans::add(self.dev, self.inum);
+        false
+    }
 
-        if block_no < NINDIRECT {
-            // load indiret block, allocating if necessary
-            let in_block_no = &mut inner.addrs[NDIRECT];
+    /// Frees blocks past the end of the inode, last block first, touching at most `bmaps` free map
+    /// blocks so the operation stays within its reservation.
+    /// Returns whether there are no blocks left past the end.
+    fn trunc_step(&self, inner: &mut SleepLockGuard<'_, InodeInner>, bmaps: usize) -> bool {
+        assert!(bmaps <= MAXOPBLOCKS, "trunc_step: too many bitmap blocks");
 
-            if *in_block_no == 0 {
-                let block = try_log!(Block::alloc(self.dev));
-                *in_block_no = block.0;
+        let keep = (inner.size as usize).div_ceil(BSIZE);
+
+        let mut touched = [0u32; MAXOPBLOCKS];
+        let mut ntouched = 0;
+
+        // frees `block` unless its free map block would go over the limit
+        let mut free = |block: u32| {
+            let bmap = block / BPB;
+
+            if !touched[..ntouched].contains(&bmap) {
+                if ntouched == bmaps {
+                    return false;
+                }
+
+                touched[ntouched] = bmap;
+                ntouched += 1;
             }
 
-            let mut buf = BCACHE.read(self.dev, *in_block_no);
-            let in_block = unsafe {
+            Block(block).free(self.dev);
+            true
+        };
+
+        let mut done = true;
+        let mut changed = false;
+
+        if inner.addrs[NDIRECT] != 0 {
+            let mut buf = BCACHE.read(self.dev, inner.addrs[NDIRECT]);
+            let array = unsafe {
                 slice::from_raw_parts_mut(buf.data_mut().as_mut_ptr() as *mut u32, NINDIRECT)
             };
 
-            let addr = &mut in_block[block_no];
-            if *addr == 0 {
-                let block = try_log!(Block::alloc(self.dev));
+            let mut dirty = false;
+            for addr in array[keep.saturating_sub(NDIRECT)..].iter_mut().rev() {
+                if *addr != 0 {
+                    if !free(*addr) {
+                        done = false;
+                        break;
+                    }
 
-                *addr = block.0;
-                log::write(&buf);
+                    *addr = 0;
+                    dirty = true;
+                }
             }
 
+            let empty = array.iter().all(|addr| *addr == 0);
+
+            if dirty {
+                log::write(&buf);
+            }
             BCACHE.release(buf);
 
-            return Ok(*addr);
+            if empty {
+                if free(inner.addrs[NDIRECT]) {
+                    inner.addrs[NDIRECT] = 0;
+                    changed = true;
+                } else {
+                    done = false;
+                }
+            }
         }
 
-        Err(FsError::OutOfRange)
-    }
+        if done {
+            for addr in inner.addrs[keep.min(NDIRECT)..NDIRECT].iter_mut().rev() {
+                if *addr != 0 {
+                    if !free(*addr) {
+                        done = false;
+                        break;
+                    }
 
-    pub fn stat(&self, inner: &SleepLockGuard<'_, InodeInner>) -> Stat {
-        Stat {
-            dev: self.dev,
-            r#type: inner.r#type,
-            nlink: inner.nlink,
-            size: inner.size as u64,
-            ino: self.inum,
+                    *addr = 0;
+                    changed = true;
+                }
+            }
+        }
+
+        if changed {
+            self.update(inner);
         }
+
+        done
     }
 
-    /// Reads data from inode.
-    /// `dst_user` indicates whether `dst` is a user space address.
-    pub fn read(
-        &self,
-        inner: &mut SleepLockGuard<'_, InodeInner>,
-        offset: u32,
-        dst: &mut [u8],
-        dst_user: bool,
-    ) -> Result<u32, FsError> {
-        let mut dst = dst;
-        let mut n = dst.len() as u32;
-        let mut offset = offset;
+    /// Returns whether the inode still has blocks past its end, waiting for `reap_orphans()`.
+    fn past_end(&self, inner: &SleepLockGuard<'_, InodeInner>) -> bool {
+        let keep = (inner.size as usize).div_ceil(BSIZE);
 
-        if offset > inner.size || offset.checked_add(n).is_none() {
-            err!(FsError::Read);
+        if inner.addrs[keep.min(NDIRECT)..NDIRECT]
+            .iter()
+            .any(|addr| *addr != 0)
+        {
+            return true;
         }
 
-        if offset + n > inner.size {
-            n = inner.size - offset;
+        if inner.addrs[NDIRECT] == 0 {
+            return false;
         }
 
-        let mut total = 0;
+        // an indirect block is freed along with its last entry
+        if keep <= NDIRECT {
+            return true;
+        }
 
-        while total < n {
-            if let Ok(addr) = log!(self.map(inner, offset / BSIZE as u32)) {
-                let buf = BCACHE.read(self.dev, addr);
+        let buf = BCACHE.read(self.dev, inner.addrs[NDIRECT]);
+        let array = unsafe { slice::from_raw_parts(buf.data().as_ptr() as *const u32, NINDIRECT) };
+        let past = array[keep - NDIRECT..].iter().any(|addr| *addr != 0);
+        BCACHE.release(buf);
 
-                let m = (n - total).min(BSIZE as u32 - offset % BSIZE as u32);
+        past
+    }
 
-                let src = &buf.data()[(offset as usize % BSIZE)..][..m as usize];
+    /// Frees the blocks past the end of the inode now, in operations of its own, rather than
+    /// waiting for `reap_orphans()`. Must not be called inside a transaction.
+    fn finish_trim(&self) {
+        loop {
+            let _op = Operation::begin();
 
-                if dst_user {
-                    let dst_va = VA::from(dst.as_mut_ptr() as usize);
-                    if log!(proc::copy_to_user(src, dst_va)).is_err() {
-                        BCACHE.release(buf);
-                        err!(FsError::Read);
+            let Ok(mut inner) = log!(self.lock()) else {
+                return;
+            };
+            let done = self.trunc_step(&mut inner, TRUNCSTEP);
+            if done {
+                Orphans::remove(self.dev, self.inum);
+            }
+            self.unlock(inner);
+
+            if done {
+                return;
+            }
+        }
+    }
+
+    /// Frees the rest of the blocks of the inodes in the orphan list, one operation per step.
+    /// Called after each operation ends. Does nothing if another process is already at it, since
+    /// that process also picks up the inodes queued meanwhile.
+    pub fn reap_orphans() {
+        {
+            let mut orphans = ORPHANS.lock();
+            if orphans.reaping || orphans.pending.is_empty() {
+                return;
+            }
+            orphans.reaping = true;
+        }
+
+        loop {
+            let inode = {
+                let mut orphans = ORPHANS.lock();
+                match orphans.pending.pop() {
+                    Some(inode) => inode,
+                    None => {
+                        orphans.reaping = false;
+                        return;
                     }
-                } else {
-                    unsafe { ptr::copy_nonoverlapping(src.as_ptr(), dst.as_mut_ptr(), src.len()) }
                 }
+            };
 
-                BCACHE.release(buf);
+            loop {
+                let _op = Operation::begin();
 
-                total += m;
-                offset += m;
-                dst = &mut dst[m as usize..];
+                let Ok(mut inner) = log!(inode.lock()) else {
+                    // stays listed in the orphan block for the next mount to retry
+                    inode.put();
+                    break;
+                };
+                let done = inode.trunc_step(&mut inner, TRUNCSTEP);
+                if done {
+                    Orphans::remove(inode.dev, inode.inum);
+                }
+                inode.unlock(inner);
+
+                if done {
+                    // frees the inode too if this was the last reference to an unlinked file
+                    inode.put();
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Returns the disk block address of the nth block in `inode`, if it has one.
+    fn mapped(&self, inner: &SleepLockGuard<'_, InodeInner>, block_no: u32) -> Option<u32> {
+        let block_no = block_no as usize;
+
+        let addr = if block_no < NDIRECT {
+            inner.addrs[block_no]
+        } else if block_no < MAXFILE && inner.addrs[NDIRECT] != 0 {
+            let buf = BCACHE.read(self.dev, inner.addrs[NDIRECT]);
+            let in_block =
+                unsafe { slice::from_raw_parts(buf.data().as_ptr() as *const u32, NINDIRECT) };
+            let addr = in_block[block_no - NDIRECT];
+            BCACHE.release(buf);
+            addr
+        } else {
+            0
+        };
+
+        (addr != 0).then_some(addr)
+    }
+
+    /// Returns the disk block address of the nth block in `inode`.
+    /// If there is no such block, allocates one, right after the block before it if that is free,
+    /// so that files written in order are contiguous on disk.
+    pub fn map(
+        &self,
+        inner: &mut SleepLockGuard<'_, InodeInner>,
+        block_no: u32,
+    ) -> Result<u32, FsError> {
+        let mut block_no = block_no as usize;
+
+        if block_no < NDIRECT {
+            if inner.addrs[block_no] == 0 {
+                let goal = self.after(&inner.addrs[..block_no]);
+                let block = try_log!(Block::alloc(self.dev, goal));
+                inner.addrs[block_no] = block.0;
+            }
+
+            return Ok(inner.addrs[block_no]);
+        }
+
+        block_no -= NDIRECT;
+
+        if block_no < NINDIRECT {
+            // load indiret block, allocating if necessary
+            if inner.addrs[NDIRECT] == 0 {
+                let goal = self.after(&inner.addrs[..NDIRECT]);
+                let block = try_log!(Block::alloc(self.dev, goal));
+                inner.addrs[NDIRECT] = block.0;
+            }
+
+            let mut buf = BCACHE.read(self.dev, inner.addrs[NDIRECT]);
+            let in_block = unsafe {
+                slice::from_raw_parts_mut(buf.data_mut().as_mut_ptr() as *mut u32, NINDIRECT)
+            };
+
+            if in_block[block_no] == 0 {
+                let goal = match block_no.checked_sub(1).map(|prev| in_block[prev]) {
+                    Some(prev) if prev != 0 => prev + 1,
+                    // start right after the indirect block
+                    _ => inner.addrs[NDIRECT] + 1,
+                };
+
+                let block = match log!(Block::alloc(self.dev, goal)) {
+                    Ok(block) => block,
+                    Err(e) => {
+                        BCACHE.release(buf);
+                        return Err(e);
+                    }
+                };
+
+                in_block[block_no] = block.0;
+                log::write(&buf);
+            }
+
+            let addr = in_block[block_no];
+            BCACHE.release(buf);
+
+            return Ok(addr);
+        }
+
+        Err(FsError::OutOfRange)
+    }
+
+    /// Returns where to place the block after `addrs`: right after the last of them, or at the
+    /// inode's home if that is a hole or there is none.
+    fn after(&self, addrs: &[u32]) -> u32 {
+        match addrs.last() {
+            Some(&addr) if addr != 0 => addr + 1,
+            _ => self.home(),
+        }
+    }
+
+    pub fn stat(&self, inner: &SleepLockGuard<'_, InodeInner>) -> Stat {
+        Stat {
+            dev: self.dev,
+            r#type: inner.r#type,
+            major: inner.major,
+            minor: inner.minor,
+            nlink: inner.nlink,
+            size: inner.size as u64,
+            ino: self.inum,
+        }
+    }
+
+    /// Reads data from inode.
+    /// `dst_user` indicates whether `dst` is a user space address.
+    pub fn read(
+        &self,
+        inner: &mut SleepLockGuard<'_, InodeInner>,
+        offset: u32,
+        dst: &mut [u8],
+        dst_user: bool,
+    ) -> Result<u32, FsError> {
+        let mut dst = dst;
+        let mut n = dst.len() as u32;
+        let mut offset = offset;
+
+        if offset > inner.size || offset.checked_add(n).is_none() {
+            err!(FsError::Read);
+        }
+
+        if offset + n > inner.size {
+            n = inner.size - offset;
+        }
+
+        let start = offset;
+        let mut total = 0;
+
+        while total < n {
+            let m = (n - total).min(BSIZE as u32 - offset % BSIZE as u32);
+
+            // holes have no block and read as zeros
+            let buf = self
+                .mapped(inner, offset / BSIZE as u32)
+                .map(|addr| BCACHE.read(self.dev, addr));
+            let src = match &buf {
+                Some(buf) => &buf.data()[(offset as usize % BSIZE)..][..m as usize],
+                None => &ZEROS[..m as usize],
+            };
+
+            let copied = if dst_user {
+                let dst_va = VA::from(dst.as_mut_ptr() as usize);
+                log!(proc::copy_to_user(src, dst_va)).is_ok()
             } else {
-                break;
+                unsafe { ptr::copy_nonoverlapping(src.as_ptr(), dst.as_mut_ptr(), src.len()) }
+                true
+            };
+
+            if let Some(buf) = buf {
+                BCACHE.release(buf);
+            }
+
+            if !copied {
+                err!(FsError::Read);
             }
+
+            total += m;
+            offset += m;
+            dst = &mut dst[m as usize..];
         }
 
+        // the file is being read sequentially, start reading the next blocks from disk
+        if total > 0 && (start == 0 || start == inner.read_end) {
+            let next = offset.div_ceil(BSIZE as u32);
+            let end = inner
+                .size
+                .div_ceil(BSIZE as u32)
+                .min(next + READAHEAD as u32);
+
+            // blocks that follow each other on disk are read with a single request
+            let mut block_no = next;
+            while block_no < end {
+                let Some(addr) = self.mapped(inner, block_no) else {
+                    block_no += 1;
+                    continue;
+                };
+
+                let mut count = 1;
+                while block_no + count < end
+                    && self.mapped(inner, block_no + count) == Some(addr + count)
+                {
+                    count += 1;
+                }
+
+                BCACHE.read_ahead(self.dev, addr, count as usize);
+                block_no += count;
+            }
+        }
+        inner.read_end = offset;
+
         Ok(total)
     }
 
@@ -692,7 +1489,8 @@ impl Inode {
         let n = src.len() as u32;
         let mut offset = offset;
 
-        if offset > inner.size || offset.checked_add(n).is_none() {
+        // writing past the end leaves a hole, only the blocks written to are allocated
+        if offset.checked_add(n).is_none() {
             err!(FsError::Write);
         }
 
@@ -741,26 +1539,40 @@ impl Inode {
         Ok(total)
     }
 
+    /// Creates `name` in directory `parent`, returning it locked.
     pub fn create(
-        path: &Path,
+        parent: &Inode,
+        name: &str,
         r#type: InodeType,
         major: u16,
         minor: u16,
+        exclusive: bool,
     ) -> Result<(Self, SleepLockGuard<'static, InodeInner>), FsError> {
-        let (parent, name) = try_log!(path.resolve_parent());
-
-        let mut parent_inner = parent.lock();
+        let mut parent_inner = try_log!(parent.lock());
 
         // check if the file already exists
-        if let Ok(Some((_, inode))) = log!(Directory::lookup(&parent, &mut parent_inner, name)) {
-            parent.unlock_put(parent_inner);
+        if let Ok(Some((_, inode))) = log!(Directory::lookup(parent, &mut parent_inner, name)) {
+            parent.unlock(parent_inner);
+
+            if exclusive {
+                inode.put();
+                err!(FsError::Link);
+            }
 
-            let inode_inner = inode.lock();
+            let inode_inner = match log!(inode.lock()) {
+                Ok(inner) => inner,
+                Err(e) => {
+                    inode.put();
+                    return Err(e);
+                }
+            };
 
             // check type matches
             if r#type == InodeType::File
-                && (inode_inner.r#type == InodeType::File
-                    || inode_inner.r#type == InodeType::Device)
+                && matches!(
+                    inode_inner.r#type,
+                    InodeType::File | InodeType::Device | InodeType::Fifo
+                )
             {
                 return Ok((inode, inode_inner));
             }
@@ -770,15 +1582,23 @@ impl Inode {
             err!(FsError::Create);
         }
 
-        let inode = match log!(Self::alloc(parent.dev, r#type)) {
+        let inode = match log!(Self::alloc(parent.dev, r#type, parent.inum)) {
             Ok(i) => i,
             Err(e) => {
-                parent.unlock_put(parent_inner);
+                parent.unlock(parent_inner);
                 return Err(e);
             }
         };
 
-        let mut inode_inner = inode.lock();
+        let mut inode_inner = match log!(inode.lock()) {
+            Ok(inner) => inner,
+            Err(e) => {
+                parent.unlock(parent_inner);
+                // reclaimed at the next mount, since it has no links
+                inode.put();
+                return Err(e);
+            }
+        };
         inode_inner.major = major;
         inode_inner.minor = minor;
         inode_inner.nlink = 1;
@@ -806,12 +1626,12 @@ impl Inode {
             inode_inner.nlink = 0;
             inode.update(&inode_inner);
             inode.unlock_put(inode_inner);
-            parent.unlock_put(parent_inner);
+            parent.unlock(parent_inner);
             err!(FsError::Create);
         }
 
         if log!(Directory::link(
-            &parent,
+            parent,
             &mut parent_inner,
             name,
             inode.inum as u16
@@ -822,7 +1642,7 @@ impl Inode {
             inode_inner.nlink = 0;
             inode.update(&inode_inner);
             inode.unlock_put(inode_inner);
-            parent.unlock_put(parent_inner);
+            parent.unlock(parent_inner);
             err!(FsError::Create);
         }
 
@@ -832,70 +1652,400 @@ impl Inode {
             parent.update(&parent_inner);
         }
 
-        parent.unlock_put(parent_inner);
+        parent.unlock(parent_inner);
 
         Ok((inode, inode_inner))
     }
 }
 
+/// On-disk directory entry header, followed by `name_len` bytes of name.
+///
+/// A directory is a sequence of `BSIZE` blocks, each completely covered by variable-length
+/// records that never cross a block boundary. A record's `rec_len` includes the header, the name
+/// and any free space after it, so deleted entries are absorbed by their predecessor and reused by
+/// later links. With `FEATURE_CSUM`, the records stop short of the checksum at the end of the block.
 #[repr(C)]
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Copy, Default)]
 pub struct Directory {
+    /// Inode number, 0 if the record is unused
     pub inum: u16,
-    pub name: [u8; DIRSIZE],
+    /// Length of this record (bytes)
+    pub rec_len: u16,
+    /// Length of the name (bytes)
+    pub name_len: u8,
+    pub reserved: u8,
 }
 
-impl Directory {
-    pub const SIZE: usize = size_of::<Self>();
+/// A directory record borrowed from a directory block.
+#[derive(Debug, Clone, Copy)]
+pub struct DirRecord<'a> {
+    /// Byte offset of the record in the block
+    pub offset: usize,
+    pub entry: Directory,
+    pub name: &'a [u8],
+}
 
-    pub const fn new_empty() -> Self {
-        Self {
-            inum: 0,
-            name: [0; DIRSIZE],
+/// Iterator over the records of a directory block.
+/// Yields `FsError::Read` and stops if the block is malformed.
+#[derive(Debug, Clone)]
+pub struct DirRecords<'a> {
+    block: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Iterator for DirRecords<'a> {
+    type Item = Result<DirRecord<'a>, FsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset + Directory::HEADER_SIZE > self.block.len() {
+            return None;
+        }
+
+        let offset = self.offset;
+        let entry = Directory::from_bytes(&self.block[offset..]);
+        let rec_len = entry.rec_len as usize;
+
+        // a record must be aligned and hold its own name, which `insert_in_block()` relies on
+        if !rec_len.is_multiple_of(4)
+            || rec_len < Directory::rec_len_for(entry.name_len as usize)
+            || offset + rec_len > self.block.len()
+        {
+            // stop iterating, the rest of the block cannot be trusted
+            self.offset = self.block.len();
+            return Some(Err(FsError::Read));
+        }
+
+        self.offset += rec_len;
+
+        let name_start = offset + Directory::HEADER_SIZE;
+        let name = &self.block[name_start..name_start + entry.name_len as usize];
+
+        Some(Ok(DirRecord {
+            offset,
+            entry,
+            name,
+        }))
+    }
+}
+
+/// Header of a directory index.
+///
+/// A directory that outgrows its first block is converted to an indexed layout, in the spirit of
+/// ext3's htree: block 0 keeps `.` and `..`, and the index lives in the unused tail of the `..`
+/// record, so linear readers still see a valid block. The index is a sorted array of `DxEntry`s,
+/// each pointing at a leaf block holding the names whose hash falls in its range. Entries with the
+/// same hash are never split across leaves. A directory whose index fills up, or whose full leaf
+/// cannot be split, drops the index and is searched linearly from then on.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct DxRoot {
+    /// Must be `DXMAGIC`
+    pub magic: u32,
+    /// Number of index entries in use
+    pub count: u16,
+    /// Capacity of the index
+    pub limit: u16,
+}
+
+/// Directory index entry.
+/// Names with hash in `[hash, next.hash)` live in leaf block `block`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct DxEntry {
+    pub hash: u32,
+    pub block: u32,
+}
+
+impl DxRoot {
+    /// Byte offset of the index in block 0, right after the `.` and `..` records
+    pub const OFFSET: usize = Directory::rec_len_for(1) + Directory::rec_len_for(2);
+    /// Maximum number of index entries (and leaves), clear of a block checksum
+    pub const LIMIT: usize =
+        (BSIZE - CSUMSIZE - Self::OFFSET - size_of::<Self>()) / size_of::<DxEntry>();
+
+    /// Returns the index header if `block` is the root of an indexed directory.
+    fn from_block(block: &[u8]) -> Option<Self> {
+        let dotdot = Directory::from_bytes(&block[Directory::rec_len_for(1)..]);
+        if dotdot.reserved & Directory::INDEXED == 0 {
+            return None;
         }
+
+        let root = unsafe { ptr::read_unaligned(block[Self::OFFSET..].as_ptr() as *const Self) };
+        if root.magic != DXMAGIC || root.count == 0 || root.count > root.limit {
+            return None;
+        }
+
+        Some(root)
+    }
+
+    fn write_to(&self, block: &mut [u8]) {
+        unsafe { ptr::write_unaligned(block[Self::OFFSET..].as_mut_ptr() as *mut Self, *self) }
+    }
+
+    fn entry_offset(i: usize) -> usize {
+        Self::OFFSET + size_of::<Self>() + i * size_of::<DxEntry>()
+    }
+
+    fn entry(block: &[u8], i: usize) -> DxEntry {
+        unsafe { ptr::read_unaligned(block[Self::entry_offset(i)..].as_ptr() as *const DxEntry) }
+    }
+
+    fn set_entry(block: &mut [u8], i: usize, entry: DxEntry) {
+        unsafe {
+            ptr::write_unaligned(
+                block[Self::entry_offset(i)..].as_mut_ptr() as *mut DxEntry,
+                entry,
+            )
+        }
+    }
+
+    /// Returns the index of the entry covering `hash`.
+    fn find(&self, block: &[u8], hash: u32) -> usize {
+        // binary search for the last entry with entry.hash <= hash; entry 0 always has hash 0
+        let (mut lo, mut hi) = (0, self.count as usize);
+        while hi - lo > 1 {
+            let mid = (lo + hi) / 2;
+            if Self::entry(block, mid).hash <= hash {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        lo
+    }
+
+    /// Hashes a directory entry name (32-bit FNV-1a).
+    pub fn hash(name: &[u8]) -> u32 {
+        name.iter().fold(0x811c9dc5, |hash, &b| {
+            (hash ^ b as u32).wrapping_mul(0x01000193)
+        })
+    }
+}
+
+impl Directory {
+    pub const HEADER_SIZE: usize = size_of::<Self>();
+
+    /// `reserved` flag set on the `..` record of an indexed directory
+    pub const INDEXED: u8 = 0x1;
+
+    /// Returns the smallest record length that can hold a name of `name_len` bytes.
+    pub const fn rec_len_for(name_len: usize) -> usize {
+        (Self::HEADER_SIZE + name_len + 3) & !3
+    }
+
+    /// Returns an iterator over the records of a directory block.
+    pub fn records(block: &[u8]) -> DirRecords<'_> {
+        DirRecords { block, offset: 0 }
     }
 
-    fn from_bytes(bytes: &[u8; Self::SIZE]) -> Self {
+    fn from_bytes(bytes: &[u8]) -> Self {
+        assert!(bytes.len() >= Self::HEADER_SIZE);
         unsafe { ptr::read_unaligned(bytes.as_ptr() as *const Self) }
     }
 
-    pub fn as_bytes(&self) -> &[u8] {
-        unsafe { slice::from_raw_parts(self as *const Self as *const u8, Self::SIZE) }
+    fn write_to(&self, bytes: &mut [u8]) {
+        assert!(bytes.len() >= Self::HEADER_SIZE);
+        unsafe { ptr::write_unaligned(bytes.as_mut_ptr() as *mut Self, *self) }
+    }
+
+    /// Writes a record with `name` at `offset` of `block`, spanning `rec_len` bytes.
+    fn write_record(block: &mut [u8], offset: usize, rec_len: usize, inum: u16, name: &[u8]) {
+        let entry = Self {
+            inum,
+            rec_len: rec_len as u16,
+            name_len: name.len() as u8,
+            reserved: 0,
+        };
+        entry.write_to(&mut block[offset..]);
+
+        let name_start = offset + Self::HEADER_SIZE;
+        block[name_start..name_start + name.len()].copy_from_slice(name);
+    }
+
+    /// Rewrites `block` to hold exactly `entries`, the last record covering the free space.
+    /// The caller must make sure the entries fit.
+    pub fn pack(block: &mut [u8], entries: &[(u16, &[u8])]) {
+        block.fill(0);
+
+        if entries.is_empty() {
+            Self::write_record(block, 0, block.len(), 0, b"");
+            return;
+        }
+
+        let mut offset = 0;
+        for (i, (inum, name)) in entries.iter().enumerate() {
+            let rec_len = if i == entries.len() - 1 {
+                block.len() - offset
+            } else {
+                Self::rec_len_for(name.len())
+            };
+
+            Self::write_record(block, offset, rec_len, *inum, name);
+            offset += rec_len;
+        }
     }
 
-    fn from_inode(
+    /// Calls `f` with the byte offset and buffer of directory block `block_no`.
+    fn with_block<T>(
         inode: &Inode,
         inner: &mut SleepLockGuard<'_, InodeInner>,
-        offset: u32,
-    ) -> Result<Self, FsError> {
-        let mut buf = [0; Self::SIZE];
-        let read = try_log!(inode.read(inner, offset, &mut buf, false));
-        assert_eq!(read as usize, Self::SIZE, "dir read from inode");
-        Ok(Self::from_bytes(&buf))
+        block_no: u32,
+        f: impl FnOnce(u32, &mut Buf<'_>) -> Result<T, FsError>,
+    ) -> Result<T, FsError> {
+        let addr = try_log!(inode.map(inner, block_no));
+        let mut buf = BCACHE.read(inode.dev, addr);
+
+        // a block past the end is being added, and has no checksum yet
+        if block_no < inner.size / BSIZE as u32
+            && let Err(e) = log!(Checksum::verify(inode.dev, &buf))
+        {
+            BCACHE.release(buf);
+            return Err(e);
+        }
+
+        let result = f(block_no * BSIZE as u32, &mut buf);
+        BCACHE.release(buf);
+        result
+    }
+
+    /// Calls `f` with the byte offset and buffer of each block of the directory, until `f` returns
+    /// an error or a value.
+    fn scan<T>(
+        inode: &Inode,
+        inner: &mut SleepLockGuard<'_, InodeInner>,
+        mut f: impl FnMut(u32, &mut Buf<'_>) -> Result<Option<T>, FsError>,
+    ) -> Result<Option<T>, FsError> {
+        for block_no in 0..inner.size / BSIZE as u32 {
+            match Self::with_block(inode, inner, block_no, &mut f) {
+                Ok(None) => {}
+                result => return result,
+            }
+        }
+
+        Ok(None)
     }
 
-    fn is_name_equal(&self, name: &str) -> bool {
-        let end = self.name.iter().position(|&c| c == 0).unwrap_or(DIRSIZE);
-        &self.name[..end] == name.as_bytes()
+    /// Returns the leaf block that holds `name` if the directory is indexed.
+    fn leaf_for(
+        inode: &Inode,
+        inner: &mut SleepLockGuard<'_, InodeInner>,
+        name: &[u8],
+    ) -> Result<Option<u32>, FsError> {
+        if inner.size < 2 * BSIZE as u32 {
+            return Ok(None);
+        }
+
+        Self::with_block(inode, inner, 0, |_, buf| {
+            let Some(root) = DxRoot::from_block(buf.data()) else {
+                return Ok(None);
+            };
+
+            let entry = DxRoot::entry(buf.data(), root.find(buf.data(), DxRoot::hash(name)));
+            Ok(Some(entry.block))
+        })
     }
 
-    fn set_name(&mut self, name: &str) {
-        self.name.fill(0);
-        let bytes = name.as_bytes();
-        let len = bytes.len().min(DIRSIZE);
-        self.name[..len].copy_from_slice(&bytes[..len]);
+    /// Looks for `name` in a directory block, returning its offset in the block and inode number.
+    fn find_in_block(block: &[u8], name: &[u8]) -> Result<Option<(usize, u16)>, FsError> {
+        for record in Self::records(block) {
+            let record = try_log!(record);
+            if record.entry.inum != 0 && record.name == name {
+                return Ok(Some((record.offset, record.entry.inum)));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Places (name, inum) in a free slot of `block`, either in an unused record or after a live
+    /// entry. Returns false if the block has no room.
+    fn insert_in_block(block: &mut [u8], name: &[u8], inum: u16) -> Result<bool, FsError> {
+        let needed = Self::rec_len_for(name.len());
+        let mut slot = None;
+
+        for record in Self::records(block) {
+            let record = try_log!(record);
+            let used = if record.entry.inum == 0 {
+                0
+            } else {
+                Self::rec_len_for(record.entry.name_len as usize)
+            };
+
+            if record.entry.rec_len as usize - used >= needed {
+                slot = Some((record.offset, record.entry, used));
+                break;
+            }
+        }
+
+        let Some((offset, mut entry, used)) = slot else {
+            return Ok(false);
+        };
+
+        if used == 0 {
+            Self::write_record(block, offset, entry.rec_len as usize, inum, name);
+        } else {
+            // split: the live entry keeps what it uses, the new one takes the rest
+            let rec_len = entry.rec_len as usize - used;
+            entry.rec_len = used as u16;
+            entry.write_to(&mut block[offset..]);
+            Self::write_record(block, offset + used, rec_len, inum, name);
+        }
+
+        Ok(true)
     }
 
     /// Checks whether the directory is empty (only contains `.` and `..`).
     pub fn is_empty(inode: &Inode, inner: &mut SleepLockGuard<'_, InodeInner>) -> bool {
-        for offset in ((2 * Self::SIZE as u32)..inner.size).step_by(Self::SIZE) {
-            let dir = log!(Self::from_inode(inode, inner, offset)).expect("dir is_empty");
-            if dir.inum != 0 {
-                return false;
+        let found = log!(Self::scan(inode, inner, |_, buf| {
+            for record in Self::records(buf.data()) {
+                let record = try_log!(record);
+                if record.entry.inum != 0 && record.name != b"." && record.name != b".." {
+                    return Ok(Some(()));
+                }
+            }
+            Ok(None)
+        }));
+
+        found.expect("dir is_empty").is_none()
+    }
+
+    /// Returns the first entry at or after byte `offset`, and the offset of the record after it.
+    pub fn next_entry(
+        inode: &Inode,
+        inner: &mut SleepLockGuard<'_, InodeInner>,
+        offset: u32,
+    ) -> Result<Option<(DirEntry, u32)>, FsError> {
+        for block_no in offset / BSIZE as u32..inner.size / BSIZE as u32 {
+            let found = try_log!(Self::with_block(
+                inode,
+                inner,
+                block_no,
+                |block_offset, buf| {
+                    for record in Self::records(buf.data()) {
+                        let record = try_log!(record);
+                        let end =
+                            block_offset + (record.offset + record.entry.rec_len as usize) as u32;
+
+                        if end <= offset || record.entry.inum == 0 {
+                            continue;
+                        }
+
+                        let entry = DirEntry {
+                            ino: record.entry.inum as u32,
+                            name: String::from_utf8_lossy(record.name).into_owned(),
+                        };
+                        return Ok(Some((entry, end)));
+                    }
+                    Ok(None)
+                }
+            ));
+
+            if found.is_some() {
+                return Ok(found);
             }
         }
 
-        true
+        Ok(None)
     }
 
     /// Looks up for a directory entry in a directory.
@@ -907,21 +2057,28 @@ impl Directory {
     ) -> Result<Option<(u32, Inode)>, FsError> {
         assert_eq!(inner.r#type, InodeType::Directory, "dirlookup not DIR");
 
-        for offset in (0..inner.size).step_by(Self::SIZE) {
-            let dir = try_log!(Self::from_inode(inode, inner, offset));
-
-            if dir.inum == 0 {
-                continue;
-            }
+        let name = name.as_bytes();
+
+        let found = match try_log!(Self::leaf_for(inode, inner, name)) {
+            // indexed: only the leaf covering the name's hash can hold it
+            Some(leaf) => try_log!(Self::with_block(inode, inner, leaf, |block_offset, buf| {
+                let found = try_log!(Self::find_in_block(buf.data(), name));
+                Ok(found.map(|(offset, inum)| (block_offset + offset as u32, inum)))
+            })),
+            None => try_log!(Self::scan(inode, inner, |block_offset, buf| {
+                let found = try_log!(Self::find_in_block(buf.data(), name));
+                Ok(found.map(|(offset, inum)| (block_offset + offset as u32, inum)))
+            })),
+        };
 
-            if dir.is_name_equal(name) {
+        match found {
+            Some((offset, inum)) => {
                 // entry matches path element
-                let dir_inode = try_log!(Inode::get(inode.dev, dir.inum as u32));
-                return Ok(Some((offset, dir_inode)));
+                let dir_inode = try_log!(Inode::get(inode.dev, inum as u32));
+                Ok(Some((offset, dir_inode)))
             }
+            None => Ok(None),
         }
-
-        Ok(None)
     }
 
     /// Writes a new directory entry (name, inum) into the directory Inode.
@@ -931,141 +2088,645 @@ impl Directory {
         name: &str,
         inum: u16,
     ) -> Result<(), FsError> {
+        if name.len() > MAXNAME {
+            err!(FsError::NameTooLong);
+        }
+
         // check the name is not present
         if let Ok(Some((_, dir))) = log!(Self::lookup(inode, inner, name)) {
             dir.put();
             err!(FsError::Link);
         }
 
-        // look for an empty directory
-        let mut dir = Self::new_empty();
-        let mut offset = 0;
+        let name = name.as_bytes();
 
-        while offset < inner.size {
-            dir = try_log!(Self::from_inode(inode, inner, offset));
+        if let Some(leaf) = try_log!(Self::leaf_for(inode, inner, name)) {
+            if try_log!(Self::link_indexed(inode, inner, leaf, name, inum)) {
+                return Ok(());
+            }
 
-            // if we find an empty slot break and use it.
-            // otherwise, the dir will be appended to the end
-            if dir.inum == 0 {
-                break;
+            try_log!(Self::drop_index(inode, inner));
+        }
+
+        // look for a record with enough free space, either unused or trailing a live entry
+        let placed = try_log!(Self::scan(inode, inner, |_, buf| {
+            if try_log!(Self::insert_in_block(buf.data_mut(), name, inum)) {
+                Checksum::write(inode.dev, buf);
+                Ok(Some(()))
             } else {
-                offset += Self::SIZE as u32;
+                Ok(None)
             }
+        }));
+
+        if placed.is_some() {
+            return Ok(());
         }
 
-        dir.set_name(name);
-        dir.inum = inum;
+        if inner.size == BSIZE as u32 {
+            // the directory outgrew its first block: switch to an indexed layout
+            try_log!(Self::convert_to_indexed(inode, inner));
+            if try_log!(Self::link_indexed(inode, inner, 1, name, inum)) {
+                return Ok(());
+            }
 
-        let write = try_log!(inode.write(inner, offset, dir.as_bytes(), false));
-        if write as usize != Self::SIZE {
-            err!(FsError::Link);
+            try_log!(Self::drop_index(inode, inner));
+        }
+
+        // no room left, append a new block holding a single record
+        let offset = inner.size;
+        try_log!(Self::with_block(
+            inode,
+            inner,
+            offset / BSIZE as u32,
+            |_, buf| {
+                Self::pack(
+                    &mut buf.data_mut()[..Checksum::space(inode.dev)],
+                    &[(inum, name)],
+                );
+                Checksum::write(inode.dev, buf);
+                Ok(())
+            }
+        ));
+
+        inner.size = offset + BSIZE as u32;
+        inode.update(inner);
+
+        Ok(())
+    }
+
+    /// Moves the entries of a single-block directory to a new leaf and turns block 0 into an
+    /// index root pointing at it.
+    /// Writes block 0, the leaf, the bitmap and the inode, which fits in one log operation.
+    fn convert_to_indexed(
+        inode: &Inode,
+        inner: &mut SleepLockGuard<'_, InodeInner>,
+    ) -> Result<(), FsError> {
+        let mut dot = 0;
+        let mut dotdot = 0;
+        let mut entries: Vec<(u16, Vec<u8>)> = Vec::new();
+
+        try_log!(Self::with_block(inode, inner, 0, |_, buf| {
+            for record in Self::records(buf.data()) {
+                let record = try_log!(record);
+                match (record.entry.inum, record.name) {
+                    (0, _) => {}
+                    (inum, b".") => dot = inum,
+                    (inum, b"..") => dotdot = inum,
+                    (inum, name) => entries.push((inum, name.to_vec())),
+                }
+            }
+            Ok(())
+        }));
+
+        let entries = entries
+            .iter()
+            .map(|(inum, name)| (*inum, name.as_slice()))
+            .collect::<Vec<_>>();
+
+        try_log!(Self::with_block(inode, inner, 1, |_, buf| {
+            Self::pack(&mut buf.data_mut()[..Checksum::space(inode.dev)], &entries);
+            Checksum::write(inode.dev, buf);
+            Ok(())
+        }));
+
+        try_log!(Self::with_block(inode, inner, 0, |_, buf| {
+            let space = Checksum::space(inode.dev);
+            let data = buf.data_mut();
+            Self::pack(&mut data[..space], &[(dot, b"."), (dotdot, b"..")]);
+
+            let mut entry = Self::from_bytes(&data[Self::rec_len_for(1)..]);
+            entry.reserved |= Self::INDEXED;
+            entry.write_to(&mut data[Self::rec_len_for(1)..]);
+
+            let root = DxRoot {
+                magic: DXMAGIC,
+                count: 1,
+                limit: DxRoot::LIMIT as u16,
+            };
+            root.write_to(data);
+            DxRoot::set_entry(data, 0, DxEntry { hash: 0, block: 1 });
+
+            Checksum::write(inode.dev, buf);
+            Ok(())
+        }));
+
+        inner.size = 2 * BSIZE as u32;
+        inode.update(inner);
+
+        Ok(())
+    }
+
+    /// Turns an indexed directory back into a plain one, whose blocks are all searched linearly.
+    /// The leaves stay where they are, as ordinary directory blocks.
+    fn drop_index(
+        inode: &Inode,
+        inner: &mut SleepLockGuard<'_, InodeInner>,
+    ) -> Result<(), FsError> {
+        Self::with_block(inode, inner, 0, |_, buf| {
+            let data = buf.data_mut();
+            let mut entry = Self::from_bytes(&data[Self::rec_len_for(1)..]);
+            entry.reserved &= !Self::INDEXED;
+            entry.write_to(&mut data[Self::rec_len_for(1)..]);
+
+            Checksum::write(inode.dev, buf);
+            Ok(())
+        })
+    }
+
+    /// Inserts (name, inum) into `leaf` of an indexed directory, splitting the leaf in two if it
+    /// is full. Returns false without changing anything if the index has no room for another leaf
+    /// or the leaf cannot be split, since all its names share a hash.
+    /// Writes the root, both leaves, the bitmap, the inode and possibly the indirect block, which
+    /// fits in one log operation.
+    fn link_indexed(
+        inode: &Inode,
+        inner: &mut SleepLockGuard<'_, InodeInner>,
+        leaf: u32,
+        name: &[u8],
+        inum: u16,
+    ) -> Result<bool, FsError> {
+        let mut entries: Vec<(u32, u16, Vec<u8>)> = Vec::new();
+
+        let placed = try_log!(Self::with_block(inode, inner, leaf, |_, buf| {
+            if try_log!(Self::insert_in_block(buf.data_mut(), name, inum)) {
+                Checksum::write(inode.dev, buf);
+                return Ok(true);
+            }
+
+            for record in Self::records(buf.data()) {
+                let record = try_log!(record);
+                if record.entry.inum != 0 {
+                    let hash = DxRoot::hash(record.name);
+                    entries.push((hash, record.entry.inum, record.name.to_vec()));
+                }
+            }
+            Ok(false)
+        }));
+
+        if placed {
+            return Ok(true);
+        }
+
+        // the leaf is full: split it at a hash boundary close to the middle by bytes
+        entries.push((DxRoot::hash(name), inum, name.to_vec()));
+        entries.sort_by_key(|(hash, _, _)| *hash);
+
+        let sizes = entries
+            .iter()
+            .map(|(_, _, name)| Self::rec_len_for(name.len()))
+            .collect::<Vec<_>>();
+        let total: usize = sizes.iter().sum();
+
+        let mut middle = 0;
+        let mut acc = 0;
+        while middle < entries.len() && acc + sizes[middle] <= total / 2 {
+            acc += sizes[middle];
+            middle += 1;
+        }
+
+        // both halves must fit, so try every boundary, starting from the middle
+        let space = Checksum::space(inode.dev);
+        let split = (middle..entries.len()).chain((1..middle).rev()).find(|&i| {
+            i > 0
+                && entries[i - 1].0 != entries[i].0
+                && sizes[..i].iter().sum::<usize>() <= space
+                && sizes[i..].iter().sum::<usize>() <= space
+        });
+
+        let Some(split) = split else {
+            return Ok(false);
+        };
+
+        let new_leaf = inner.size / BSIZE as u32;
+        let split_hash = entries[split].0;
+
+        let entries = entries
+            .iter()
+            .map(|(_, inum, name)| (*inum, name.as_slice()))
+            .collect::<Vec<_>>();
+        let (low, high) = entries.split_at(split);
+
+        // check for room in the index before touching anything
+        let full = try_log!(Self::with_block(inode, inner, 0, |_, buf| {
+            match DxRoot::from_block(buf.data()) {
+                Some(root) => Ok(root.count >= root.limit),
+                None => err!(FsError::Read),
+            }
+        }));
+
+        if full {
+            return Ok(false);
+        }
+
+        // allocate and fill the new leaf before linking it into the index
+        try_log!(Self::with_block(inode, inner, new_leaf, |_, buf| {
+            Self::pack(&mut buf.data_mut()[..Checksum::space(inode.dev)], high);
+            Checksum::write(inode.dev, buf);
+            Ok(())
+        }));
+
+        try_log!(Self::with_block(inode, inner, 0, |_, buf| {
+            let data = buf.data_mut();
+            let Some(mut root) = DxRoot::from_block(data) else {
+                err!(FsError::Read);
+            };
+
+            // shift the entries after the split leaf to make room
+            let at = root.find(data, split_hash) + 1;
+            for i in (at..root.count as usize).rev() {
+                let entry = DxRoot::entry(data, i);
+                DxRoot::set_entry(data, i + 1, entry);
+            }
+            DxRoot::set_entry(
+                data,
+                at,
+                DxEntry {
+                    hash: split_hash,
+                    block: new_leaf,
+                },
+            );
+
+            root.count += 1;
+            root.write_to(data);
+
+            Checksum::write(inode.dev, buf);
+            Ok(())
+        }));
+
+        try_log!(Self::with_block(inode, inner, leaf, |_, buf| {
+            Self::pack(&mut buf.data_mut()[..Checksum::space(inode.dev)], low);
+            Checksum::write(inode.dev, buf);
+            Ok(())
+        }));
+
+        inner.size += BSIZE as u32;
+        inode.update(inner);
+
+        Ok(true)
+    }
+
+    /// Removes the directory entry at byte `offset`, as returned by `lookup()`.
+    /// The record's space is merged into the previous record in its block, so it can be reused.
+    pub fn unlink(
+        inode: &Inode,
+        inner: &mut SleepLockGuard<'_, InodeInner>,
+        offset: u32,
+    ) -> Result<(), FsError> {
+        let target = offset as usize % BSIZE;
+        let addr = try_log!(inode.map(inner, offset / BSIZE as u32));
+        let mut buf = BCACHE.read(inode.dev, addr);
+        if let Err(e) = log!(Checksum::verify(inode.dev, &buf)) {
+            BCACHE.release(buf);
+            return Err(e);
         }
 
+        let mut prev: Option<DirRecord<'_>> = None;
+        let mut found = None;
+
+        for record in Self::records(buf.data()) {
+            let Ok(record) = log!(record) else {
+                break;
+            };
+
+            if record.offset == target {
+                found = Some((prev.map(|p| (p.offset, p.entry)), record.entry));
+                break;
+            }
+
+            prev = Some(record);
+        }
+
+        let Some((prev, mut entry)) = found else {
+            BCACHE.release(buf);
+            err!(FsError::Resolve);
+        };
+
+        let data = buf.data_mut();
+        match prev {
+            Some((prev_offset, mut prev_entry)) => {
+                prev_entry.rec_len += entry.rec_len;
+                prev_entry.write_to(&mut data[prev_offset..]);
+            }
+            None => {
+                // first record of the block, keep it as an unused record
+                entry.inum = 0;
+                entry.name_len = 0;
+                entry.write_to(&mut data[target..]);
+            }
+        }
+
+        Checksum::write(inode.dev, &mut buf);
+        BCACHE.release(buf);
+
         Ok(())
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct Path<'a>(&'a str);
+/// The disk file system on virtio disk `dev`, as mounted through the VFS.
+/// Nodes are inodes, named by their inode table index.
+#[derive(Debug)]
+pub struct DiskFs {
+    dev: u32,
+}
 
-impl<'a> Path<'a> {
-    pub const fn new(name: &'a str) -> Path<'a> {
-        Self(name)
+impl DiskFs {
+    /// Mounts the file system on disk `dev`.
+    /// Must not be called inside a transaction, since mounting recovers the disk with its own.
+    pub fn mount(dev: u32) -> Result<Arc<dyn FileSystem>, FsError> {
+        try_log!(mount(dev));
+        Ok(Arc::new(DiskFs { dev }))
     }
 
-    pub fn as_str(&self) -> &'a str {
-        self.0
+    fn inode(&self, node: NodeId) -> Inode {
+        Inode::new(node.id, self.dev, node.ino)
     }
 
-    fn is_empty(&self) -> bool {
-        self.0.is_empty()
+    fn node(inode: &Inode) -> NodeId {
+        NodeId {
+            id: inode.id,
+            ino: inode.inum,
+        }
     }
 
-    fn is_absolute(&self) -> bool {
-        self.0.starts_with('/')
+    /// Drops the link `link()` counted on `inode` when the directory entry could not be made.
+    fn undo_link(inode: &Inode) {
+        if let Ok(mut inner) = log!(inode.lock()) {
+            inner.nlink -= 1;
+            inode.update(&inner);
+            inode.unlock(inner);
+        }
     }
+}
 
-    /// Returns (next path component, rest).
-    /// The returned path has no leading slashes, so the caller can check to see if the component
-    /// is the last one (Path == '\0').
-    /// If no component to remove, returns None.
-    pub fn next_component(&self) -> Option<(&'a str, Path<'a>)> {
-        let s = self.0.trim_start_matches('/');
+impl FileSystem for DiskFs {
+    fn dev(&self) -> u32 {
+        self.dev
+    }
 
-        if s.is_empty() {
-            return None;
+    fn root(&self) -> Result<NodeId, FsError> {
+        let root = try_log!(Inode::get(self.dev, ROOTINO));
+        Ok(Self::node(&root))
+    }
+
+    fn statfs(&self) -> StatFs {
+        Usage::statfs(self.dev)
+    }
+
+    fn busy(&self) -> bool {
+        Inode::in_use(self.dev)
+    }
+
+    fn unmount(&self) {
+        unmount(self.dev);
+    }
+}
+
+impl VnodeOps for DiskFs {
+    fn dup(&self, node: NodeId) {
+        self.inode(node).dup();
+    }
+
+    fn put(&self, node: NodeId) {
+        self.inode(node).put();
+    }
+
+    fn stat(&self, node: NodeId) -> Result<Stat, FsError> {
+        let inode = self.inode(node);
+        let inner = try_log!(inode.lock());
+        let stat = inode.stat(&inner);
+        inode.unlock(inner);
+        Ok(stat)
+    }
+
+    fn lookup(&self, dir: NodeId, name: &str) -> Result<Option<NodeId>, FsError> {
+        let dir = self.inode(dir);
+        let mut inner = try_log!(dir.lock());
+
+        if inner.r#type != InodeType::Directory {
+            dir.unlock(inner);
+            err!(FsError::NotDirectory);
         }
 
-        match s.find('/') {
-            Some(i) => {
-                let rest = s[i..].trim_start_matches('/');
-                Some((&s[..i], Path(rest)))
+        let found = log!(Directory::lookup(&dir, &mut inner, name));
+        dir.unlock(inner);
+
+        Ok(try_log!(found).map(|(_, inode)| Self::node(&inode)))
+    }
+
+    fn create(
+        &self,
+        dir: NodeId,
+        name: &str,
+        r#type: InodeType,
+        major: u16,
+        minor: u16,
+        exclusive: bool,
+    ) -> Result<NodeId, FsError> {
+        let (inode, inner) = try_log!(Inode::create(
+            &self.inode(dir),
+            name,
+            r#type,
+            major,
+            minor,
+            exclusive
+        ));
+        inode.unlock(inner);
+
+        Ok(Self::node(&inode))
+    }
+
+    fn link(&self, dir: NodeId, name: &str, node: NodeId) -> Result<(), FsError> {
+        let inode = self.inode(node);
+        let mut inner = try_log!(inode.lock());
+
+        // make sure it is not a directory
+        if inner.r#type == InodeType::Directory {
+            inode.unlock(inner);
+            err!(FsError::NotPermitted);
+        }
+
+        // increment number of links pointing to the inode
+        inner.nlink += 1;
+        inode.update(&inner);
+        inode.unlock(inner);
+
+        // add the inode to the directory
+        let dir = self.inode(dir);
+        let mut dir_inner = match log!(dir.lock()) {
+            Ok(inner) => inner,
+            Err(e) => {
+                Self::undo_link(&inode);
+                return Err(e);
             }
-            None => Some((s, Path(""))),
+        };
+        let result = log!(Directory::link(
+            &dir,
+            &mut dir_inner,
+            name,
+            inode.inum as u16
+        ));
+        dir.unlock(dir_inner);
+
+        if result.is_err() {
+            Self::undo_link(&inode);
         }
+
+        result
     }
 
-    fn resolve_inner(&self, parent: bool) -> Result<(Inode, &'a str), FsError> {
-        let mut inode = if self.is_absolute() {
-            try_log!(Inode::get(ROOTDEV, ROOTINO))
-        } else {
-            proc::current_proc().data().cwd.dup()
+    fn unlink(&self, dir: NodeId, name: &str) -> Result<(), FsError> {
+        let dir = self.inode(dir);
+        let mut dir_inner = try_log!(dir.lock());
+
+        // find the inode in the directory
+        let Ok(Some((offset, inode))) = log!(Directory::lookup(&dir, &mut dir_inner, name)) else {
+            dir.unlock(dir_inner);
+            err!(FsError::Resolve);
         };
 
-        let mut name = "";
-        let mut path = self.clone();
+        let mut inner = match log!(inode.lock()) {
+            Ok(inner) => inner,
+            Err(e) => {
+                inode.put();
+                dir.unlock(dir_inner);
+                return Err(e);
+            }
+        };
+
+        assert!(inner.nlink >= 1, "unlink nlink < 1");
+
+        // if the inode is a directory and it is not empty, cannot unlink
+        if inner.r#type == InodeType::Directory && !Directory::is_empty(&inode, &mut inner) {
+            inode.unlock_put(inner);
+            dir.unlock(dir_inner);
+            err!(FsError::NotEmpty);
+        }
+
+        // remove the directory entry, freeing its space for later links
+        if let Err(e) = log!(Directory::unlink(&dir, &mut dir_inner, offset)) {
+            inode.unlock_put(inner);
+            dir.unlock(dir_inner);
+            return Err(e);
+        }
+
+        // if it is a directory, decrement parent's link count
+        if inner.r#type == InodeType::Directory {
+            dir_inner.nlink -= 1;
+            dir.update(&dir_inner);
+        }
+        dir.unlock(dir_inner);
+
+        // decrement the inode's link count
+        inner.nlink -= 1;
+        inode.update(&inner);
+        inode.unlock_put(inner);
+
+        Ok(())
+    }
+
+    fn readdir(&self, dir: NodeId, offset: u32) -> Result<Option<(DirEntry, u32)>, FsError> {
+        let dir = self.inode(dir);
+        let mut inner = try_log!(dir.lock());
+        let result = log!(Directory::next_entry(&dir, &mut inner, offset));
+        dir.unlock(inner);
+        result
+    }
+}
+
+impl FileOps for DiskFs {
+    fn read(&self, node: NodeId, offset: u32, dst: &mut [u8], user: bool) -> Result<u32, FsError> {
+        let inode = self.inode(node);
+        let mut inner = try_log!(inode.lock());
+        let result = log!(inode.read(&mut inner, offset, dst, user));
+        inode.unlock(inner);
+        result
+    }
+
+    fn write(
+        &self,
+        node: NodeId,
+        offset: &mut u32,
+        src: &[u8],
+        user: bool,
+        append: bool,
+    ) -> Result<u32, FsError> {
+        let inode = self.inode(node);
+
+        // write as much as fits in one log transaction at a time, so writes up to `max` bytes are
+        // atomic. each data block may need an allocation block, plus the inode, the indirect
+        // block, and 2 blocks of slop for non-aligned writes.
+        let op_blocks = |bytes: usize| bytes.div_ceil(BSIZE) * 2 + 1 + 1 + 2;
+        let max = ((log::max_op_blocks(self.dev) - 1 - 1 - 2) / 2) * BSIZE;
+        let n = src.len();
+        let mut i = 0;
 
-        // walk the path, one component at at time
-        while let Some((component, rest)) = path.next_component() {
-            let mut inner = inode.lock();
+        while i < n {
+            let n1 = (n - i).min(max);
 
-            if inner.r#type != InodeType::Directory {
-                inode.unlock_put(inner);
-                err!(FsError::Resolve);
+            let _op = Operation::reserve(self.dev, op_blocks(n1));
+            let Ok(mut inner) = log!(inode.lock()) else {
+                break;
+            };
+
+            // appends are atomic since the offset is taken under the inode lock
+            if append {
+                *offset = inner.size;
             }
 
-            // stop one level early
-            if parent && rest.is_empty() {
+            // blocks left past the end by a shrink would show up again in the grown file
+            if *offset as usize + n1 > inner.size as usize && inode.past_end(&inner) {
                 inode.unlock(inner);
-                return Ok((inode, component));
+                drop(_op);
+                inode.finish_trim();
+                continue;
             }
 
-            // get the next inode
-            match log!(Directory::lookup(&inode, &mut inner, component)) {
-                Ok(Some((_, next))) => {
-                    inode.unlock_put(inner);
-                    inode = next;
-                }
-                Ok(None) => {
-                    inode.unlock_put(inner);
-                    err!(FsError::Resolve);
-                }
-                Err(e) => {
-                    inode.unlock_put(inner);
-                    return Err(e);
-                }
+            let write = log!(inode.write(&mut inner, *offset, &src[i..i + n1], user));
+
+            if let Ok(w) = write {
+                *offset += w;
             }
 
-            name = component;
-            path = rest;
+            inode.unlock(inner);
+            drop(_op);
+
+            match write {
+                Ok(w) if w as usize == n1 => i += n1,
+                _ => break,
+            }
         }
 
-        // we returned early to put the last inode
-        if parent {
-            inode.put();
-            err!(FsError::Resolve);
+        if i != n {
+            err!(FsError::Write);
         }
 
-        Ok((inode, name))
+        Ok(n as u32)
     }
 
-    /// Resolves the full path to an inode.
-    pub fn resolve(&self) -> Result<Inode, FsError> {
-        log!(self.resolve_inner(false).map(|(inode, _)| inode))
+    fn truncate(&self, node: NodeId, size: usize) -> Result<(), FsError> {
+        let inode = self.inode(node);
+
+        loop {
+            let _op = Operation::begin();
+            let mut inner = try_log!(inode.lock());
+
+            // blocks left past the end by a shrink would show up again in the grown file
+            if size > inner.size as usize && inode.past_end(&inner) {
+                inode.unlock(inner);
+                drop(_op);
+                inode.finish_trim();
+                continue;
+            }
+
+            let result = log!(inode.resize(&mut inner, size));
+            inode.unlock(inner);
+            return result;
+        }
     }
 
-    /// Resolves to the parent directory, returning (parent, final_name).
-    pub fn resolve_parent(&self) -> Result<(Inode, &'a str), FsError> {
-        log!(self.resolve_inner(true))
+    fn sync(&self, _node: NodeId) -> Result<(), FsError> {
+        // data and metadata of every file on a disk share its log, so committing it is enough
+        log::force(self.dev);
+        Ok(())
     }
 }
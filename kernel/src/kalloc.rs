@@ -34,6 +34,20 @@ unsafe impl GlobalAlloc for Kmem {
     }
 }
 
+/// Returns the number of bytes managed by the kernel memory allocator.
+pub fn total() -> usize {
+    unsafe { (PHYSTOP as *const u8).offset_from(end.as_ptr()) as usize }
+}
+
+/// Returns the number of free bytes in the kernel memory allocator.
+pub fn available() -> usize {
+    KMEM.0
+        .lock()
+        .as_ref()
+        .expect("kmem to be init")
+        .available_bytes()
+}
+
 /// Initialize kernel memory allocator.
 ///
 /// # Safety
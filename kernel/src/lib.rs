@@ -9,20 +9,27 @@ pub(crate) mod printf;
 pub(crate) mod error;
 pub(crate) mod buf;
 pub(crate) mod console;
+pub(crate) mod crc;
+pub(crate) mod devfs;
 pub(crate) mod entry;
 pub(crate) mod exec;
+pub(crate) mod fat;
 pub(crate) mod file;
 pub(crate) mod fs;
 pub(crate) mod kalloc;
 pub(crate) mod kernelvec;
 pub(crate) mod log;
+pub(crate) mod memdev;
 pub(crate) mod memlayout;
+pub(crate) mod mount;
 pub(crate) mod param;
 pub(crate) mod pipe;
 pub(crate) mod plic;
 pub(crate) mod proc;
+pub(crate) mod procfs;
 pub(crate) mod riscv;
 pub(crate) mod sleeplock;
+pub(crate) mod socket;
 pub(crate) mod spinlock;
 pub(crate) mod start;
 pub(crate) mod swtch;
@@ -30,9 +37,11 @@ pub(crate) mod sync;
 pub(crate) mod syscall;
 pub(crate) mod sysfile;
 pub(crate) mod sysproc;
+pub(crate) mod tmpfs;
 pub(crate) mod trampoline;
 pub(crate) mod trap;
 pub(crate) mod uart;
+pub(crate) mod vfs;
 pub(crate) mod virtio_disk;
 pub(crate) mod vm;
 
@@ -68,6 +77,7 @@ pub extern "C" fn main() -> ! {
         println!("");
 
         println!("hart {} is starting", cpu_id);
+        proc::HARTS.fetch_add(1, Ordering::SeqCst);
 
         STARTED.store(true, Ordering::SeqCst);
     } else {
@@ -76,6 +86,7 @@ pub extern "C" fn main() -> ! {
         }
 
         println!("hart {} is starting", cpu_id);
+        proc::HARTS.fetch_add(1, Ordering::SeqCst);
 
         unsafe {
             vm::init_hart();
@@ -5,23 +5,47 @@
 // about whether a commit might write an uncommitted system call's updates to disk.
 //
 // A system call should call begin_op()/end_op() to mark its start and end. Usually begin_op() just
-// increments the count of in-progress FS system calls and returns. But if it thinks the log is
-// close to running out, it sleeps until the last outstanding end_op() commits.
+// reserves the number of blocks the operation may write and returns. But if the reservations would
+// overflow the log, it sleeps until the last outstanding end_op() commits. Most operations reserve
+// `MAXOPBLOCKS`; large writes reserve more with `Operation::reserve()`, up to `max_op_blocks()`.
 //
-// The log is a physical re-do log containing disk blocks.
+// Commits are deferred so that many small operations share one log write (group commit). The
+// last end_op() only commits once `LOGFLUSHBLOCKS` blocks are waiting or a commit was forced by
+// `force()` (fsync). Otherwise the `logd` kernel thread commits whatever is pending every
+// `LOGFLUSHTICKS` ticks.
+//
+// The log is a physical re-do log containing disk blocks. Its size comes from the superblock, up
+// to the `LOGBLOCKS` blocks the header block can list.
 // The on-disk log format:
 //   header block, containing block #s for block A, B, C, ...
 //   block A
 //   block B
 //   block C
 //   ...
-// Log appends are synchronous.
+// Log appends are synchronous. On a file system with checksums, the last 4 bytes of the header
+// block hold the CRC32C of the rest of it, and a header that does not match is treated as empty,
+// since the commit that wrote it did not finish.
+//
+// Installing a committed transaction only marks the cached blocks dirty; the buffer cache writes
+// them to their home locations later. So the log keeps committed transactions after the ones
+// being built, and the header lists the blocks of all of them. Recovery replays them in order.
+// Once the log is half full, or an operation needs the space, a checkpoint writes every dirty
+// buffer back and empties the log. Since there are no operations during a checkpoint, the cache
+// only holds committed changes then.
+//
+// Every mounted disk has its own log, described by its own superblock. An operation does not know
+// in advance which disks it will modify, so it begins on the log of every mounted disk. Logs are
+// always entered in device order, so operations waiting for different logs cannot deadlock.
 
 use crate::buf::{BCACHE, Buf};
-use crate::fs::{BSIZE, SuperBlock};
-use crate::param::{LOGBLOCKS, MAXOPBLOCKS};
+use crate::fs::{BSIZE, CSUMSIZE, Checksum, Inode, SuperBlock};
+use crate::param::{LOGFLUSHBLOCKS, LOGFLUSHTICKS, MAXOPBLOCKS, NDISK};
 use crate::proc::{self, Channel};
-use crate::spinlock::SpinLock;
+use crate::spinlock::{SpinLock, SpinLockGuard};
+use crate::trap::TICKS;
+
+/// Most data blocks a log can hold, as many as the header block lists in front of its checksum
+const LOGBLOCKS: usize = (BSIZE - CSUMSIZE) / size_of::<u32>() - 1;
 
 /// Contents of the header block, used for both the on-disk header block and to keep track in memory
 /// of logged block# before commit.
@@ -35,14 +59,33 @@ pub struct LogHeader {
 #[derive(Debug)]
 pub struct LogInner {
     start: u32,
-    size: u32,
+    /// Number of blocks a transaction may hold
+    capacity: usize,
     outstanding: u32,
+    /// Blocks reserved by outstanding operations
+    reserved: usize,
+    /// Number of logged blocks of committed transactions, which come first in the header
+    committed: u32,
     committing: bool,
+    /// A caller is waiting for the pending transaction to be committed
+    force: bool,
+    /// Number of completed commits
+    commits: usize,
+    /// The disk is mounted and operations begin on this log
+    active: bool,
+    /// The header block carries a checksum
+    checksummed: bool,
     dev: u32,
     header: LogHeader,
 }
 
-pub static LOG: Log = Log::new();
+/// Log `i` belongs to the disk with device number `i + 1`
+pub static LOGS: [Log; NDISK] = [const { Log::new() }; NDISK];
+
+/// Returns the log of device `dev`.
+fn log(dev: u32) -> &'static Log {
+    &LOGS[dev as usize - 1]
+}
 
 #[derive(Debug)]
 pub struct Log {
@@ -55,9 +98,15 @@ impl Log {
             inner: SpinLock::new(
                 LogInner {
                     start: 0,
-                    size: 0,
+                    capacity: 0,
                     outstanding: 0,
+                    reserved: 0,
+                    committed: 0,
                     committing: false,
+                    force: false,
+                    commits: 0,
+                    active: false,
+                    checksummed: false,
                     dev: 0,
                     header: LogHeader {
                         n: 0,
@@ -69,16 +118,38 @@ impl Log {
         }
     }
 
-    /// Copies committed blocks from log to their home location
-    fn install_trans(recovering: bool) {
+    /// Installs the transaction being committed into the buffer cache.
+    /// Its blocks are already cached, so they are only unpinned and marked dirty for write back.
+    fn install_trans(&self) {
+        let (dev, committed, n) = {
+            let inner = self.inner.lock();
+            (inner.dev, inner.committed, inner.header.n)
+        }; // LOG lock dropped here
+
+        for tail in committed..n {
+            let block = {
+                let inner = self.inner.lock();
+                inner.header.blocks[tail as usize]
+            }; // LOG lock dropped here
+
+            let buf = BCACHE.read(dev, block);
+            BCACHE.mark_dirty(&buf);
+            BCACHE.unpin(&buf);
+            BCACHE.release(buf);
+        }
+    }
+
+    /// Copies all blocks in the log to their home location.
+    /// Called at recovery, when nothing is cached.
+    fn replay(&self) {
         let (dev, start, n) = {
-            let inner = LOG.inner.lock();
+            let inner = self.inner.lock();
             (inner.dev, inner.start, inner.header.n)
         }; // LOG lock dropped here
 
         for tail in 0..n {
             let block = {
-                let inner = LOG.inner.lock();
+                let inner = self.inner.lock();
                 inner.header.blocks[tail as usize]
             }; // LOG lock dropped here
 
@@ -93,10 +164,6 @@ impl Log {
             // write dst to disk
             BCACHE.write(&mut dbuf);
 
-            if !recovering {
-                BCACHE.unpin(&dbuf);
-            }
-
             BCACHE.release(lbuf);
             BCACHE.release(dbuf);
         }
@@ -107,17 +174,24 @@ impl Log {
     /// # Safety
     /// This function performs raw pointer dereferencing. Make sure `start` is pointing to the
     /// location of the `header`.
-    unsafe fn read_head() {
-        let (dev, start) = {
-            let inner = LOG.inner.lock();
-            (inner.dev, inner.start)
+    unsafe fn read_head(&self) {
+        let (dev, start, checksummed) = {
+            let inner = self.inner.lock();
+            (inner.dev, inner.start, inner.checksummed)
         }; // LOG lock dropped here
 
         let buf = BCACHE.read(dev, start);
         let header = unsafe { &*(buf.data().as_ptr() as *const LogHeader) };
 
+        if checksummed && Checksum::compute(buf.data()) != Checksum::stored(buf.data()) {
+            println!("log: bad header checksum on disk {}, ignoring the log", dev);
+            self.inner.lock().header.n = 0;
+            BCACHE.release(buf);
+            return;
+        }
+
         {
-            let mut inner = LOG.inner.lock();
+            let mut inner = self.inner.lock();
             inner.header.n = header.n;
             for i in 0..inner.header.n {
                 inner.header.blocks[i as usize] = header.blocks[i as usize];
@@ -133,37 +207,42 @@ impl Log {
     /// # Safety
     /// This function performs raw pointer dereferencing. Make sure `start` is pointing to the
     /// location of the `header`.
-    unsafe fn write_head() {
-        let (dev, start) = {
-            let inner = LOG.inner.lock();
-            (inner.dev, inner.start)
+    unsafe fn write_head(&self) {
+        let (dev, start, checksummed) = {
+            let inner = self.inner.lock();
+            (inner.dev, inner.start, inner.checksummed)
         }; // LOG lock dropped here
 
         let mut buf = BCACHE.read(dev, start);
         let header = unsafe { &mut *(buf.data_mut().as_mut_ptr() as *mut LogHeader) };
 
         {
-            let inner = LOG.inner.lock();
+            let inner = self.inner.lock();
             header.n = inner.header.n;
             for i in 0..inner.header.n {
                 header.blocks[i as usize] = inner.header.blocks[i as usize];
             }
         } // LOG lock dropped here
 
+        if checksummed {
+            let sum = Checksum::compute(buf.data());
+            buf.data_mut()[BSIZE - CSUMSIZE..].copy_from_slice(&sum.to_le_bytes());
+        }
+
         BCACHE.write(&mut buf);
         BCACHE.release(buf);
     }
 
-    /// Copies modified blocks from cache to log
-    fn write_log() {
-        let (dev, start, n) = {
-            let inner = LOG.inner.lock();
-            (inner.dev, inner.start, inner.header.n)
+    /// Copies modified blocks of the transaction being committed from cache to log
+    fn write_log(&self) {
+        let (dev, start, committed, n) = {
+            let inner = self.inner.lock();
+            (inner.dev, inner.start, inner.committed, inner.header.n)
         }; // LOG lock dropped here
 
-        for tail in 0..n {
+        for tail in committed..n {
             let block = {
-                let inner = LOG.inner.lock();
+                let inner = self.inner.lock();
                 inner.header.blocks[tail as usize]
             }; // LOG lock dropped here
 
@@ -177,6 +256,213 @@ impl Log {
             BCACHE.release(from);
         }
     }
+
+    /// Writes committed blocks back to their home location and empties the log.
+    /// Must be called while committing, so that the cache holds no uncommitted changes.
+    fn checkpoint(&self) {
+        let n = {
+            let inner = self.inner.lock();
+            inner.header.n
+        };
+
+        if n > 0 {
+            BCACHE.flush();
+
+            {
+                let mut inner = self.inner.lock();
+                inner.header.n = 0;
+                inner.committed = 0;
+            }
+
+            // erase the transactions from the log
+            unsafe { self.write_head() };
+        }
+    }
+
+    /// Begins a new operation on the log that may write up to `blocks` blocks.
+    /// Returns false without waiting if the log is not in use.
+    fn begin_op(&'static self, blocks: usize) -> bool {
+        let mut inner = self.inner.lock();
+
+        if !inner.active {
+            return false;
+        }
+
+        assert!(blocks <= inner.capacity, "begin_op: reservation too big");
+
+        loop {
+            if inner.committing {
+                inner = proc::sleep(Channel::Log, inner);
+            } else if inner.header.n as usize + inner.reserved + blocks > inner.capacity {
+                if inner.outstanding == 0 {
+                    // only finished operations are in the log; commit and checkpoint to make room
+                    inner = self.group_commit(inner, true);
+                } else {
+                    // this op might exhaust log space; wait for commit
+                    inner = proc::sleep(Channel::Log, inner);
+                }
+            } else if inner.force {
+                if inner.outstanding == 0 {
+                    inner = self.group_commit(inner, false);
+                } else {
+                    // do not delay a forced commit; wait for it
+                    inner = proc::sleep(Channel::Log, inner);
+                }
+            } else {
+                inner.outstanding += 1;
+                inner.reserved += blocks;
+                return true;
+            }
+        }
+    }
+
+    /// Ends the current operation on the log, releasing its reservation of `blocks` blocks.
+    /// Commits if this was the last outstanding operation and the log is filling up or a commit
+    /// was forced.
+    fn end_op(&'static self, blocks: usize) {
+        let mut inner = self.inner.lock();
+
+        inner.outstanding -= 1;
+        inner.reserved -= blocks;
+
+        if inner.committing {
+            panic!("log committing");
+        }
+
+        let pending = (inner.header.n - inner.committed) as usize;
+
+        if inner.outstanding == 0 && (inner.force || pending >= LOGFLUSHBLOCKS) {
+            drop(self.group_commit(inner, false));
+        } else {
+            // `begin_op()` may be waiting for log space, and decrementing `outstanding` has
+            // decreased the amount of reserved space
+            proc::wakeup(Channel::Log);
+        }
+    }
+
+    /// Commits the pending transaction and wakes up everyone waiting on the log.
+    /// Also checkpoints if `checkpoint` is set or the log is half full.
+    /// Must be called with no outstanding operations. The lock is released during the commit,
+    /// since it is not allowed to sleep with locks.
+    fn group_commit(
+        &'static self,
+        mut inner: SpinLockGuard<'static, LogInner>,
+        checkpoint: bool,
+    ) -> SpinLockGuard<'static, LogInner> {
+        assert_eq!(inner.outstanding, 0, "group_commit outstanding");
+
+        inner.committing = true;
+        let half = inner.capacity / 2;
+        drop(inner);
+
+        self.commit();
+
+        if checkpoint || self.inner.lock().header.n as usize > half {
+            self.checkpoint();
+        }
+
+        let mut inner = self.inner.lock();
+        inner.committing = false;
+        inner.force = false;
+        inner.commits += 1;
+        proc::wakeup(Channel::Log);
+
+        inner
+    }
+
+    /// Commits the current transaction.
+    fn commit(&self) {
+        let (committed, n) = {
+            let inner = self.inner.lock();
+            (inner.committed, inner.header.n)
+        };
+
+        if n > committed {
+            // write modified blocks from cache to log
+            self.write_log();
+            // write header to disk -- the real commit
+            unsafe { self.write_head() };
+            // now install write to the cache, to be written back later
+            self.install_trans();
+
+            let mut inner = self.inner.lock();
+            inner.committed = n;
+        }
+    }
+
+    /// Commits all finished operations and waits until they are on disk.
+    fn force(&'static self) {
+        let mut inner = self.inner.lock();
+
+        // finished operations are either pending in the log or part of the commit in progress,
+        // since no operation can begin while committing
+        if !inner.committing && inner.header.n == inner.committed {
+            return;
+        }
+
+        let target = inner.commits + 1;
+        inner.force = true;
+
+        while inner.commits < target {
+            if !inner.committing && inner.outstanding == 0 {
+                inner = self.group_commit(inner, false);
+            } else {
+                // the last outstanding `end_op()` commits for us
+                inner = proc::sleep(Channel::Log, inner);
+            }
+        }
+    }
+
+    /// Caller has modified `buf` and is done with the buffer.
+    /// Record the block number and pin in the cache by increasing ref count.
+    fn write(&self, buf: &Buf<'_>, block_no: u32) {
+        let mut inner = self.inner.lock();
+
+        if inner.header.n as usize >= inner.capacity {
+            panic!("log_write: transaction too big");
+        }
+
+        if inner.outstanding < 1 {
+            panic!("log_write: outside of trans");
+        }
+
+        // blocks of committed transactions are logged again, so that the replay ends with the
+        // newest
+        let mut i = inner.committed as usize;
+        while i < inner.header.n as usize {
+            if inner.header.blocks[i] == block_no {
+                // log absorption
+                break;
+            }
+
+            i += 1;
+        }
+
+        inner.header.blocks[i] = block_no;
+
+        if i == inner.header.n as usize {
+            BCACHE.pin(buf);
+            inner.header.n += 1;
+        }
+    }
+
+    /// Recovers the log by installing any committed transactions found in the log on disk.
+    ///
+    /// # Safety
+    /// This should only be called at mount and after `Log` init.
+    unsafe fn recover(&self) {
+        unsafe { self.read_head() };
+
+        // if committed, copy from log to disk
+        self.replay();
+
+        // clear the log
+        {
+            let mut inner = self.inner.lock();
+            inner.header.n = 0;
+        }
+        unsafe { self.write_head() };
+    }
 }
 
 /// A guard that begins a log operation on creation and ends it on drop.
@@ -185,14 +471,27 @@ impl Log {
 pub struct Operation<F: FnOnce() = fn()> {
     on_err: Option<F>,
     success: bool,
+    /// Blocks reserved in the log of each disk, 0 if the operation did not begin on it
+    blocks: [usize; NDISK],
 }
 
 impl Operation {
     pub fn begin() -> Self {
-        begin_op();
         Self {
             on_err: None,
             success: false,
+            blocks: begin_op(None, MAXOPBLOCKS),
+        }
+    }
+
+    /// Begins an operation that may write up to `blocks` blocks of device `dev`, and up to
+    /// `MAXOPBLOCKS` of other devices.
+    /// `blocks` must not be more than `max_op_blocks(dev)`.
+    pub fn reserve(dev: u32, blocks: usize) -> Self {
+        Self {
+            on_err: None,
+            success: false,
+            blocks: begin_op(Some(dev), blocks),
         }
     }
 }
@@ -200,10 +499,10 @@ impl Operation {
 #[allow(unused)]
 impl<F: FnOnce()> Operation<F> {
     pub fn begin_with(on_err: F) -> Self {
-        begin_op();
         Self {
             on_err: Some(on_err),
             success: false,
+            blocks: begin_op(None, MAXOPBLOCKS),
         }
     }
 
@@ -219,86 +518,81 @@ impl<F: FnOnce()> Drop for Operation<F> {
         {
             f();
         }
-        end_op();
+        end_op(&self.blocks);
+
+        // free the blocks of truncated and unlinked files that did not fit in this operation
+        Inode::reap_orphans();
     }
 }
 
-/// Begins a new operation on the log.
+/// Returns the largest number of blocks a single operation can reserve on device `dev`.
+pub fn max_op_blocks(dev: u32) -> usize {
+    log(dev).inner.lock().capacity
+}
+
+/// Begins a new operation on the log of every mounted disk, reserving `blocks` blocks of device
+/// `dev` and `MAXOPBLOCKS` of the others.
 /// Must be called at the start of each FS system call.
-fn begin_op() {
-    let mut inner = LOG.inner.lock();
+/// Returns the reservation made on each log.
+fn begin_op(dev: Option<u32>, blocks: usize) -> [usize; NDISK] {
+    let mut reserved = [0; NDISK];
 
-    loop {
-        if inner.committing {
-            inner = proc::sleep(Channel::Log, inner);
-        } else if inner.header.n as usize + (inner.outstanding as usize + 1) * MAXOPBLOCKS
-            > LOGBLOCKS
-        {
-            // this op might exhaust log space; wait for commit
-            inner = proc::sleep(Channel::Log, inner);
+    for (i, log) in LOGS.iter().enumerate() {
+        let n = if dev == Some(i as u32 + 1) {
+            blocks
         } else {
-            inner.outstanding += 1;
-            break;
+            MAXOPBLOCKS
+        };
+
+        if log.begin_op(n) {
+            reserved[i] = n;
         }
     }
+
+    reserved
 }
 
-/// Ends the current operation on the log.
+/// Ends the current operation, releasing the reservations returned by `begin_op()`.
 /// Must be called at the end of each FS system call.
-/// Commits if this was the last outstanding operation.
-fn end_op() {
-    let mut do_commit = false;
-
-    {
-        let mut inner = LOG.inner.lock();
-
-        inner.outstanding -= 1;
-
-        if inner.committing {
-            panic!("log committing");
-        }
-
-        if inner.outstanding == 0 {
-            do_commit = true;
-            inner.committing = true;
-        } else {
-            // `begin_op()` may be waiting for log space, and decrementing `outstanding` has
-            // decreased the amount of reserved space
-            proc::wakeup(Channel::Log);
+fn end_op(reserved: &[usize; NDISK]) {
+    for (log, &blocks) in LOGS.iter().zip(reserved) {
+        if blocks > 0 {
+            log.end_op(blocks);
         }
-    } // drop inner lock
-
-    if do_commit {
-        // call commit without holding locks, since not allowed to sleep with locks
-        commit();
-        let mut inner = LOG.inner.lock();
-        inner.committing = false;
-        proc::wakeup(Channel::Log);
     }
 }
 
-/// Commits the current transaction.
-fn commit() {
-    let n = {
-        let inner = LOG.inner.lock();
-        inner.header.n
-    };
-
-    if n > 0 {
-        // write modified blocks from cache to log
-        Log::write_log();
-        // write header to disk -- the real commit
-        unsafe { Log::write_head() };
-        // now install write to home location
-        Log::install_trans(false);
+/// Commits all finished operations on device `dev` and waits until they are on disk.
+pub fn force(dev: u32) {
+    log(dev).force();
+}
 
+/// Body of the `logd` kernel thread.
+/// Commits pending operations every `LOGFLUSHTICKS` ticks, bounding how long a finished operation
+/// can stay in memory.
+pub fn flusher() -> ! {
+    loop {
         {
-            let mut inner = LOG.inner.lock();
-            inner.header.n = 0;
+            let mut ticks = TICKS.lock();
+            let ticks0 = *ticks;
+            while *ticks - ticks0 < LOGFLUSHTICKS {
+                ticks = proc::sleep(Channel::Ticks, ticks);
+            }
         }
 
-        // erase the transactions from the log
-        unsafe { Log::write_head() };
+        for log in LOGS.iter() {
+            let mut inner = log.inner.lock();
+            if !inner.active || inner.committing || inner.header.n == inner.committed {
+                continue;
+            }
+
+            if inner.outstanding == 0 {
+                drop(log.group_commit(inner, false));
+            } else {
+                // have the last outstanding `end_op()` commit
+                inner.force = true;
+            }
+        }
     }
 }
 
@@ -308,70 +602,56 @@ fn commit() {
 ///
 /// `write()` replaces `BCACHE::write()`
 pub fn write(buf: &Buf<'_>) {
-    let mut inner = LOG.inner.lock();
-
-    if inner.header.n as usize >= LOGBLOCKS || inner.header.n >= inner.size - 1 {
-        panic!("log_write: transaction too big");
-    }
-
-    if inner.outstanding < 1 {
-        panic!("log_write: outside of trans");
-    }
-
-    let block_no = {
+    let (dev, block_no) = {
         let bcache = BCACHE.inner.lock();
-        bcache.meta[buf.id].block_no
+        (bcache.meta[buf.id].dev, bcache.meta[buf.id].block_no)
     };
 
-    let mut i = 0;
-    while i < inner.header.n as usize {
-        if inner.header.blocks[i] == block_no {
-            // log absorption
-            break;
-        }
-
-        i += 1;
-    }
-
-    inner.header.blocks[i] = block_no;
-
-    if i == inner.header.n as usize {
-        BCACHE.pin(buf);
-        inner.header.n += 1;
-    }
-}
-
-/// Recovers the log by installing any committed transactions found in the log on disk.
-///
-/// # Safety
-/// This should only be called at file system initialization and after `Log` init.
-pub unsafe fn recover_from_log() {
-    unsafe { Log::read_head() };
-
-    // if committed, copy from log to disk
-    Log::install_trans(true);
-
-    // clear the log
-    {
-        let mut inner = LOG.inner.lock();
-        inner.header.n = 0;
-    }
-    unsafe { Log::write_head() };
+    log(dev).write(buf, block_no);
 }
 
-/// Initialize the log system.
+/// Initializes the log of device `dev` from its superblock and recovers it.
+/// Operations begin on the log from then on.
 pub fn init(dev: u32, sb: &SuperBlock) {
-    if size_of::<LogHeader>() >= BSIZE {
+    if size_of::<LogHeader>() > BSIZE - CSUMSIZE {
         panic!("init_log: log header too big");
     }
 
+    let log = log(dev);
+
     {
-        let mut inner = LOG.inner.lock();
+        let mut inner = log.inner.lock();
         inner.start = sb.logstart;
-        inner.size = sb.nlogs;
+        // every logged block stays pinned in the buffer cache until it is installed, so leave
+        // enough buffers for the operations themselves, shared by the logs of all disks
+        inner.capacity = (sb.nlogs as usize - 1)
+            .min(LOGBLOCKS)
+            .min((BCACHE.len() - MAXOPBLOCKS) / NDISK);
         inner.dev = dev;
+        inner.checksummed = sb.checksummed();
+        inner.committed = 0;
+
+        assert!(inner.capacity >= MAXOPBLOCKS, "init_log: log too small");
     }
 
     // # Safety: This is called after log initialization.
-    unsafe { recover_from_log() };
+    unsafe { log.recover() };
+
+    log.inner.lock().active = true;
+}
+
+/// Commits and checkpoints everything in the log of device `dev`, then stops using it.
+/// Operations that already began on the log finish first. Called at unmount.
+pub fn stop(dev: u32) {
+    let log = log(dev);
+    let mut inner = log.inner.lock();
+
+    // new operations skip the log from now on
+    inner.active = false;
+
+    while inner.committing || inner.outstanding > 0 {
+        inner = proc::sleep(Channel::Log, inner);
+    }
+
+    drop(log.group_commit(inner, true));
 }
@@ -0,0 +1,148 @@
+// Memory devices.
+//
+// `null` discards writes and reads as empty, `zero` reads as an endless run of zeros, and `full`
+// reads like `zero` but fails every write as if the disk were full. `random` reads as pseudo-random
+// bytes from a xorshift generator whose state is stirred with the timer on every read and with
+// whatever is written to it; it is not suitable for cryptography.
+
+use crate::proc;
+use crate::riscv::registers::time;
+use crate::spinlock::SpinLock;
+use crate::syscall::SysError;
+use crate::vm::VA;
+
+/// Bytes copied to user space at a time
+const CHUNK: usize = 64;
+
+/// Copies the bytes `fill` produces to user address `dst`, `CHUNK` bytes at a time.
+fn fill_user(dst: VA, len: usize, mut fill: impl FnMut(&mut [u8])) -> Result<usize, SysError> {
+    let mut dst = dst;
+    let mut n = 0;
+
+    let mut buf = [0u8; CHUNK];
+
+    while n < len {
+        let chunk = CHUNK.min(len - n);
+        fill(&mut buf[..chunk]);
+
+        if proc::copy_to_user(&buf[..chunk], dst).is_err() {
+            if n == 0 {
+                err!(SysError::BadAddress);
+            }
+            break;
+        }
+
+        n += chunk;
+        dst += chunk;
+    }
+
+    Ok(n)
+}
+
+/// The null device
+pub struct Null;
+
+impl Null {
+    pub fn read(_dst: VA, _len: usize, _nonblock: bool) -> Result<usize, SysError> {
+        Ok(0)
+    }
+
+    pub fn write(_src: VA, len: usize) -> Result<usize, SysError> {
+        Ok(len)
+    }
+}
+
+/// The zero device
+pub struct Zero;
+
+impl Zero {
+    pub fn read(dst: VA, len: usize, _nonblock: bool) -> Result<usize, SysError> {
+        fill_user(dst, len, |buf| buf.fill(0))
+    }
+
+    pub fn write(_src: VA, len: usize) -> Result<usize, SysError> {
+        Ok(len)
+    }
+}
+
+/// The full device
+pub struct Full;
+
+impl Full {
+    pub fn read(dst: VA, len: usize, _nonblock: bool) -> Result<usize, SysError> {
+        fill_user(dst, len, |buf| buf.fill(0))
+    }
+
+    pub fn write(_src: VA, _len: usize) -> Result<usize, SysError> {
+        err!(SysError::NoSpace);
+    }
+}
+
+/// State of the random number generator, 0 until it is first seeded
+static RANDOM: SpinLock<u64> = SpinLock::new(0, "random");
+
+/// The random device
+pub struct Random;
+
+impl Random {
+    /// Mixes `value` into the generator state, which must never become 0.
+    fn stir(state: &mut u64, value: u64) {
+        *state ^= value.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        if *state == 0 {
+            *state = 0x9E37_79B9_7F4A_7C15;
+        }
+    }
+
+    /// Steps the xorshift64* generator.
+    fn next(state: &mut u64) -> u64 {
+        *state ^= *state >> 12;
+        *state ^= *state << 25;
+        *state ^= *state >> 27;
+        state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    pub fn read(dst: VA, len: usize, _nonblock: bool) -> Result<usize, SysError> {
+        // the time of each read adds jitter from the timer
+        let mut state = {
+            let mut random = RANDOM.lock();
+            Self::stir(&mut random, unsafe { time::read() } as u64);
+            Self::next(&mut random);
+            *random
+        };
+
+        fill_user(dst, len, |buf| {
+            for bytes in buf.chunks_mut(8) {
+                let value = Self::next(&mut state).to_le_bytes();
+                bytes.copy_from_slice(&value[..bytes.len()]);
+            }
+        })
+    }
+
+    /// Written bytes are mixed into the generator state.
+    pub fn write(src: VA, len: usize) -> Result<usize, SysError> {
+        let mut src = src;
+        let mut n = 0;
+
+        let mut buf = [0u8; CHUNK];
+
+        while n < len {
+            let chunk = CHUNK.min(len - n);
+            if proc::copy_from_user(src, &mut buf[..chunk]).is_err() {
+                break;
+            }
+
+            let mut random = RANDOM.lock();
+            for bytes in buf[..chunk].chunks(8) {
+                let mut value = [0u8; 8];
+                value[..bytes.len()].copy_from_slice(bytes);
+                Self::stir(&mut random, u64::from_le_bytes(value));
+                Self::next(&mut random);
+            }
+
+            n += chunk;
+            src += chunk;
+        }
+
+        Ok(n)
+    }
+}
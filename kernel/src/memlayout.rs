@@ -29,6 +29,10 @@ pub const UART0_IRQ: usize = 10;
 // virtio mmio interface
 pub const VIRTIO0: usize = 0x1000_1000;
 pub const VIRTIO0_IRQ: usize = 1;
+pub const VIRTIO1: usize = 0x1000_2000;
+pub const VIRTIO1_IRQ: usize = 2;
+pub const VIRTIO2: usize = 0x1000_3000;
+pub const VIRTIO2_IRQ: usize = 3;
 
 // Platform-Level Interrupt Controller (PLIC)
 // https://github.com/riscv/riscv-plic-spec/blob/master/riscv-plic.adoc
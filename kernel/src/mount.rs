@@ -0,0 +1,212 @@
+// Mount table.
+//
+// The root file system is always the disk on `ROOTDEV`. Other file systems are mounted on
+// directories of the tree, which then stand for the root directory of the mounted file system:
+// `Path::resolve` enters the mounted root when it reaches a mount point, and leaves to the mount
+// point when it walks `..` out of a mounted root.
+//
+// A mount keeps a reference to its mount point, so the directory stays in its file system's node
+// table. A file system can only be unmounted when none of its nodes are referenced, which includes
+// open files and the working directories of processes.
+
+use alloc::sync::Arc;
+
+use crate::fs::FsError;
+use crate::param::NMOUNT;
+use crate::sleeplock::SleepLock;
+use crate::spinlock::SpinLock;
+use crate::sync::OnceLock;
+use crate::vfs::{FS_TYPES, FileSystem, Vnode};
+
+/// A file system mounted on a directory
+#[derive(Debug)]
+struct Mount {
+    fs: Arc<dyn FileSystem>,
+    /// Inode number of the root directory of the mounted file system
+    root: u32,
+    /// Directory the file system is mounted on, holding a reference
+    mountpoint: Vnode,
+}
+
+/// The root file system and the inode number of its root directory
+static ROOT: OnceLock<(Arc<dyn FileSystem>, u32)> = OnceLock::new();
+
+static MOUNTS: SpinLock<[Option<Mount>; NMOUNT]> =
+    SpinLock::new([const { None }; NMOUNT], "mounts");
+
+/// Serializes `mount()` and `umount()`, which sleep while the file system is set up or written
+/// back.
+static MOUNTING: SleepLock<()> = SleepLock::new((), "mounting");
+
+/// Makes `fs` the root file system.
+pub fn init(fs: Arc<dyn FileSystem>) {
+    let root = log!(fs.root()).expect("root directory");
+    fs.put(root);
+
+    ROOT.initialize(|| Ok::<_, ()>((fs, root.ino)));
+}
+
+/// Returns the root directory of the root file system.
+pub fn root() -> Result<Vnode, FsError> {
+    let (fs, _) = ROOT.get().expect("root file system");
+    let root = try_log!(fs.root());
+    Ok(Vnode::new(fs.clone(), root))
+}
+
+/// Returns whether `vnode` is the root directory of the root file system.
+pub fn is_system_root(vnode: &Vnode) -> bool {
+    let (fs, root) = ROOT.get().expect("root file system");
+    fs.dev() == vnode.dev() && *root == vnode.ino()
+}
+
+/// Returns whether `vnode` is the root directory of a mounted file system.
+fn is_root(mounts: &[Option<Mount>], vnode: &Vnode) -> bool {
+    is_system_root(vnode)
+        || mounts
+            .iter()
+            .flatten()
+            .any(|m| m.fs.dev() == vnode.dev() && m.root == vnode.ino())
+}
+
+/// Mounts a file system of type `fstype` from device `dev` on directory `mountpoint`.
+/// Must not be called inside a transaction, since mounting a disk recovers it with its own.
+pub fn mount(fstype: &str, dev: u32, mountpoint: &Vnode) -> Result<(), FsError> {
+    let Some(fstype) = FS_TYPES.iter().find(|t| t.name == fstype) else {
+        err!(FsError::NoDevice);
+    };
+
+    let _mounting = MOUNTING.lock();
+
+    {
+        let mounts = MOUNTS.lock();
+        let (root, _) = ROOT.get().expect("root file system");
+
+        // a device is mounted once, and a directory holds one file system. mounting on the root
+        // of a mounted file system would hide it.
+        if root.dev() == dev
+            || mounts.iter().flatten().any(|m| m.fs.dev() == dev)
+            || is_root(&*mounts, mountpoint)
+            || is_mountpoint_locked(&*mounts, mountpoint)
+        {
+            err!(FsError::Busy);
+        }
+
+        if mounts.iter().all(|m| m.is_some()) {
+            err!(FsError::Busy);
+        }
+    }
+
+    let fs = try_log!((fstype.mount)(dev));
+
+    let root = match log!(fs.root()) {
+        Ok(root) => root,
+        Err(e) => {
+            fs.unmount();
+            return Err(e);
+        }
+    };
+    fs.put(root);
+
+    let mut mounts = MOUNTS.lock();
+    let slot = mounts
+        .iter_mut()
+        .find(|m| m.is_none())
+        .expect("mount: no slot");
+
+    *slot = Some(Mount {
+        fs,
+        root: root.ino,
+        mountpoint: mountpoint.dup(),
+    });
+
+    Ok(())
+}
+
+/// Unmounts the file system on device `dev` whose root directory is inode `root`, returning the
+/// directory it was mounted on. The caller must put it inside a transaction.
+/// Must not be called inside a transaction, since unmounting waits for them to end.
+pub fn umount(dev: u32, root: u32) -> Result<Vnode, FsError> {
+    let _mounting = MOUNTING.lock();
+
+    let (fs, mountpoint) = {
+        let mut mounts = MOUNTS.lock();
+
+        let Some(slot) = mounts.iter_mut().find(|m| {
+            m.as_ref()
+                .is_some_and(|m| m.fs.dev() == dev && m.root == root)
+        }) else {
+            err!(FsError::NotMounted);
+        };
+
+        // checked under the lock, so that no path walk can enter the file system in the meantime
+        if slot.as_ref().unwrap().fs.busy() {
+            err!(FsError::Busy);
+        }
+
+        let mount = slot.take().unwrap();
+        (mount.fs, mount.mountpoint)
+    };
+
+    fs.unmount();
+
+    Ok(mountpoint)
+}
+
+fn is_mountpoint_locked(mounts: &[Option<Mount>], vnode: &Vnode) -> bool {
+    mounts.iter().flatten().any(|m| m.mountpoint.is(vnode))
+}
+
+/// Returns whether a file system is mounted on `vnode`.
+pub fn is_mountpoint(vnode: &Vnode) -> bool {
+    is_mountpoint_locked(&*MOUNTS.lock(), vnode)
+}
+
+/// Returns the root of the file system mounted on `vnode`, or `vnode` itself if nothing is
+/// mounted on it.
+/// Must be called inside a transaction, since it may put `vnode`.
+pub fn enter(vnode: Vnode) -> Result<Vnode, FsError> {
+    let root = {
+        let mounts = MOUNTS.lock();
+
+        mounts
+            .iter()
+            .flatten()
+            .find(|m| m.mountpoint.is(&vnode))
+            .map(|m| m.fs.root().map(|root| Vnode::new(m.fs.clone(), root)))
+    };
+
+    match root {
+        Some(Ok(root)) => {
+            vnode.put();
+            Ok(root)
+        }
+        Some(Err(e)) => {
+            vnode.put();
+            Err(e)
+        }
+        None => Ok(vnode),
+    }
+}
+
+/// Returns the directory the file system with root `vnode` is mounted on, or `vnode` itself if it
+/// is not the root of a mounted file system.
+/// Must be called inside a transaction, since it may put `vnode`.
+pub fn leave(vnode: Vnode) -> Vnode {
+    let mountpoint = {
+        let mounts = MOUNTS.lock();
+
+        mounts
+            .iter()
+            .flatten()
+            .find(|m| m.fs.dev() == vnode.dev() && m.root == vnode.ino())
+            .map(|m| m.mountpoint.dup())
+    };
+
+    match mountpoint {
+        Some(mountpoint) => {
+            vnode.put();
+            mountpoint
+        }
+        None => vnode,
+    }
+}
@@ -19,20 +19,44 @@ pub const NDEV: usize = 10;
 /// device nubmer of file system root disk
 pub const ROOTDEV: u32 = 1;
 
+/// number of virtio disks, with device numbers 1 to NDISK
+pub const NDISK: usize = 3;
+
+/// maximum number of mounted file systems, besides the root
+pub const NMOUNT: usize = 8;
+
+/// maximum bytes of file data held by each tmpfs
+pub const TMPFSSIZE: usize = 4 * 1024 * 1024;
+
+/// maximum connections waiting to be accepted by a listening socket
+pub const MAXBACKLOG: usize = 8;
+
 /// max exec arguments
 pub const MAXARG: usize = 32;
 
-/// max # of blocks any FS op writes
+/// # of blocks an FS op may write unless it reserves more
 pub const MAXOPBLOCKS: usize = 10;
 
-/// max data blocks in on-disk log
-pub const LOGBLOCKS: usize = MAXOPBLOCKS * 3;
+/// commit once this many blocks are waiting in the log
+pub const LOGFLUSHBLOCKS: usize = MAXOPBLOCKS;
+
+/// commit pending log blocks at least this often (ticks)
+pub const LOGFLUSHTICKS: usize = 10;
+
+/// min size of disk block cache
+pub const NBUF: usize = MAXOPBLOCKS * 13;
+
+/// percentage of free memory used for the disk block cache
+pub const BCACHEPCT: usize = 5;
+
+/// write dirty buffers back at least this often (ticks)
+pub const BFLUSHTICKS: usize = 30;
 
-/// size of disk block cache
-pub const NBUF: usize = MAXOPBLOCKS * 3;
+/// blocks to read ahead of sequential file reads
+pub const READAHEAD: usize = 8;
 
 /// maximum file path name
-pub const MAXPATH: usize = 128;
+pub const MAXPATH: usize = 512;
 
 /// kernel stack pages per process (debug needs more stack to dump)
 #[cfg(debug_assertions)]
@@ -1,14 +1,18 @@
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 
 use crate::file::{FILE_TABLE, File, FileType};
 use crate::fs::FsError;
-use crate::proc::{self, Channel, current_proc_and_data_mut};
+use crate::proc::{self, Channel, current_proc, current_proc_and_data_mut};
 use crate::spinlock::SpinLock;
 use crate::syscall::SysError;
 use crate::vm::VA;
 
 const PIPESIZE: usize = 512;
 
+/// Pipes of the FIFOs that are open, with the device and inode number of their FIFO
+static FIFOS: SpinLock<Vec<(u32, u32, Arc<Pipe>)>> = SpinLock::new(Vec::new(), "fifos");
+
 #[derive(Debug)]
 /// Inner state of a pipe
 pub struct PipeInner {
@@ -18,10 +22,14 @@ pub struct PipeInner {
     num_read: usize,
     /// Number of bytes written
     num_write: usize,
-    /// Read fd is still open
-    read_open: bool,
-    /// Write fd is still open
-    write_open: bool,
+    /// Open read ends
+    readers: usize,
+    /// Open write ends
+    writers: usize,
+    /// Times a read end has been opened, for FIFO writers waiting for one
+    reads_opened: usize,
+    /// Times a write end has been opened, for FIFO readers waiting for one
+    writes_opened: usize,
 }
 
 #[derive(Debug)]
@@ -43,22 +51,13 @@ impl Pipe {
             }
         };
 
-        // arc allocates pipe on the heap
-        let Ok(pipe) = log!(Arc::try_new(Pipe {
-            inner: SpinLock::new(
-                PipeInner {
-                    data: [0; PIPESIZE],
-                    num_read: 0,
-                    num_write: 0,
-                    read_open: true,
-                    write_open: true,
-                },
-                "pipe",
-            ),
-        })) else {
-            f0.close();
-            f1.close();
-            err!(FsError::OutOfPipe)
+        let pipe = match log!(Self::new(1, 1)) {
+            Ok(pipe) => pipe,
+            Err(e) => {
+                f0.close();
+                f1.close();
+                return Err(e);
+            }
         };
 
         // f0 = read end
@@ -69,6 +68,8 @@ impl Pipe {
             };
             f0_inner.readable = true;
             f0_inner.writeable = false;
+            f0_inner.append = false;
+            f0_inner.nonblock = false;
         }
 
         // f1 = write end
@@ -77,11 +78,152 @@ impl Pipe {
             f1_inner.r#type = FileType::Pipe { pipe };
             f1_inner.readable = false;
             f1_inner.writeable = true;
+            f1_inner.append = false;
+            f1_inner.nonblock = false;
         }
 
         Ok((f0, f1))
     }
 
+    /// Allocates a pipe with `readers` read ends and `writers` write ends open.
+    pub fn new(readers: usize, writers: usize) -> Result<Arc<Self>, FsError> {
+        // arc allocates pipe on the heap
+        let Ok(pipe) = log!(Arc::try_new(Pipe {
+            inner: SpinLock::new(
+                PipeInner {
+                    data: [0; PIPESIZE],
+                    num_read: 0,
+                    num_write: 0,
+                    readers,
+                    writers,
+                    reads_opened: readers,
+                    writes_opened: writers,
+                },
+                "pipe",
+            ),
+        })) else {
+            err!(FsError::OutOfPipe)
+        };
+
+        Ok(pipe)
+    }
+
+    /// Opens FIFO `ino` of device `dev` for reading, writing or both, attaching to the pipe that
+    /// everyone who has it open shares. The first open creates the pipe, and the last close drops
+    /// it with any data left in it.
+    /// Returns the pipe, and how many times the other end had been opened, for `wait_fifo()`.
+    pub fn open_fifo(
+        dev: u32,
+        ino: u32,
+        readable: bool,
+        writeable: bool,
+    ) -> Result<(Arc<Self>, usize), FsError> {
+        let mut fifos = FIFOS.lock();
+
+        let pipe = match fifos.iter().find(|(d, i, _)| *d == dev && *i == ino) {
+            Some((_, _, pipe)) => Arc::clone(pipe),
+            None => {
+                let pipe = try_log!(Self::new(0, 0));
+                if fifos.try_reserve(1).is_err() {
+                    err!(FsError::OutOfPipe);
+                }
+                fifos.push((dev, ino, Arc::clone(&pipe)));
+                pipe
+            }
+        };
+
+        let mut inner = pipe.inner.lock();
+
+        if readable {
+            inner.readers += 1;
+            inner.reads_opened += 1;
+            proc::wakeup(Channel::PipeWrite(pipe.pipe_id()));
+        }
+
+        if writeable {
+            inner.writers += 1;
+            inner.writes_opened += 1;
+            proc::wakeup(Channel::PipeRead(pipe.pipe_id()));
+        }
+
+        let seen = if writeable {
+            inner.reads_opened
+        } else {
+            inner.writes_opened
+        };
+
+        drop(inner);
+        Ok((pipe, seen))
+    }
+
+    /// Waits until the other end of a FIFO opened for writing, as `writeable` says, or for
+    /// reading is open, or has been opened since it was opened `seen` times, however briefly.
+    /// If `nonblock` is set, a reader does not wait and a writer fails instead.
+    pub fn wait_fifo(&self, writeable: bool, seen: usize, nonblock: bool) -> Result<(), SysError> {
+        let proc = current_proc();
+
+        let mut inner = self.inner.lock();
+
+        loop {
+            let (open, opened) = if writeable {
+                (inner.readers, inner.reads_opened)
+            } else {
+                (inner.writers, inner.writes_opened)
+            };
+
+            if open > 0 || opened != seen {
+                return Ok(());
+            }
+
+            if nonblock {
+                if writeable {
+                    err!(SysError::NoDeviceOrAddress);
+                }
+                return Ok(());
+            }
+
+            if proc.is_killed() {
+                err!(SysError::Interrupted);
+            }
+
+            let chan = if writeable {
+                Channel::PipeWrite(self.pipe_id())
+            } else {
+                Channel::PipeRead(self.pipe_id())
+            };
+            inner = proc::sleep(chan, inner);
+        }
+    }
+
+    /// Closes the ends of FIFO pipe `pipe` that `open_fifo()` opened, dropping the pipe from the
+    /// FIFOs once no end is open.
+    pub fn close_fifo(pipe: &Arc<Self>, readable: bool, writeable: bool) {
+        let mut fifos = FIFOS.lock();
+
+        if readable {
+            pipe.close(false);
+        }
+
+        if writeable {
+            pipe.close(true);
+        }
+
+        let inner = pipe.inner.lock();
+        if inner.readers == 0 && inner.writers == 0 {
+            fifos.retain(|(_, _, p)| !Arc::ptr_eq(p, pipe));
+        }
+    }
+
+    /// Returns whether a read end is open.
+    pub fn has_readers(&self) -> bool {
+        self.inner.lock().readers > 0
+    }
+
+    /// Returns whether a write end is open.
+    pub fn has_writers(&self) -> bool {
+        self.inner.lock().writers > 0
+    }
+
     /// Returns the Arc pointer address as pipe id
     /// The pointer will be unique and constant for the life time of this pipe.
     fn pipe_id(&self) -> usize {
@@ -93,10 +235,10 @@ impl Pipe {
         let mut inner = self.inner.lock();
 
         if writeable {
-            inner.write_open = false;
+            inner.writers -= 1;
             proc::wakeup(Channel::PipeRead(self.pipe_id()));
         } else {
-            inner.read_open = false;
+            inner.readers -= 1;
             proc::wakeup(Channel::PipeWrite(self.pipe_id()));
         }
 
@@ -104,7 +246,8 @@ impl Pipe {
     }
 
     /// Writes to the pipe from the user space
-    pub fn write(&self, addr: VA, n: usize) -> Result<usize, SysError> {
+    /// If `nonblock` is set, returns what fits instead of waiting for the reader.
+    pub fn write(&self, addr: VA, n: usize, nonblock: bool) -> Result<usize, SysError> {
         let (proc, data) = current_proc_and_data_mut();
 
         let mut inner = self.inner.lock();
@@ -114,11 +257,18 @@ impl Pipe {
             if proc.is_killed() {
                 err!(SysError::Interrupted);
             }
-            if !inner.read_open {
+            if inner.readers == 0 {
                 err!(SysError::BrokenPipe);
             }
 
             if inner.num_write == inner.num_read + PIPESIZE {
+                if nonblock {
+                    if i == 0 {
+                        err!(SysError::ResourceUnavailable);
+                    }
+                    break;
+                }
+
                 proc::wakeup(Channel::PipeRead(self.pipe_id()));
                 inner = proc::sleep(Channel::PipeWrite(self.pipe_id()), inner);
             } else {
@@ -139,15 +289,19 @@ impl Pipe {
         Ok(i)
     }
 
-    /// Reads from the pipe into the user space
-    pub fn read(&self, addr: VA, n: usize) -> Result<usize, SysError> {
+    /// Reads from the pipe into the user space.
+    /// If `nonblock` is set, fails instead of waiting while the pipe is empty.
+    pub fn read(&self, addr: VA, n: usize, nonblock: bool) -> Result<usize, SysError> {
         let (proc, data) = current_proc_and_data_mut();
 
         let mut inner = self.inner.lock();
 
         let mut i = 0;
 
-        while inner.num_read == inner.num_write && inner.write_open {
+        while inner.num_read == inner.num_write && inner.writers > 0 {
+            if nonblock {
+                err!(SysError::ResourceUnavailable);
+            }
             if proc.is_killed() {
                 err!(SysError::Interrupted);
             }
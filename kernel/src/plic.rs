@@ -1,7 +1,10 @@
 // RISCV Platfform Level Interrupt Controller (PLIC)
 // https://github.com/riscv/riscv-plic-spec/blob/master/riscv-plic.adoc
 
-use crate::memlayout::{PLIC, PLIC_SCLAIM, PLIC_SENABLE, PLIC_SPRIORITY, UART0_IRQ, VIRTIO0_IRQ};
+use crate::memlayout::{
+    PLIC, PLIC_SCLAIM, PLIC_SENABLE, PLIC_SPRIORITY, UART0_IRQ, VIRTIO0_IRQ, VIRTIO1_IRQ,
+    VIRTIO2_IRQ,
+};
 use crate::proc;
 
 /// Asks PLIC what interrupt we should server.
@@ -35,6 +38,8 @@ pub unsafe fn init() {
     unsafe {
         *((PLIC + (UART0_IRQ * 4)) as *mut u32) = 1;
         *((PLIC + (VIRTIO0_IRQ * 4)) as *mut u32) = 1;
+        *((PLIC + (VIRTIO1_IRQ * 4)) as *mut u32) = 1;
+        *((PLIC + (VIRTIO2_IRQ * 4)) as *mut u32) = 1;
     }
 
     println!("plic init");
@@ -50,8 +55,9 @@ pub unsafe fn init_hart() {
         // # Safety: cpu is locked
         let hart = proc::current_id();
 
-        // set enable bits for this hart's S-mode for uart and virtio disk
-        *(PLIC_SENABLE(hart) as *mut u32) = (1 << UART0_IRQ) | (1 << VIRTIO0_IRQ);
+        // set enable bits for this hart's S-mode for uart and virtio disks
+        *(PLIC_SENABLE(hart) as *mut u32) =
+            (1 << UART0_IRQ) | (1 << VIRTIO0_IRQ) | (1 << VIRTIO1_IRQ) | (1 << VIRTIO2_IRQ);
 
         // set this hart's S-mode priority threshold to 0
         *(PLIC_SPRIORITY(hart) as *mut u32) = 0;
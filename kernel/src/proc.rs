@@ -6,26 +6,31 @@ use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 use alloc::boxed::Box;
 use alloc::string::String;
+use alloc::vec::Vec;
 
 use crate::error::KernelError;
 use crate::exec::exec;
 use crate::file::File;
-use crate::fs::{self, Inode, Path};
+use crate::fs;
 use crate::log::Operation;
 use crate::memlayout::{TRAMPOLINE, TRAPFRAME, kstack};
-use crate::param::{NCPU, NKSTACK_PAGES, NOFILE, NPROC, ROOTDEV};
+use crate::param::{NCPU, NKSTACK_PAGES, NOFILE, NPROC};
 use crate::riscv::{PGSIZE, PTE_R, PTE_W, PTE_X, interrupts, registers::tp};
 use crate::spinlock::{SpinLock, SpinLockGuard};
 use crate::swtch::swtch;
 use crate::sync::OnceLock;
 use crate::trampoline::trampoline;
 use crate::trap::usertrapret;
+use crate::vfs::{Path, Vnode};
 use crate::vm::{Kvm, PA, PageTable, Uvm, VA};
 
 pub static CPU_TABLE: CpuTable = CpuTable::new();
 pub static PROC_TABLE: ProcTable = ProcTable::new();
 pub static INIT_PROC: OnceLock<&Proc> = OnceLock::new();
 
+/// Number of harts that have started
+pub static HARTS: AtomicUsize = AtomicUsize::new(0);
+
 /// Per-CPU state
 pub struct Cpu {
     pub proc: Option<&'static Proc>,
@@ -306,6 +311,10 @@ pub enum Channel {
     PipeRead(usize),
     /// Write end of pipe.
     PipeWrite(usize),
+    /// `flock` on the file with this inode number
+    Flock(usize),
+    /// Connections of a listening socket, or files sent over a connection
+    Socket(usize),
 }
 
 /// Process control block
@@ -373,10 +382,18 @@ pub struct ProcData {
     pub context: Context,
     /// Open files
     pub open_files: [Option<File>; NOFILE],
+    /// Descriptors closed by `exec`
+    pub close_on_exec: [bool; NOFILE],
     /// Current directory
-    pub cwd: Inode,
+    pub cwd: Option<Vnode>,
+    /// Root directory set by `chroot`, or None for the root of the root file system
+    pub root: Option<Vnode>,
     /// Process name
     pub name: String,
+    /// Arguments given to `exec`, separated by spaces
+    pub cmdline: String,
+    /// Entry point if this is a kernel thread
+    pub kthread: Option<fn() -> !>,
 }
 
 impl ProcData {
@@ -388,8 +405,12 @@ impl ProcData {
             trapframe: None,
             context: Context::new(),
             open_files: [const { None }; NOFILE],
-            cwd: Inode::new(0, 0, 0),
+            close_on_exec: [false; NOFILE],
+            cwd: None,
+            root: None,
             name: String::new(),
+            cmdline: String::new(),
+            kthread: None,
         }
     }
 
@@ -508,6 +529,8 @@ impl Proc {
         data.size = 0;
         inner.pid = Pid(0);
         data.name.clear();
+        data.cmdline.clear();
+        data.kthread = None;
         inner.channel = None;
         inner.killed = false;
         inner.xstate = 0;
@@ -632,6 +655,53 @@ impl ProcTable {
         Err(KernelError::OutOfProc)
     }
 
+    /// Returns the PIDs of the processes in the table.
+    pub fn pids(&self) -> Vec<usize> {
+        self.iter()
+            .filter_map(|proc| {
+                let inner = proc.inner.lock();
+                (inner.state != ProcState::Unused).then_some(*inner.pid)
+            })
+            .collect()
+    }
+
+    /// Calls `f` with the process `pid` and its locked state, or returns None if there is no such
+    /// process. The data of the process is passed along when it cannot change underneath, that is
+    /// unless it is being set up or is running on another hart.
+    ///
+    /// `f` runs with the process lock held, so it must not sleep.
+    pub fn inspect<T>(
+        &self,
+        pid: usize,
+        f: impl FnOnce(&Proc, &ProcInner, Option<&ProcData>) -> T,
+    ) -> Option<T> {
+        let current = current_proc_opt();
+
+        for proc in self.iter() {
+            let inner = proc.inner.lock();
+            if inner.state == ProcState::Unused || *inner.pid != pid {
+                continue;
+            }
+
+            let stable = current.is_some_and(|current| ptr::eq(current, proc))
+                || matches!(
+                    inner.state,
+                    ProcState::Sleeping | ProcState::Runnable | ProcState::Zombie
+                );
+
+            return Some(f(proc, &inner, stable.then(|| proc.data())));
+        }
+
+        None
+    }
+
+    /// Returns the PID of the parent of `proc`, if it has one.
+    pub fn parent_pid(&self, proc: &Proc) -> Option<usize> {
+        let parent = self.parents.lock()[proc.id]?;
+        let pid = *self.get(parent).inner.lock().pid;
+        Some(pid)
+    }
+
     /// Prints a process listing to the console.
     /// For debugging only, it does not lock to avoid creating more problems.
     pub unsafe fn dump(&self) {
@@ -655,6 +725,14 @@ impl ProcTable {
 /// const initialization.
 unsafe impl Sync for ProcTable {}
 
+/// Returns the PID of the process hart `id` is running.
+/// For monitoring only, it reads the state of another hart without locking, so the answer may be
+/// stale by the time it is used.
+pub fn running_on(id: usize) -> Option<usize> {
+    let proc = unsafe { ptr::read_volatile(&raw const (*CPU_TABLE.0[id].get()).proc) };
+    proc.map(|proc| *proc.inner.lock().pid)
+}
+
 /// Sets up first user process.
 pub fn user_init() {
     let (proc, mut inner) = log!(PROC_TABLE.alloc()).unwrap();
@@ -663,13 +741,30 @@ pub fn user_init() {
     // # Safety: during initialization, we are the only ones with access to this proc
     let data = unsafe { proc.data_mut() };
 
-    data.cwd = log!(Path::new("/").resolve()).expect("root path to exist");
+    data.cwd = Some(log!(Path::new("/").resolve()).expect("root path to exist"));
 
     inner.state = ProcState::Runnable;
 
     // inner lock is dropped
 }
 
+/// Creates a kernel thread that runs `entry` in its own process slot.
+/// The thread never returns to user space and has no parent, so it is never waited for.
+pub fn spawn(name: &str, entry: fn() -> !) -> Result<Pid, KernelError> {
+    let (proc, mut inner) = try_log!(PROC_TABLE.alloc());
+
+    // # Safety: proc is not yet runnable, so we are the only ones with access to it
+    let data = unsafe { proc.data_mut() };
+
+    data.name.push_str(name);
+    data.kthread = Some(entry);
+    data.context.ra = kthread_ret as *const () as usize;
+
+    inner.state = ProcState::Runnable;
+
+    Ok(inner.pid)
+}
+
 /// Grows or shrinks user memory by `n` bytes.
 /// The new size is reflected in `proc.data.size` and return.
 ///
@@ -728,9 +823,12 @@ pub fn fork() -> Result<Pid, KernelError> {
             new_data.open_files[i] = Some(file.dup());
         }
     }
-    new_data.cwd = data.cwd.dup();
+    new_data.close_on_exec = data.close_on_exec;
+    new_data.cwd = data.cwd.as_ref().map(Vnode::dup);
+    new_data.root = data.root.as_ref().map(Vnode::dup);
 
     new_data.name = data.name.clone();
+    new_data.cmdline = data.cmdline.clone();
 
     let pid = new_inner.pid;
 
@@ -773,12 +871,16 @@ pub fn exit(status: isize) -> ! {
         }
     }
 
-    {
+    if let Some(cwd) = data.cwd.take() {
         let _op = Operation::begin();
-        let cwd = data.cwd.clone();
         cwd.put();
     }
 
+    if let Some(root) = data.root.take() {
+        let _op = Operation::begin();
+        root.put();
+    }
+
     let mut parents = PROC_TABLE.parents.lock();
 
     // give any children to init
@@ -962,7 +1064,7 @@ pub unsafe extern "C" fn fork_ret() {
     {
         // file system initialization must be run in the context of a regular process (because it
         // calls sleep), and thus cannot be run from `main()`.
-        fs::init(ROOTDEV);
+        fs::init();
 
         println!("\nexec init\n");
 
@@ -981,6 +1083,20 @@ pub unsafe extern "C" fn fork_ret() {
     unsafe { usertrapret() };
 }
 
+/// Entry point for kernel threads created by `spawn()`.
+///
+/// # Safety
+/// This function is not called directly, but used as the return address for context switch.
+unsafe extern "C" fn kthread_ret() {
+    let proc = current_proc();
+
+    // Still holding process lock from scheduler.
+    unsafe { proc.inner.force_unlock() };
+
+    let entry = proc.data().kthread.expect("kthread entry");
+    entry()
+}
+
 /// Atomically releases a condition's lock and sleeps on channel.
 /// Reacquires the condition's lock when awakened.
 pub fn sleep<T>(channel: Channel, condition_lock: SpinLockGuard<'_, T>) -> SpinLockGuard<'_, T> {
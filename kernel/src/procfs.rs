@@ -0,0 +1,508 @@
+// Process file system.
+//
+// A procfs has no contents of its own: every file is generated from kernel state when it is read,
+// so `cat` is enough to look at processes and kernel tables. The root directory holds global files
+// and a directory for each process, named after its PID:
+//
+//   meminfo   bytes of kernel memory in total and free
+//   uptime    ticks since boot
+//   cpuinfo   the process each hart is running
+//   bcache    buffer cache occupancy and hit counts
+//   inodes    inode table occupancy
+//   <pid>/status, cmdline, fd, maps, cwd
+//
+// Inode numbers encode what a node is, so there is no node table: the root is 1, global files
+// follow it, and a process directory is its PID shifted left by 4 with its files numbered after it.
+// A node of an exited process still resolves, but reading it fails.
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::buf::BCACHE;
+use crate::file::{FILE_TABLE, FileType};
+use crate::fs::{FsError, Inode, InodeType, Stat};
+use crate::kalloc;
+use crate::param::NINODE;
+use crate::proc::{self, PROC_TABLE, ProcData};
+use crate::riscv::{PGSIZE, PTE_R, PTE_U, PTE_W, PTE_X};
+use crate::trap::TICKS;
+use crate::vfs::{self, DirEntry, FileOps, FileSystem, NodeId, StatFs, VnodeOps};
+use crate::vm::VA;
+
+/// Inode number of the root directory
+const ROOTINO: u32 = 1;
+
+/// Files of the root directory, numbered from `ROOTINO + 1`
+const GLOBAL: [&str; 5] = ["meminfo", "uptime", "cpuinfo", "bcache", "inodes"];
+
+/// Files of a process directory, numbered from the directory's inode number plus one
+const PER_PROC: [&str; 5] = ["status", "cmdline", "fd", "maps", "cwd"];
+
+/// Bits of an inode number that select a file inside a process directory
+const PID_SHIFT: u32 = 4;
+
+const _: () = assert!(ROOTINO as usize + GLOBAL.len() < 1 << PID_SHIFT);
+const _: () = assert!(PER_PROC.len() < 1 << PID_SHIFT);
+
+/// What a procfs node stands for
+#[derive(Debug, Clone, Copy)]
+enum Node {
+    Root,
+    Global(usize),
+    ProcDir(usize),
+    ProcFile(usize, usize),
+}
+
+impl Node {
+    fn from_ino(ino: u32) -> Self {
+        let pid = (ino >> PID_SHIFT) as usize;
+        let file = (ino & ((1 << PID_SHIFT) - 1)) as usize;
+
+        match (pid, file) {
+            _ if ino == ROOTINO => Node::Root,
+            (0, file) => Node::Global(file - ROOTINO as usize - 1),
+            (pid, 0) => Node::ProcDir(pid),
+            (pid, file) => Node::ProcFile(pid, file - 1),
+        }
+    }
+
+    fn ino(self) -> u32 {
+        match self {
+            Node::Root => ROOTINO,
+            Node::Global(i) => ROOTINO + 1 + i as u32,
+            Node::ProcDir(pid) => (pid as u32) << PID_SHIFT,
+            Node::ProcFile(pid, i) => ((pid as u32) << PID_SHIFT) + 1 + i as u32,
+        }
+    }
+
+    fn id(self) -> NodeId {
+        let ino = self.ino();
+        NodeId {
+            id: ino as usize,
+            ino,
+        }
+    }
+}
+
+/// A file system generated from kernel state
+#[derive(Debug)]
+pub struct ProcFs {
+    dev: u32,
+    /// References to any of the nodes
+    refs: AtomicUsize,
+}
+
+impl ProcFs {
+    /// Creates a procfs. `dev` is ignored, since a procfs has no disk; it gets a device number of
+    /// its own.
+    pub fn mount(_dev: u32) -> Result<Arc<dyn FileSystem>, FsError> {
+        Ok(Arc::new(ProcFs {
+            dev: vfs::alloc_dev(),
+            refs: AtomicUsize::new(0),
+        }))
+    }
+
+    /// Takes a reference to `node`.
+    fn get(&self, node: Node) -> NodeId {
+        self.refs.fetch_add(1, Ordering::Relaxed);
+        node.id()
+    }
+
+    /// Generates the contents of `node`.
+    fn generate(&self, node: Node) -> Result<String, FsError> {
+        let mut out = String::new();
+
+        let result = match node {
+            Node::Root | Node::ProcDir(_) => err!(FsError::Type),
+            Node::Global(0) => meminfo(&mut out),
+            Node::Global(1) => uptime(&mut out),
+            Node::Global(2) => cpuinfo(&mut out),
+            Node::Global(3) => bcache(&mut out),
+            Node::Global(_) => inodes(&mut out),
+            Node::ProcFile(pid, 0) => status(&mut out, pid),
+            Node::ProcFile(pid, 1) => cmdline(&mut out, pid),
+            Node::ProcFile(pid, 2) => fd(&mut out, pid),
+            Node::ProcFile(pid, 3) => maps(&mut out, pid),
+            Node::ProcFile(pid, _) => cwd(&mut out, pid),
+        };
+
+        match result {
+            Ok(true) => Ok(out),
+            // the process is gone
+            Ok(false) => err!(FsError::Resolve),
+            Err(_) => err!(FsError::Read),
+        }
+    }
+}
+
+/// Returns whether there is a process `pid`.
+fn exists(pid: usize) -> bool {
+    PROC_TABLE.inspect(pid, |_, _, _| ()).is_some()
+}
+
+fn meminfo(out: &mut String) -> Result<bool, fmt::Error> {
+    writeln!(out, "total {} kB", kalloc::total() / 1024)?;
+    writeln!(out, "free  {} kB", kalloc::available() / 1024)?;
+    Ok(true)
+}
+
+fn uptime(out: &mut String) -> Result<bool, fmt::Error> {
+    writeln!(out, "{} ticks", *TICKS.lock())?;
+    Ok(true)
+}
+
+fn cpuinfo(out: &mut String) -> Result<bool, fmt::Error> {
+    for hart in 0..proc::HARTS.load(Ordering::SeqCst) {
+        match proc::running_on(hart) {
+            Some(pid) => writeln!(out, "hart {hart}: pid {pid}")?,
+            None => writeln!(out, "hart {hart}: idle")?,
+        }
+    }
+    Ok(true)
+}
+
+fn bcache(out: &mut String) -> Result<bool, fmt::Error> {
+    let (used, valid, dirty) = {
+        let inner = BCACHE.inner.lock();
+        // buffer 0 is the head of the LRU list
+        inner.meta[1..]
+            .iter()
+            .fold((0, 0, 0), |(used, valid, dirty), buf| {
+                (
+                    used + (buf.ref_count > 0) as usize,
+                    valid + buf.valid as usize,
+                    dirty + buf.dirty as usize,
+                )
+            })
+    };
+    let (hits, misses) = BCACHE.stats();
+
+    writeln!(out, "buffers {}", BCACHE.len())?;
+    writeln!(out, "in use  {used}")?;
+    writeln!(out, "valid   {valid}")?;
+    writeln!(out, "dirty   {dirty}")?;
+    writeln!(out, "hits    {hits}")?;
+    writeln!(out, "misses  {misses}")?;
+    Ok(true)
+}
+
+fn inodes(out: &mut String) -> Result<bool, fmt::Error> {
+    writeln!(out, "inodes {NINODE}")?;
+    writeln!(out, "in use {}", Inode::active())?;
+    Ok(true)
+}
+
+fn status(out: &mut String, pid: usize) -> Result<bool, fmt::Error> {
+    let Some((id, state, killed, name, size)) = PROC_TABLE.inspect(pid, |proc, inner, data| {
+        (
+            proc.id,
+            format!("{:?}", inner.state),
+            inner.killed,
+            data.map(|data| data.name.clone()),
+            data.map(|data| data.size),
+        )
+    }) else {
+        return Ok(false);
+    };
+
+    writeln!(out, "name   {}", name.as_deref().unwrap_or("?"))?;
+    writeln!(out, "pid    {pid}")?;
+    match PROC_TABLE.parent_pid(PROC_TABLE.get(id)) {
+        Some(ppid) => writeln!(out, "ppid   {ppid}")?,
+        None => writeln!(out, "ppid   -")?,
+    }
+    writeln!(out, "state  {state}")?;
+    writeln!(out, "killed {killed}")?;
+    if let Some(size) = size {
+        writeln!(out, "size   {size}")?;
+    }
+    Ok(true)
+}
+
+/// Calls `f` with the data of process `pid`, or writes `?` if it is running on another hart.
+fn with_data(
+    out: &mut String,
+    pid: usize,
+    f: impl FnOnce(&mut String, &ProcData) -> fmt::Result,
+) -> Result<bool, fmt::Error> {
+    let result = PROC_TABLE.inspect(pid, |_, _, data| match data {
+        Some(data) => f(out, data),
+        None => writeln!(out, "?"),
+    });
+
+    match result {
+        Some(result) => result.map(|_| true),
+        None => Ok(false),
+    }
+}
+
+fn cmdline(out: &mut String, pid: usize) -> Result<bool, fmt::Error> {
+    with_data(out, pid, |out, data| writeln!(out, "{}", data.cmdline))
+}
+
+fn fd(out: &mut String, pid: usize) -> Result<bool, fmt::Error> {
+    // collect the descriptors under the process lock, and describe them after releasing it, since
+    // the files are behind sleeplocks
+    let mut files = Vec::new();
+    let found = with_data(out, pid, |_, data| {
+        for (fd, file) in data.open_files.iter().enumerate() {
+            if let Some(file) = file {
+                files.push((fd, file.id));
+            }
+        }
+        Ok(())
+    })?;
+
+    for (fd, id) in files {
+        write!(out, "{fd} ")?;
+
+        // a file in use, such as this one while it is being read, is not waited for
+        let Some(file) = FILE_TABLE.inner[id].try_lock() else {
+            writeln!(out, "busy")?;
+            continue;
+        };
+
+        match &file.r#type {
+            FileType::None => write!(out, "closed")?,
+            FileType::Pipe { .. } => write!(out, "pipe")?,
+            FileType::Vnode { vnode } => write!(
+                out,
+                "file dev {} ino {} offset {}",
+                vnode.dev(),
+                vnode.ino(),
+                file.offset
+            )?,
+            FileType::Device { major, .. } => write!(out, "device {major}")?,
+            FileType::Fifo { vnode, .. } => {
+                write!(out, "fifo dev {} ino {}", vnode.dev(), vnode.ino())?
+            }
+            FileType::Socket { .. } => write!(out, "socket")?,
+        }
+
+        let mode = match (file.readable, file.writeable) {
+            (true, true) => "rw",
+            (true, false) => "r",
+            (false, true) => "w",
+            (false, false) => "-",
+        };
+        writeln!(out, " {mode}")?;
+    }
+
+    Ok(found)
+}
+
+fn maps(out: &mut String, pid: usize) -> Result<bool, fmt::Error> {
+    with_data(out, pid, |out, data| {
+        let Some(uvm) = data.pagetable.as_ref() else {
+            // kernel threads have no user memory
+            return Ok(());
+        };
+
+        // group contiguous pages with the same permissions, leaving out those not yet faulted in
+        let mut region: Option<(usize, usize, usize)> = None;
+
+        for va in (0..data.size).step_by(PGSIZE).chain([data.size]) {
+            let flags = (va < data.size)
+                .then(|| uvm.page_flags(VA::from(va)))
+                .flatten();
+
+            if let Some((start, end, perm)) = region
+                && (flags != Some(perm) || end != va)
+            {
+                writeln!(out, "{start:#010x}-{end:#010x} {}", permissions(perm))?;
+                region = None;
+            }
+
+            if let Some(perm) = flags {
+                region = match region {
+                    Some((start, _, perm)) => Some((start, va + PGSIZE, perm)),
+                    None => Some((va, va + PGSIZE, perm)),
+                };
+            }
+        }
+
+        Ok(())
+    })
+}
+
+/// Formats PTE flags like `rwxu`, with `-` for those missing.
+fn permissions(flags: usize) -> String {
+    [(PTE_R, 'r'), (PTE_W, 'w'), (PTE_X, 'x'), (PTE_U, 'u')]
+        .iter()
+        .map(|&(bit, c)| if flags & bit != 0 { c } else { '-' })
+        .collect()
+}
+
+fn cwd(out: &mut String, pid: usize) -> Result<bool, fmt::Error> {
+    with_data(out, pid, |out, data| match &data.cwd {
+        Some(cwd) => writeln!(out, "dev {} ino {}", cwd.dev(), cwd.ino()),
+        None => writeln!(out, "-"),
+    })
+}
+
+impl FileSystem for ProcFs {
+    fn dev(&self) -> u32 {
+        self.dev
+    }
+
+    fn root(&self) -> Result<NodeId, FsError> {
+        Ok(self.get(Node::Root))
+    }
+
+    fn statfs(&self) -> StatFs {
+        // nothing is stored
+        StatFs::default()
+    }
+
+    fn busy(&self) -> bool {
+        self.refs.load(Ordering::Relaxed) > 0
+    }
+
+    fn unmount(&self) {
+        // nothing is kept
+    }
+}
+
+impl VnodeOps for ProcFs {
+    fn dup(&self, node: NodeId) {
+        self.get(Node::from_ino(node.ino));
+    }
+
+    fn put(&self, _node: NodeId) {
+        let refs = self.refs.fetch_sub(1, Ordering::Relaxed);
+        assert!(refs >= 1, "procfs put");
+    }
+
+    fn stat(&self, node: NodeId) -> Result<Stat, FsError> {
+        let r#type = match Node::from_ino(node.ino) {
+            Node::Root | Node::ProcDir(_) => InodeType::Directory,
+            Node::Global(_) | Node::ProcFile(..) => InodeType::File,
+        };
+
+        // the size of a file is not known until it is generated
+        Ok(Stat {
+            dev: self.dev,
+            ino: node.ino,
+            r#type,
+            major: 0,
+            minor: 0,
+            nlink: 1,
+            size: 0,
+        })
+    }
+
+    fn lookup(&self, dir: NodeId, name: &str) -> Result<Option<NodeId>, FsError> {
+        let dir = Node::from_ino(dir.ino);
+
+        let node = match (dir, name) {
+            (Node::Global(_) | Node::ProcFile(..), _) => err!(FsError::NotDirectory),
+            (_, ".") => Some(dir),
+            (_, "..") => Some(Node::Root),
+            (Node::Root, name) => match GLOBAL.iter().position(|&file| file == name) {
+                Some(i) => Some(Node::Global(i)),
+                None => name
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|&pid| pid > 0 && pid < 1 << (32 - PID_SHIFT) && exists(pid))
+                    .map(Node::ProcDir),
+            },
+            (Node::ProcDir(pid), name) => PER_PROC
+                .iter()
+                .position(|&file| file == name)
+                .map(|i| Node::ProcFile(pid, i)),
+        };
+
+        Ok(node.map(|node| self.get(node)))
+    }
+
+    fn create(
+        &self,
+        _dir: NodeId,
+        _name: &str,
+        _type: InodeType,
+        _major: u16,
+        _minor: u16,
+        _exclusive: bool,
+    ) -> Result<NodeId, FsError> {
+        err!(FsError::NotPermitted)
+    }
+
+    fn link(&self, _dir: NodeId, _name: &str, _node: NodeId) -> Result<(), FsError> {
+        err!(FsError::NotPermitted)
+    }
+
+    fn unlink(&self, _dir: NodeId, _name: &str) -> Result<(), FsError> {
+        err!(FsError::NotPermitted)
+    }
+
+    fn readdir(&self, dir: NodeId, offset: u32) -> Result<Option<(DirEntry, u32)>, FsError> {
+        let dir = Node::from_ino(dir.ino);
+
+        // offsets count entries, `.` and `..` first
+        let node = match (dir, offset as usize) {
+            (Node::Global(_) | Node::ProcFile(..), _) => err!(FsError::NotDirectory),
+            (_, 0) => Some((String::from("."), dir)),
+            (_, 1) => Some((String::from(".."), Node::Root)),
+            (Node::Root, i) if i - 2 < GLOBAL.len() => {
+                Some((String::from(GLOBAL[i - 2]), Node::Global(i - 2)))
+            }
+            (Node::Root, i) => PROC_TABLE
+                .pids()
+                .get(i - 2 - GLOBAL.len())
+                .map(|&pid| (pid.to_string(), Node::ProcDir(pid))),
+            (Node::ProcDir(pid), i) => PER_PROC
+                .get(i - 2)
+                .map(|&file| (String::from(file), Node::ProcFile(pid, i - 2))),
+        };
+
+        Ok(node.map(|(name, node)| {
+            let entry = DirEntry {
+                ino: node.ino(),
+                name,
+            };
+            (entry, offset + 1)
+        }))
+    }
+}
+
+impl FileOps for ProcFs {
+    fn read(&self, node: NodeId, offset: u32, dst: &mut [u8], user: bool) -> Result<u32, FsError> {
+        let contents = try_log!(self.generate(Node::from_ino(node.ino)));
+
+        // the contents may have shrunk since the last read
+        let offset = (offset as usize).min(contents.len());
+        let n = dst.len().min(contents.len() - offset);
+
+        try_log!(vfs::copy_out(
+            &contents.as_bytes()[offset..offset + n],
+            dst,
+            user
+        ));
+
+        Ok(n as u32)
+    }
+
+    fn write(
+        &self,
+        _node: NodeId,
+        _offset: &mut u32,
+        _src: &[u8],
+        _user: bool,
+        _append: bool,
+    ) -> Result<u32, FsError> {
+        err!(FsError::NotPermitted)
+    }
+
+    fn truncate(&self, _node: NodeId, _size: usize) -> Result<(), FsError> {
+        err!(FsError::NotPermitted)
+    }
+
+    fn sync(&self, _node: NodeId) -> Result<(), FsError> {
+        // nothing to write back
+        Ok(())
+    }
+}
@@ -56,6 +56,20 @@ impl<T> SleepLock<T> {
         SleepLockGuard { lock: self }
     }
 
+    /// Acquires the mutex if it is free, without sleeping.
+    pub fn try_lock(&self) -> Option<SleepLockGuard<'_, T>> {
+        let mut inner = self.inner.lock();
+
+        if inner.locked {
+            return None;
+        }
+
+        inner.locked = true;
+        inner.pid = Some(proc::current_proc().inner.lock().pid);
+
+        Some(SleepLockGuard { lock: self })
+    }
+
     /// Returns a reference to the inner data from a shared reference to the mutex.
     ///
     /// # Safety
@@ -0,0 +1,356 @@
+// Unix domain stream sockets.
+//
+// A socket starts out unconnected. A server binds it to a path, which creates a socket node in the
+// file system for clients to find, and listens on it. `connect()` on the path queues a new
+// connection on the listening socket, and `accept()` takes it off the queue as a socket of its own.
+// A connection is a pair of pipes, one for each direction, so its ends read and write like pipes.
+// Open files can be passed over a connection too, with `send_file()` and `recv_file()`.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem;
+
+use crate::file::File;
+use crate::fs::FsError;
+use crate::log::Operation;
+use crate::param::MAXBACKLOG;
+use crate::pipe::Pipe;
+use crate::proc::{self, Channel, current_proc};
+use crate::spinlock::SpinLock;
+use crate::syscall::SysError;
+use crate::vfs::Vnode;
+use crate::vm::VA;
+
+/// Socket domains
+pub struct AddressFamily;
+
+impl AddressFamily {
+    /// Local sockets, named by paths
+    pub const UNIX: usize = 1;
+}
+
+/// Socket types
+pub struct SocketType;
+
+impl SocketType {
+    /// Connection-oriented byte streams
+    pub const STREAM: usize = 1;
+}
+
+/// Listening sockets, with the device and inode number of the socket node they are bound to
+static LISTENERS: SpinLock<Vec<(u32, u32, Arc<Socket>)>> = SpinLock::new(Vec::new(), "listeners");
+
+/// One direction of a connection
+#[derive(Debug)]
+struct Stream {
+    pipe: Arc<Pipe>,
+    /// Open files sent and not received yet
+    files: SpinLock<VecDeque<File>>,
+}
+
+impl Stream {
+    fn new() -> Result<Arc<Self>, FsError> {
+        let pipe = try_log!(Pipe::new(1, 1));
+
+        let Ok(stream) = Arc::try_new(Stream {
+            pipe,
+            files: SpinLock::new(VecDeque::new(), "stream"),
+        }) else {
+            err!(FsError::OutOfPipe);
+        };
+
+        Ok(stream)
+    }
+
+    /// Returns the Arc pointer address as channel id for `recv_file()`
+    fn id(&self) -> usize {
+        self as *const Stream as usize
+    }
+}
+
+#[derive(Debug)]
+enum State {
+    /// Neither bound nor connected
+    Unbound,
+    /// Bound to a socket node, holding a reference to it
+    Bound {
+        vnode: Vnode,
+    },
+    /// Taking connections on the socket node it is bound to
+    Listening {
+        vnode: Vnode,
+        /// Connections not accepted yet, as the sockets `accept()` returns
+        pending: VecDeque<Arc<Socket>>,
+        backlog: usize,
+    },
+    /// An end of a connection, reading from `rx` and writing to `tx`
+    Connected {
+        rx: Arc<Stream>,
+        tx: Arc<Stream>,
+    },
+    Closed,
+}
+
+/// A Unix domain stream socket
+#[derive(Debug)]
+pub struct Socket {
+    state: SpinLock<State>,
+}
+
+impl Socket {
+    /// Allocates an unconnected socket.
+    pub fn new() -> Result<Arc<Self>, FsError> {
+        Self::with_state(State::Unbound)
+    }
+
+    fn with_state(state: State) -> Result<Arc<Self>, FsError> {
+        let Ok(socket) = Arc::try_new(Socket {
+            state: SpinLock::new(state, "socket"),
+        }) else {
+            err!(FsError::OutOfFile);
+        };
+
+        Ok(socket)
+    }
+
+    /// Returns the Arc pointer address as channel id for `accept()`
+    fn id(&self) -> usize {
+        self as *const Socket as usize
+    }
+
+    /// Returns whether the socket can still be bound.
+    pub fn is_unbound(&self) -> bool {
+        matches!(*self.state.lock(), State::Unbound)
+    }
+
+    /// Binds the socket to socket node `vnode`, taking over the reference.
+    /// On error, the reference is handed back to be put inside an `Operation`.
+    pub fn bind(&self, vnode: Vnode) -> Result<(), (SysError, Vnode)> {
+        let mut state = self.state.lock();
+
+        match *state {
+            State::Unbound => {
+                *state = State::Bound { vnode };
+                Ok(())
+            }
+            _ => Err((SysError::InvalidArgument, vnode)),
+        }
+    }
+
+    /// Starts taking connections, keeping up to `backlog` of them waiting for `accept()`.
+    pub fn listen(self: &Arc<Self>, backlog: usize) -> Result<(), SysError> {
+        let mut listeners = LISTENERS.lock();
+        let mut state = self.state.lock();
+
+        let backlog = backlog.clamp(1, MAXBACKLOG);
+
+        match &mut *state {
+            State::Bound { vnode } => {
+                if listeners.try_reserve(1).is_err() {
+                    err!(SysError::OutOfMemory);
+                }
+                listeners.push((vnode.dev(), vnode.ino(), Arc::clone(self)));
+
+                // the reference moves over
+                let vnode = vnode.clone();
+                *state = State::Listening {
+                    vnode,
+                    pending: VecDeque::new(),
+                    backlog,
+                };
+            }
+            State::Listening { backlog: old, .. } => *old = backlog,
+            _ => err!(SysError::InvalidArgument),
+        }
+
+        Ok(())
+    }
+
+    /// Connects the socket to the socket listening on socket node `ino` of device `dev`.
+    /// Returns once the connection is queued, without waiting for it to be accepted.
+    pub fn connect(&self, dev: u32, ino: u32) -> Result<(), SysError> {
+        // `LISTENERS` is always locked before any socket
+        let listeners = LISTENERS.lock();
+        let mut state = self.state.lock();
+
+        match *state {
+            State::Unbound => {}
+            State::Connected { .. } => err!(SysError::IsConnected),
+            _ => err!(SysError::InvalidArgument),
+        }
+
+        let Some((_, _, listener)) = listeners.iter().find(|(d, i, _)| *d == dev && *i == ino)
+        else {
+            err!(SysError::ConnectionRefused);
+        };
+
+        let (Ok(up), Ok(down)) = (log!(Stream::new()), log!(Stream::new())) else {
+            err!(SysError::OutOfMemory);
+        };
+
+        let Ok(server) = log!(Self::with_state(State::Connected {
+            rx: Arc::clone(&up),
+            tx: Arc::clone(&down),
+        })) else {
+            err!(SysError::OutOfMemory);
+        };
+
+        match &mut *listener.state.lock() {
+            State::Listening {
+                pending, backlog, ..
+            } => {
+                // a full queue refuses rather than waits, since the socket stays locked
+                if pending.len() >= *backlog || pending.try_reserve(1).is_err() {
+                    err!(SysError::ResourceUnavailable);
+                }
+                pending.push_back(server);
+            }
+            _ => err!(SysError::ConnectionRefused),
+        }
+
+        proc::wakeup(Channel::Socket(listener.id()));
+
+        *state = State::Connected { rx: down, tx: up };
+        Ok(())
+    }
+
+    /// Takes the next connection off the queue of the listening socket, waiting for one unless
+    /// `nonblock` is set.
+    pub fn accept(&self, nonblock: bool) -> Result<Arc<Socket>, SysError> {
+        let proc = current_proc();
+
+        let mut state = self.state.lock();
+
+        loop {
+            let State::Listening { pending, .. } = &mut *state else {
+                err!(SysError::InvalidArgument);
+            };
+
+            if let Some(socket) = pending.pop_front() {
+                return Ok(socket);
+            }
+
+            if nonblock {
+                err!(SysError::ResourceUnavailable);
+            }
+
+            if proc.is_killed() {
+                err!(SysError::Interrupted);
+            }
+
+            state = proc::sleep(Channel::Socket(self.id()), state);
+        }
+    }
+
+    /// Returns the streams of a connected socket.
+    fn streams(&self) -> Result<(Arc<Stream>, Arc<Stream>), SysError> {
+        match &*self.state.lock() {
+            State::Connected { rx, tx } => Ok((Arc::clone(rx), Arc::clone(tx))),
+            _ => err!(SysError::NotConnected),
+        }
+    }
+
+    /// Reads from the connection into the user space, like `Pipe::read()`.
+    pub fn read(&self, addr: VA, n: usize, nonblock: bool) -> Result<usize, SysError> {
+        let (rx, _) = try_log!(self.streams());
+        rx.pipe.read(addr, n, nonblock)
+    }
+
+    /// Writes to the connection from the user space, like `Pipe::write()`.
+    pub fn write(&self, addr: VA, n: usize, nonblock: bool) -> Result<usize, SysError> {
+        let (_, tx) = try_log!(self.streams());
+        tx.pipe.write(addr, n, nonblock)
+    }
+
+    /// Sends open file `file` to the other end of the connection, taking over the reference.
+    /// On error, the reference is handed back to be closed.
+    pub fn send_file(&self, file: File) -> Result<(), (SysError, File)> {
+        let tx = match self.streams() {
+            Ok((_, tx)) => tx,
+            Err(e) => return Err((e, file)),
+        };
+
+        let mut files = tx.files.lock();
+
+        // the other end drains the files once it has closed its read end
+        if !tx.pipe.has_readers() {
+            return Err((SysError::BrokenPipe, file));
+        }
+
+        if files.try_reserve(1).is_err() {
+            return Err((SysError::OutOfMemory, file));
+        }
+
+        files.push_back(file);
+        proc::wakeup(Channel::Socket(tx.id()));
+
+        Ok(())
+    }
+
+    /// Receives the next open file sent from the other end of the connection, waiting for one
+    /// unless `nonblock` is set.
+    pub fn recv_file(&self, nonblock: bool) -> Result<File, SysError> {
+        let proc = current_proc();
+        let (rx, _) = try_log!(self.streams());
+
+        let mut files = rx.files.lock();
+
+        loop {
+            if let Some(file) = files.pop_front() {
+                return Ok(file);
+            }
+
+            // nothing more can arrive
+            if !rx.pipe.has_writers() {
+                err!(SysError::NotConnected);
+            }
+
+            if nonblock {
+                err!(SysError::ResourceUnavailable);
+            }
+
+            if proc.is_killed() {
+                err!(SysError::Interrupted);
+            }
+
+            files = proc::sleep(Channel::Socket(rx.id()), files);
+        }
+    }
+
+    /// Closes the socket. The other end of a connection sees the end of the data, and the
+    /// connections still waiting on a listening socket are closed too.
+    pub fn close(self: &Arc<Self>) {
+        let state = {
+            let mut listeners = LISTENERS.lock();
+            listeners.retain(|(_, _, listener)| !Arc::ptr_eq(listener, self));
+
+            let mut state = self.state.lock();
+            mem::replace(&mut *state, State::Closed)
+        };
+
+        match state {
+            State::Unbound | State::Closed => {}
+            State::Bound { vnode } => {
+                let _op = Operation::begin();
+                vnode.put();
+            }
+            State::Listening { vnode, pending, .. } => {
+                proc::wakeup(Channel::Socket(self.id()));
+                pending.iter().for_each(|socket| socket.close());
+
+                let _op = Operation::begin();
+                vnode.put();
+            }
+            State::Connected { rx, tx } => {
+                rx.pipe.close(false);
+                tx.pipe.close(true);
+                proc::wakeup(Channel::Socket(tx.id()));
+
+                // files sent to this end will never be received
+                let files = mem::take(&mut *rx.files.lock());
+                files.into_iter().for_each(|mut file| file.close());
+            }
+        }
+    }
+}
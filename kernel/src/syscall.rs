@@ -22,25 +22,36 @@ pub enum SysError {
     NoProcess = 3,
     Interrupted = 4,
     IoError = 5,
+    NoDeviceOrAddress = 6,
     InvalidExecutable = 8,
     BadDescriptor = 9,
     NoChildren = 10,
     ResourceUnavailable = 11,
     OutOfMemory = 12,
     BadAddress = 14,
+    Busy = 16,
     AlreadyExists = 17,
     CrossDeviceLink = 18,
+    NoDevice = 19,
     NotDirectory = 20,
     IsDirectory = 21,
     InvalidArgument = 22,
     FileTableFull = 23,
     TooManyFiles = 24,
     NoSpace = 28,
+    ReadOnly = 30,
     TooManyLinks = 31,
     BrokenPipe = 32,
+    OutOfRange = 34,
     NameTooLong = 36,
     NotImplemented = 38,
     NotEmpty = 39,
+    NotSocket = 88,
+    AddressFamilyNotSupported = 97,
+    AddressInUse = 98,
+    IsConnected = 106,
+    NotConnected = 107,
+    ConnectionRefused = 111,
 }
 
 impl SysError {
@@ -57,25 +68,36 @@ impl SysError {
             3 => Self::NoProcess,
             4 => Self::Interrupted,
             5 => Self::IoError,
+            6 => Self::NoDeviceOrAddress,
             8 => Self::InvalidExecutable,
             9 => Self::BadDescriptor,
             10 => Self::NoChildren,
             11 => Self::ResourceUnavailable,
             12 => Self::OutOfMemory,
             14 => Self::BadAddress,
+            16 => Self::Busy,
             17 => Self::AlreadyExists,
             18 => Self::CrossDeviceLink,
+            19 => Self::NoDevice,
             20 => Self::NotDirectory,
             21 => Self::IsDirectory,
             22 => Self::InvalidArgument,
             23 => Self::FileTableFull,
             24 => Self::TooManyFiles,
             28 => Self::NoSpace,
+            30 => Self::ReadOnly,
             31 => Self::TooManyLinks,
             32 => Self::BrokenPipe,
+            34 => Self::OutOfRange,
             36 => Self::NameTooLong,
             38 => Self::NotImplemented,
             39 => Self::NotEmpty,
+            88 => Self::NotSocket,
+            97 => Self::AddressFamilyNotSupported,
+            98 => Self::AddressInUse,
+            106 => Self::IsConnected,
+            107 => Self::NotConnected,
+            111 => Self::ConnectionRefused,
             _ => Self::InvalidArgument,
         }
     }
@@ -89,25 +111,38 @@ impl Display for SysError {
             SysError::NoProcess => write!(f, "no such process"),
             SysError::Interrupted => write!(f, "interrupted"),
             SysError::IoError => write!(f, "input/output error"),
+            SysError::NoDeviceOrAddress => write!(f, "no such device or address"),
             SysError::InvalidExecutable => write!(f, "exec format error"),
             SysError::BadDescriptor => write!(f, "bad file descriptor"),
             SysError::NoChildren => write!(f, "no child processes"),
             SysError::ResourceUnavailable => write!(f, "resource temporarily unavailable"),
             SysError::OutOfMemory => write!(f, "cannot allocate memory"),
             SysError::BadAddress => write!(f, "bad address"),
+            SysError::Busy => write!(f, "device or resource busy"),
             SysError::AlreadyExists => write!(f, "file exists"),
             SysError::CrossDeviceLink => write!(f, "cross-device link"),
+            SysError::NoDevice => write!(f, "no such device"),
             SysError::NotDirectory => write!(f, "not a directory"),
             SysError::IsDirectory => write!(f, "is a directory"),
             SysError::InvalidArgument => write!(f, "invalid argument"),
             SysError::FileTableFull => write!(f, "too many open files in system"),
             SysError::TooManyFiles => write!(f, "too many open files"),
             SysError::NoSpace => write!(f, "no space left on device"),
+            SysError::ReadOnly => write!(f, "read-only file system"),
             SysError::TooManyLinks => write!(f, "too many links"),
             SysError::BrokenPipe => write!(f, "broken pipe"),
+            SysError::OutOfRange => write!(f, "numerical result out of range"),
             SysError::NameTooLong => write!(f, "file name too long"),
             SysError::NotImplemented => write!(f, "function not implemented"),
             SysError::NotEmpty => write!(f, "directory not empty"),
+            SysError::NotSocket => write!(f, "socket operation on non-socket"),
+            SysError::AddressFamilyNotSupported => {
+                write!(f, "address family not supported by protocol")
+            }
+            SysError::AddressInUse => write!(f, "address already in use"),
+            SysError::IsConnected => write!(f, "transport endpoint is already connected"),
+            SysError::NotConnected => write!(f, "transport endpoint is not connected"),
+            SysError::ConnectionRefused => write!(f, "connection refused"),
         }
     }
 }
@@ -124,6 +159,16 @@ impl From<FsError> for SysError {
             FsError::Resolve => SysError::NoEntry,
             FsError::Type => SysError::InvalidArgument,
             FsError::Copy => SysError::BadAddress,
+            FsError::NameTooLong => SysError::NameTooLong,
+            FsError::NoDevice => SysError::NoDevice,
+            FsError::BadSuperBlock => SysError::InvalidArgument,
+            FsError::Busy => SysError::Busy,
+            FsError::NotDirectory => SysError::NotDirectory,
+            FsError::NotEmpty => SysError::NotEmpty,
+            FsError::NotPermitted => SysError::NotPermitted,
+            FsError::CrossDevice => SysError::CrossDeviceLink,
+            FsError::NotMounted => SysError::InvalidArgument,
+            FsError::ReadOnly => SysError::ReadOnly,
         }
     }
 }
@@ -239,6 +284,33 @@ pub enum Syscall {
     Link = 19,
     Mkdir = 20,
     Close = 21,
+    Fsync = 22,
+    Fdatasync = 23,
+    Ftruncate = 24,
+    Truncate = 25,
+    Dup2 = 26,
+    Dup3 = 27,
+    Fcntl = 28,
+    Flock = 29,
+    Mount = 30,
+    Umount = 31,
+    Statfs = 32,
+    Mkfifo = 33,
+    Socket = 34,
+    Bind = 35,
+    Listen = 36,
+    Accept = 37,
+    Connect = 38,
+    Sendfd = 39,
+    Recvfd = 40,
+    Getcwd = 41,
+    Openat = 42,
+    Mkdirat = 43,
+    Unlinkat = 44,
+    Linkat = 45,
+    Fstatat = 46,
+    Fchdir = 47,
+    Chroot = 48,
 }
 
 impl TryFrom<usize> for Syscall {
@@ -267,6 +339,33 @@ impl TryFrom<usize> for Syscall {
             19 => Ok(Syscall::Link),
             20 => Ok(Syscall::Mkdir),
             21 => Ok(Syscall::Close),
+            22 => Ok(Syscall::Fsync),
+            23 => Ok(Syscall::Fdatasync),
+            24 => Ok(Syscall::Ftruncate),
+            25 => Ok(Syscall::Truncate),
+            26 => Ok(Syscall::Dup2),
+            27 => Ok(Syscall::Dup3),
+            28 => Ok(Syscall::Fcntl),
+            29 => Ok(Syscall::Flock),
+            30 => Ok(Syscall::Mount),
+            31 => Ok(Syscall::Umount),
+            32 => Ok(Syscall::Statfs),
+            33 => Ok(Syscall::Mkfifo),
+            34 => Ok(Syscall::Socket),
+            35 => Ok(Syscall::Bind),
+            36 => Ok(Syscall::Listen),
+            37 => Ok(Syscall::Accept),
+            38 => Ok(Syscall::Connect),
+            39 => Ok(Syscall::Sendfd),
+            40 => Ok(Syscall::Recvfd),
+            41 => Ok(Syscall::Getcwd),
+            42 => Ok(Syscall::Openat),
+            43 => Ok(Syscall::Mkdirat),
+            44 => Ok(Syscall::Unlinkat),
+            45 => Ok(Syscall::Linkat),
+            46 => Ok(Syscall::Fstatat),
+            47 => Ok(Syscall::Fchdir),
+            48 => Ok(Syscall::Chroot),
             _ => Err(SysError::NotImplemented),
         }
     }
@@ -312,6 +411,33 @@ pub unsafe fn syscall(trapframe: &mut TrapFrame) {
             Syscall::Link => sys_link(&args),
             Syscall::Mkdir => sys_mkdir(&args),
             Syscall::Close => sys_close(&args),
+            Syscall::Fsync => sys_fsync(&args),
+            Syscall::Fdatasync => sys_fdatasync(&args),
+            Syscall::Ftruncate => sys_ftruncate(&args),
+            Syscall::Truncate => sys_truncate(&args),
+            Syscall::Dup2 => sys_dup2(&args),
+            Syscall::Dup3 => sys_dup3(&args),
+            Syscall::Fcntl => sys_fcntl(&args),
+            Syscall::Flock => sys_flock(&args),
+            Syscall::Mount => sys_mount(&args),
+            Syscall::Umount => sys_umount(&args),
+            Syscall::Statfs => sys_statfs(&args),
+            Syscall::Mkfifo => sys_mkfifo(&args),
+            Syscall::Socket => sys_socket(&args),
+            Syscall::Bind => sys_bind(&args),
+            Syscall::Listen => sys_listen(&args),
+            Syscall::Accept => sys_accept(&args),
+            Syscall::Connect => sys_connect(&args),
+            Syscall::Sendfd => sys_sendfd(&args),
+            Syscall::Recvfd => sys_recvfd(&args),
+            Syscall::Getcwd => sys_getcwd(&args),
+            Syscall::Openat => sys_openat(&args),
+            Syscall::Mkdirat => sys_mkdirat(&args),
+            Syscall::Unlinkat => sys_unlinkat(&args),
+            Syscall::Linkat => sys_linkat(&args),
+            Syscall::Fstatat => sys_fstatat(&args),
+            Syscall::Fchdir => sys_fchdir(&args),
+            Syscall::Chroot => sys_chroot(&args),
         },
         Err(e) => Err(e),
     };
@@ -1,29 +1,38 @@
-use core::mem;
 use core::slice;
 
 use alloc::string::String;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 
-use crate::abi::OpenFlag;
+use crate::abi::{At, OpenFlag};
 use crate::exec::exec;
-use crate::file::{FILE_TABLE, File, FileType};
-use crate::fs::{Directory, Inode, InodeType, Path};
+use crate::file::{FILE_TABLE, Fcntl, File, FileType};
+use crate::fs::{InodeType, Stat};
 use crate::log::Operation;
-use crate::param::{MAXARG, MAXPATH, NDEV};
+use crate::mount;
+use crate::param::{MAXARG, MAXPATH, NDEV, NOFILE};
 use crate::pipe::Pipe;
-use crate::proc::current_proc_and_data_mut;
+use crate::proc::{self, current_proc, current_proc_and_data_mut};
 use crate::riscv::PGSIZE;
+use crate::socket::{AddressFamily, Socket, SocketType};
 use crate::syscall::{SysError, SyscallArgs};
+use crate::vfs::{Path, StatFs, Vnode, path_of};
 use crate::vm::VA;
 
 /// Allocates a file descriptor for the give file.
 /// Takes over file reference from caller on success.
 fn fd_alloc(file: File) -> Result<usize, SysError> {
+    fd_alloc_from(file, 0)
+}
+
+/// Allocates the lowest file descriptor not below `min` for the given file.
+fn fd_alloc_from(file: File, min: usize) -> Result<usize, SysError> {
     let (_proc, data) = current_proc_and_data_mut();
 
-    for (fd, open_file) in data.open_files.iter_mut().enumerate() {
+    for (fd, open_file) in data.open_files.iter_mut().enumerate().skip(min) {
         if open_file.is_none() {
             *open_file = Some(file);
+            data.close_on_exec[fd] = false;
             return Ok(fd);
         }
     }
@@ -31,6 +40,24 @@ fn fd_alloc(file: File) -> Result<usize, SysError> {
     err!(SysError::TooManyFiles)
 }
 
+/// Returns the directory that relative paths of an `*at` syscall start from, open as descriptor
+/// argument `index`, or None for `At::FDCWD`. No reference is taken, since the descriptor keeps
+/// the directory open for the rest of the syscall.
+fn get_dir(args: &SyscallArgs, index: usize) -> Result<Option<Vnode>, SysError> {
+    if args.get_raw(index) == At::FDCWD {
+        return Ok(None);
+    }
+
+    let (_, file) = try_log!(args.get_file(index));
+
+    match &FILE_TABLE.inner[file.id].lock().r#type {
+        FileType::Vnode { vnode } if vnode.r#type() == Ok(InodeType::Directory) => {
+            Ok(Some(vnode.clone()))
+        }
+        _ => err!(SysError::NotDirectory),
+    }
+}
+
 pub fn sys_dup(args: &SyscallArgs) -> Result<usize, SysError> {
     let (_, mut file) = try_log!(args.get_file(0));
     let fd = try_log!(fd_alloc(file.clone()));
@@ -38,6 +65,89 @@ pub fn sys_dup(args: &SyscallArgs) -> Result<usize, SysError> {
     Ok(fd)
 }
 
+pub fn sys_dup2(args: &SyscallArgs) -> Result<usize, SysError> {
+    let (old_fd, file) = try_log!(args.get_file(0));
+    let new_fd = args.get_int(1) as usize;
+
+    if old_fd == new_fd {
+        return Ok(new_fd);
+    }
+
+    log!(dup_to(file, new_fd, false))
+}
+
+pub fn sys_dup3(args: &SyscallArgs) -> Result<usize, SysError> {
+    let (old_fd, file) = try_log!(args.get_file(0));
+    let new_fd = args.get_int(1) as usize;
+    let flags = args.get_int(2) as usize;
+
+    if old_fd == new_fd || flags & !OpenFlag::CLOEXEC != 0 {
+        err!(SysError::InvalidArgument);
+    }
+
+    log!(dup_to(file, new_fd, flags & OpenFlag::CLOEXEC != 0))
+}
+
+/// Makes `new_fd` refer to `file`, closing whatever `new_fd` referred to.
+fn dup_to(mut file: File, new_fd: usize, cloexec: bool) -> Result<usize, SysError> {
+    if new_fd >= NOFILE {
+        err!(SysError::BadDescriptor);
+    }
+
+    let (_proc, data) = current_proc_and_data_mut();
+
+    let old = data.open_files[new_fd].replace(file.dup());
+    data.close_on_exec[new_fd] = cloexec;
+
+    if let Some(mut old) = old {
+        old.close();
+    }
+
+    Ok(new_fd)
+}
+
+pub fn sys_fcntl(args: &SyscallArgs) -> Result<usize, SysError> {
+    let (fd, mut file) = try_log!(args.get_file(0));
+    let cmd = args.get_int(1) as usize;
+    let arg = args.get_int(2) as usize;
+
+    let (_proc, data) = current_proc_and_data_mut();
+
+    match cmd {
+        Fcntl::DUPFD => {
+            if arg >= NOFILE {
+                err!(SysError::InvalidArgument);
+            }
+
+            let new_fd = try_log!(fd_alloc_from(file.clone(), arg));
+            file.dup();
+            Ok(new_fd)
+        }
+        Fcntl::GETFD => Ok(if data.close_on_exec[fd] {
+            Fcntl::FD_CLOEXEC
+        } else {
+            0
+        }),
+        Fcntl::SETFD => {
+            data.close_on_exec[fd] = arg & Fcntl::FD_CLOEXEC != 0;
+            Ok(0)
+        }
+        Fcntl::GETFL => Ok(file.flags()),
+        Fcntl::SETFL => {
+            file.set_flags(arg);
+            Ok(0)
+        }
+        _ => err!(SysError::InvalidArgument),
+    }
+}
+
+pub fn sys_flock(args: &SyscallArgs) -> Result<usize, SysError> {
+    let (_, file) = try_log!(args.get_file(0));
+    let op = args.get_int(1) as usize;
+    try_log!(file.flock(op));
+    Ok(0)
+}
+
 pub fn sys_read(args: &SyscallArgs) -> Result<usize, SysError> {
     let addr = args.get_addr(1);
     let n = args.get_int(2);
@@ -70,231 +180,413 @@ pub fn sys_fstat(args: &SyscallArgs) -> Result<usize, SysError> {
     Ok(0)
 }
 
-pub fn sys_link(args: &SyscallArgs) -> Result<usize, SysError> {
-    let old = try_log!(args.fetch_string(args.get_addr(0), MAXPATH));
-    let new = try_log!(args.fetch_string(args.get_addr(1), MAXPATH));
+pub fn sys_fstatat(args: &SyscallArgs) -> Result<usize, SysError> {
+    let dir = try_log!(get_dir(args, 0));
+    let path = try_log!(args.fetch_string(args.get_addr(1), MAXPATH));
+    let addr = args.get_addr(2);
 
-    let _op = Operation::begin();
+    // there are no symbolic links to follow
+    if args.get_int(3) != 0 {
+        err!(SysError::InvalidArgument);
+    }
 
-    // get the inode of the old
-    let Ok(old_inode) = log!(Path::new(&old).resolve()) else {
-        err!(SysError::NoEntry)
-    };
+    let stat = {
+        let _op = Operation::begin();
+
+        let Ok(vnode) = log!(Path::new(&path).resolve_at(dir.as_ref())) else {
+            err!(SysError::NoEntry);
+        };
 
-    let mut old_inner = old_inode.lock();
+        let stat = log!(vnode.stat());
+        vnode.put();
+        try_log!(stat)
+    };
 
-    // make sure it is not a directory
-    if old_inner.r#type == InodeType::Directory {
-        old_inode.unlock_put(old_inner);
-        err!(SysError::NotPermitted);
+    let src = unsafe { slice::from_raw_parts(&stat as *const _ as *const u8, size_of::<Stat>()) };
+    if log!(proc::copy_to_user(src, addr)).is_err() {
+        err!(SysError::BadAddress);
     }
 
-    // increment number of links pointing to the inode
-    old_inner.nlink += 1;
-    old_inode.update(&old_inner);
-    old_inode.unlock(old_inner);
+    Ok(0)
+}
 
-    // after incrementing nlink, failures must goto `bad`
-    let result = (|| {
-        // get the inode of the new's parent
-        let (parent, name) = match log!(Path::new(&new).resolve_parent()) {
-            Ok(v) => v,
-            Err(_) => err!(SysError::NoEntry),
-        };
+pub fn sys_fsync(args: &SyscallArgs) -> Result<usize, SysError> {
+    let (_, file) = try_log!(args.get_file(0));
+    try_log!(log!(file.sync()));
+    Ok(0)
+}
 
-        // make sure they are in the same device
-        if parent.dev != old_inode.dev {
-            err!(SysError::CrossDeviceLink);
-        }
+pub fn sys_fdatasync(args: &SyscallArgs) -> Result<usize, SysError> {
+    // there is no cheaper data-only commit, since data blocks go through the log too
+    sys_fsync(args)
+}
 
-        let mut parent_inner = parent.lock();
+pub fn sys_ftruncate(args: &SyscallArgs) -> Result<usize, SysError> {
+    let (_, file) = try_log!(args.get_file(0));
+    let Ok(length) = usize::try_from(args.get_int(1)) else {
+        err!(SysError::InvalidArgument);
+    };
 
-        // add the inode to the new's parent
-        if let Err(e) = log!(Directory::link(
-            &parent,
-            &mut parent_inner,
-            name,
-            old_inode.inum as u16
-        )) {
-            parent.unlock_put(parent_inner);
-            err!(SysError::from(e));
-        }
+    try_log!(file.truncate(length));
+    Ok(0)
+}
+
+pub fn sys_truncate(args: &SyscallArgs) -> Result<usize, SysError> {
+    let path = try_log!(args.fetch_string(args.get_addr(0), MAXPATH));
+    let Ok(length) = usize::try_from(args.get_int(1)) else {
+        err!(SysError::InvalidArgument);
+    };
+
+    let vnode = {
+        let _op = Operation::begin();
 
-        parent.unlock_put(parent_inner);
-        Ok(0)
-    })();
+        let Ok(vnode) = log!(Path::new(&path).resolve()) else {
+            err!(SysError::NoEntry);
+        };
+
+        vnode
+    };
 
-    // bad
-    if result.is_err() {
-        let mut old_inner = old_inode.lock();
-        old_inner.nlink -= 1;
-        old_inode.update(&old_inner);
-        old_inode.unlock(old_inner);
+    let result = log!(vnode.truncate(length));
+
+    {
+        let _op = Operation::begin();
+        vnode.put();
     }
 
-    old_inode.put();
+    try_log!(result);
+    Ok(0)
+}
+
+pub fn sys_link(args: &SyscallArgs) -> Result<usize, SysError> {
+    let old = try_log!(args.fetch_string(args.get_addr(0), MAXPATH));
+    let new = try_log!(args.fetch_string(args.get_addr(1), MAXPATH));
 
-    result
+    log!(link(None, &old, None, &new))
 }
 
-pub fn sys_unlink(args: &SyscallArgs) -> Result<usize, SysError> {
-    let path = try_log!(args.fetch_string(args.get_addr(0), MAXPATH));
+pub fn sys_linkat(args: &SyscallArgs) -> Result<usize, SysError> {
+    let old_dir = try_log!(get_dir(args, 0));
+    let old = try_log!(args.fetch_string(args.get_addr(1), MAXPATH));
+    let new_dir = try_log!(get_dir(args, 2));
+    let new = try_log!(args.fetch_string(args.get_addr(3), MAXPATH));
+
+    // there are no symbolic links to follow
+    if args.get_int(4) != 0 {
+        err!(SysError::InvalidArgument);
+    }
+
+    log!(link(old_dir.as_ref(), &old, new_dir.as_ref(), &new))
+}
 
+/// Links `new`, resolved from `new_dir`, to `old`, resolved from `old_dir`.
+fn link(
+    old_dir: Option<&Vnode>,
+    old: &str,
+    new_dir: Option<&Vnode>,
+    new: &str,
+) -> Result<usize, SysError> {
     let _op = Operation::begin();
 
-    // get the parent inode and name
-    let Ok((parent, name)) = log!(Path::new(&path).resolve_parent()) else {
+    // get the vnode of the old
+    let Ok(old_vnode) = log!(Path::new(old).resolve_at(old_dir)) else {
+        err!(SysError::NoEntry)
+    };
+
+    // get the vnode of the new's parent
+    let Ok((parent, name)) = log!(Path::new(new).resolve_parent_at(new_dir)) else {
+        old_vnode.put();
         err!(SysError::NoEntry);
     };
 
-    let mut parent_inner = parent.lock();
+    // add the old to the new's parent, which must be on the same file system
+    let result = log!(parent.link(name, &old_vnode));
 
-    // cannot unlink `.` or `..`
-    if name == "." || name == ".." {
-        parent.unlock_put(parent_inner);
+    parent.put();
+    old_vnode.put();
+
+    try_log!(result);
+    Ok(0)
+}
+
+pub fn sys_unlink(args: &SyscallArgs) -> Result<usize, SysError> {
+    let path = try_log!(args.fetch_string(args.get_addr(0), MAXPATH));
+
+    log!(unlink(None, &path, None))
+}
+
+pub fn sys_unlinkat(args: &SyscallArgs) -> Result<usize, SysError> {
+    let dir = try_log!(get_dir(args, 0));
+    let path = try_log!(args.fetch_string(args.get_addr(1), MAXPATH));
+    let flags = args.get_int(2) as usize;
+
+    if flags & !At::REMOVEDIR != 0 {
         err!(SysError::InvalidArgument);
     }
 
-    // find the inode in the parent's directory entry
-    let Ok(Some((offset, inode))) = log!(Directory::lookup(&parent, &mut parent_inner, name))
-    else {
-        parent.unlock_put(parent_inner);
+    log!(unlink(
+        dir.as_ref(),
+        &path,
+        Some(flags & At::REMOVEDIR != 0)
+    ))
+}
+
+/// Unlinks `path`, resolved from `dir`. `directory` says whether the node must be a directory or
+/// must not be one, if given.
+fn unlink(dir: Option<&Vnode>, path: &str, directory: Option<bool>) -> Result<usize, SysError> {
+    let _op = Operation::begin();
+
+    // get the parent vnode and name
+    let Ok((parent, name)) = log!(Path::new(path).resolve_parent_at(dir)) else {
         err!(SysError::NoEntry);
     };
 
-    let mut inode_inner = inode.lock();
+    // cannot unlink `.` or `..`
+    if name == "." || name == ".." {
+        parent.put();
+        err!(SysError::InvalidArgument);
+    }
 
-    assert!(inode_inner.nlink >= 1, "unlink nlink < 1");
+    // the mount table keeps using a mount point
+    match log!(parent.lookup(name)) {
+        Ok(Some(vnode)) => {
+            let busy = mount::is_mountpoint(&vnode);
+            let is_dir = vnode.r#type() == Ok(InodeType::Directory);
+            vnode.put();
 
-    // if the inode is a directory and it is not empty, cannot unlink
-    if inode_inner.r#type == InodeType::Directory && !Directory::is_empty(&inode, &mut inode_inner)
-    {
-        inode.unlock_put(inode_inner);
-        parent.unlock_put(parent_inner);
-        err!(SysError::NotEmpty);
-    }
+            if busy {
+                parent.put();
+                err!(SysError::Busy);
+            }
 
-    // replace the directory entry with an empty one
-    let dir = Directory::new_empty();
-    match log!(parent.write(&mut parent_inner, offset, dir.as_bytes(), false)) {
-        Ok(write) => {
-            assert_eq!(write, Directory::SIZE as u32, "unlink write");
+            match directory {
+                Some(true) if !is_dir => {
+                    parent.put();
+                    err!(SysError::NotDirectory);
+                }
+                Some(false) if is_dir => {
+                    parent.put();
+                    err!(SysError::IsDirectory);
+                }
+                _ => {}
+            }
         }
-        Err(_) => {
-            parent.unlock_put(parent_inner);
-            err!(SysError::IoError)
+        _ => {
+            parent.put();
+            err!(SysError::NoEntry);
         }
     }
 
-    // if it is a directory, decrement parent's link count
-    if inode_inner.r#type == InodeType::Directory {
-        parent_inner.nlink -= 1;
-        parent.update(&parent_inner);
-    }
-    parent.unlock_put(parent_inner);
-
-    // decrement the inode's link count
-    inode_inner.nlink -= 1;
-    inode.update(&inode_inner);
-    inode.unlock_put(inode_inner);
+    let result = log!(parent.unlink(name));
+    parent.put();
 
+    try_log!(result);
     Ok(0)
 }
 
+/// Creates `path`, resolved from `dir`, as a node of `type`, returning a new reference to it.
+fn create(
+    dir: Option<&Vnode>,
+    path: &Path,
+    r#type: InodeType,
+    major: u16,
+    minor: u16,
+    exclusive: bool,
+) -> Result<Vnode, SysError> {
+    let Ok((parent, name)) = log!(path.resolve_parent_at(dir)) else {
+        err!(SysError::NoEntry);
+    };
+
+    let vnode = log!(parent.create(name, r#type, major, minor, exclusive));
+    parent.put();
+
+    Ok(try_log!(vnode))
+}
+
 pub fn sys_open(args: &SyscallArgs) -> Result<usize, SysError> {
     let o_mode = args.get_int(1) as usize;
     let path = try_log!(args.fetch_string(args.get_addr(0), MAXPATH));
-    let path = Path::new(&path);
 
-    let _op = Operation::begin();
+    log!(open(None, &path, o_mode))
+}
 
-    let (mut inode, mut inode_inner);
+pub fn sys_openat(args: &SyscallArgs) -> Result<usize, SysError> {
+    let dir = try_log!(get_dir(args, 0));
+    let path = try_log!(args.fetch_string(args.get_addr(1), MAXPATH));
+    let o_mode = args.get_int(2) as usize;
+
+    log!(open(dir.as_ref(), &path, o_mode))
+}
+
+/// Opens `path`, resolved from `dir`, with `o_mode` from `OpenFlag`, returning its descriptor.
+fn open(dir: Option<&Vnode>, path: &str, o_mode: usize) -> Result<usize, SysError> {
+    let path = Path::new(path);
+
+    let op = Operation::begin();
 
     // either create a new file or find the file from the path
-    if (o_mode & OpenFlag::CREATE) != 0 {
-        (inode, inode_inner) = match log!(Inode::create(&path, InodeType::File, 0, 0)) {
-            Ok(i) => i,
-            Err(e) => {
-                err!(SysError::from(e))
-            }
-        };
+    let vnode = if (o_mode & OpenFlag::CREATE) != 0 {
+        let exclusive = (o_mode & OpenFlag::EXCLUSIVE) != 0;
+        try_log!(create(dir, &path, InodeType::File, 0, 0, exclusive))
     } else {
-        inode = match log!(path.resolve()) {
-            Ok(i) => i,
+        match log!(path.resolve_at(dir)) {
+            Ok(v) => v,
             Err(_) => {
                 err!(SysError::NoEntry);
             }
-        };
-
-        inode_inner = inode.lock();
+        }
+    };
 
-        // if it is a directory, cannot open with write mode
-        if inode_inner.r#type == InodeType::Directory && o_mode != OpenFlag::READ_ONLY {
-            inode.unlock_put(inode_inner);
-            err!(SysError::IsDirectory);
+    let stat = match log!(vnode.stat()) {
+        Ok(stat) => stat,
+        Err(e) => {
+            vnode.put();
+            return Err(e.into());
         }
+    };
+
+    // if it is a directory, cannot open with write mode
+    let write = o_mode & (OpenFlag::WRITE_ONLY | OpenFlag::READ_WRITE) != 0;
+    if stat.r#type == InodeType::Directory && write {
+        vnode.put();
+        err!(SysError::IsDirectory);
+    }
+
+    // a socket node only names a socket, so it can be opened to look at but holds no data
+    if stat.r#type == InodeType::Socket && write {
+        vnode.put();
+        err!(SysError::NoDeviceOrAddress);
+    }
+
+    if (o_mode & OpenFlag::DIRECTORY) != 0 && stat.r#type != InodeType::Directory {
+        vnode.put();
+        err!(SysError::NotDirectory);
     }
 
     // cannot open device out of range
-    if inode_inner.r#type == InodeType::Device && inode_inner.major >= NDEV as u16 {
-        inode.unlock_put(inode_inner);
+    if stat.r#type == InodeType::Device && stat.major >= NDEV as u16 {
+        vnode.put();
         err!(SysError::NoEntry);
     }
 
     // allocate a file structure and a file descriptor
-    let (fd, file) = match log!(File::alloc()) {
+    let (fd, mut file) = match log!(File::alloc()) {
         Ok(mut file) => match log!(fd_alloc(file.clone())) {
             Ok(fd) => (fd, file),
             Err(e) => {
                 // if err here, we must also close the file
                 file.close();
-                inode.unlock_put(inode_inner);
+                vnode.put();
                 return Err(e);
             }
         },
         Err(e) => {
-            inode.unlock_put(inode_inner);
+            vnode.put();
             err!(SysError::from(e));
         }
     };
 
+    let (_proc, data) = current_proc_and_data_mut();
+    data.close_on_exec[fd] = (o_mode & OpenFlag::CLOEXEC) != 0;
+
+    let readable = (o_mode & OpenFlag::WRITE_ONLY) == 0;
+    let writeable = (o_mode & OpenFlag::WRITE_ONLY) != 0 || (o_mode & OpenFlag::READ_WRITE != 0);
+    let nonblock = (o_mode & OpenFlag::NONBLOCK) != 0;
+
+    // a FIFO attaches to the pipe shared by everyone who has it open
+    let fifo = if stat.r#type == InodeType::Fifo {
+        match log!(Pipe::open_fifo(
+            vnode.dev(),
+            vnode.ino(),
+            readable,
+            writeable
+        )) {
+            Ok(fifo) => Some(fifo),
+            Err(e) => {
+                data.open_files[fd] = None;
+                file.close();
+                vnode.put();
+                err!(SysError::from(e));
+            }
+        }
+    } else {
+        None
+    };
+
+    let truncate = ((o_mode & OpenFlag::TRUNCATE) != 0 && stat.r#type == InodeType::File)
+        .then(|| vnode.clone());
+
     let mut file_inner = FILE_TABLE.inner[file.id].lock();
-    if inode_inner.r#type == InodeType::Device {
+    if stat.r#type == InodeType::Device {
         file_inner.r#type = FileType::Device {
-            inode: inode.clone(),
-            major: inode_inner.major,
+            vnode,
+            major: stat.major,
         };
-    } else {
-        file_inner.r#type = FileType::Inode {
-            inode: inode.clone(),
+    } else if let Some((pipe, _)) = &fifo {
+        file_inner.r#type = FileType::Fifo {
+            vnode,
+            pipe: pipe.clone(),
         };
+    } else {
+        file_inner.r#type = FileType::Vnode { vnode };
         file_inner.offset = 0;
     }
-    file_inner.readable = (o_mode & OpenFlag::WRITE_ONLY) == 0;
-    file_inner.writeable =
-        (o_mode & OpenFlag::WRITE_ONLY) != 0 || (o_mode & OpenFlag::READ_WRITE != 0);
-
-    if (o_mode & OpenFlag::TRUNCATE) != 0 && inode_inner.r#type == InodeType::File {
-        inode.trunc(&mut inode_inner);
+    file_inner.readable = readable;
+    file_inner.writeable = writeable;
+    file_inner.append = (o_mode & OpenFlag::APPEND) != 0;
+    file_inner.nonblock = nonblock;
+    drop(file_inner);
+
+    // an end opened alone waits for the other, which must not hold up the log
+    drop(op);
+
+    // truncating runs operations of its own, the open file holds the reference meanwhile
+    if let Some(vnode) = truncate
+        && let Err(e) = log!(vnode.truncate(0))
+    {
+        data.open_files[fd] = None;
+        file.close();
+        err!(SysError::from(e));
     }
 
-    inode.unlock(inode_inner);
+    if let Some((pipe, seen)) = fifo
+        && readable != writeable
+        && let Err(e) = log!(pipe.wait_fifo(writeable, seen, nonblock))
+    {
+        data.open_files[fd] = None;
+        file.close();
+        return Err(e);
+    }
 
     Ok(fd)
 }
 
 pub fn sys_mkdir(args: &SyscallArgs) -> Result<usize, SysError> {
-    let _op = Operation::begin();
-
     let path = try_log!(args.fetch_string(args.get_addr(0), MAXPATH));
 
-    let (inode, inode_inner) =
-        match log!(Inode::create(&Path::new(&path), InodeType::Directory, 0, 0)) {
-            Ok(i) => i,
-            Err(e) => err!(SysError::from(e)),
-        };
+    log!(mkdir(None, &path))
+}
+
+pub fn sys_mkdirat(args: &SyscallArgs) -> Result<usize, SysError> {
+    let dir = try_log!(get_dir(args, 0));
+    let path = try_log!(args.fetch_string(args.get_addr(1), MAXPATH));
+
+    log!(mkdir(dir.as_ref(), &path))
+}
+
+/// Creates directory `path`, resolved from `dir`.
+fn mkdir(dir: Option<&Vnode>, path: &str) -> Result<usize, SysError> {
+    let _op = Operation::begin();
 
-    inode.unlock_put(inode_inner);
+    let vnode = try_log!(create(
+        dir,
+        &Path::new(path),
+        InodeType::Directory,
+        0,
+        0,
+        true
+    ));
+    vnode.put();
 
     Ok(0)
 }
@@ -306,17 +598,26 @@ pub fn sys_mknod(args: &SyscallArgs) -> Result<usize, SysError> {
     let minor = args.get_int(2) as u16;
     let path = try_log!(args.fetch_string(args.get_addr(0), MAXPATH));
 
-    let (inode, inner) = match log!(Inode::create(
+    let vnode = try_log!(create(
+        None,
         &Path::new(&path),
         InodeType::Device,
         major,
         minor,
-    )) {
-        Ok(i) => i,
-        Err(e) => err!(SysError::from(e)),
-    };
+        true
+    ));
+    vnode.put();
+
+    Ok(0)
+}
 
-    inode.unlock_put(inner);
+pub fn sys_mkfifo(args: &SyscallArgs) -> Result<usize, SysError> {
+    let _op = Operation::begin();
+
+    let path = try_log!(args.fetch_string(args.get_addr(0), MAXPATH));
+
+    let vnode = try_log!(create(None, &Path::new(&path), InodeType::Fifo, 0, 0, true));
+    vnode.put();
 
     Ok(0)
 }
@@ -328,21 +629,166 @@ pub fn sys_chdir(args: &SyscallArgs) -> Result<usize, SysError> {
 
     let path = try_log!(args.fetch_string(args.get_addr(0), MAXPATH));
 
-    let Ok(inode) = log!(Path::new(&path).resolve()) else {
+    let Ok(vnode) = log!(Path::new(&path).resolve()) else {
         err!(SysError::NoEntry);
     };
 
-    let inner = inode.lock();
+    if vnode.r#type() != Ok(InodeType::Directory) {
+        vnode.put();
+        err!(SysError::NotDirectory);
+    }
+
+    if let Some(old_cwd) = data.cwd.replace(vnode) {
+        old_cwd.put();
+    }
+
+    Ok(0)
+}
+
+pub fn sys_fchdir(args: &SyscallArgs) -> Result<usize, SysError> {
+    let (_proc, data) = current_proc_and_data_mut();
+
+    let Some(dir) = try_log!(get_dir(args, 0)) else {
+        err!(SysError::BadDescriptor);
+    };
+
+    let _op = Operation::begin();
 
-    if inner.r#type != InodeType::Directory {
-        inode.unlock_put(inner);
+    if let Some(old_cwd) = data.cwd.replace(dir.dup()) {
+        old_cwd.put();
+    }
+
+    Ok(0)
+}
+
+pub fn sys_chroot(args: &SyscallArgs) -> Result<usize, SysError> {
+    let (_proc, data) = current_proc_and_data_mut();
+
+    let _op = Operation::begin();
+
+    let path = try_log!(args.fetch_string(args.get_addr(0), MAXPATH));
+
+    let Ok(vnode) = log!(Path::new(&path).resolve()) else {
+        err!(SysError::NoEntry);
+    };
+
+    if vnode.r#type() != Ok(InodeType::Directory) {
+        vnode.put();
         err!(SysError::NotDirectory);
     }
 
-    inode.unlock(inner);
+    // the current directory stays where it is, even outside of the new root
+    if let Some(old_root) = data.root.replace(vnode) {
+        old_root.put();
+    }
+
+    Ok(0)
+}
+
+pub fn sys_getcwd(args: &SyscallArgs) -> Result<usize, SysError> {
+    let addr = args.get_addr(0);
+    let size = args.get_int(1) as usize;
+
+    let path = {
+        let _op = Operation::begin();
+
+        let cwd = current_proc().data().cwd.as_ref().expect("getcwd cwd");
+        try_log!(path_of(cwd))
+    };
+
+    // room for the terminating null byte too
+    if path.len() >= size {
+        err!(SysError::OutOfRange);
+    }
+
+    let mut buf = path.into_bytes();
+    buf.push(0);
+
+    if log!(proc::copy_to_user(&buf, addr)).is_err() {
+        err!(SysError::BadAddress);
+    }
+
+    Ok(buf.len() - 1)
+}
+
+pub fn sys_mount(args: &SyscallArgs) -> Result<usize, SysError> {
+    let fstype = try_log!(args.fetch_string(args.get_addr(0), MAXPATH));
+    let dev = args.get_int(1) as u32;
+    let path = try_log!(args.fetch_string(args.get_addr(2), MAXPATH));
+
+    let mountpoint = {
+        let _op = Operation::begin();
+
+        let Ok(vnode) = log!(Path::new(&path).resolve()) else {
+            err!(SysError::NoEntry);
+        };
+
+        if vnode.r#type() != Ok(InodeType::Directory) {
+            vnode.put();
+            err!(SysError::NotDirectory);
+        }
+
+        vnode
+    };
+
+    // mounting a disk recovers it in transactions of its own
+    let result = log!(mount::mount(&fstype, dev, &mountpoint));
+
+    {
+        let _op = Operation::begin();
+        mountpoint.put();
+    }
+
+    try_log!(result);
+    Ok(0)
+}
+
+pub fn sys_umount(args: &SyscallArgs) -> Result<usize, SysError> {
+    let path = try_log!(args.fetch_string(args.get_addr(0), MAXPATH));
+
+    // a path naming a mount point resolves to the root of the file system mounted on it
+    let (dev, ino) = {
+        let _op = Operation::begin();
+
+        let Ok(vnode) = log!(Path::new(&path).resolve()) else {
+            err!(SysError::NoEntry);
+        };
+
+        let (dev, ino) = (vnode.dev(), vnode.ino());
+        vnode.put();
+
+        (dev, ino)
+    };
+
+    let mountpoint = try_log!(mount::umount(dev, ino));
+
+    let _op = Operation::begin();
+    mountpoint.put();
+
+    Ok(0)
+}
+
+pub fn sys_statfs(args: &SyscallArgs) -> Result<usize, SysError> {
+    let addr = args.get_addr(1);
+    let path = try_log!(args.fetch_string(args.get_addr(0), MAXPATH));
+
+    let statfs = {
+        let _op = Operation::begin();
+
+        let Ok(vnode) = log!(Path::new(&path).resolve()) else {
+            err!(SysError::NoEntry);
+        };
+
+        let statfs = vnode.statfs();
+        vnode.put();
+        statfs
+    };
 
-    let old_cwd = mem::replace(&mut data.cwd, inode);
-    old_cwd.put();
+    let src =
+        unsafe { slice::from_raw_parts(&statfs as *const _ as *const u8, size_of::<StatFs>()) };
+    if log!(proc::copy_to_user(src, addr)).is_err() {
+        err!(SysError::BadAddress);
+    }
 
     Ok(0)
 }
@@ -424,3 +870,189 @@ pub fn sys_pipe(args: &SyscallArgs) -> Result<usize, SysError> {
 
     Ok(0)
 }
+
+/// Allocates a readable and writeable file for `socket`.
+fn socket_file(socket: Arc<Socket>) -> Result<File, SysError> {
+    let Ok(file) = log!(File::alloc()) else {
+        err!(SysError::TooManyFiles);
+    };
+
+    let mut inner = FILE_TABLE.inner[file.id].lock();
+    inner.r#type = FileType::Socket { socket };
+    inner.readable = true;
+    inner.writeable = true;
+    inner.append = false;
+    inner.nonblock = false;
+    drop(inner);
+
+    Ok(file)
+}
+
+/// Returns the socket open as file descriptor `index`, and whether it does not block.
+fn get_socket(args: &SyscallArgs, index: usize) -> Result<(Arc<Socket>, bool), SysError> {
+    let (_, file) = try_log!(args.get_file(index));
+    let nonblock = file.flags() & OpenFlag::NONBLOCK != 0;
+
+    match &FILE_TABLE.inner[file.id].lock().r#type {
+        FileType::Socket { socket } => Ok((Arc::clone(socket), nonblock)),
+        _ => err!(SysError::NotSocket),
+    }
+}
+
+pub fn sys_socket(args: &SyscallArgs) -> Result<usize, SysError> {
+    let domain = args.get_int(0) as usize;
+    let r#type = args.get_int(1) as usize;
+    let protocol = args.get_int(2) as usize;
+
+    if domain != AddressFamily::UNIX {
+        err!(SysError::AddressFamilyNotSupported);
+    }
+
+    if r#type != SocketType::STREAM || protocol != 0 {
+        err!(SysError::InvalidArgument);
+    }
+
+    let Ok(socket) = log!(Socket::new()) else {
+        err!(SysError::OutOfMemory);
+    };
+
+    let mut file = try_log!(socket_file(socket));
+
+    let Ok(fd) = log!(fd_alloc(file.clone())) else {
+        file.close();
+        err!(SysError::TooManyFiles);
+    };
+
+    Ok(fd)
+}
+
+pub fn sys_bind(args: &SyscallArgs) -> Result<usize, SysError> {
+    let (socket, _) = try_log!(get_socket(args, 0));
+    let path = try_log!(args.fetch_string(args.get_addr(1), MAXPATH));
+
+    // a socket is bound once, check before its node shows up in the file system
+    if !socket.is_unbound() {
+        err!(SysError::InvalidArgument);
+    }
+
+    let _op = Operation::begin();
+
+    // the name of a socket is never reused, like `mkfifo`
+    let vnode = match log!(create(
+        None,
+        &Path::new(&path),
+        InodeType::Socket,
+        0,
+        0,
+        true
+    )) {
+        Ok(vnode) => vnode,
+        Err(SysError::AlreadyExists) => err!(SysError::AddressInUse),
+        Err(e) => err!(e),
+    };
+
+    if let Err((e, vnode)) = log!(socket.bind(vnode)) {
+        vnode.put();
+
+        // bound by another process sharing the socket meanwhile, the new node goes away again
+        if let Ok((parent, name)) = log!(Path::new(&path).resolve_parent_at(None)) {
+            let _ = log!(parent.unlink(name));
+            parent.put();
+        }
+
+        err!(e);
+    }
+
+    Ok(0)
+}
+
+pub fn sys_listen(args: &SyscallArgs) -> Result<usize, SysError> {
+    let (socket, _) = try_log!(get_socket(args, 0));
+    let backlog = args.get_int(1) as usize;
+
+    try_log!(socket.listen(backlog));
+    Ok(0)
+}
+
+pub fn sys_accept(args: &SyscallArgs) -> Result<usize, SysError> {
+    let (socket, nonblock) = try_log!(get_socket(args, 0));
+
+    let connection = try_log!(socket.accept(nonblock));
+
+    let mut file = match log!(socket_file(Arc::clone(&connection))) {
+        Ok(file) => file,
+        Err(e) => {
+            connection.close();
+            err!(e);
+        }
+    };
+
+    let Ok(fd) = log!(fd_alloc(file.clone())) else {
+        file.close();
+        err!(SysError::TooManyFiles);
+    };
+
+    Ok(fd)
+}
+
+pub fn sys_connect(args: &SyscallArgs) -> Result<usize, SysError> {
+    let (socket, _) = try_log!(get_socket(args, 0));
+    let path = try_log!(args.fetch_string(args.get_addr(1), MAXPATH));
+
+    // a listening socket is found by its node, which only has to exist while looking it up
+    let (dev, ino) = {
+        let _op = Operation::begin();
+
+        let Ok(vnode) = log!(Path::new(&path).resolve()) else {
+            err!(SysError::NoEntry);
+        };
+
+        let r#type = vnode.r#type();
+        let (dev, ino) = (vnode.dev(), vnode.ino());
+        vnode.put();
+
+        if r#type != Ok(InodeType::Socket) {
+            err!(SysError::ConnectionRefused);
+        }
+
+        (dev, ino)
+    };
+
+    try_log!(socket.connect(dev, ino));
+    Ok(0)
+}
+
+pub fn sys_sendfd(args: &SyscallArgs) -> Result<usize, SysError> {
+    let (socket, _) = try_log!(get_socket(args, 0));
+    let (_, mut file) = try_log!(args.get_file(1));
+
+    // a queued file is only closed when the receiving socket is, so two sockets sent over each
+    // other and never received would keep each other open forever
+    if matches!(
+        FILE_TABLE.inner[file.id].lock().r#type,
+        FileType::Socket { .. }
+    ) {
+        err!(SysError::InvalidArgument);
+    }
+
+    // the receiver gets a reference of its own, so the descriptor stays open here
+    if let Err((e, mut file)) = log!(socket.send_file(file.dup())) {
+        file.close();
+        err!(e);
+    }
+
+    Ok(0)
+}
+
+pub fn sys_recvfd(args: &SyscallArgs) -> Result<usize, SysError> {
+    let (socket, nonblock) = try_log!(get_socket(args, 0));
+
+    let mut file = try_log!(socket.recv_file(nonblock));
+
+    let Ok(fd) = log!(fd_alloc(file.clone())) else {
+        file.close();
+        err!(SysError::TooManyFiles);
+    };
+
+    Ok(fd)
+}
@@ -0,0 +1,480 @@
+// In-memory file system.
+//
+// A tmpfs keeps its files and directories in the kernel heap, so nothing it holds goes through the
+// buffer cache or a log, and it is all gone once unmounted. File data is a `Vec` that grows with
+// writes and is freed when the last link and the last reference to the file are gone. The bytes of
+// file data a tmpfs may hold are capped at `TMPFSSIZE`.
+//
+// Nodes live in a table and are named by their index, which stays the same for as long as the node
+// exists. Like the inode table, reference counts are kept under a spinlock, so that the mount table
+// can look at them without sleeping, and everything else under a sleeplock, which is held while
+// file data is copied to and from user space.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::fs::{BSIZE, FsError, InodeType, MAXNAME, Stat};
+use crate::param::TMPFSSIZE;
+use crate::proc;
+use crate::sleeplock::{SleepLock, SleepLockGuard};
+use crate::spinlock::SpinLock;
+use crate::vfs::{self, DirEntry, FileOps, FileSystem, NodeId, StatFs, VnodeOps};
+use crate::vm::VA;
+
+/// Index of the root directory in the node table
+const ROOT: usize = 0;
+
+/// A file, directory or device of a tmpfs
+#[derive(Debug)]
+struct TmpNode {
+    r#type: InodeType,
+    major: u16,
+    minor: u16,
+    nlink: u16,
+    /// Directory this node was created in, `..` of a directory
+    parent: usize,
+    /// Contents of a file
+    data: Vec<u8>,
+    /// Entries of a directory, besides `.` and `..`
+    entries: Vec<(String, usize)>,
+}
+
+#[derive(Debug)]
+struct TmpInner {
+    nodes: Vec<Option<TmpNode>>,
+    /// Bytes of file data held
+    used: usize,
+}
+
+impl TmpInner {
+    fn node(&self, id: usize) -> &TmpNode {
+        self.nodes[id].as_ref().expect("tmpfs node")
+    }
+
+    fn node_mut(&mut self, id: usize) -> &mut TmpNode {
+        self.nodes[id].as_mut().expect("tmpfs node")
+    }
+
+    /// Returns the node `name` refers to in directory `dir`.
+    fn find(&self, dir: usize, name: &str) -> Option<usize> {
+        match name {
+            "." => Some(dir),
+            ".." => Some(self.node(dir).parent),
+            _ => self
+                .node(dir)
+                .entries
+                .iter()
+                .find(|(entry, _)| entry == name)
+                .map(|(_, id)| *id),
+        }
+    }
+
+    /// Sets the length of file `id` to `size`, keeping within `TMPFSSIZE`.
+    fn resize(&mut self, id: usize, size: usize) -> Result<(), FsError> {
+        let len = self.node(id).data.len();
+
+        if size > len {
+            if self.used + (size - len) > TMPFSSIZE {
+                err!(FsError::OutOfBlock);
+            }
+
+            if self.node_mut(id).data.try_reserve(size - len).is_err() {
+                err!(FsError::OutOfBlock);
+            }
+        }
+
+        let data = &mut self.node_mut(id).data;
+        data.resize(size, 0);
+        if data.is_empty() {
+            data.shrink_to_fit();
+        }
+
+        self.used = self.used + size - len;
+        Ok(())
+    }
+
+    /// Frees node `id`, which has no links and no references left.
+    fn free(&mut self, id: usize) {
+        if let Some(node) = self.nodes[id].take() {
+            self.used -= node.data.len();
+        }
+    }
+}
+
+/// An in-memory file system
+#[derive(Debug)]
+pub struct TmpFs {
+    dev: u32,
+    /// Reference counts, indexed like `TmpInner::nodes`
+    refs: SpinLock<Vec<u32>>,
+    inner: SleepLock<TmpInner>,
+}
+
+impl TmpFs {
+    /// Creates an empty tmpfs. `dev` is ignored, since a tmpfs has no disk; it gets a device
+    /// number of its own.
+    pub fn mount(_dev: u32) -> Result<Arc<dyn FileSystem>, FsError> {
+        let root = TmpNode {
+            r#type: InodeType::Directory,
+            major: 0,
+            minor: 0,
+            nlink: 1,
+            parent: ROOT,
+            data: Vec::new(),
+            entries: Vec::new(),
+        };
+
+        Ok(Arc::new(TmpFs {
+            dev: vfs::alloc_dev(),
+            refs: SpinLock::new(vec![0], "tmpfs"),
+            inner: SleepLock::new(
+                TmpInner {
+                    nodes: vec![Some(root)],
+                    used: 0,
+                },
+                "tmpfs",
+            ),
+        }))
+    }
+
+    fn node(id: usize) -> NodeId {
+        NodeId {
+            id,
+            ino: id as u32 + 1,
+        }
+    }
+
+    /// Takes a reference to node `id`.
+    fn get(&self, id: usize) -> NodeId {
+        self.refs.lock()[id] += 1;
+        Self::node(id)
+    }
+
+    /// Frees node `id` if it has no links and no references left.
+    fn reclaim(&self, inner: &mut SleepLockGuard<'_, TmpInner>, id: usize) {
+        let unused = self.refs.lock()[id] == 0;
+
+        if unused && inner.nodes[id].as_ref().is_some_and(|node| node.nlink == 0) {
+            inner.free(id);
+        }
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn dev(&self) -> u32 {
+        self.dev
+    }
+
+    fn root(&self) -> Result<NodeId, FsError> {
+        Ok(self.get(ROOT))
+    }
+
+    fn statfs(&self) -> StatFs {
+        // only file data is capped, nodes are not
+        let used = self.inner.lock().used;
+        StatFs {
+            bsize: BSIZE as u32,
+            blocks: (TMPFSSIZE / BSIZE) as u32,
+            bfree: ((TMPFSSIZE - used) / BSIZE) as u32,
+            ..Default::default()
+        }
+    }
+
+    fn busy(&self) -> bool {
+        self.refs.lock().iter().any(|&r| r > 0)
+    }
+
+    fn unmount(&self) {
+        // the nodes are freed with the file system, once the mount table drops it
+    }
+}
+
+impl VnodeOps for TmpFs {
+    fn dup(&self, node: NodeId) {
+        self.get(node.id);
+    }
+
+    fn put(&self, node: NodeId) {
+        let last = {
+            let mut refs = self.refs.lock();
+            assert!(refs[node.id] >= 1, "tmpfs put");
+            refs[node.id] -= 1;
+            refs[node.id] == 0
+        };
+
+        if last {
+            let mut inner = self.inner.lock();
+            self.reclaim(&mut inner, node.id);
+        }
+    }
+
+    fn stat(&self, node: NodeId) -> Result<Stat, FsError> {
+        let inner = self.inner.lock();
+        let tmp = inner.node(node.id);
+
+        Ok(Stat {
+            dev: self.dev,
+            ino: node.ino,
+            r#type: tmp.r#type,
+            major: tmp.major,
+            minor: tmp.minor,
+            nlink: tmp.nlink,
+            size: tmp.data.len() as u64,
+        })
+    }
+
+    fn lookup(&self, dir: NodeId, name: &str) -> Result<Option<NodeId>, FsError> {
+        let inner = self.inner.lock();
+
+        if inner.node(dir.id).r#type != InodeType::Directory {
+            err!(FsError::NotDirectory);
+        }
+
+        // `..` of a removed directory may be gone too
+        if inner.node(dir.id).nlink == 0 {
+            err!(FsError::Resolve);
+        }
+
+        Ok(inner.find(dir.id, name).map(|id| self.get(id)))
+    }
+
+    fn create(
+        &self,
+        dir: NodeId,
+        name: &str,
+        r#type: InodeType,
+        major: u16,
+        minor: u16,
+        exclusive: bool,
+    ) -> Result<NodeId, FsError> {
+        let mut inner = self.inner.lock();
+
+        // entries of a removed directory would never be freed
+        if inner.node(dir.id).nlink == 0 {
+            err!(FsError::Resolve);
+        }
+
+        // check if the file already exists
+        if let Some(id) = inner.find(dir.id, name) {
+            let existing = inner.node(id).r#type;
+
+            if !exclusive
+                && r#type == InodeType::File
+                && matches!(
+                    existing,
+                    InodeType::File | InodeType::Device | InodeType::Fifo
+                )
+            {
+                return Ok(self.get(id));
+            }
+
+            err!(if exclusive {
+                FsError::Link
+            } else {
+                FsError::Create
+            });
+        }
+
+        if name.len() > MAXNAME {
+            err!(FsError::NameTooLong);
+        }
+
+        let node = TmpNode {
+            r#type,
+            major,
+            minor,
+            nlink: 1,
+            parent: dir.id,
+            data: Vec::new(),
+            entries: Vec::new(),
+        };
+
+        // reuse a free slot, there is no reference to it left
+        let id = match inner.nodes.iter().position(|n| n.is_none()) {
+            Some(id) => {
+                inner.nodes[id] = Some(node);
+                id
+            }
+            None => {
+                inner.nodes.push(Some(node));
+                self.refs.lock().push(0);
+                inner.nodes.len() - 1
+            }
+        };
+
+        let parent = inner.node_mut(dir.id);
+        parent.entries.push((String::from(name), id));
+        if r#type == InodeType::Directory {
+            parent.nlink += 1;
+        }
+
+        Ok(self.get(id))
+    }
+
+    fn link(&self, dir: NodeId, name: &str, node: NodeId) -> Result<(), FsError> {
+        let mut inner = self.inner.lock();
+
+        if inner.node(node.id).r#type == InodeType::Directory {
+            err!(FsError::NotPermitted);
+        }
+
+        if inner.node(dir.id).nlink == 0 {
+            err!(FsError::Resolve);
+        }
+
+        if name.len() > MAXNAME {
+            err!(FsError::NameTooLong);
+        }
+
+        if inner.find(dir.id, name).is_some() {
+            err!(FsError::Link);
+        }
+
+        inner
+            .node_mut(dir.id)
+            .entries
+            .push((String::from(name), node.id));
+        inner.node_mut(node.id).nlink += 1;
+
+        Ok(())
+    }
+
+    fn unlink(&self, dir: NodeId, name: &str) -> Result<(), FsError> {
+        let mut inner = self.inner.lock();
+
+        let Some(i) = inner
+            .node(dir.id)
+            .entries
+            .iter()
+            .position(|(entry, _)| entry == name)
+        else {
+            err!(FsError::Resolve);
+        };
+
+        let id = inner.node(dir.id).entries[i].1;
+        let is_dir = inner.node(id).r#type == InodeType::Directory;
+
+        if is_dir && !inner.node(id).entries.is_empty() {
+            err!(FsError::NotEmpty);
+        }
+
+        let parent = inner.node_mut(dir.id);
+        parent.entries.remove(i);
+        if is_dir {
+            parent.nlink -= 1;
+        }
+
+        inner.node_mut(id).nlink -= 1;
+        self.reclaim(&mut inner, id);
+
+        Ok(())
+    }
+
+    fn readdir(&self, dir: NodeId, offset: u32) -> Result<Option<(DirEntry, u32)>, FsError> {
+        let inner = self.inner.lock();
+        let tmp = inner.node(dir.id);
+
+        // a removed directory is empty, and its parent may be gone
+        if tmp.nlink == 0 {
+            return Ok(None);
+        }
+
+        // offsets count entries, `.` and `..` first
+        let (name, id) = match offset as usize {
+            0 => (String::from("."), dir.id),
+            1 => (String::from(".."), tmp.parent),
+            i => match tmp.entries.get(i - 2) {
+                Some((name, id)) => (name.clone(), *id),
+                None => return Ok(None),
+            },
+        };
+
+        let entry = DirEntry {
+            ino: Self::node(id).ino,
+            name,
+        };
+        Ok(Some((entry, offset + 1)))
+    }
+}
+
+impl FileOps for TmpFs {
+    fn read(&self, node: NodeId, offset: u32, dst: &mut [u8], user: bool) -> Result<u32, FsError> {
+        let inner = self.inner.lock();
+        let data = &inner.node(node.id).data;
+
+        let offset = offset as usize;
+        if offset > data.len() {
+            err!(FsError::Read);
+        }
+
+        let n = dst.len().min(data.len() - offset);
+        try_log!(vfs::copy_out(&data[offset..offset + n], dst, user));
+
+        Ok(n as u32)
+    }
+
+    fn write(
+        &self,
+        node: NodeId,
+        offset: &mut u32,
+        src: &[u8],
+        user: bool,
+        append: bool,
+    ) -> Result<u32, FsError> {
+        let mut inner = self.inner.lock();
+        let len = inner.node(node.id).data.len();
+
+        // appends are atomic since the offset is taken under the lock
+        if append {
+            *offset = len as u32;
+        }
+
+        let Some(end) = offset.checked_add(src.len() as u32) else {
+            err!(FsError::Write);
+        };
+
+        // writing past the end fills the gap with zeros
+        if end as usize > len {
+            try_log!(inner.resize(node.id, end as usize));
+        }
+
+        let dst = &mut inner.node_mut(node.id).data[*offset as usize..end as usize];
+
+        if user {
+            let src_va = VA::from(src.as_ptr() as usize);
+            if log!(proc::copy_from_user(src_va, dst)).is_err() {
+                // leave the file as it was
+                if end as usize > len {
+                    let _ = inner.resize(node.id, len);
+                }
+                err!(FsError::Write);
+            }
+        } else {
+            dst.copy_from_slice(src);
+        }
+
+        *offset = end;
+        Ok(src.len() as u32)
+    }
+
+    fn truncate(&self, node: NodeId, size: usize) -> Result<(), FsError> {
+        let mut inner = self.inner.lock();
+
+        if inner.node(node.id).r#type != InodeType::File {
+            err!(FsError::Type);
+        }
+
+        if size > u32::MAX as usize {
+            err!(FsError::OutOfRange);
+        }
+
+        inner.resize(node.id, size)
+    }
+
+    fn sync(&self, _node: NodeId) -> Result<(), FsError> {
+        // nothing to write back
+        Ok(())
+    }
+}
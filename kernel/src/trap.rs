@@ -1,7 +1,7 @@
 use core::mem;
 
 use crate::kernelvec::kernelvec;
-use crate::memlayout::{TRAMPOLINE, UART0_IRQ, VIRTIO0_IRQ};
+use crate::memlayout::{TRAMPOLINE, UART0_IRQ, VIRTIO0_IRQ, VIRTIO1_IRQ, VIRTIO2_IRQ};
 use crate::param::NKSTACK_PAGES;
 use crate::plic;
 use crate::proc::{self, Channel};
@@ -248,7 +248,9 @@ fn device_interrupt(intr: scause::Interrupt) -> Option<InterruptType> {
             match irq as usize {
                 0 => {} // spurious interrupt from PLIC, ignore
                 UART0_IRQ => UART.handle_interrupt(),
-                VIRTIO0_IRQ => virtio_disk::handle_interrupt(),
+                VIRTIO0_IRQ => virtio_disk::handle_interrupt(1),
+                VIRTIO1_IRQ => virtio_disk::handle_interrupt(2),
+                VIRTIO2_IRQ => virtio_disk::handle_interrupt(3),
                 _ => println!("unexpected interrupt irq = {}", irq),
             }
 
@@ -0,0 +1,567 @@
+// Virtual file system.
+//
+// System calls work on vnodes, references to the files and directories of whatever file system is
+// mounted there, instead of calling into a file system directly. Each mounted file system is a
+// `FileSystem`, which implements `VnodeOps` for the directory tree and `FileOps` for the contents
+// of files. File system types are listed in `FS_TYPES`, so that `mount` can find them by name.
+//
+// A node is named by a `NodeId` that only means something to its file system. Nodes are reference
+// counted like inodes: `lookup()` and `create()` return a new reference, `dup()` takes another one
+// and `put()` releases it. Putting the last reference of an unlinked disk file frees it, so
+// `put()`, and every call that changes the tree, must be made inside an `Operation`. `FileOps`
+// are called outside of one, since a disk file system splits large writes and truncations into
+// operations of its own.
+//
+// Path names are resolved here, one component at a time with `lookup()`, crossing into mounted file
+// systems on the way (see mount.rs). Absolute paths start at the root directory of the process,
+// which `chroot` can move, and `..` never climbs above it.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::Debug;
+use core::ptr;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::devfs::DevFs;
+use crate::fat::FatFs;
+use crate::fs::{BSIZE, Directory, DiskFs, FsError, InodeType, Stat};
+use crate::mount;
+use crate::param::NDISK;
+use crate::proc;
+use crate::procfs::ProcFs;
+use crate::tmpfs::TmpFs;
+use crate::vm::VA;
+
+/// Identifies a node inside its file system
+#[derive(Debug, Clone, Copy)]
+pub struct NodeId {
+    /// Index into the file system's node table, only stable while the node is referenced
+    pub id: usize,
+    /// Inode number, reported by `stat`
+    pub ino: u32,
+}
+
+/// A directory entry returned by `readdir()`
+#[derive(Debug)]
+pub struct DirEntry {
+    pub ino: u32,
+    pub name: String,
+}
+
+/// Space used by a file system, reported by `statfs`.
+/// Counts that a file system does not limit are 0.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StatFs {
+    /// Size of a block in bytes
+    pub bsize: u32,
+    /// Data blocks
+    pub blocks: u32,
+    /// Free data blocks
+    pub bfree: u32,
+    /// Inodes
+    pub files: u32,
+    /// Free inodes
+    pub ffree: u32,
+}
+
+/// A mounted file system
+pub trait FileSystem: VnodeOps + FileOps + Debug + Send + Sync {
+    /// Returns the device number reported by `stat`.
+    fn dev(&self) -> u32;
+
+    /// Returns a new reference to the root directory.
+    /// Must not sleep, since it is called with the mount table locked.
+    fn root(&self) -> Result<NodeId, FsError>;
+
+    /// Returns how much space is used and free.
+    fn statfs(&self) -> StatFs;
+
+    /// Returns whether any node is still referenced, keeping the file system from being unmounted.
+    /// Must not sleep, since it is called with the mount table locked.
+    fn busy(&self) -> bool;
+
+    /// Writes everything back before the file system is dropped from the mount table.
+    fn unmount(&self);
+}
+
+/// Operations on the directory tree
+pub trait VnodeOps {
+    /// Takes another reference to `node`.
+    fn dup(&self, node: NodeId);
+
+    /// Releases a reference to `node`, freeing it if it was the last one of an unlinked node.
+    fn put(&self, node: NodeId);
+
+    fn stat(&self, node: NodeId) -> Result<Stat, FsError>;
+
+    /// Looks up `name` in directory `dir`, returning a new reference to it if it exists.
+    fn lookup(&self, dir: NodeId, name: &str) -> Result<Option<NodeId>, FsError>;
+
+    /// Creates `name` in directory `dir`, returning a new reference to it.
+    /// Unless `exclusive`, creating a file that exists opens the file or device instead.
+    fn create(
+        &self,
+        dir: NodeId,
+        name: &str,
+        r#type: InodeType,
+        major: u16,
+        minor: u16,
+        exclusive: bool,
+    ) -> Result<NodeId, FsError>;
+
+    /// Adds `name` to directory `dir` as another link to `node`.
+    fn link(&self, dir: NodeId, name: &str, node: NodeId) -> Result<(), FsError>;
+
+    /// Removes `name` from directory `dir`. Directories must be empty.
+    fn unlink(&self, dir: NodeId, name: &str) -> Result<(), FsError>;
+
+    /// Returns the first entry of directory `dir` at or after `offset`, and the offset just past
+    /// it. Offsets start at 0 and are otherwise up to the file system, like byte offsets on disk.
+    fn readdir(&self, dir: NodeId, offset: u32) -> Result<Option<(DirEntry, u32)>, FsError>;
+}
+
+/// Operations on the contents of files
+pub trait FileOps {
+    /// Reads from `node` at `offset` into `dst`, returning the number of bytes read.
+    /// `user` indicates whether `dst` is a user space address.
+    fn read(&self, node: NodeId, offset: u32, dst: &mut [u8], user: bool) -> Result<u32, FsError>;
+
+    /// Writes `src` to `node` at `*offset`, or at the end of the file if `append`, advancing
+    /// `*offset` past what was written. Fails unless all of `src` was written.
+    /// `user` indicates whether `src` is a user space address.
+    fn write(
+        &self,
+        node: NodeId,
+        offset: &mut u32,
+        src: &[u8],
+        user: bool,
+        append: bool,
+    ) -> Result<u32, FsError>;
+
+    /// Sets the size of file `node` to `size` bytes.
+    fn truncate(&self, node: NodeId, size: usize) -> Result<(), FsError>;
+
+    /// Waits until all changes made to `node` are stored.
+    fn sync(&self, node: NodeId) -> Result<(), FsError>;
+}
+
+/// A file system type that can be mounted by name
+pub struct FsType {
+    pub name: &'static str,
+    /// Mounts the file system on device `dev`
+    pub mount: fn(dev: u32) -> Result<Arc<dyn FileSystem>, FsError>,
+}
+
+/// Mountable file system types
+pub static FS_TYPES: &[FsType] = &[
+    FsType {
+        name: "xv6fs",
+        mount: DiskFs::mount,
+    },
+    FsType {
+        name: "tmpfs",
+        mount: TmpFs::mount,
+    },
+    FsType {
+        name: "procfs",
+        mount: ProcFs::mount,
+    },
+    FsType {
+        name: "fat32",
+        mount: FatFs::mount,
+    },
+    FsType {
+        name: "devfs",
+        mount: DevFs::mount,
+    },
+];
+
+/// Copies `src` to the start of `dst`, which is a user address if `user`.
+/// For file systems that keep file contents in kernel memory.
+pub fn copy_out(src: &[u8], dst: &mut [u8], user: bool) -> Result<(), FsError> {
+    if user {
+        let dst_va = VA::from(dst.as_mut_ptr() as usize);
+        if log!(proc::copy_to_user(src, dst_va)).is_err() {
+            err!(FsError::Copy);
+        }
+    } else {
+        unsafe { ptr::copy_nonoverlapping(src.as_ptr(), dst.as_mut_ptr(), src.len()) }
+    }
+
+    Ok(())
+}
+
+/// Next device number for file systems without a disk, past those of the disks
+static NEXT_DEV: AtomicU32 = AtomicU32::new(NDISK as u32 + 1);
+
+/// Returns a new device number for a file system without a disk.
+pub fn alloc_dev() -> u32 {
+    NEXT_DEV.fetch_add(1, Ordering::Relaxed)
+}
+
+/// A reference to a node of a mounted file system
+#[derive(Debug, Clone)]
+pub struct Vnode {
+    pub fs: Arc<dyn FileSystem>,
+    pub node: NodeId,
+}
+
+impl Vnode {
+    /// Wraps a reference to `node` returned by `fs`.
+    pub fn new(fs: Arc<dyn FileSystem>, node: NodeId) -> Self {
+        Self { fs, node }
+    }
+
+    pub fn dev(&self) -> u32 {
+        self.fs.dev()
+    }
+
+    pub fn ino(&self) -> u32 {
+        self.node.ino
+    }
+
+    /// Returns whether both vnodes refer to the same node.
+    pub fn is(&self, other: &Vnode) -> bool {
+        self.dev() == other.dev() && self.ino() == other.ino()
+    }
+
+    /// Takes another reference to the node.
+    pub fn dup(&self) -> Self {
+        self.fs.dup(self.node);
+        self.clone()
+    }
+
+    /// Releases the reference. Must be called inside an `Operation`.
+    pub fn put(self) {
+        self.fs.put(self.node);
+    }
+
+    pub fn stat(&self) -> Result<Stat, FsError> {
+        self.fs.stat(self.node)
+    }
+
+    pub fn r#type(&self) -> Result<InodeType, FsError> {
+        Ok(try_log!(self.stat()).r#type)
+    }
+
+    /// Returns the space used by the file system the node is on.
+    pub fn statfs(&self) -> StatFs {
+        self.fs.statfs()
+    }
+
+    pub fn lookup(&self, name: &str) -> Result<Option<Vnode>, FsError> {
+        let node = try_log!(self.fs.lookup(self.node, name));
+        Ok(node.map(|node| Vnode::new(self.fs.clone(), node)))
+    }
+
+    pub fn create(
+        &self,
+        name: &str,
+        r#type: InodeType,
+        major: u16,
+        minor: u16,
+        exclusive: bool,
+    ) -> Result<Vnode, FsError> {
+        let node = try_log!(
+            self.fs
+                .create(self.node, name, r#type, major, minor, exclusive)
+        );
+        Ok(Vnode::new(self.fs.clone(), node))
+    }
+
+    pub fn link(&self, name: &str, node: &Vnode) -> Result<(), FsError> {
+        if node.dev() != self.dev() {
+            err!(FsError::CrossDevice);
+        }
+
+        self.fs.link(self.node, name, node.node)
+    }
+
+    pub fn unlink(&self, name: &str) -> Result<(), FsError> {
+        self.fs.unlink(self.node, name)
+    }
+
+    pub fn readdir(&self, offset: u32) -> Result<Option<(DirEntry, u32)>, FsError> {
+        self.fs.readdir(self.node, offset)
+    }
+
+    pub fn truncate(&self, size: usize) -> Result<(), FsError> {
+        self.fs.truncate(self.node, size)
+    }
+
+    pub fn read(&self, offset: u32, dst: &mut [u8], user: bool) -> Result<u32, FsError> {
+        self.fs.read(self.node, offset, dst, user)
+    }
+
+    pub fn write(
+        &self,
+        offset: &mut u32,
+        src: &[u8],
+        user: bool,
+        append: bool,
+    ) -> Result<u32, FsError> {
+        self.fs.write(self.node, offset, src, user, append)
+    }
+
+    pub fn sync(&self) -> Result<(), FsError> {
+        self.fs.sync(self.node)
+    }
+
+    /// Reads the directory entries at `*offset` into the user buffer `dst` as one block of
+    /// `Directory` records, the format `read` returns for directories whatever file system they
+    /// are on. Returns 0 at the end of the directory.
+    pub fn read_dir(&self, offset: &mut u32, dst: &mut [u8]) -> Result<u32, FsError> {
+        if dst.len() < BSIZE {
+            err!(FsError::OutOfRange);
+        }
+
+        let mut entries: Vec<(u16, Vec<u8>)> = Vec::new();
+        let mut used = 0;
+        let mut next = *offset;
+
+        while let Some((entry, end)) = try_log!(self.readdir(next)) {
+            let rec_len = Directory::rec_len_for(entry.name.len());
+            if used + rec_len > BSIZE {
+                break;
+            }
+
+            // 0 marks an unused record
+            let ino = entry.ino.clamp(1, u16::MAX as u32) as u16;
+            entries.push((ino, entry.name.into_bytes()));
+            used += rec_len;
+            next = end;
+        }
+
+        if entries.is_empty() {
+            return Ok(0);
+        }
+
+        let entries: Vec<(u16, &[u8])> = entries
+            .iter()
+            .map(|(ino, name)| (*ino, name.as_slice()))
+            .collect();
+
+        let mut block = vec![0u8; BSIZE];
+        Directory::pack(&mut block, &entries);
+
+        try_log!(copy_out(&block, dst, true));
+
+        *offset = next;
+        Ok(BSIZE as u32)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Path<'a>(&'a str);
+
+impl<'a> Path<'a> {
+    pub const fn new(name: &'a str) -> Path<'a> {
+        Self(name)
+    }
+
+    pub fn as_str(&self) -> &'a str {
+        self.0
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn is_absolute(&self) -> bool {
+        self.0.starts_with('/')
+    }
+
+    /// Returns (next path component, rest).
+    /// The returned path has no leading slashes, so the caller can check to see if the component
+    /// is the last one (Path == '\0').
+    /// If no component to remove, returns None.
+    pub fn next_component(&self) -> Option<(&'a str, Path<'a>)> {
+        let s = self.0.trim_start_matches('/');
+
+        if s.is_empty() {
+            return None;
+        }
+
+        match s.find('/') {
+            Some(i) => {
+                let rest = s[i..].trim_start_matches('/');
+                Some((&s[..i], Path(rest)))
+            }
+            None => Some((s, Path(""))),
+        }
+    }
+
+    fn resolve_inner(
+        &self,
+        start: Option<&Vnode>,
+        parent: bool,
+    ) -> Result<(Vnode, &'a str), FsError> {
+        let root = proc_root();
+
+        let mut vnode = if self.is_absolute() {
+            match &root {
+                Some(root) => root.dup(),
+                None => try_log!(mount::root()),
+            }
+        } else if let Some(start) = start {
+            start.dup()
+        } else {
+            proc::current_proc()
+                .data()
+                .cwd
+                .as_ref()
+                .expect("resolve cwd")
+                .dup()
+        };
+
+        let mut name = "";
+        let mut path = self.clone();
+
+        // walk the path, one component at at time
+        while let Some((component, rest)) = path.next_component() {
+            // `..` of the root of a mounted file system is looked up in the directory it is
+            // mounted on
+            if component == ".." && !(parent && rest.is_empty()) {
+                // the root directory of the process is its own parent
+                if root.as_ref().is_some_and(|root| root.is(&vnode)) {
+                    name = component;
+                    path = rest;
+                    continue;
+                }
+
+                vnode = mount::leave(vnode);
+            }
+
+            if vnode.r#type() != Ok(InodeType::Directory) {
+                vnode.put();
+                err!(FsError::Resolve);
+            }
+
+            // stop one level early
+            if parent && rest.is_empty() {
+                return Ok((vnode, component));
+            }
+
+            // get the next vnode
+            match log!(vnode.lookup(component)) {
+                Ok(Some(next)) => {
+                    vnode.put();
+                    vnode = try_log!(mount::enter(next));
+                }
+                Ok(None) => {
+                    vnode.put();
+                    err!(FsError::Resolve);
+                }
+                Err(e) => {
+                    vnode.put();
+                    return Err(e);
+                }
+            }
+
+            name = component;
+            path = rest;
+        }
+
+        // we returned early to put the last vnode
+        if parent {
+            vnode.put();
+            err!(FsError::Resolve);
+        }
+
+        Ok((vnode, name))
+    }
+
+    /// Resolves the full path to a vnode.
+    pub fn resolve(&self) -> Result<Vnode, FsError> {
+        self.resolve_at(None)
+    }
+
+    /// Resolves the full path to a vnode, starting a relative path at directory `start` instead
+    /// of the current directory if given.
+    pub fn resolve_at(&self, start: Option<&Vnode>) -> Result<Vnode, FsError> {
+        log!(self.resolve_inner(start, false).map(|(vnode, _)| vnode))
+    }
+
+    /// Resolves to the parent directory, returning (parent, final_name), starting a relative path
+    /// at directory `start` instead of the current directory if given.
+    pub fn resolve_parent_at(&self, start: Option<&Vnode>) -> Result<(Vnode, &'a str), FsError> {
+        log!(self.resolve_inner(start, true))
+    }
+}
+
+/// Returns the root directory set by `chroot` for the current process, without taking a
+/// reference, or None if it uses the root of the root file system.
+fn proc_root() -> Option<Vnode> {
+    proc::current_proc_opt().and_then(|proc| proc.data().root.clone())
+}
+
+/// Returns the absolute path of directory `dir`, as seen from the root directory of the current
+/// process, by looking up `..` until reaching it and the name of each directory in its parent.
+/// Must be called inside a transaction.
+pub fn path_of(dir: &Vnode) -> Result<String, FsError> {
+    let root = proc_root();
+
+    let mut names = Vec::new();
+    let mut vnode = dir.dup();
+
+    // a directory outside of the process root ends up at the root of the root file system
+    while !root.as_ref().is_some_and(|root| root.is(&vnode)) && !mount::is_system_root(&vnode) {
+        // a mounted file system is named in the directory it is mounted on
+        let child = mount::leave(vnode);
+
+        let parent = match log!(child.lookup("..")) {
+            Ok(Some(parent)) => parent,
+            Ok(None) => {
+                child.put();
+                err!(FsError::Resolve);
+            }
+            Err(e) => {
+                child.put();
+                return Err(e);
+            }
+        };
+
+        let name = log!(name_in(&parent, &child));
+        child.put();
+
+        match name {
+            Ok(name) => names.push(name),
+            Err(e) => {
+                parent.put();
+                return Err(e);
+            }
+        }
+
+        vnode = parent;
+    }
+
+    vnode.put();
+
+    if names.is_empty() {
+        return Ok(String::from("/"));
+    }
+
+    let mut path = String::new();
+    for name in names.iter().rev() {
+        path.push('/');
+        path.push_str(name);
+    }
+
+    Ok(path)
+}
+
+/// Returns the name of `child` in directory `dir`.
+fn name_in(dir: &Vnode, child: &Vnode) -> Result<String, FsError> {
+    let mut offset = 0;
+
+    while let Some((entry, next)) = try_log!(dir.readdir(offset)) {
+        if entry.ino == child.ino() && entry.name != "." && entry.name != ".." {
+            return Ok(entry.name);
+        }
+        offset = next;
+    }
+
+    err!(FsError::Resolve);
+}
@@ -10,11 +10,13 @@ use core::ptr;
 
 use crate::buf::{BCACHE, Buf};
 use crate::fs::BSIZE;
-use crate::memlayout::VIRTIO0;
+use crate::memlayout::{VIRTIO0, VIRTIO1, VIRTIO2};
+use crate::param::{NDISK, ROOTDEV};
 use crate::proc::{self, Channel};
 use crate::spinlock::SpinLock;
 
-// virtio mmio control registers, mapped starting at 0x10001000.
+// virtio mmio control registers, mapped starting at 0x10001000 for the first disk and 0x10002000
+// for the second.
 // from qemu virtio_mmio.h
 const VIRTIO_MMIO_MAGIC_VALUE: u32 = 0x000; // 0x74726976
 const VIRTIO_MMIO_VERSION: u32 = 0x004; // version; should be 2
@@ -98,7 +100,12 @@ struct VirtqUsedElem {
     len: u32,
 }
 
-pub static VIRTIO_DISK: SpinLock<Disk> = SpinLock::new(Disk::new(), "virtio_disk");
+/// Disk `i` has device number `i + 1`
+pub static VIRTIO_DISK: [SpinLock<Disk>; NDISK] = [
+    SpinLock::new(Disk::new(VIRTIO0), "virtio_disk0"),
+    SpinLock::new(Disk::new(VIRTIO1), "virtio_disk1"),
+    SpinLock::new(Disk::new(VIRTIO2), "virtio_disk2"),
+];
 
 #[repr(C)]
 #[derive(Debug, Clone)]
@@ -115,7 +122,7 @@ const VIRTIO_BLK_T_IN: u32 = 0; // read the disk
 const VIRTIO_BLK_T_OUT: u32 = 1; // write the disk
 
 /// The format of the first descriptor in a disk request.
-/// To be followed by two more descriptors containing the block, and a one-byte status.
+/// To be followed by a descriptor for each block, and a one-byte status.
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
 struct BlockReq {
@@ -129,6 +136,8 @@ struct BlockReq {
 struct Info {
     buf_id: usize,
     status: u8,
+    /// No one waits for this request, so `handle_interrupt()` finishes it
+    read_ahead: bool,
 }
 
 #[derive(Debug)]
@@ -152,15 +161,20 @@ pub struct Disk {
     used_idx: u16,
 
     /// Track info about in-flight operations, for use when completion interrupt arrives.
-    /// Indexed by first descriptor index of chain.
+    /// Indexed by first descriptor index of chain, except that the buffers of a read-ahead are
+    /// recorded at the descriptors of their data.
     info: [Info; NUM],
 
     ops: [BlockReq; NUM],
+
+    /// Base address of the mmio registers
+    base: usize,
+    /// The device was found and initialized
+    present: bool,
 }
 
 impl Disk {
-    #[allow(clippy::new_without_default)]
-    pub const fn new() -> Self {
+    pub const fn new(base: usize) -> Self {
         Disk {
             desc: [VirtqDesc {
                 addr: 0,
@@ -184,25 +198,28 @@ impl Disk {
             info: [Info {
                 buf_id: usize::MAX,
                 status: 0,
+                read_ahead: false,
             }; NUM],
             ops: [BlockReq {
                 r#type: 0,
                 reserved: 0,
                 sector: 0,
             }; NUM],
+            base,
+            present: false,
         }
     }
 
     /// Reads a 4 bytes from the given VIRTIO register.
     fn read(&self, reg: u32) -> u32 {
         // Safety: reading from memory-mapped VIRTIO register
-        unsafe { ptr::read_volatile((VIRTIO0 + reg as usize) as *const u32) }
+        unsafe { ptr::read_volatile((self.base + reg as usize) as *const u32) }
     }
 
-    /// Writes 4 bytes to the given VIRTIO0 register.
+    /// Writes 4 bytes to the given VIRTIO register.
     unsafe fn write(&mut self, reg: u32, value: u32) {
         // Safety: writing to memory-mapped UART register
-        unsafe { ptr::write_volatile((VIRTIO0 + reg as usize) as *mut u32, value) }
+        unsafe { ptr::write_volatile((self.base + reg as usize) as *mut u32, value) }
     }
 
     /// Finds a free descriptor, marks it non-free and returns its index.
@@ -248,44 +265,202 @@ impl Disk {
         }
     }
 
-    /// Allocates three descriptors (the need not be contiguous).
-    /// Disk transfers always use three descriptors.
-    fn alloc3_desc(&mut self) -> Result<[usize; 3], VirtioError> {
-        let mut result = [None; 3];
-
-        for i in 0..3 {
+    /// Allocates a descriptor for each of `ids` (they need not be contiguous), or none at all.
+    fn alloc_descs(&mut self, ids: &mut [usize]) -> Result<(), VirtioError> {
+        for i in 0..ids.len() {
             match log!(self.alloc_desc()) {
-                Ok(alloc) => {
-                    result[i] = Some(alloc);
-                }
+                Ok(alloc) => ids[i] = alloc,
                 Err(e) => {
-                    result.iter_mut().take(i).for_each(|j| {
-                        if let Some(idx) = j {
-                            self.free_desc(*idx);
-                        }
-                    });
+                    ids[..i].iter().for_each(|&j| self.free_desc(j));
                     return Err(e);
                 }
-            };
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Allocates three descriptors (the need not be contiguous).
+    /// Disk transfers of a single block use three descriptors.
+    fn alloc3_desc(&mut self) -> Result<[usize; 3], VirtioError> {
+        let mut ids = [0; 3];
+        try_log!(self.alloc_descs(&mut ids));
+        Ok(ids)
+    }
+
+    /// Formats the descriptors `ids` to transfer consecutive blocks from `block_no` on to or from
+    /// the buffers at `data`, one per block, and tells the device about them. `ids` holds the
+    /// header, one descriptor per buffer and the status, and the caller records the buffers in
+    /// `info`.
+    fn submit(&mut self, ids: &[usize], data: &[u64], block_no: u32, write: bool) {
+        assert_eq!(ids.len(), data.len() + 2, "virtio submit");
+        let sector = block_no as u64 * (BSIZE as u64 / 512);
+
+        // the spec's Section 5.2 says that legacy block operations use
+        // three descriptors: one for type/reserved/sector, one for the
+        // data, one for a 1-byte status result. the data may be split
+        // over several descriptors.
+
+        // format the descriptors
+        // qemu's virtio-blk.c reads them
+        let head = ids[0];
+        let status = ids[ids.len() - 1];
+        let buf0 = &mut self.ops[head];
+
+        buf0.r#type = if write {
+            VIRTIO_BLK_T_OUT
+        } else {
+            VIRTIO_BLK_T_IN
+        };
+        buf0.reserved = 0;
+        buf0.sector = sector;
+
+        self.desc[head].addr = buf0 as *const _ as u64;
+        self.desc[head].len = size_of::<BlockReq>() as u32;
+        self.desc[head].flags = VRING_DESC_F_NEXT;
+        self.desc[head].next = ids[1] as u16;
+
+        for (i, &addr) in data.iter().enumerate() {
+            let id = ids[i + 1];
+            self.desc[id].addr = addr;
+            self.desc[id].len = BSIZE as u32;
+            self.desc[id].flags = if write { 0 } else { VRING_DESC_F_WRITE };
+            self.desc[id].flags |= VRING_DESC_F_NEXT;
+            self.desc[id].next = ids[i + 2] as u16;
         }
 
-        Ok(result.map(|i| i.unwrap()))
+        self.info[head].status = 0xFF; // device writes 0 on success
+        self.desc[status].addr = &self.info[head].status as *const _ as u64;
+        self.desc[status].len = 1;
+        self.desc[status].flags = VRING_DESC_F_WRITE; //device writes the status
+        self.desc[status].next = 0;
+
+        // tell the device the first index in our chain of descriptors
+        let avail_index = self.avail.idx as usize % NUM;
+        self.avail.ring[avail_index] = ids[0] as u16;
+
+        // tell the device another avail ring entry is available
+        self.avail.idx += 1;
+
+        // value 0 is the queue number
+        unsafe { self.write(VIRTIO_MMIO_QUEUE_NOTIFY, 0) };
+    }
+
+    /// Checks that a virtio disk is attached at the registers.
+    fn probe(&self) -> bool {
+        self.read(VIRTIO_MMIO_MAGIC_VALUE) == 0x74726976
+            && self.read(VIRTIO_MMIO_VERSION) == 2
+            && self.read(VIRTIO_MMIO_DEVICE_ID) == 2
+            && self.read(VIRTIO_MMIO_VENDOR_ID) == 0x554d4551
     }
+
+    /// Negotiates features with the device and sets up queue 0.
+    ///
+    /// # Safety
+    /// This function writes to memory-mapped I/O registers, which must belong to a virtio disk.
+    unsafe fn setup(&mut self) {
+        unsafe {
+            let mut status = 0;
+
+            // reset device
+            self.write(VIRTIO_MMIO_STATUS, status);
+
+            // set ACKNOWLEDGE status bit
+            status |= VIRTIO_CONFIG_S_ACKNOWLEDGE;
+            self.write(VIRTIO_MMIO_STATUS, status);
+
+            // set DRIVER status bit
+            status |= VIRTIO_CONFIG_S_DRIVER;
+            self.write(VIRTIO_MMIO_STATUS, status);
+
+            // negotiate features
+            let mut features = self.read(VIRTIO_MMIO_DEVICE_FEATURES);
+            features &= !(1 << VIRTIO_BLK_F_RO);
+            features &= !(1 << VIRTIO_BLK_F_SCSI);
+            features &= !(1 << VIRTIO_BLK_F_CONFIG_WCE);
+            features &= !(1 << VIRTIO_BLK_F_MQ);
+            features &= !(1 << VIRTIO_F_ANY_LAYOUT);
+            features &= !(1 << VIRTIO_RING_F_EVENT_IDX);
+            features &= !(1 << VIRTIO_RING_F_INDIRECT_DESC);
+            self.write(VIRTIO_MMIO_DRIVER_FEATURES, features);
+
+            // tell device that feature negotiation is complete
+            status |= VIRTIO_CONFIG_S_FEATURES_OK;
+            self.write(VIRTIO_MMIO_STATUS, status);
+
+            // re-read status to ensure FEATURES_OK is set
+            status = self.read(VIRTIO_MMIO_STATUS);
+            assert_ne!(
+                status & VIRTIO_CONFIG_S_FEATURES_OK,
+                0,
+                "virtio disk features negotiation failed"
+            );
+
+            // initialize queue 0
+            self.write(VIRTIO_MMIO_QUEUE_SEL, 0);
+
+            // ensure queue 0 is not in use
+            assert_eq!(
+                self.read(VIRTIO_MMIO_QUEUE_READY),
+                0,
+                "virtio disk queue 0 in use"
+            );
+
+            // check maximum queue size
+            let max = self.read(VIRTIO_MMIO_QUEUE_NUM_MAX);
+            assert_ne!(max, 0, "virtio disk has no queue 0");
+            assert!(max as usize > NUM, "virito disk max queue too short");
+
+            // set queue size
+            self.write(VIRTIO_MMIO_QUEUE_NUM, NUM.try_into().unwrap());
+
+            // write physical addresses
+            let desc_addr = &self.desc as *const _ as usize;
+            self.write(VIRTIO_MMIO_QUEUE_DESC_LOW, desc_addr as u32);
+            self.write(VIRTIO_MMIO_QUEUE_DESC_HIGH, (desc_addr >> 32) as u32);
+
+            let avail_addr = &self.avail as *const _ as usize;
+            self.write(VIRTIO_MMIO_DRIVER_DESC_LOW, avail_addr as u32);
+            self.write(VIRTIO_MMIO_DRIVER_DESC_HIGH, (avail_addr >> 32) as u32);
+
+            let used_addr = &self.used as *const _ as usize;
+            self.write(VIRTIO_MMIO_DEVICE_DESC_LOW, used_addr as u32);
+            self.write(VIRTIO_MMIO_DEVICE_DESC_HIGH, (used_addr >> 32) as u32);
+
+            // queue is ready
+            self.write(VIRTIO_MMIO_QUEUE_READY, 1);
+
+            // tell device we are completely ready
+            status |= VIRTIO_CONFIG_S_DRIVER_OK;
+            self.write(VIRTIO_MMIO_STATUS, status);
+        }
+
+        self.present = true;
+    }
+}
+
+/// Returns the disk with device number `dev`.
+fn disk(dev: u32) -> &'static SpinLock<Disk> {
+    assert!(
+        dev >= 1 && dev as usize <= NDISK,
+        "virtio disk: bad device {}",
+        dev
+    );
+    &VIRTIO_DISK[dev as usize - 1]
+}
+
+/// Returns whether there is a disk with device number `dev`.
+pub fn present(dev: u32) -> bool {
+    dev >= 1 && dev as usize <= NDISK && disk(dev).lock().present
 }
 
 pub fn rw(buf: &mut Buf<'_>, write: bool) {
-    let block_no = {
+    let (dev, block_no) = {
         let inner = BCACHE.inner.lock();
-        inner.meta[buf.id].block_no
+        (inner.meta[buf.id].dev, inner.meta[buf.id].block_no)
     };
 
-    let sector = block_no as u64 * (BSIZE as u64 / 512);
-
-    let mut disk = VIRTIO_DISK.lock();
-
-    // the spec's Section 5.2 says that legacy block operations use
-    // three descriptors: one for type/reserved/sector, one for the
-    // data, one for a 1-byte status result.
+    let mut disk = disk(dev).lock();
 
     // allocate the three descriptors
     let ids = loop {
@@ -296,51 +471,14 @@ pub fn rw(buf: &mut Buf<'_>, write: bool) {
         disk = proc::sleep(Channel::Buffer(&disk.free[0] as *const _ as usize), disk);
     };
 
-    // format the three descriptors
-    // qemu's virtio-blk.c reads them
-    let buf0 = &mut disk.ops[ids[0]];
-
-    buf0.r#type = if write {
-        VIRTIO_BLK_T_OUT
-    } else {
-        VIRTIO_BLK_T_IN
-    };
-    buf0.reserved = 0;
-    buf0.sector = sector;
-
-    disk.desc[ids[0]].addr = buf0 as *const _ as u64;
-    disk.desc[ids[0]].len = size_of::<BlockReq>() as u32;
-    disk.desc[ids[0]].flags = VRING_DESC_F_NEXT;
-    disk.desc[ids[0]].next = ids[1] as u16;
-
-    disk.desc[ids[1]].addr = buf.data().as_ptr() as u64;
-    disk.desc[ids[1]].len = BSIZE as u32;
-    disk.desc[ids[1]].flags = if write { 0 } else { VRING_DESC_F_WRITE };
-    disk.desc[ids[1]].flags |= VRING_DESC_F_NEXT;
-    disk.desc[ids[1]].next = ids[2] as u16;
-
-    disk.info[ids[0]].status = 0xFF; // device writes 0 on success
-    disk.desc[ids[2]].addr = &disk.info[ids[0]].status as *const _ as u64;
-    disk.desc[ids[2]].len = 1;
-    disk.desc[ids[2]].flags = VRING_DESC_F_WRITE; //device writes the status
-    disk.desc[ids[2]].next = 0;
-
-    // record struct buf for `handle_interrupt()`
-    disk.info[ids[0]].buf_id = buf.id;
     {
         let mut inner = BCACHE.inner.lock();
         inner.meta[buf.id].disk = true;
     }
 
-    // tell the device the first index in our chain of descriptors
-    let avail_index = disk.avail.idx as usize % NUM;
-    disk.avail.ring[avail_index] = ids[0] as u16;
-
-    // tell the device another avail ring entry is available
-    disk.avail.idx += 1;
-
-    // value 0 is the queue number
-    unsafe { disk.write(VIRTIO_MMIO_QUEUE_NOTIFY, 0) };
+    // record struct buf for `handle_interrupt()`
+    disk.info[ids[0]].buf_id = buf.id;
+    disk.submit(&ids, &[buf.data().as_ptr() as u64], block_no, write);
 
     // wait for `handle_interrupt()` to say request has finished
     loop {
@@ -360,9 +498,56 @@ pub fn rw(buf: &mut Buf<'_>, write: bool) {
     disk.free_chain(ids[0]);
 }
 
-/// Handles a disk interrupt.
-pub fn handle_interrupt() {
-    let mut disk = VIRTIO_DISK.lock();
+/// Starts reading up to `count` consecutive blocks from `block_no` on of device `dev` into the
+/// buffer cache without waiting, with a single request. Stops short at a block that is already
+/// cached, and reads fewer blocks or none if descriptors are short.
+pub fn read_ahead(dev: u32, block_no: u32, count: usize) {
+    let mut disk = disk(dev).lock();
+
+    // a read-ahead is only a hint, so do not wait for descriptors
+    let free = disk.free.iter().filter(|free| **free).count();
+    let count = count.min(free.saturating_sub(2));
+    if count == 0 {
+        return;
+    }
+
+    // header, a descriptor per block, status
+    let mut ids = [0; NUM];
+    if log!(disk.alloc_descs(&mut ids[..count + 2])).is_err() {
+        return;
+    }
+
+    let mut data = [0; NUM];
+    let mut n = 0;
+    while n < count {
+        let Some((buf_id, addr)) = BCACHE.claim(dev, block_no + n as u32) else {
+            break;
+        };
+
+        disk.info[ids[n + 1]].buf_id = buf_id;
+        data[n] = addr as u64;
+        n += 1;
+    }
+
+    // the blocks from the first cached one on are not read
+    ids[n + 1..count + 1]
+        .iter()
+        .for_each(|&i| disk.free_desc(i));
+    ids[n + 1] = ids[count + 1];
+
+    if n == 0 {
+        disk.free_desc(ids[0]);
+        disk.free_desc(ids[1]);
+        return;
+    }
+
+    disk.info[ids[0]].read_ahead = true;
+    disk.submit(&ids[..n + 2], &data[..n], block_no, false);
+}
+
+/// Handles an interrupt of the disk with device number `dev`.
+pub fn handle_interrupt(dev: u32) {
+    let mut disk = disk(dev).lock();
 
     // the device won't raise another interrupt until we tell it
     // we've seen this interrupt, which the following line does.
@@ -375,107 +560,52 @@ pub fn handle_interrupt() {
 
     // the device increments disk.used->idx when it adds an entry to the used ring.
     while disk.used_idx != disk.used.idx {
-        let id = disk.used.ring[disk.used_idx as usize % NUM].id;
-        let buf_id = disk.info[id as usize].buf_id;
+        let id = disk.used.ring[disk.used_idx as usize % NUM].id as usize;
+        if disk.info[id].read_ahead {
+            // no one waits for a read-ahead, so finish each of its buffers here
+            let valid = disk.info[id].status == 0;
+            let mut i = disk.desc[id].next as usize;
+
+            while disk.desc[i].flags & VRING_DESC_F_NEXT != 0 {
+                let buf_id = disk.info[i].buf_id;
+                {
+                    let mut inner = BCACHE.inner.lock();
+                    inner.meta[buf_id].disk = false;
+                    inner.meta[buf_id].valid = valid;
+                    inner.meta[buf_id].ref_count -= 1;
+                }
 
-        {
-            let mut inner = BCACHE.inner.lock();
-            inner.meta[buf_id].disk = false;
-        }
+                proc::wakeup(Channel::Buffer(buf_id));
+                disk.info[i].buf_id = usize::MAX;
+                i = disk.desc[i].next as usize;
+            }
 
-        proc::wakeup(Channel::Buffer(buf_id));
+            disk.info[id].read_ahead = false;
+            disk.free_chain(id);
+        } else {
+            let buf_id = disk.info[id].buf_id;
+            BCACHE.inner.lock().meta[buf_id].disk = false;
+            proc::wakeup(Channel::Buffer(buf_id));
+        }
 
         disk.used_idx += 1;
     }
 }
 
 /// Initializes the virtio disk driver.
+/// Disks other than the root disk are optional.
 ///
 /// # Safety
 /// This function performs raw pointer dereferencing to access memory-mapped I/O registers.
 pub unsafe fn init() {
-    unsafe {
-        let mut disk = VIRTIO_DISK.lock();
-        let mut status = 0;
-
-        assert!(
-            disk.read(VIRTIO_MMIO_MAGIC_VALUE) == 0x74726976
-                && disk.read(VIRTIO_MMIO_VERSION) == 2
-                && disk.read(VIRTIO_MMIO_DEVICE_ID) == 2
-                && disk.read(VIRTIO_MMIO_VENDOR_ID) == 0x554d4551,
-            "could not find virtio disk"
-        );
-
-        // reset device
-        disk.write(VIRTIO_MMIO_STATUS, status);
-
-        // set ACKNOWLEDGE status bit
-        status |= VIRTIO_CONFIG_S_ACKNOWLEDGE;
-        disk.write(VIRTIO_MMIO_STATUS, status);
-
-        // set DRIVER status bit
-        status |= VIRTIO_CONFIG_S_DRIVER;
-        disk.write(VIRTIO_MMIO_STATUS, status);
-
-        // negotiate features
-        let mut features = disk.read(VIRTIO_MMIO_DEVICE_FEATURES);
-        features &= !(1 << VIRTIO_BLK_F_RO);
-        features &= !(1 << VIRTIO_BLK_F_SCSI);
-        features &= !(1 << VIRTIO_BLK_F_CONFIG_WCE);
-        features &= !(1 << VIRTIO_BLK_F_MQ);
-        features &= !(1 << VIRTIO_F_ANY_LAYOUT);
-        features &= !(1 << VIRTIO_RING_F_EVENT_IDX);
-        features &= !(1 << VIRTIO_RING_F_INDIRECT_DESC);
-        disk.write(VIRTIO_MMIO_DRIVER_FEATURES, features);
-
-        // tell device that feature negotiation is complete
-        status |= VIRTIO_CONFIG_S_FEATURES_OK;
-        disk.write(VIRTIO_MMIO_STATUS, status);
-
-        // re-read status to ensure FEATURES_OK is set
-        status = disk.read(VIRTIO_MMIO_STATUS);
-        assert_ne!(
-            status & VIRTIO_CONFIG_S_FEATURES_OK,
-            0,
-            "virtio disk features negotiation failed"
-        );
-
-        // initialize queue 0
-        disk.write(VIRTIO_MMIO_QUEUE_SEL, 0);
-
-        // ensure queue 0 is not in use
-        assert_eq!(
-            disk.read(VIRTIO_MMIO_QUEUE_READY),
-            0,
-            "virtio disk queue 0 in use"
-        );
-
-        // check maximum queue size
-        let max = disk.read(VIRTIO_MMIO_QUEUE_NUM_MAX);
-        assert_ne!(max, 0, "virtio disk has no queue 0");
-        assert!(max as usize > NUM, "virito disk max queue too short");
-
-        // set queue size
-        disk.write(VIRTIO_MMIO_QUEUE_NUM, NUM.try_into().unwrap());
-
-        // write physical addresses
-        let desc_addr = &disk.desc as *const _ as usize;
-        disk.write(VIRTIO_MMIO_QUEUE_DESC_LOW, desc_addr as u32);
-        disk.write(VIRTIO_MMIO_QUEUE_DESC_HIGH, (desc_addr >> 32) as u32);
-
-        let avail_addr = &disk.avail as *const _ as usize;
-        disk.write(VIRTIO_MMIO_DRIVER_DESC_LOW, avail_addr as u32);
-        disk.write(VIRTIO_MMIO_DRIVER_DESC_HIGH, (avail_addr >> 32) as u32);
-
-        let used_addr = &disk.used as *const _ as usize;
-        disk.write(VIRTIO_MMIO_DEVICE_DESC_LOW, used_addr as u32);
-        disk.write(VIRTIO_MMIO_DEVICE_DESC_HIGH, (used_addr >> 32) as u32);
-
-        // queue is ready
-        disk.write(VIRTIO_MMIO_QUEUE_READY, 1);
-
-        // tell device we are completely ready
-        status |= VIRTIO_CONFIG_S_DRIVER_OK;
-        disk.write(VIRTIO_MMIO_STATUS, status);
+    for (i, disk) in VIRTIO_DISK.iter().enumerate() {
+        let mut disk = disk.lock();
+
+        if !disk.probe() {
+            assert_ne!(i + 1, ROOTDEV as usize, "could not find virtio disk");
+            continue;
+        }
+
+        unsafe { disk.setup() };
     }
 }
@@ -4,17 +4,18 @@ use core::fmt::Display;
 use core::mem::MaybeUninit;
 use core::ptr::{self, NonNull};
 
-use crate::fs::{Inode, InodeInner};
-use crate::memlayout::{KERNBASE, PHYSTOP, PLIC, TRAMPOLINE, TRAPFRAME, UART0, VIRTIO0};
+use crate::memlayout::{
+    KERNBASE, PHYSTOP, PLIC, TRAMPOLINE, TRAPFRAME, UART0, VIRTIO0, VIRTIO1, VIRTIO2,
+};
 use crate::proc::{self, PROC_TABLE};
 use crate::riscv::{
     MAXVA, PGSIZE, PTE_R, PTE_U, PTE_V, PTE_W, PTE_X, pa_to_pte, pg_round_down, pg_round_up,
     pte_flags, pte_to_pa, px,
     registers::{satp, vma},
 };
-use crate::sleeplock::SleepLockGuard;
 use crate::sync::OnceLock;
 use crate::trampoline::trampoline;
+use crate::vfs::Vnode;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum VmError {
@@ -364,6 +365,29 @@ impl PageTable {
         }
     }
 
+    /// Returns the flags of the PTE for virtual address `va`, or None if it is not mapped.
+    /// Unlike `walk`, it never allocates.
+    fn flags(&self, va: VA) -> Option<usize> {
+        if va >= MAXVA {
+            return None;
+        }
+
+        let mut pagetable = self.ptr;
+
+        unsafe {
+            for level in (1..=2).rev() {
+                let pte = pagetable.as_ref()[va.px(level)];
+                if !pte.is_v() {
+                    return None;
+                }
+                pagetable = NonNull::new(pte.as_pa().as_mut_ptr()).unwrap();
+            }
+
+            let pte = pagetable.as_ref()[va.px(0)];
+            pte.is_v().then(|| pte.flags())
+        }
+    }
+
     /// Looks up a virtual address, return the physical address, or Error if not mapped.
     ///
     /// Can only be used to look up user pages.
@@ -436,8 +460,7 @@ impl PageTable {
 
     pub fn load_elf_segment(
         &mut self,
-        inode: &mut Inode,
-        inner: &mut SleepLockGuard<'_, InodeInner>,
+        vnode: &Vnode,
         va: VA,
         offset: u32,
         size: usize,
@@ -453,7 +476,7 @@ impl PageTable {
             }
 
             let dst = unsafe { core::slice::from_raw_parts_mut(pa.as_usize() as *mut u8, n) };
-            match log!(inode.read(inner, offset + i as u32, dst, false)) {
+            match log!(vnode.read(offset + i as u32, dst, false)) {
                 Ok(read) if read as usize == dst.len() => {}
                 _ => err!(VmError::Fs),
             }
@@ -499,8 +522,10 @@ impl Kvm {
         // uart registers
         self.map(VA::from(UART0), PA::from(UART0), PGSIZE, PTE_R | PTE_W);
 
-        // virtio mmio disk interface
+        // virtio mmio disk interfaces
         self.map(VA::from(VIRTIO0), PA::from(VIRTIO0), PGSIZE, PTE_R | PTE_W);
+        self.map(VA::from(VIRTIO1), PA::from(VIRTIO1), PGSIZE, PTE_R | PTE_W);
+        self.map(VA::from(VIRTIO2), PA::from(VIRTIO2), PGSIZE, PTE_R | PTE_W);
 
         // PLIC
         self.map(VA::from(PLIC), PA::from(PLIC), 0x400_0000, PTE_R | PTE_W);
@@ -692,6 +717,11 @@ impl Uvm {
         Ok(())
     }
 
+    /// Returns the PTE flags of the page at `va`, or None if it is not mapped.
+    pub fn page_flags(&self, va: VA) -> Option<usize> {
+        self.0.flags(va)
+    }
+
     /// Marks a PTE invalid for user access.
     ///
     /// Used by `exec()` for the user stack guard page.
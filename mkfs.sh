@@ -3,5 +3,20 @@
 cargo build --release --package user
 
 pushd mkfs
-cargo run --release --target "$(rustc -vV | grep host | cut -d' ' -f2)" -- ../target/fs.img $(ls ../user/bin/*.rs | sed 's|../user/bin/\(.*\)\.rs|../target/riscv64gc-unknown-none-elf/release/\1|') ../LICENSE
+cargo run --release --target "$(rustc -vV | grep host | cut -d' ' -f2)" -- -d devices.txt ../target/fs.img $(ls ../user/bin/*.rs | sed 's|../user/bin/\(.*\)\.rs|../target/riscv64gc-unknown-none-elf/release/\1|') ../LICENSE
+
+# the second disk, mounted on /mnt, keeps its contents across rebuilds
+if [ ! -f ../target/mnt.img ]; then
+    cargo run --release --target "$(rustc -vV | grep host | cut -d' ' -f2)" -- ../target/mnt.img
+fi
+
+# the third disk, mounted on /fat, is a FAT32 image for exchanging files with the host
+if [ ! -f ../target/fat.img ]; then
+    if command -v mformat > /dev/null; then
+        mformat -C -F -T 131072 -i ../target/fat.img ::
+    else
+        echo "mformat not found, leaving target/fat.img blank"
+        truncate -s 64M ../target/fat.img
+    fi
+fi
 popd
@@ -0,0 +1,785 @@
+// Checks an octopos file system image and optionally repairs it.
+//
+// Passes:
+// 1. superblock layout
+// 2. log replay of a committed transaction, as the kernel does at boot
+// 3. inode types and block claims (out of range, claimed twice)
+// 4. directory tree from the root: records, `.` and `..`, the hash index
+// 5. link counts, orphaned and unreferenced inodes
+// 6. free bitmap against the claimed blocks
+//
+// On an image with checksums, each pass also checks the checksums of the metadata blocks it reads,
+// and repairs store new checksums in every metadata block once all passes are done.
+//
+// Exit status follows e2fsck: 0 clean, 1 errors corrected, 4 errors left uncorrected,
+// 8 operational error.
+
+use std::collections::VecDeque;
+use std::env::args;
+use std::fmt::Display;
+use std::fs::{File, OpenOptions};
+use std::io::Read;
+use std::os::unix::fs::FileExt;
+use std::process::exit;
+
+use mkfs::{
+    BPB, BSIZE, DXMAGIC, Directory, DiskInode, DxEntry, DxRoot, FEATURE_CSUM, FSMAGIC, IPB,
+    InodeType, MAXFILE, MAXLOGBLOCKS, NDIRECT, ROOTINO, SuperBlock, block_checksum, seal,
+    stored_checksum,
+};
+
+/// In-memory copy of an image being checked. Only written back when repairing.
+struct Image {
+    data: Vec<u8>,
+    sb: SuperBlock,
+    repair: bool,
+    /// Image was modified in memory
+    dirty: bool,
+    errors: usize,
+    fixed: usize,
+    /// Inode claiming each block, 0 if none
+    owner: Vec<u32>,
+    /// Directory entries referring to each inode, not counting `.`
+    refs: Vec<u32>,
+    /// Blocks of the directories found in the tree
+    dir_blocks: Vec<u32>,
+}
+
+impl Image {
+    /// Reports a problem. Returns whether the caller should fix it.
+    fn error(&mut self, msg: impl Display) -> bool {
+        self.errors += 1;
+
+        if self.repair {
+            self.fixed += 1;
+            println!("{msg}: fixed");
+        } else {
+            println!("{msg}");
+        }
+
+        self.repair
+    }
+
+    /// Reports a problem fsck does not know how to fix.
+    fn unfixable(&mut self, msg: impl Display) {
+        self.errors += 1;
+        println!("{msg}: not fixed");
+    }
+
+    fn block(&self, block: u32) -> &[u8] {
+        let start = (block * BSIZE) as usize;
+        &self.data[start..start + BSIZE as usize]
+    }
+
+    fn block_mut(&mut self, block: u32) -> &mut [u8] {
+        self.dirty = true;
+        let start = (block * BSIZE) as usize;
+        &mut self.data[start..start + BSIZE as usize]
+    }
+
+    /// Returns the first data block; everything before it is metadata.
+    fn data_start(&self) -> u32 {
+        self.sb.size - self.sb.nblocks
+    }
+
+    fn is_data(&self, block: u32) -> bool {
+        block >= self.data_start() && block < self.sb.size && !self.reserved_block(block)
+    }
+
+    /// Returns whether `block` is never used, since its bit overlaps a bitmap checksum.
+    fn reserved_block(&self, block: u32) -> bool {
+        block % BPB >= self.sb.space() as u32 * 8
+    }
+
+    /// Checks the checksum of metadata block `block`, if the image keeps them. Repairs store a
+    /// new one at the end, after the contents are fixed.
+    fn check_checksum(&mut self, block: u32, what: impl Display) {
+        let buf = self.block(block);
+        if self.sb.checksummed()
+            && stored_checksum(buf) != block_checksum(buf)
+            && self.error(format_args!("{what}: bad checksum in block {block}"))
+        {
+            self.dirty = true;
+        }
+    }
+
+    /// Stores the checksum of every metadata block.
+    fn seal_all(&mut self) {
+        let inode_blocks = self.sb.inodestart..self.sb.bmapstart;
+        let bitmap_blocks = self.sb.bmapstart..self.data_start();
+
+        let blocks = inode_blocks
+            .chain(bitmap_blocks)
+            .chain(self.dir_blocks.clone())
+            .chain([self.sb.logstart]);
+
+        for block in blocks {
+            seal(self.block_mut(block));
+        }
+    }
+
+    fn inode(&self, inum: u32) -> DiskInode {
+        let buf = self.block(self.sb.inodestart + inum / IPB);
+        let offset = (inum % IPB) as usize * size_of::<DiskInode>();
+        *bytemuck::from_bytes(&buf[offset..offset + size_of::<DiskInode>()])
+    }
+
+    fn set_inode(&mut self, inum: u32, din: &DiskInode) {
+        let block = self.sb.inodestart + inum / IPB;
+        let offset = (inum % IPB) as usize * size_of::<DiskInode>();
+        self.block_mut(block)[offset..offset + size_of::<DiskInode>()]
+            .copy_from_slice(bytemuck::bytes_of(din));
+    }
+
+    /// Returns the address of block `bn` of an inode, 0 if it is not mapped.
+    fn bmap(&self, din: &DiskInode, bn: u32) -> u32 {
+        if bn < NDIRECT {
+            return din.addrs[bn as usize];
+        }
+
+        let indirect = din.addrs[NDIRECT as usize];
+        if bn >= MAXFILE || !self.is_data(indirect) {
+            return 0;
+        }
+
+        self.indirect(indirect)[(bn - NDIRECT) as usize]
+    }
+
+    fn indirect(&self, block: u32) -> &[u32] {
+        bytemuck::cast_slice(self.block(block))
+    }
+
+    fn orphans(&self) -> Vec<u32> {
+        let slots: &[u32] = bytemuck::cast_slice(self.block(self.sb.orphan));
+        slots.iter().copied().filter(|inum| *inum != 0).collect()
+    }
+
+    /// Pass 1: the superblock must describe the layout mkfs writes.
+    fn check_superblock(&self, len: usize) -> Result<(), String> {
+        let sb = &self.sb;
+
+        if sb.magic != FSMAGIC {
+            return Err(format!("bad magic {:#x}", sb.magic));
+        }
+
+        if sb.features & !FEATURE_CSUM != 0 {
+            return Err(format!("unknown features {:#x}", sb.features));
+        }
+
+        if sb.checksummed() && sb.checksum != sb.compute_checksum() {
+            return Err("bad checksum".to_string());
+        }
+
+        if len < (sb.size * BSIZE) as usize {
+            return Err(format!("image is smaller than {} blocks", sb.size));
+        }
+
+        let ninodeblocks = sb.ninodes / IPB + 1;
+        let nbitmap = sb.size / BPB + 1;
+
+        if sb.logstart != 2
+            || sb.nlogs < 2
+            || sb.orphan != sb.logstart + sb.nlogs
+            || sb.inodestart != sb.orphan + 1
+            || sb.bmapstart != sb.inodestart + ninodeblocks
+            || sb.nblocks > sb.size
+            || sb.size - sb.nblocks != sb.bmapstart + nbitmap
+        {
+            return Err(format!("inconsistent layout {sb:?}"));
+        }
+
+        if sb.ninodes <= ROOTINO {
+            return Err(format!("bad inode count {}", sb.ninodes));
+        }
+
+        Ok(())
+    }
+
+    /// Pass 2: installs a committed transaction still in the log.
+    fn replay_log(&mut self) {
+        let buf = self.block(self.sb.logstart);
+        if self.sb.checksummed() && stored_checksum(buf) != block_checksum(buf) {
+            // the commit that wrote the header did not finish, so there is nothing to replay
+            if self.error("log: bad header checksum") {
+                self.block_mut(self.sb.logstart).fill(0);
+            }
+            return;
+        }
+
+        // header block: count of committed blocks, then their home block numbers
+        let header: &[u32] = bytemuck::cast_slice(self.block(self.sb.logstart));
+        let n = header[0];
+
+        if n == 0 {
+            return;
+        }
+
+        let capacity = (self.sb.nlogs - 1).min(MAXLOGBLOCKS);
+        let blocks = header[1..=n.min(MAXLOGBLOCKS) as usize].to_vec();
+        let valid = n <= capacity
+            && blocks
+                .iter()
+                .all(|block| *block >= self.sb.orphan && *block < self.sb.size);
+
+        if valid {
+            println!("log: replaying {n} committed blocks");
+
+            for (i, block) in blocks.iter().enumerate() {
+                let copy = self.block(self.sb.logstart + 1 + i as u32).to_vec();
+                self.block_mut(*block).copy_from_slice(&copy);
+            }
+        } else if !self.error(format_args!("log: corrupt header with {n} blocks")) {
+            return;
+        }
+
+        self.block_mut(self.sb.logstart)[..size_of::<u32>()].fill(0);
+    }
+
+    /// Pass 3: checks inode types and records which inode claims each block.
+    fn check_inodes(&mut self) {
+        // the inode area may end with a block holding no inodes, which is never sealed
+        let blocks = self.sb.ninodes.div_ceil(IPB);
+        for block in self.sb.inodestart..self.sb.inodestart + blocks {
+            self.check_checksum(block, "inodes");
+        }
+
+        for inum in 1..self.sb.ninodes {
+            if self.sb.reserved_inode(inum) {
+                continue;
+            }
+
+            let mut din = self.inode(inum);
+            let mut changed = false;
+
+            if din.r#type == InodeType::FREE {
+                continue;
+            }
+
+            if din.r#type.0 > InodeType::SOCKET.0 {
+                if self.error(format_args!("inode {inum}: bad type {}", din.r#type.0)) {
+                    self.set_inode(inum, &DiskInode::new(InodeType::FREE));
+                }
+                continue;
+            }
+
+            if din.size > MAXFILE * BSIZE
+                && self.error(format_args!("inode {inum}: size {} too large", din.size))
+            {
+                din.size = MAXFILE * BSIZE;
+                changed = true;
+            }
+
+            for addr in din.addrs.iter_mut().take(NDIRECT as usize) {
+                if *addr != 0 && !self.claim(inum, *addr) {
+                    *addr = 0;
+                    changed = true;
+                }
+            }
+
+            let indirect = din.addrs[NDIRECT as usize];
+            if indirect != 0 {
+                if !self.claim(inum, indirect) {
+                    din.addrs[NDIRECT as usize] = 0;
+                    changed = true;
+                } else if self.is_data(indirect) {
+                    let mut addrs = self.indirect(indirect).to_vec();
+                    let mut dropped = false;
+
+                    for addr in addrs.iter_mut().filter(|addr| **addr != 0) {
+                        if !self.claim(inum, *addr) {
+                            *addr = 0;
+                            dropped = true;
+                        }
+                    }
+
+                    if dropped {
+                        self.block_mut(indirect)
+                            .copy_from_slice(bytemuck::cast_slice(&addrs));
+                    }
+                }
+            }
+
+            if changed {
+                self.set_inode(inum, &din);
+            }
+        }
+    }
+
+    /// Claims `block` for inode `inum`. Returns false if the address should be dropped.
+    fn claim(&mut self, inum: u32, block: u32) -> bool {
+        if !self.is_data(block) {
+            return !self.error(format_args!("inode {inum}: block {block} out of range"));
+        }
+
+        let owner = self.owner[block as usize];
+        if owner != 0 {
+            return !self.error(format_args!(
+                "inode {inum}: block {block} already claimed by inode {owner}"
+            ));
+        }
+
+        self.owner[block as usize] = inum;
+        true
+    }
+
+    /// Pass 4: walks the directory tree from the root, counting references to each inode.
+    fn check_tree(&mut self) {
+        let root = self.inode(ROOTINO);
+        if root.r#type != InodeType::DIRECTORY {
+            self.unfixable("root inode is not a directory");
+            return;
+        }
+
+        let mut visited = vec![false; self.sb.ninodes as usize];
+        let mut queue = VecDeque::from([(ROOTINO, ROOTINO)]);
+        visited[ROOTINO as usize] = true;
+
+        while let Some((dir, parent)) = queue.pop_front() {
+            let din = self.inode(dir);
+
+            if !din.size.is_multiple_of(BSIZE) {
+                self.unfixable(format_args!(
+                    "directory {dir}: size {} is not a multiple of the block size",
+                    din.size
+                ));
+            }
+
+            for bn in 0..din.size / BSIZE {
+                let block = self.bmap(&din, bn);
+                if !self.is_data(block) {
+                    self.unfixable(format_args!("directory {dir}: block {bn} is not mapped"));
+                    continue;
+                }
+
+                self.check_checksum(block, format_args!("directory {dir}"));
+                self.dir_blocks.push(block);
+
+                for (inum, name) in self.check_records(dir, parent, bn, block) {
+                    let child = self.inode(inum);
+                    if child.r#type != InodeType::DIRECTORY {
+                        continue;
+                    }
+
+                    if visited[inum as usize] {
+                        self.unfixable(format_args!(
+                            "directory {dir}: {name:?} links directory {inum} a second time"
+                        ));
+                    } else {
+                        visited[inum as usize] = true;
+                        queue.push_back((inum, dir));
+                    }
+                }
+            }
+
+            self.check_index(dir, &din);
+        }
+    }
+
+    /// Checks the records of block `bn` of directory `dir`.
+    /// Returns the entries other than `.` and `..`.
+    fn check_records(&mut self, dir: u32, parent: u32, bn: u32, block: u32) -> Vec<(u32, String)> {
+        let space = self.sb.space();
+        let mut buf = self.block(block).to_vec();
+        let mut changed = false;
+        let mut entries = Vec::new();
+
+        let mut offset = 0;
+        let mut index = 0;
+        while offset < space {
+            let de: Directory =
+                *bytemuck::from_bytes(&buf[offset..offset + Directory::HEADER_SIZE]);
+            let rec_len = de.rec_len as usize;
+
+            if rec_len < Directory::HEADER_SIZE
+                || rec_len % 4 != 0
+                || offset + rec_len > space
+                || Directory::HEADER_SIZE + de.name_len as usize > rec_len
+            {
+                if self.error(format_args!(
+                    "directory {dir}: bad record at block {bn} offset {offset}"
+                )) {
+                    // drop the rest of the block
+                    let de = Directory {
+                        inum: 0,
+                        rec_len: (space - offset) as u16,
+                        name_len: 0,
+                        reserved: 0,
+                    };
+                    buf[offset..offset + Directory::HEADER_SIZE]
+                        .copy_from_slice(bytemuck::bytes_of(&de));
+                    changed = true;
+                }
+                break;
+            }
+
+            let start = offset + Directory::HEADER_SIZE;
+            let name = String::from_utf8_lossy(&buf[start..start + de.name_len as usize]);
+            let mut inum = de.inum as u32;
+
+            // the first two records of a directory are `.` and `..`
+            let dot = match (bn, index) {
+                (0, 0) => Some((".", dir)),
+                (0, 1) => Some(("..", parent)),
+                _ => None,
+            };
+
+            match dot {
+                Some((expected, _)) if name != expected => {
+                    self.unfixable(format_args!(
+                        "directory {dir}: {name:?} found where {expected:?} belongs"
+                    ));
+                }
+                Some((expected, target)) => {
+                    if inum != target
+                        && self.error(format_args!(
+                            "directory {dir}: {expected:?} points to {inum} instead of {target}"
+                        ))
+                    {
+                        inum = target;
+                        buf[offset..offset + 2].copy_from_slice(&(inum as u16).to_le_bytes());
+                        changed = true;
+                    }
+
+                    if expected == ".." {
+                        self.refs[inum as usize] += 1;
+                    }
+                }
+                None if inum == 0 => {}
+                None if name == "." || name == ".." => {
+                    if self.error(format_args!("directory {dir}: stray {name:?} entry")) {
+                        buf[offset..offset + 2].fill(0);
+                        changed = true;
+                    }
+                }
+                None => {
+                    let free = inum >= self.sb.ninodes
+                        || self.sb.reserved_inode(inum)
+                        || self.inode(inum).r#type == InodeType::FREE;
+
+                    if !free {
+                        self.refs[inum as usize] += 1;
+                        entries.push((inum, name.into_owned()));
+                    } else if self.error(format_args!(
+                        "directory {dir}: {name:?} points to free inode {inum}"
+                    )) {
+                        buf[offset..offset + 2].fill(0);
+                        changed = true;
+                    }
+                }
+            }
+
+            offset += rec_len;
+            index += 1;
+        }
+
+        if changed {
+            self.block_mut(block).copy_from_slice(&buf);
+        }
+
+        entries
+    }
+
+    /// Checks the hash index of directory `dir`, if it has one. Names the index cannot reach
+    /// would be invisible to lookups, so a bad index is dropped and the directory searched
+    /// linearly instead.
+    fn check_index(&mut self, dir: u32, din: &DiskInode) {
+        let block = self.bmap(din, 0);
+        if !self.is_data(block) {
+            return;
+        }
+
+        let dotdot_offset = Directory::rec_len_for(1);
+        let dotdot: Directory = *bytemuck::from_bytes(
+            &self.block(block)[dotdot_offset..dotdot_offset + Directory::HEADER_SIZE],
+        );
+        if dotdot.reserved & Directory::INDEXED == 0 {
+            return;
+        }
+
+        let nblocks = din.size / BSIZE;
+        let problem = match self.index_problem(block, din, nblocks) {
+            Some(problem) => problem,
+            None => return,
+        };
+
+        if self.error(format_args!("directory {dir}: {problem}")) {
+            let buf = self.block_mut(block);
+            buf[dotdot_offset + 5] &= !Directory::INDEXED;
+        }
+    }
+
+    fn index_problem(&self, block: u32, din: &DiskInode, nblocks: u32) -> Option<String> {
+        let buf = self.block(block);
+        let root: DxRoot =
+            *bytemuck::from_bytes(&buf[DxRoot::OFFSET..DxRoot::OFFSET + size_of::<DxRoot>()]);
+
+        if root.magic != DXMAGIC || root.count == 0 || root.count > root.limit {
+            return Some("bad index header".to_string());
+        }
+
+        // indexes made without checksums may reach the end of the block
+        let limit = (self.sb.space() - DxRoot::OFFSET - size_of::<DxRoot>()) / size_of::<DxEntry>();
+        if root.limit as usize > limit {
+            return Some(format!("index limit {} too large", root.limit));
+        }
+
+        let start = DxRoot::OFFSET + size_of::<DxRoot>();
+        let entries: Vec<DxEntry> = (0..root.count as usize)
+            .map(|i| {
+                let offset = start + i * size_of::<DxEntry>();
+                *bytemuck::from_bytes(&buf[offset..offset + size_of::<DxEntry>()])
+            })
+            .collect();
+
+        let mut listed = vec![false; nblocks as usize];
+        for (i, entry) in entries.iter().enumerate() {
+            if entry.block == 0 || entry.block >= nblocks {
+                return Some(format!("index entry {i} points past the directory"));
+            }
+
+            if (i == 0 && entry.hash != 0) || (i > 0 && entry.hash < entries[i - 1].hash) {
+                return Some(format!("index entry {i} out of order"));
+            }
+
+            listed[entry.block as usize] = true;
+
+            let end = entries.get(i + 1).map(|next| next.hash);
+            let leaf = self.bmap(din, entry.block);
+            if !self.is_data(leaf) {
+                continue;
+            }
+
+            for name in live_names(&self.block(leaf)[..self.sb.space()]) {
+                let hash = DxRoot::hash(&name);
+                if hash < entry.hash || end.is_some_and(|end| hash >= end) {
+                    return Some(format!(
+                        "{:?} is in the wrong leaf",
+                        String::from_utf8_lossy(&name)
+                    ));
+                }
+            }
+        }
+
+        for bn in 1..nblocks {
+            let leaf = self.bmap(din, bn);
+            if !listed[bn as usize]
+                && self.is_data(leaf)
+                && !live_names(&self.block(leaf)[..self.sb.space()]).is_empty()
+            {
+                return Some(format!("block {bn} holds entries but is not indexed"));
+            }
+        }
+
+        None
+    }
+
+    /// Pass 5: compares link counts with the references found in the tree, and frees inodes
+    /// nothing refers to, except those in the orphan block.
+    fn check_links(&mut self) {
+        let orphans = self.orphans();
+
+        for inum in 1..self.sb.ninodes {
+            let mut din = self.inode(inum);
+            if self.sb.reserved_inode(inum) || din.r#type == InodeType::FREE {
+                continue;
+            }
+
+            let refs = self.refs[inum as usize];
+
+            if refs == 0 && orphans.contains(&inum) {
+                // a crash while the file was being trimmed, the kernel finishes at mount
+                println!("inode {inum}: orphaned, left for the kernel to reclaim");
+            } else if refs == 0 {
+                let msg = if din.nlink == 0 {
+                    format!("inode {inum}: orphaned but not in the orphan block")
+                } else {
+                    format!("inode {inum}: unreferenced with {} links", din.nlink)
+                };
+
+                if self.error(msg) {
+                    self.free_inode(inum);
+                }
+            } else if din.nlink as u32 != refs
+                && self.error(format_args!(
+                    "inode {inum}: {} links but {refs} references",
+                    din.nlink
+                ))
+            {
+                din.nlink = refs as u16;
+                self.set_inode(inum, &din);
+            }
+        }
+
+        for inum in orphans {
+            let listed = inum < self.sb.ninodes && self.inode(inum).r#type != InodeType::FREE;
+            if listed && self.refs[inum as usize] != 0 {
+                // the kernel only trims files that are still linked, nothing to repair
+                println!("orphan block: inode {inum} still linked, pending truncation");
+            }
+        }
+    }
+
+    /// Frees inode `inum` and releases the blocks it claims.
+    fn free_inode(&mut self, inum: u32) {
+        for owner in self.owner.iter_mut().filter(|owner| **owner == inum) {
+            *owner = 0;
+        }
+
+        self.set_inode(inum, &DiskInode::new(InodeType::FREE));
+    }
+
+    /// Pass 6: the bitmap must mark exactly the metadata and the claimed blocks in use.
+    fn check_bitmap(&mut self) {
+        for bmap in self.sb.bmapstart..self.data_start() {
+            self.check_checksum(bmap, "bitmap");
+        }
+
+        let mut wrong = 0;
+
+        for block in 0..self.sb.size {
+            if self.reserved_block(block) {
+                continue;
+            }
+
+            let used = block < self.data_start() || self.owner[block as usize] != 0;
+            let bmap = self.sb.bmapstart + block / BPB;
+            let bit = (block % BPB) as usize;
+            let marked = self.block(bmap)[bit / 8] & (1 << (bit % 8)) != 0;
+
+            if used == marked {
+                continue;
+            }
+
+            wrong += 1;
+            if used {
+                println!("block {block}: in use but marked free");
+            } else {
+                println!("block {block}: free but marked in use");
+            }
+
+            if self.repair {
+                self.block_mut(bmap)[bit / 8] ^= 1 << (bit % 8);
+            }
+        }
+
+        if wrong > 0 {
+            self.error(format_args!("bitmap: {wrong} blocks marked wrong"));
+        }
+    }
+}
+
+/// Returns the names of the live entries in a directory block, skipping `.` and `..`.
+fn live_names(buf: &[u8]) -> Vec<Vec<u8>> {
+    let mut names = Vec::new();
+    let mut offset = 0;
+
+    while offset + Directory::HEADER_SIZE <= buf.len() {
+        let de: Directory = *bytemuck::from_bytes(&buf[offset..offset + Directory::HEADER_SIZE]);
+        if (de.rec_len as usize) < Directory::HEADER_SIZE {
+            break;
+        }
+
+        let start = offset + Directory::HEADER_SIZE;
+        let name = &buf[start..(start + de.name_len as usize).min(buf.len())];
+        if de.inum != 0 && name != b"." && name != b".." {
+            names.push(name.to_vec());
+        }
+
+        offset += de.rec_len as usize;
+    }
+
+    names
+}
+
+fn main() {
+    let args = args().collect::<Vec<String>>();
+
+    let (repair, path) = match args.as_slice() {
+        [_, path] => (false, path),
+        [_, flag, path] if flag == "-r" || flag == "--repair" => (true, path),
+        _ => {
+            println!("Usage: fsck [-r] <fs.img>");
+            exit(8);
+        }
+    };
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(repair)
+        .open(path)
+        .unwrap_or_else(|e| {
+            println!("fsck: cannot open {path}: {e}");
+            exit(8);
+        });
+
+    let mut data = Vec::new();
+    file.read_to_end(&mut data).expect("failed to read image");
+
+    if data.len() < 2 * BSIZE as usize {
+        println!("fsck: {path} is too small to hold a file system");
+        exit(8);
+    }
+
+    let sb: SuperBlock =
+        *bytemuck::from_bytes(&data[BSIZE as usize..BSIZE as usize + size_of::<SuperBlock>()]);
+
+    let mut image = Image {
+        data,
+        sb,
+        repair,
+        dirty: false,
+        errors: 0,
+        fixed: 0,
+        owner: Vec::new(),
+        refs: Vec::new(),
+        dir_blocks: Vec::new(),
+    };
+
+    if let Err(e) = image.check_superblock(image.data.len()) {
+        println!("superblock: {e}");
+        exit(8);
+    }
+
+    image.owner = vec![0; sb.size as usize];
+    image.refs = vec![0; sb.ninodes as usize];
+
+    image.replay_log();
+    image.check_inodes();
+    image.check_tree();
+    image.check_links();
+    image.check_bitmap();
+
+    if repair && image.dirty && sb.checksummed() {
+        image.seal_all();
+    }
+
+    let inodes = (1..sb.ninodes)
+        .filter(|inum| !sb.reserved_inode(*inum) && image.inode(*inum).r#type != InodeType::FREE)
+        .count();
+    let ninodes = (1..sb.ninodes)
+        .filter(|inum| !sb.reserved_inode(*inum))
+        .count();
+    let blocks = image.owner.iter().filter(|owner| **owner != 0).count();
+    println!(
+        "{path}: {inodes}/{ninodes} inodes, {blocks}/{} blocks",
+        sb.nblocks
+    );
+
+    if repair && image.dirty {
+        write_image(&file, &image.data);
+    }
+
+    let status = if image.errors == 0 {
+        0
+    } else if image.fixed == image.errors {
+        1
+    } else {
+        4
+    };
+
+    exit(status);
+}
+
+fn write_image(file: &File, data: &[u8]) {
+    file.write_all_at(data, 0).expect("failed to write image");
+}
@@ -0,0 +1,380 @@
+// Inspects an octopos file system image and pulls files out of it.
+//
+// The image is never modified. A committed transaction still in the log is applied to the
+// in-memory copy first, so the tool sees what the kernel would after replaying it at boot.
+
+use std::collections::HashSet;
+use std::env::args;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::exit;
+
+use mkfs::{
+    BSIZE, Directory, DiskInode, FSMAGIC, IPB, InodeType, MAXFILE, MAXLOGBLOCKS, NDIRECT, ROOTINO,
+    SuperBlock, block_checksum, stored_checksum,
+};
+
+const USAGE: &str = "Usage: fsdump <fs.img> <command>
+Commands:
+    sb                       print the superblock
+    ls [path]                list a directory
+    cat <path>               write a file to stdout
+    stat <path | #inum>      print an inode
+    extract <path> <dest>    copy a file or directory tree to the host";
+
+/// Read-only in-memory copy of an image
+struct Image {
+    data: Vec<u8>,
+    sb: SuperBlock,
+}
+
+impl Image {
+    fn open(path: &str) -> Result<Self, String> {
+        let data = fs::read(path).map_err(|e| format!("cannot read {path}: {e}"))?;
+
+        if data.len() < 2 * BSIZE as usize {
+            return Err(format!("{path} is too small to hold a file system"));
+        }
+
+        let sb: SuperBlock =
+            *bytemuck::from_bytes(&data[BSIZE as usize..BSIZE as usize + size_of::<SuperBlock>()]);
+
+        if sb.magic != FSMAGIC {
+            return Err(format!("{path}: bad magic {:#x}", sb.magic));
+        }
+
+        if data.len() < (sb.size * BSIZE) as usize {
+            return Err(format!("{path} is smaller than {} blocks", sb.size));
+        }
+
+        let mut image = Image { data, sb };
+        image.apply_log();
+        Ok(image)
+    }
+
+    /// Copies the blocks of a committed transaction over their home locations.
+    fn apply_log(&mut self) {
+        let buf = self.block(self.sb.logstart);
+        if self.sb.checksummed() && stored_checksum(buf) != block_checksum(buf) {
+            eprintln!("fsdump: ignoring log header with a bad checksum");
+            return;
+        }
+
+        // header block: count of committed blocks, then their home block numbers
+        let header: &[u32] = bytemuck::cast_slice(self.block(self.sb.logstart));
+        let n = header[0];
+        let blocks = header[1..=n.min(MAXLOGBLOCKS) as usize].to_vec();
+
+        if n == 0 {
+            return;
+        }
+
+        if n > (self.sb.nlogs - 1).min(MAXLOGBLOCKS)
+            || blocks.iter().any(|block| *block >= self.sb.size)
+        {
+            eprintln!("fsdump: ignoring corrupt log header with {n} blocks");
+            return;
+        }
+
+        for (i, block) in blocks.iter().enumerate() {
+            let src = ((self.sb.logstart + 1 + i as u32) * BSIZE) as usize;
+            let dst = (block * BSIZE) as usize;
+            self.data.copy_within(src..src + BSIZE as usize, dst);
+        }
+    }
+
+    fn block(&self, block: u32) -> &[u8] {
+        let start = (block * BSIZE) as usize;
+        &self.data[start..start + BSIZE as usize]
+    }
+
+    fn inode(&self, inum: u32) -> Result<DiskInode, String> {
+        if inum == 0 || inum >= self.sb.ninodes || self.sb.reserved_inode(inum) {
+            return Err(format!("inode {inum} out of range"));
+        }
+
+        let buf = self.block(self.sb.inodestart + inum / IPB);
+        let offset = (inum % IPB) as usize * size_of::<DiskInode>();
+        Ok(*bytemuck::from_bytes(
+            &buf[offset..offset + size_of::<DiskInode>()],
+        ))
+    }
+
+    /// Returns the address of block `bn` of an inode, 0 if it is not mapped.
+    fn bmap(&self, din: &DiskInode, bn: u32) -> u32 {
+        if bn < NDIRECT {
+            return din.addrs[bn as usize];
+        }
+
+        let indirect = din.addrs[NDIRECT as usize];
+        if bn >= MAXFILE || indirect == 0 || indirect >= self.sb.size {
+            return 0;
+        }
+
+        let addrs: &[u32] = bytemuck::cast_slice(self.block(indirect));
+        addrs[(bn - NDIRECT) as usize]
+    }
+
+    /// Reads the contents of an inode. Unmapped blocks read as zeros.
+    fn read(&self, din: &DiskInode) -> Vec<u8> {
+        let size = din.size.min(MAXFILE * BSIZE) as usize;
+        let mut data = vec![0u8; size];
+
+        for (bn, chunk) in data.chunks_mut(BSIZE as usize).enumerate() {
+            let block = self.bmap(din, bn as u32);
+            if block != 0 && block < self.sb.size {
+                chunk.copy_from_slice(&self.block(block)[..chunk.len()]);
+            }
+        }
+
+        data
+    }
+
+    /// Returns the live `(inum, name)` entries of a directory, including `.` and `..`.
+    fn entries(&self, din: &DiskInode) -> Vec<(u32, String)> {
+        let data = self.read(din);
+        let mut entries = Vec::new();
+
+        for block in data.chunks(BSIZE as usize) {
+            let mut offset = 0;
+
+            while offset + Directory::HEADER_SIZE <= block.len() {
+                let de: Directory =
+                    *bytemuck::from_bytes(&block[offset..offset + Directory::HEADER_SIZE]);
+                let rec_len = de.rec_len as usize;
+                let start = offset + Directory::HEADER_SIZE;
+
+                if rec_len < Directory::HEADER_SIZE || start + de.name_len as usize > block.len() {
+                    // corrupt record, skip the rest of the block
+                    break;
+                }
+
+                if de.inum != 0 {
+                    let name = &block[start..start + de.name_len as usize];
+                    entries.push((de.inum as u32, String::from_utf8_lossy(name).into_owned()));
+                }
+
+                offset += rec_len;
+            }
+        }
+
+        entries
+    }
+
+    /// Resolves a path from the root, or `#<inum>` for a raw inode number.
+    fn resolve(&self, path: &str) -> Result<u32, String> {
+        if let Some(inum) = path.strip_prefix('#') {
+            return inum
+                .parse()
+                .map_err(|_| format!("invalid inode number {inum:?}"));
+        }
+
+        let mut inum = ROOTINO;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            let din = self.inode(inum)?;
+            if din.r#type != InodeType::DIRECTORY {
+                return Err(format!("{path}: not a directory"));
+            }
+
+            inum = self
+                .entries(&din)
+                .into_iter()
+                .find(|(_, entry)| entry == name)
+                .map(|(inum, _)| inum)
+                .ok_or(format!("{path}: no such file or directory"))?;
+        }
+
+        Ok(inum)
+    }
+
+    fn print_superblock(&self) {
+        let sb = &self.sb;
+        println!("magic       {:#x}", sb.magic);
+        println!("size        {} blocks", sb.size);
+        println!("nblocks     {}", sb.nblocks);
+        println!("ninodes     {}", sb.ninodes);
+        println!("log         {} blocks at {}", sb.nlogs, sb.logstart);
+        println!("orphan      {}", sb.orphan);
+        println!("inodestart  {}", sb.inodestart);
+        println!("bmapstart   {}", sb.bmapstart);
+        println!("features    {:#x}", sb.features);
+        if sb.checksummed() {
+            let state = if sb.checksum == sb.compute_checksum() {
+                "ok"
+            } else {
+                "bad"
+            };
+            println!("checksum    {:#010x} ({state})", sb.checksum);
+        }
+    }
+
+    fn list(&self, path: &str) -> Result<(), String> {
+        let inum = self.resolve(path)?;
+        let din = self.inode(inum)?;
+
+        if din.r#type != InodeType::DIRECTORY {
+            println!(
+                "{} {:>5} {:>8} {path}",
+                type_char(din.r#type),
+                inum,
+                din.size
+            );
+            return Ok(());
+        }
+
+        for (inum, name) in self.entries(&din) {
+            let child = self.inode(inum)?;
+            println!(
+                "{} {:>5} {:>8} {name}",
+                type_char(child.r#type),
+                inum,
+                child.size
+            );
+        }
+
+        Ok(())
+    }
+
+    fn stat(&self, path: &str) -> Result<(), String> {
+        let inum = self.resolve(path)?;
+        let din = self.inode(inum)?;
+
+        println!("inode  {inum}");
+        println!("type   {}", type_name(din.r#type));
+        println!("device {},{}", din.major, din.minor);
+        println!("nlink  {}", din.nlink);
+        println!("size   {}", din.size);
+        println!("direct {:?}", &din.addrs[..NDIRECT as usize]);
+
+        let indirect = din.addrs[NDIRECT as usize];
+        if indirect != 0 && indirect < self.sb.size {
+            let addrs: &[u32] = bytemuck::cast_slice(self.block(indirect));
+            let mapped = addrs.iter().filter(|addr| **addr != 0).count();
+            println!("indirect {indirect} ({mapped} blocks)");
+        }
+
+        Ok(())
+    }
+
+    fn cat(&self, path: &str) -> Result<(), String> {
+        let din = self.inode(self.resolve(path)?)?;
+        if din.r#type == InodeType::DIRECTORY {
+            return Err(format!("{path}: is a directory"));
+        }
+
+        io::stdout()
+            .write_all(&self.read(&din))
+            .map_err(|e| e.to_string())
+    }
+
+    /// Copies inode `inum` to `dest`, recursing into directories. `visited` holds the directories
+    /// copied so far, so that a cycle in a damaged image is only copied once.
+    fn extract(&self, inum: u32, dest: &Path, visited: &mut HashSet<u32>) -> Result<(), String> {
+        let din = self.inode(inum)?;
+        let err = |e: io::Error| format!("{}: {e}", dest.display());
+
+        match din.r#type {
+            InodeType::DIRECTORY => {
+                if !visited.insert(inum) {
+                    eprintln!(
+                        "fsdump: skipping directory {inum} at {}, already copied",
+                        dest.display()
+                    );
+                    return Ok(());
+                }
+
+                fs::create_dir_all(dest).map_err(err)?;
+
+                for (child, name) in self.entries(&din) {
+                    if name == "." || name == ".." {
+                        continue;
+                    }
+
+                    // names come from the image, they must not lead out of `dest`
+                    if name.is_empty() || name.contains('/') {
+                        eprintln!("fsdump: skipping bad name {name:?} in {}", dest.display());
+                        continue;
+                    }
+
+                    self.extract(child, &dest.join(name), visited)?;
+                }
+            }
+            InodeType::FILE => fs::write(dest, self.read(&din)).map_err(err)?,
+            InodeType::DEVICE => {
+                eprintln!(
+                    "fsdump: skipping device {},{} at {}",
+                    din.major,
+                    din.minor,
+                    dest.display()
+                );
+            }
+            InodeType::FIFO => eprintln!("fsdump: skipping fifo at {}", dest.display()),
+            InodeType::SOCKET => eprintln!("fsdump: skipping socket at {}", dest.display()),
+            _ => eprintln!("fsdump: skipping free inode {inum} at {}", dest.display()),
+        }
+
+        Ok(())
+    }
+}
+
+fn type_char(r#type: InodeType) -> char {
+    match r#type {
+        InodeType::FILE => 'f',
+        InodeType::DIRECTORY => 'd',
+        InodeType::DEVICE => 'D',
+        InodeType::FIFO => 'p',
+        InodeType::SOCKET => 's',
+        _ => '?',
+    }
+}
+
+fn type_name(r#type: InodeType) -> &'static str {
+    match r#type {
+        InodeType::FREE => "free",
+        InodeType::FILE => "file",
+        InodeType::DIRECTORY => "directory",
+        InodeType::DEVICE => "device",
+        InodeType::FIFO => "fifo",
+        InodeType::SOCKET => "socket",
+        _ => "unknown",
+    }
+}
+
+fn main() {
+    let args = args().collect::<Vec<String>>();
+    let args = args.iter().map(|arg| arg.as_str()).collect::<Vec<_>>();
+
+    let [_, image, command @ ..] = args.as_slice() else {
+        println!("{USAGE}");
+        exit(1);
+    };
+
+    let image = Image::open(image).unwrap_or_else(|e| {
+        eprintln!("fsdump: {e}");
+        exit(1);
+    });
+
+    let result = match command {
+        ["sb"] => {
+            image.print_superblock();
+            Ok(())
+        }
+        ["ls"] => image.list("/"),
+        ["ls", path] => image.list(path),
+        ["cat", path] => image.cat(path),
+        ["stat", path] => image.stat(path),
+        ["extract", path, dest] => image
+            .resolve(path)
+            .and_then(|inum| image.extract(inum, Path::new(dest), &mut HashSet::new())),
+        _ => {
+            println!("{USAGE}");
+            exit(1);
+        }
+    };
+
+    if let Err(e) = result {
+        eprintln!("fsdump: {e}");
+        exit(1);
+    }
+}
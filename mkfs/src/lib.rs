@@ -0,0 +1,218 @@
+//! On-disk format of the octopos file system, shared by the host tools.
+//! Must match the kernel's `fs` and `log` modules.
+
+use bytemuck::{Pod, Zeroable};
+
+/// # of blocks an FS op may write unless it reserves more
+pub const MAXOPBLOCKS: u32 = 10;
+/// default data blocks in on-disk log
+pub const LOGBLOCKS: u32 = MAXOPBLOCKS * 10;
+/// max data blocks the log header can list, in front of its checksum
+pub const MAXLOGBLOCKS: u32 = (BSIZE - CSUMSIZE as u32) / (size_of::<u32>() as u32) - 1;
+
+/// File system magic number
+pub const FSMAGIC: u32 = 0x10203040;
+/// Feature flag: metadata blocks end with a CRC32C of the rest of the block
+pub const FEATURE_CSUM: u32 = 0x1;
+/// Bytes at the end of a metadata block holding its checksum
+pub const CSUMSIZE: usize = size_of::<u32>();
+/// Root inode number
+pub const ROOTINO: u32 = 1;
+/// Block size
+pub const BSIZE: u32 = 1024;
+/// Number of direct block addresses in inode
+pub const NDIRECT: u32 = 12;
+/// Number of indirect block addresses in inode
+pub const NINDIRECT: u32 = BSIZE / (size_of::<u32>() as u32);
+/// Max file size (blocks)
+pub const MAXFILE: u32 = NDIRECT + NINDIRECT;
+/// Maximum length of a directory entry name
+pub const MAXNAME: usize = 255;
+/// Directory index magic number
+pub const DXMAGIC: u32 = 0x64786964;
+
+/// Inodes per block
+pub const IPB: u32 = BSIZE / (size_of::<DiskInode>() as u32);
+/// Bitmap bits per block
+pub const BPB: u32 = BSIZE * 8;
+
+/// On-disk superblock (read at boot)
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct SuperBlock {
+    /// Must be `FSMAGIC`
+    pub magic: u32,
+    /// Size of file system image (blocks)
+    pub size: u32,
+    /// Number of data blocks
+    pub nblocks: u32,
+    /// Number of inodes
+    pub ninodes: u32,
+    /// Number of log blocks
+    pub nlogs: u32,
+    /// Block number of first log block
+    pub logstart: u32,
+    /// Block number of first inode block
+    pub inodestart: u32,
+    /// Block number of first free map block
+    pub bmapstart: u32,
+    /// Block number of the orphan block
+    pub orphan: u32,
+    /// Optional format features (`FEATURE_*`)
+    pub features: u32,
+    /// CRC32C of the fields above, if `FEATURE_CSUM` is set
+    pub checksum: u32,
+}
+
+impl SuperBlock {
+    /// Returns whether metadata blocks carry checksums.
+    pub fn checksummed(&self) -> bool {
+        self.features & FEATURE_CSUM != 0
+    }
+
+    /// Returns the CRC32C of the fields before `checksum`.
+    pub fn compute_checksum(&self) -> u32 {
+        crc32c(&bytemuck::bytes_of(self)[..std::mem::offset_of!(SuperBlock, checksum)])
+    }
+
+    /// Returns whether an inode number is never used, since its slot overlaps the checksum of its
+    /// block.
+    pub fn reserved_inode(&self, inum: u32) -> bool {
+        self.checksummed() && inum % IPB == IPB - 1
+    }
+
+    /// Returns the bytes at the start of a metadata block that are free for its contents.
+    pub fn space(&self) -> usize {
+        if self.checksummed() {
+            BSIZE as usize - CSUMSIZE
+        } else {
+            BSIZE as usize
+        }
+    }
+}
+
+/// Returns the CRC32C (Castagnoli) of `data`, must match the kernel.
+pub fn crc32c(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &b| {
+        (0..8).fold(crc ^ b as u32, |crc, _| {
+            if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82F6_3B78
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+/// Returns the checksum a metadata block with contents `block` should carry.
+pub fn block_checksum(block: &[u8]) -> u32 {
+    crc32c(&block[..BSIZE as usize - CSUMSIZE])
+}
+
+/// Returns the checksum stored at the end of a metadata block.
+pub fn stored_checksum(block: &[u8]) -> u32 {
+    u32::from_le_bytes(block[BSIZE as usize - CSUMSIZE..].try_into().unwrap())
+}
+
+/// Stores the checksum of metadata block `block` in its last bytes.
+pub fn seal(block: &mut [u8]) {
+    let sum = block_checksum(block);
+    block[BSIZE as usize - CSUMSIZE..].copy_from_slice(&sum.to_le_bytes());
+}
+
+/// Inode types
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Pod, Zeroable)]
+pub struct InodeType(pub u16);
+
+impl InodeType {
+    pub const FREE: Self = Self(0);
+    pub const DIRECTORY: Self = Self(1);
+    pub const FILE: Self = Self(2);
+    pub const DEVICE: Self = Self(3);
+    pub const FIFO: Self = Self(4);
+    pub const SOCKET: Self = Self(5);
+}
+
+/// On-disk inode structure
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct DiskInode {
+    /// File type
+    pub r#type: InodeType,
+    /// Major device number
+    pub major: u16,
+    /// Minor device number
+    pub minor: u16,
+    /// Number of links to inode in file system
+    pub nlink: u16,
+    // Size of file (bytes)
+    pub size: u32,
+    // Data block addresses
+    pub addrs: [u32; NDIRECT as usize + 1],
+}
+
+impl DiskInode {
+    pub fn new(r#type: InodeType) -> Self {
+        Self {
+            r#type,
+            major: 0,
+            minor: 0,
+            nlink: 0,
+            size: 0,
+            addrs: [0; NDIRECT as usize + 1],
+        }
+    }
+}
+
+/// Directory entry header, followed by `name_len` bytes of name.
+/// Records never cross a block boundary and the last record of a block covers its free space.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct Directory {
+    pub inum: u16,
+    pub rec_len: u16,
+    pub name_len: u8,
+    pub reserved: u8,
+}
+
+impl Directory {
+    pub const HEADER_SIZE: usize = size_of::<Self>();
+    /// `reserved` flag set on the `..` record of an indexed directory
+    pub const INDEXED: u8 = 0x1;
+
+    /// Returns the smallest record length that can hold a name of `name_len` bytes.
+    pub const fn rec_len_for(name_len: usize) -> usize {
+        (Self::HEADER_SIZE + name_len + 3) & !3
+    }
+}
+
+/// Header of a directory index, stored in block 0 after the `.` and `..` records
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct DxRoot {
+    pub magic: u32,
+    pub count: u16,
+    pub limit: u16,
+}
+
+/// Directory index entry: names with hash in `[hash, next.hash)` live in leaf `block`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct DxEntry {
+    pub hash: u32,
+    pub block: u32,
+}
+
+impl DxRoot {
+    pub const OFFSET: usize = Directory::rec_len_for(1) + Directory::rec_len_for(2);
+    pub const LIMIT: usize =
+        (BSIZE as usize - CSUMSIZE - Self::OFFSET - size_of::<Self>()) / size_of::<DxEntry>();
+
+    /// Hashes a directory entry name (32-bit FNV-1a), must match the kernel.
+    pub fn hash(name: &[u8]) -> u32 {
+        name.iter().fold(0x811c9dc5, |hash, &b| {
+            (hash ^ b as u32).wrapping_mul(0x01000193)
+        })
+    }
+}
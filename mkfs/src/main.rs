@@ -1,133 +1,362 @@
 // Disk Layout:
-// [ boot block | sb block | log | inode blocks | free bit map | data blocks ]
+// [ boot block | sb block | log | orphan block | inode blocks | free bit map | data blocks ]
 
 use std::env::args;
-use std::fs::{File, OpenOptions};
-use std::io::Read;
+use std::fs::{self, File, OpenOptions};
 use std::os::unix::fs::FileExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::process::exit;
 
-use bytemuck::{Pod, Zeroable};
+use mkfs::{
+    BPB, BSIZE, CSUMSIZE, DXMAGIC, Directory, DiskInode, DxEntry, DxRoot, FEATURE_CSUM, FSMAGIC,
+    IPB, InodeType, LOGBLOCKS, MAXFILE, MAXLOGBLOCKS, MAXNAME, MAXOPBLOCKS, NDIRECT, ROOTINO,
+    SuperBlock, seal,
+};
 
-/// max # of blocks any FS op writes
-const MAXOPBLOCKS: u32 = 10;
-/// max data blocks in on-disk log
-const LOGBLOCKS: u32 = MAXOPBLOCKS * 3;
-/// size of file system in blocks
+/// default size of file system in blocks
 const FSSIZE: u32 = 2000;
+/// default number of inodes
+const NINODES: u32 = 200;
+/// bytes of a directory block records may cover, in front of its checksum
+const DIRSPACE: usize = BSIZE as usize - CSUMSIZE;
+/// bits of a bitmap block that stand for blocks, in front of its checksum
+const BITS: u32 = DIRSPACE as u32 * 8;
+/// max number of inodes, directory entries hold 16-bit inode numbers
+const MAXINODES: u32 = u16::MAX as u32 + 1;
+
+const USAGE: &str = "Usage: mkfs [-s blocks] [-i inodes] [-l logblocks] [-d manifest] <fs.img> [files or directories]";
+
+/// Command-line options
+struct Options {
+    /// size of file system in blocks
+    size: u32,
+    /// number of inodes
+    ninodes: u32,
+    /// data blocks in the log
+    nlog: u32,
+    /// device manifest, lines of `<path> <major> <minor>`
+    devices: Option<PathBuf>,
+    image: PathBuf,
+    inputs: Vec<PathBuf>,
+}
 
-/// File system magic number
-const FSMAGIC: u32 = 0x10203040;
-/// Root inode number
-pub const ROOTINO: u32 = 1;
-/// Block size
-const BSIZE: u32 = 1024;
-/// Number of direct block addresses in inode
-const NDIRECT: u32 = 12;
-/// Number of indirect block addresses in inode
-const NINDIRECT: u32 = BSIZE / (size_of::<u32>() as u32);
-/// Max file size (blocks)
-const MAXFILE: u32 = NDIRECT + NINDIRECT;
-/// Directory entry name size
-const DIRSIZE: usize = 14;
-
-/// Inodes per block
-const IPB: u32 = BSIZE / (size_of::<DiskInode>() as u32);
-/// Bitmap bits per block
-const BPB: u32 = BSIZE * 8;
+impl Options {
+    fn parse() -> Result<Self, String> {
+        let mut options = Options {
+            size: FSSIZE,
+            ninodes: NINODES,
+            nlog: LOGBLOCKS,
+            devices: None,
+            image: PathBuf::new(),
+            inputs: Vec::new(),
+        };
 
-const NINODES: u32 = 200;
-const NBITMAP: u32 = FSSIZE / BPB + 1;
-const NINODEBLOCKS: u32 = NINODES / IPB + 1;
-/// Header followed by LOGBLOCKS data blocks
-const NLOG: u32 = LOGBLOCKS + 1;
-/// Number of meta blocks (boot, sb, nlog, inode, bitmap)
-const NMETA: u32 = NLOG + NINODEBLOCKS + NBITMAP + 2;
-/// Number of data blocks
-const NBLOCKS: u32 = FSSIZE - NMETA;
-
-/// On-disk superblock (read at boot)
-#[repr(C)]
-#[derive(Debug, Clone, Copy, Pod, Zeroable)]
-struct SuperBlock {
-    /// Must be `FSMAGIC`
-    pub magic: u32,
-    /// Size of file system image (blocks)
-    pub size: u32,
-    /// Number of data blocks
-    pub nblocks: u32,
-    /// Number of inodes
-    pub ninodes: u32,
-    /// Number of log blocks
-    pub nlogs: u32,
-    /// Block number of first log block
-    pub logstart: u32,
-    /// Block number of first inode block
-    pub inodestart: u32,
-    /// Block number of first free map block
-    pub bmapstart: u32,
+        let mut positional = Vec::new();
+        let mut args = args().skip(1);
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("missing value for {arg}"));
+
+            match arg.as_str() {
+                "-s" | "--size" => options.size = parse_number(&value()?)?,
+                "-i" | "--inodes" => options.ninodes = parse_number(&value()?)?,
+                "-l" | "--log" => options.nlog = parse_number(&value()?)?,
+                "-d" | "--devices" => options.devices = Some(PathBuf::from(value()?)),
+                _ if arg.starts_with('-') => return Err(format!("unknown option {arg}")),
+                _ => positional.push(PathBuf::from(arg)),
+            }
+        }
+
+        if positional.is_empty() {
+            return Err("missing image path".to_string());
+        }
+
+        options.image = positional.remove(0);
+        options.inputs = positional;
+
+        if options.ninodes <= ROOTINO || options.ninodes > MAXINODES {
+            return Err(format!("inode count must be in 2..={MAXINODES}"));
+        }
+
+        if options.nlog < MAXOPBLOCKS || options.nlog > MAXLOGBLOCKS {
+            return Err(format!(
+                "log size must be in {MAXOPBLOCKS}..={MAXLOGBLOCKS} blocks"
+            ));
+        }
+
+        Ok(options)
+    }
+}
+
+fn parse_number(s: &str) -> Result<u32, String> {
+    s.parse().map_err(|_| format!("invalid number {s:?}"))
 }
 
-/// Inode types
-#[repr(C)]
-#[derive(Debug, Clone, Copy, Pod, Zeroable)]
-struct InodeType(u16);
+/// A file system tree to write into the image
+enum Node {
+    File(PathBuf),
+    Directory(Vec<(String, Node)>),
+    Device { major: u16, minor: u16 },
+}
+
+impl Node {
+    /// Adds `node` as `name` to a directory node.
+    fn insert(&mut self, name: String, node: Node) -> Result<(), String> {
+        let Node::Directory(entries) = self else {
+            return Err(format!("cannot add {name:?} to a non-directory"));
+        };
+
+        if name.len() > MAXNAME || name.is_empty() || name == "." || name == ".." {
+            return Err(format!("invalid name {name:?}"));
+        }
+
+        if entries.iter().any(|(n, _)| *n == name) {
+            return Err(format!("duplicate name {name:?}"));
+        }
+
+        entries.push((name, node));
+        Ok(())
+    }
+
+    /// Reads a host file or directory tree.
+    fn from_host(path: &Path) -> Result<Self, String> {
+        let metadata = fs::metadata(path).map_err(|e| format!("{}: {e}", path.display()))?;
+
+        if !metadata.is_dir() {
+            return Ok(Node::File(path.to_path_buf()));
+        }
+
+        let mut children: Vec<PathBuf> = fs::read_dir(path)
+            .and_then(|dir| dir.map(|entry| entry.map(|e| e.path())).collect())
+            .map_err(|e| format!("{}: {e}", path.display()))?;
+        children.sort();
+
+        let mut dir = Node::Directory(Vec::new());
+        for child in children {
+            dir.insert(file_name(&child)?, Node::from_host(&child)?)?;
+        }
+
+        Ok(dir)
+    }
+
+    /// Adds the device nodes listed in a manifest, creating missing parent directories.
+    /// Each line is `<path> <major> <minor>`; blank lines and `#` comments are skipped.
+    fn add_devices(&mut self, manifest: &Path) -> Result<(), String> {
+        let text =
+            fs::read_to_string(manifest).map_err(|e| format!("{}: {e}", manifest.display()))?;
+
+        for (line_no, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let err = |msg: &str| format!("{}:{}: {msg}", manifest.display(), line_no + 1);
+
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let [path, major, minor] = fields[..] else {
+                return Err(err("expected <path> <major> <minor>"));
+            };
+
+            let major = major.parse().map_err(|_| err("invalid major number"))?;
+            let minor = minor.parse().map_err(|_| err("invalid minor number"))?;
+
+            let mut components = path
+                .split('/')
+                .filter(|c| !c.is_empty())
+                .collect::<Vec<_>>();
+            let Some(name) = components.pop() else {
+                return Err(err("empty path"));
+            };
+
+            let mut dir = &mut *self;
+            for component in components {
+                let Node::Directory(entries) = dir else {
+                    return Err(err("parent is not a directory"));
+                };
+
+                let index = match entries.iter().position(|(n, _)| n == component) {
+                    Some(index) => index,
+                    None => {
+                        entries.push((component.to_string(), Node::Directory(Vec::new())));
+                        entries.len() - 1
+                    }
+                };
+                dir = &mut entries[index].1;
+            }
 
-impl InodeType {
-    const _FREE: Self = Self(0);
-    const DIRECTORY: Self = Self(1);
-    const FILE: Self = Self(2);
-    const _DEVICE: Self = Self(3);
+            dir.insert(name.to_string(), Node::Device { major, minor })
+                .map_err(|e| err(&e))?;
+        }
+
+        Ok(())
+    }
 }
 
-/// On-disk inode structure
-#[repr(C)]
-#[derive(Debug, Clone, Copy, Pod, Zeroable)]
-struct DiskInode {
-    /// File type
-    pub r#type: InodeType,
-    /// Major device number
-    pub major: u16,
-    /// Minor device number
-    pub minor: u16,
-    /// Number of links to inode in file system
-    pub nlink: u16,
-    // Size of file (bytes)
-    pub size: u32,
-    // Data block addresses
-    pub addrs: [u32; NDIRECT as usize + 1],
+fn file_name(path: &Path) -> Result<String, String> {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| name.to_string())
+        .ok_or(format!("invalid file name {}", path.display()))
 }
 
-impl DiskInode {
-    fn new(r#type: InodeType) -> Self {
-        Self {
-            r#type,
-            major: 0,
-            minor: 0,
-            nlink: 0,
-            size: 0,
-            addrs: [0; NDIRECT as usize + 1],
+/// Lays out a directory's entries. `entries` must start with `.` and `..`.
+/// Directories that do not fit in one block get a hash index, like the kernel builds when a
+/// directory outgrows its first block.
+fn directory_blocks(entries: &[(u32, &str)]) -> Vec<u8> {
+    let data = pack_records(entries);
+    if data.len() <= BSIZE as usize {
+        return data;
+    }
+
+    let (dots, rest) = entries.split_at(2);
+    assert_eq!(dots[0].1, ".");
+    assert_eq!(dots[1].1, "..");
+
+    let mut sorted = rest.to_vec();
+    sorted.sort_by_key(|(_, name)| DxRoot::hash(name.as_bytes()));
+
+    // fill leaves in hash order, never splitting a run of equal hashes across leaves
+    let mut leaves: Vec<Vec<(u32, &str)>> = Vec::new();
+    let mut used = DIRSPACE;
+    for (i, &(inum, name)) in sorted.iter().enumerate() {
+        let rec_len = Directory::rec_len_for(name.len());
+        let hash = DxRoot::hash(name.as_bytes());
+
+        if used + rec_len > DIRSPACE {
+            assert!(
+                i == 0 || DxRoot::hash(sorted[i - 1].1.as_bytes()) != hash,
+                "hash collision across directory leaves"
+            );
+            leaves.push(Vec::new());
+            used = 0;
         }
+
+        leaves.last_mut().unwrap().push((inum, name));
+        used += rec_len;
+    }
+
+    assert!(leaves.len() <= DxRoot::LIMIT, "directory too large");
+
+    // root block: `.`, then `..` spanning the rest of the block with the index in its tail
+    let mut data = pack_records(dots);
+    let dotdot_offset = Directory::rec_len_for(1);
+    let dotdot: &mut Directory =
+        bytemuck::from_bytes_mut(&mut data[dotdot_offset..dotdot_offset + Directory::HEADER_SIZE]);
+    dotdot.reserved |= Directory::INDEXED;
+
+    let root = DxRoot {
+        magic: DXMAGIC,
+        count: leaves.len() as u16,
+        limit: DxRoot::LIMIT as u16,
+    };
+    let mut offset = DxRoot::OFFSET;
+    data[offset..offset + size_of::<DxRoot>()].copy_from_slice(bytemuck::bytes_of(&root));
+    offset += size_of::<DxRoot>();
+
+    for (i, leaf) in leaves.iter().enumerate() {
+        let entry = DxEntry {
+            hash: if i == 0 {
+                0
+            } else {
+                DxRoot::hash(leaf[0].1.as_bytes())
+            },
+            block: i as u32 + 1,
+        };
+        data[offset..offset + size_of::<DxEntry>()].copy_from_slice(bytemuck::bytes_of(&entry));
+        offset += size_of::<DxEntry>();
+    }
+
+    for leaf in &leaves {
+        data.extend_from_slice(&pack_records(leaf));
     }
+
+    data
 }
 
-#[repr(C)]
-#[derive(Debug, Clone, Copy, Pod, Zeroable)]
-pub struct Directory {
-    pub inum: u16,
-    pub name: [u8; DIRSIZE],
+/// Packs `(inum, name)` entries into directory blocks.
+fn pack_records(entries: &[(u32, &str)]) -> Vec<u8> {
+    let mut data = Vec::new();
+    // offset of the last record in the current block, extended to cover the block's free space
+    let mut last: Option<usize> = None;
+
+    for &(inum, name) in entries {
+        assert!(name.len() <= MAXNAME, "name too long: {name}");
+
+        let rec_len = Directory::rec_len_for(name.len());
+        let used = data.len() % BSIZE as usize;
+
+        if data.is_empty() || used + rec_len > DIRSPACE {
+            // close the current block and start a new one
+            extend_last_record(&mut data, last);
+            data.resize(data.len().next_multiple_of(BSIZE as usize), 0);
+        }
+
+        let offset = data.len();
+        let de = Directory {
+            inum: inum as u16,
+            rec_len: rec_len as u16,
+            name_len: name.len() as u8,
+            reserved: 0,
+        };
+        data.extend_from_slice(bytemuck::bytes_of(&de));
+        data.extend_from_slice(name.as_bytes());
+        data.resize(offset + rec_len, 0);
+        last = Some(offset);
+    }
+
+    extend_last_record(&mut data, last);
+    data.resize(data.len().next_multiple_of(BSIZE as usize), 0);
+    data
 }
 
-fn main() {
-    // the first block we can allocate
-    let mut free_block = NMETA;
-    let mut free_inode = 1;
+/// Grows the record at `last` so that it reaches the checksum at the end of its block.
+fn extend_last_record(data: &mut [u8], last: Option<usize>) {
+    let Some(offset) = last else {
+        return;
+    };
+
+    let end = (offset / BSIZE as usize) * BSIZE as usize + DIRSPACE;
+    let header = &mut data[offset..offset + Directory::HEADER_SIZE];
+    let de: &mut Directory = bytemuck::from_bytes_mut(header);
+    de.rec_len = (end - offset) as u16;
+}
 
-    let args = args().collect::<Vec<String>>();
+fn main() {
+    let options = Options::parse().unwrap_or_else(|e| {
+        println!("mkfs: {e}");
+        println!("{USAGE}");
+        exit(1);
+    });
+
+    let mut root = Node::Directory(Vec::new());
+    let tree = options
+        .inputs
+        .iter()
+        .try_for_each(|path| root.insert(file_name(path)?, Node::from_host(path)?))
+        .and_then(|_| match &options.devices {
+            Some(manifest) => root.add_devices(manifest),
+            None => Ok(()),
+        });
+    if let Err(e) = tree {
+        println!("mkfs: {e}");
+        exit(1);
+    }
 
-    if args.len() < 2 {
-        println!("Usage: mkfs <fs.img> [files]");
-        std::process::exit(1);
+    let nbitmap = options.size / BPB + 1;
+    let ninodeblocks = options.ninodes / IPB + 1;
+    // header followed by the log's data blocks
+    let nlog = options.nlog + 1;
+    let orphanstart = 2 + nlog;
+    let inodestart = orphanstart + 1;
+    // boot, sb, log, orphan, inode, bitmap
+    let nmeta = nlog + ninodeblocks + nbitmap + 3;
+
+    if options.size <= nmeta {
+        println!(
+            "mkfs: {} blocks cannot hold {nmeta} metadata blocks",
+            options.size
+        );
+        exit(1);
     }
 
     let file = OpenOptions::new()
@@ -135,192 +364,263 @@ fn main() {
         .write(true)
         .create(true)
         .truncate(true)
-        .open(&args[1])
+        .open(&options.image)
         .expect("failed to open file");
 
     let sb = SuperBlock {
         magic: FSMAGIC,
-        size: FSSIZE,
-        nblocks: NBLOCKS,
-        ninodes: NINODES,
-        nlogs: NLOG,
+        size: options.size,
+        nblocks: options.size - nmeta,
+        ninodes: options.ninodes,
+        nlogs: nlog,
         logstart: 2u32,
-        inodestart: (2 + NLOG),
-        bmapstart: (2 + NLOG + NINODEBLOCKS),
+        inodestart,
+        bmapstart: inodestart + ninodeblocks,
+        orphan: orphanstart,
+        features: FEATURE_CSUM,
+        checksum: 0,
+    };
+    let sb = SuperBlock {
+        checksum: sb.compute_checksum(),
+        ..sb
     };
 
     println!("{:?}", sb);
 
-    const ZEROS: [u8; BSIZE as usize] = [0u8; BSIZE as usize];
-    for i in 0..FSSIZE {
-        write_sector(&file, i, &ZEROS);
-    }
+    file.set_len((sb.size * BSIZE) as u64)
+        .expect("failed to size image");
 
     let mut buf = [0u8; BSIZE as usize];
     buf[..size_of::<SuperBlock>()].copy_from_slice(bytemuck::bytes_of(&sb));
     write_sector(&file, 1, &buf);
 
-    let rootino = allocate_inode(&file, InodeType::DIRECTORY, &mut free_inode);
-    assert_eq!(rootino, ROOTINO);
-
-    let mut de = Directory {
-        inum: ROOTINO as u16,
-        name: [0u8; DIRSIZE],
+    // an empty log header, with its checksum
+    let mut buf = [0u8; BSIZE as usize];
+    seal(&mut buf);
+    write_sector(&file, sb.logstart, &buf);
+
+    let mut image = Image {
+        file,
+        sb,
+        // the first block we can allocate
+        free_block: nmeta,
+        free_inode: 1,
     };
-    de.name[..1].copy_from_slice(b".");
-    append_inode(&file, &mut free_block, rootino, bytemuck::bytes_of(&de));
-
-    de.name[..2].copy_from_slice(b"..");
-    append_inode(&file, &mut free_block, rootino, bytemuck::bytes_of(&de));
-
-    for path in &args[2..] {
-        let name = Path::new(path).file_name().expect("invalid file name");
-        assert!(name.len() < DIRSIZE);
-
-        println!("adding file {path} as {name:?}");
-
-        let mut prog = File::open(path).expect("failed to open input file");
-        let inum = allocate_inode(&file, InodeType::FILE, &mut free_inode);
-
-        let de = Directory {
-            inum: inum as u16,
-            name: {
-                let mut n = [0u8; DIRSIZE];
-                n[..name.len()].copy_from_slice(name.to_str().unwrap().as_bytes());
-                n
-            },
-        };
-
-        append_inode(&file, &mut free_block, rootino, bytemuck::bytes_of(&de));
 
-        let mut prog_buf = Vec::new();
-        prog.read_to_end(&mut prog_buf)
-            .expect("failed to read input file");
-        append_inode(&file, &mut free_block, inum, &prog_buf);
-    }
-
-    // fix size of root inode dir
-    let mut din = read_inode(&file, rootino);
-    din.size = ((din.size / BSIZE) + 1) * BSIZE;
-    write_inode(&file, rootino, &din);
+    let rootino = image.write_node(&root, ROOTINO, Path::new(""));
+    assert_eq!(rootino, ROOTINO);
 
-    allocate_block(&file, free_block, sb.bmapstart);
+    image.write_bitmap();
+    image.seal_inodes();
 
     println!("done");
 }
 
-fn write_sector(file: &File, sec: u32, buf: &[u8]) {
-    file.write_at(buf, (sec * BSIZE) as u64)
-        .expect("failed to write sector");
+/// An image being written
+struct Image {
+    file: File,
+    sb: SuperBlock,
+    free_block: u32,
+    free_inode: u32,
 }
 
-fn read_sector(file: &File, sec: u32, buf: &mut [u8]) {
-    file.read_at(buf, (sec * BSIZE) as u64)
-        .expect("failed to read sector");
-}
+impl Image {
+    /// Writes `node` and everything below it. `parent` is the inode `..` refers to.
+    /// Returns the node's inode number.
+    fn write_node(&mut self, node: &Node, parent: u32, path: &Path) -> u32 {
+        match node {
+            Node::File(host) => {
+                println!("adding file {} as {:?}", host.display(), path);
+
+                let data = fs::read(host).expect("failed to read input file");
+                let inum = self.allocate_inode(InodeType::FILE);
+                self.append_inode(inum, &data);
+                inum
+            }
+            Node::Device { major, minor } => {
+                println!("adding device {major},{minor} as {:?}", path);
+
+                let inum = self.allocate_inode(InodeType::DEVICE);
+                let mut din = self.read_inode(inum);
+                din.major = *major;
+                din.minor = *minor;
+                self.write_inode(inum, &din);
+                inum
+            }
+            Node::Directory(children) => {
+                let inum = self.allocate_inode(InodeType::DIRECTORY);
+                // the root is its own parent
+                let parent = if inum == ROOTINO { inum } else { parent };
+
+                let mut entries = vec![(inum, ".".to_string()), (parent, "..".to_string())];
+                for (name, child) in children {
+                    let child_inum = self.write_node(child, inum, &path.join(name));
+                    entries.push((child_inum, name.clone()));
+                }
+
+                // every subdirectory's `..` links to this directory
+                let subdirs = children
+                    .iter()
+                    .filter(|(_, child)| matches!(child, Node::Directory(_)))
+                    .count();
+                let mut din = self.read_inode(inum);
+                din.nlink += subdirs as u16;
+                self.write_inode(inum, &din);
+
+                let entries = entries
+                    .iter()
+                    .map(|(inum, name)| (*inum, name.as_str()))
+                    .collect::<Vec<_>>();
+                let mut data = directory_blocks(&entries);
+                data.chunks_mut(BSIZE as usize).for_each(seal);
+                self.append_inode(inum, &data);
+                inum
+            }
+        }
+    }
 
-fn write_inode(file: &File, inum: u32, inode: &DiskInode) {
-    let mut buf = [0u8; BSIZE as usize];
-    // inode start + (inum number / inode per block)
-    let block_num = 2 + NLOG + (inum / IPB);
+    fn write_inode(&self, inum: u32, inode: &DiskInode) {
+        let mut buf = [0u8; BSIZE as usize];
+        // inode start + (inum number / inode per block)
+        let block_num = self.sb.inodestart + (inum / IPB);
 
-    read_sector(file, block_num, &mut buf);
+        read_sector(&self.file, block_num, &mut buf);
 
-    let offset = (inum % IPB) as usize * size_of::<DiskInode>();
-    buf[offset..offset + size_of::<DiskInode>()].copy_from_slice(bytemuck::bytes_of(inode));
+        let offset = (inum % IPB) as usize * size_of::<DiskInode>();
+        buf[offset..offset + size_of::<DiskInode>()].copy_from_slice(bytemuck::bytes_of(inode));
 
-    write_sector(file, block_num, &buf);
-}
+        write_sector(&self.file, block_num, &buf);
+    }
 
-fn read_inode(file: &File, inum: u32) -> DiskInode {
-    let mut buf = [0u8; BSIZE as usize];
-    // inode start + (inum number / inode per block)
-    let block_num = 2 + NLOG + (inum / IPB);
+    fn read_inode(&self, inum: u32) -> DiskInode {
+        let mut buf = [0u8; BSIZE as usize];
+        // inode start + (inum number / inode per block)
+        let block_num = self.sb.inodestart + (inum / IPB);
 
-    read_sector(file, block_num, &mut buf);
+        read_sector(&self.file, block_num, &mut buf);
 
-    let offset = (inum % IPB) as usize * size_of::<DiskInode>();
-    *bytemuck::from_bytes::<DiskInode>(&buf[offset..offset + size_of::<DiskInode>()])
-}
+        let offset = (inum % IPB) as usize * size_of::<DiskInode>();
+        *bytemuck::from_bytes::<DiskInode>(&buf[offset..offset + size_of::<DiskInode>()])
+    }
 
-fn allocate_inode(file: &File, r#type: InodeType, free_inode: &mut u32) -> u32 {
-    let inum = *free_inode;
-    *free_inode += 1;
+    fn allocate_inode(&mut self, r#type: InodeType) -> u32 {
+        while self.sb.reserved_inode(self.free_inode) {
+            self.free_inode += 1;
+        }
 
-    let mut din = DiskInode::new(r#type);
-    din.nlink = 1u16;
-    din.size = 0u32;
+        let inum = self.free_inode;
+        assert!(inum < self.sb.ninodes, "out of inodes");
+        self.free_inode += 1;
 
-    write_inode(file, inum, &din);
-    inum
-}
+        let mut din = DiskInode::new(r#type);
+        din.nlink = 1u16;
+        din.size = 0u32;
 
-fn append_inode(file: &File, free_block: &mut u32, inum: u32, mut data: &[u8]) {
-    let mut buf = [0u8; BSIZE as usize];
+        self.write_inode(inum, &din);
+        inum
+    }
 
-    let mut din = read_inode(file, inum);
-    let mut offset = din.size;
+    fn allocate_block(&mut self) -> u32 {
+        // blocks whose bits overlap a bitmap checksum are never used
+        while self.free_block % BPB >= BITS {
+            self.free_block += 1;
+        }
 
-    while !data.is_empty() {
-        let fbn = offset / BSIZE;
-        assert!(fbn < MAXFILE);
+        let block = self.free_block;
+        assert!(block < self.sb.size, "out of blocks");
+        self.free_block += 1;
+        block
+    }
 
-        let x = if fbn < NDIRECT {
-            if din.addrs[fbn as usize] == 0 {
-                din.addrs[fbn as usize] = *free_block;
-                *free_block += 1;
-            }
-            din.addrs[fbn as usize]
-        } else {
-            if din.addrs[NDIRECT as usize] == 0 {
-                din.addrs[NDIRECT as usize] = *free_block;
-                *free_block += 1;
-            }
+    fn append_inode(&mut self, inum: u32, mut data: &[u8]) {
+        let mut buf = [0u8; BSIZE as usize];
+
+        let mut din = self.read_inode(inum);
+        let mut offset = din.size;
+
+        while !data.is_empty() {
+            let fbn = offset / BSIZE;
+            assert!(fbn < MAXFILE, "file too large");
+
+            let x = if fbn < NDIRECT {
+                if din.addrs[fbn as usize] == 0 {
+                    din.addrs[fbn as usize] = self.allocate_block();
+                }
+                din.addrs[fbn as usize]
+            } else {
+                if din.addrs[NDIRECT as usize] == 0 {
+                    din.addrs[NDIRECT as usize] = self.allocate_block();
+                }
+
+                let mut indirect = [0u8; BSIZE as usize];
+                read_sector(&self.file, din.addrs[NDIRECT as usize], &mut indirect);
+
+                let index = (fbn - NDIRECT) as usize;
+                let byte_offset = index * 4;
+                let bytes: [u8; 4] = indirect[byte_offset..byte_offset + 4].try_into().unwrap();
+                let mut block_addr = u32::from_le_bytes(bytes);
+                if block_addr == 0 {
+                    block_addr = self.allocate_block();
+                    indirect[byte_offset..byte_offset + 4]
+                        .copy_from_slice(&block_addr.to_le_bytes());
+                    write_sector(&self.file, din.addrs[NDIRECT as usize], &indirect);
+                }
+
+                block_addr
+            };
+
+            let block_offset = (offset - fbn * BSIZE) as usize;
+            let n1 = data.len().min(((fbn + 1) * BSIZE - offset) as usize);
+
+            read_sector(&self.file, x, &mut buf);
+            buf[block_offset..block_offset + n1].copy_from_slice(&data[..n1]);
+            write_sector(&self.file, x, &buf);
+
+            offset += n1 as u32;
+            data = &data[n1..];
+        }
 
-            let mut indirect = [0u8; BSIZE as usize];
-            read_sector(file, din.addrs[NDIRECT as usize], &mut indirect);
-
-            let index = (fbn - NDIRECT) as usize;
-            let byte_offset = index * 4;
-            let bytes: [u8; 4] = indirect[byte_offset..byte_offset + 4].try_into().unwrap();
-            let mut block_addr = u32::from_le_bytes(bytes);
-            if block_addr == 0 {
-                block_addr = *free_block;
-                *free_block += 1;
-                indirect[byte_offset..byte_offset + 4].copy_from_slice(&block_addr.to_le_bytes());
-                write_sector(file, din.addrs[NDIRECT as usize], &indirect);
-            }
+        din.size = offset;
+        self.write_inode(inum, &din);
+    }
 
-            block_addr
-        };
+    /// Marks the first `free_block` blocks in use and writes every bitmap block with its checksum.
+    fn write_bitmap(&self) {
+        let used = self.free_block;
+        println!("first {used} blocks have been allocated");
 
-        let block_offset = (offset - fbn * BSIZE) as usize;
-        let n1 = data.len().min(((fbn + 1) * BSIZE - offset) as usize);
+        for bmap in 0..self.sb.size / BPB + 1 {
+            let mut buf = [0u8; BSIZE as usize];
 
-        read_sector(file, x, &mut buf);
-        buf[block_offset..block_offset + n1].copy_from_slice(&data[..n1]);
-        write_sector(file, x, &buf);
+            for i in 0..used.saturating_sub(bmap * BPB).min(BITS) as usize {
+                buf[i / 8] |= 0x1 << (i % 8);
+            }
 
-        offset += n1 as u32;
-        data = &data[n1..];
+            seal(&mut buf);
+            write_sector(&self.file, self.sb.bmapstart + bmap, &buf);
+            println!("wrote bitmap block at sector {}", self.sb.bmapstart + bmap);
+        }
     }
 
-    din.size = offset;
-    write_inode(file, inum, &din);
-}
-
-fn allocate_block(file: &File, used: u32, bmapstart: u32) {
-    println!("first {used} blocks have been allocated");
+    /// Stores the checksum of every inode block, once all inodes are written.
+    fn seal_inodes(&self) {
+        for inum in (0..self.sb.ninodes).step_by(IPB as usize) {
+            let block_num = self.sb.inodestart + inum / IPB;
 
-    assert!(used < BPB);
-
-    let mut buf = [0u8; BSIZE as usize];
-
-    for i in 0..used as usize {
-        buf[i / 8] |= 0x1 << (i % 8);
+            let mut buf = [0u8; BSIZE as usize];
+            read_sector(&self.file, block_num, &mut buf);
+            seal(&mut buf);
+            write_sector(&self.file, block_num, &buf);
+        }
     }
+}
 
-    write_sector(file, bmapstart, &buf);
+fn write_sector(file: &File, sec: u32, buf: &[u8]) {
+    file.write_at(buf, (sec * BSIZE) as u64)
+        .expect("failed to write sector");
+}
 
-    println!("wrote bitmap block at sector {bmapstart}");
+fn read_sector(file: &File, sec: u32, buf: &mut [u8]) {
+    file.read_at(buf, (sec * BSIZE) as u64)
+        .expect("failed to read sector");
 }
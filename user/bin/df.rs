@@ -0,0 +1,51 @@
+#![no_std]
+#![no_main]
+
+use user::*;
+
+fn df(path: &str) {
+    let mut st = StatFs::default();
+    if let Err(e) = statfs(path, &mut st) {
+        eprintln!("df: {} ({})", e, path);
+        return;
+    }
+
+    let kib = |blocks: u32| blocks as u64 * st.bsize as u64 / 1024;
+    print!(
+        "{:>9} {:>9} {:>9}",
+        kib(st.blocks),
+        kib(st.blocks - st.bfree),
+        kib(st.bfree)
+    );
+
+    // a file system that does not limit its inodes reports none
+    if st.files == 0 {
+        print!(" {:>7} {:>7} {:>7}", "-", "-", "-");
+    } else {
+        print!(
+            " {:>7} {:>7} {:>7}",
+            st.files,
+            st.files - st.ffree,
+            st.ffree
+        );
+    }
+
+    println!(" {}", path);
+}
+
+#[unsafe(no_mangle)]
+fn main(args: Args) {
+    println!(
+        "{:>9} {:>9} {:>9} {:>7} {:>7} {:>7} Path",
+        "KiB", "Used", "Free", "Inodes", "IUsed", "IFree"
+    );
+
+    if args.len() < 2 {
+        df("/");
+        return;
+    }
+
+    for path in args.args_as_str() {
+        df(path);
+    }
+}
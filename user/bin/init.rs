@@ -5,14 +5,30 @@ use user::*;
 
 #[unsafe(no_mangle)]
 fn main(_args: Args) {
-    if open("console", OpenFlag::READ_WRITE).is_err() {
-        mknod("console", CONSOLE, 0).expect("init: cannot create console");
-        open("console", OpenFlag::READ_WRITE).expect("init: cannot open console");
-    }
+    // device nodes come from the devfs; `/dev` may already exist
+    let _ = mkdir("/dev");
+    mount("devfs", 0, "/dev").expect("init: cannot mount /dev");
+    open("/dev/console", OpenFlag::READ_WRITE).expect("init: cannot open console");
 
     dup(Fd::STDIN).expect("init: dup stdout");
     dup(Fd::STDIN).expect("init: dup stderr");
 
+    // mount the second disk if there is one; `/mnt` may already exist
+    let _ = mkdir("/mnt");
+    let _ = mount("xv6fs", 2, "/mnt");
+
+    // the FAT32 disk shared with the host, read-only
+    let _ = mkdir("/fat");
+    let _ = mount("fat32", 3, "/fat");
+
+    // scratch files go to memory
+    let _ = mkdir("/tmp");
+    let _ = mount("tmpfs", 0, "/tmp");
+
+    // process and kernel state, generated on read
+    let _ = mkdir("/proc");
+    let _ = mount("procfs", 0, "/proc");
+
     loop {
         let Ok(pid) = fork() else {
             exit_with_msg("init: fork failed");
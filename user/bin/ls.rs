@@ -8,12 +8,61 @@ fn type_char(t: InodeType) -> char {
         InodeType::File => 'f',
         InodeType::Directory => 'd',
         InodeType::Device => 'D',
+        InodeType::Fifo => 'p',
+        InodeType::Socket => 's',
         InodeType::Free => '?',
     }
 }
 
+fn ls_entry(path: &str, record: &DirRecord) {
+    let mut full_path = [0u8; MAXPATH];
+
+    let mut path_len = path.len();
+    let name_len = record.name.len();
+
+    if path_len + 1 + name_len >= MAXPATH {
+        eprintln!("ls: path too long");
+        return;
+    }
+
+    full_path[..path_len].copy_from_slice(path.as_bytes());
+    if !path.ends_with('/') {
+        full_path[path_len] = b'/';
+        path_len += 1;
+    }
+    full_path[path_len..path_len + name_len].copy_from_slice(record.name);
+
+    let Ok(file_name) = core::str::from_utf8(record.name) else {
+        eprintln!("ls: malformed name");
+        return;
+    };
+    let file_path = unsafe { str_from_cstr(&full_path).expect("ls: malformed path") };
+
+    let Ok(file_fd) = open(file_path, OpenFlag::READ_ONLY | OpenFlag::NONBLOCK) else {
+        eprintln!("ls: cannot open {}", file_name);
+        return;
+    };
+
+    let mut file_stat = Stat::default();
+    if fstat(file_fd, &mut file_stat).is_err() {
+        eprintln!("ls: cannot stat {}", file_name);
+        let _ = close(file_fd);
+        return;
+    }
+
+    println!(
+        "{} {:>4} {:>8} {}",
+        type_char(file_stat.r#type),
+        file_stat.ino,
+        file_stat.size,
+        file_name,
+    );
+
+    let _ = close(file_fd);
+}
+
 fn ls(path: &str) {
-    let Ok(fd) = open(path, OpenFlag::READ_ONLY) else {
+    let Ok(fd) = open(path, OpenFlag::READ_ONLY | OpenFlag::NONBLOCK) else {
         eprintln!("ls: cannot open {}", path);
         return;
     };
@@ -28,57 +77,24 @@ fn ls(path: &str) {
     match stat.r#type {
         InodeType::Free => {}
         InodeType::Directory => {
-            let mut buf = [0u8; size_of::<Directory>()];
+            // directories are read a block at a time, since records never cross blocks
+            let mut buf = [0u8; BSIZE];
             while read(fd, &mut buf) == Ok(buf.len()) {
-                let dir: &Directory = unsafe { &*(buf.as_ptr() as *const Directory) };
-
-                if dir.inum == 0 {
-                    continue; // empty slot
-                }
+                for record in Directory::records(&buf) {
+                    let Ok(record) = record else {
+                        eprintln!("ls: malformed directory {}", path);
+                        break;
+                    };
 
-                let mut full_path = [0u8; MAXPATH];
+                    if record.entry.inum == 0 {
+                        continue; // empty slot
+                    }
 
-                let mut path_len = path.len();
-                let name_len = dir
-                    .name
-                    .iter()
-                    .position(|&c| c == 0)
-                    .unwrap_or(dir.name.len());
-
-                full_path[..path_len].copy_from_slice(path.as_bytes());
-                if !path.ends_with('/') {
-                    full_path[path_len] = b'/';
-                    path_len += 1;
+                    ls_entry(path, &record);
                 }
-                full_path[path_len..path_len + name_len].copy_from_slice(&dir.name[..name_len]);
-
-                let file_name = unsafe { str_from_cstr(&dir.name).expect("ls: malformed path") };
-                let file_path = unsafe { str_from_cstr(&full_path).expect("ls: malformed path") };
-
-                let Ok(file_fd) = open(file_path, OpenFlag::READ_ONLY) else {
-                    eprintln!("ls: cannot open {}", file_name);
-                    continue;
-                };
-
-                let mut file_stat = Stat::default();
-                if fstat(file_fd, &mut file_stat).is_err() {
-                    eprintln!("ls: cannot stat {}", file_name);
-                    let _ = close(file_fd);
-                    continue;
-                }
-
-                println!(
-                    "{} {:>4} {:>8} {}",
-                    type_char(file_stat.r#type),
-                    file_stat.ino,
-                    file_stat.size,
-                    file_name,
-                );
-
-                let _ = close(file_fd);
             }
         }
-        InodeType::File | InodeType::Device => {
+        InodeType::File | InodeType::Device | InodeType::Fifo | InodeType::Socket => {
             println!(
                 "{} {:>4} {:>8} {}",
                 type_char(stat.r#type),
@@ -0,0 +1,18 @@
+#![no_std]
+#![no_main]
+
+use user::*;
+
+#[unsafe(no_mangle)]
+fn main(args: Args) {
+    if args.len() < 2 {
+        exit_with_msg("usage: mkfifo path...");
+    }
+
+    for path in args.args_as_str() {
+        if let Err(e) = mkfifo(path) {
+            eprintln!("mkfifo: {} ({})", e, path);
+            break;
+        }
+    }
+}
@@ -0,0 +1,31 @@
+#![no_std]
+#![no_main]
+
+use user::*;
+
+#[unsafe(no_mangle)]
+fn main(args: Args) {
+    let mut args = args.args_as_str().peekable();
+
+    // the disk file system is the default type
+    let mut fstype = "xv6fs";
+    if args.peek() == Some(&"-t") {
+        args.next();
+        fstype = args.next().unwrap_or_else(|| {
+            exit_with_msg("usage: mount [-t type] disk directory");
+        });
+    }
+
+    let (Some(disk), Some(dir), None) = (args.next(), args.next(), args.next()) else {
+        exit_with_msg("usage: mount [-t type] disk directory");
+    };
+
+    let dev = disk.parse::<usize>().unwrap_or_else(|_| {
+        exit_with_msg("mount: invalid disk");
+    });
+
+    if let Err(e) = mount(fstype, dev, dir) {
+        eprintln!("mount: {} ({})", e, dir);
+        exit(1);
+    }
+}
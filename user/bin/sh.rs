@@ -284,7 +284,7 @@ impl<'a> Parser<'a> {
                     cmd = CommandType::Redirect {
                         cmd: inner,
                         file,
-                        mode: OpenFlag::WRITE_ONLY | OpenFlag::CREATE,
+                        mode: OpenFlag::WRITE_ONLY | OpenFlag::CREATE | OpenFlag::APPEND,
                         fd: Fd::STDOUT,
                     };
                 }
@@ -322,10 +322,14 @@ fn run_cmd(cmd: CommandType, arena: &mut Arena) -> ! {
             mode,
             fd,
         } => {
-            close(fd).unwrap();
-            if open(file, mode).is_err() {
+            let Ok(file_fd) = open(file, mode) else {
                 eprintln!("sh: cannot open {}", file);
                 exit(1);
+            };
+
+            if file_fd != fd {
+                dup2(file_fd, fd).unwrap();
+                close(file_fd).unwrap();
             }
 
             // now fd 0 or 1 points to the file
@@ -338,10 +342,8 @@ fn run_cmd(cmd: CommandType, arena: &mut Arena) -> ! {
             if fork().expect("sh: fork failed") == 0 {
                 // won't read from pipe, close read side
                 close(read_fd).unwrap();
-                // close STDOUT to free fd 1
-                close(Fd::STDOUT).unwrap();
-                // write_fd is now copied to lowest free fd (1)
-                dup(write_fd).unwrap();
+                // point STDOUT at the write side
+                dup2(write_fd, Fd::STDOUT).unwrap();
                 // won't need the original fd anymore
                 close(write_fd).unwrap();
 
@@ -353,10 +355,8 @@ fn run_cmd(cmd: CommandType, arena: &mut Arena) -> ! {
             if fork().expect("sh: fork failed") == 0 {
                 // won't write to pipe, close write side
                 close(write_fd).unwrap();
-                // close STDIN to free fd 0
-                close(Fd::STDIN).unwrap();
-                // read_fd is now copied to lowest free fd (0)
-                dup(read_fd).unwrap();
+                // point STDIN at the read side
+                dup2(read_fd, Fd::STDIN).unwrap();
                 // won't need the original fd anymore
                 close(read_fd).unwrap();
 
@@ -414,7 +414,7 @@ fn exec_cmd(cmd: &str, args: &[&str]) -> ! {
 fn main(_args: Args) {
     // ensure that three file descriptors are open
     loop {
-        let Ok(fd) = open("console", OpenFlag::READ_WRITE) else {
+        let Ok(fd) = open("/dev/console", OpenFlag::READ_WRITE) else {
             exit_with_msg("sh: cannot open console");
         };
 
@@ -425,9 +425,13 @@ fn main(_args: Args) {
     }
 
     let mut buf = [0u8; MAXLINE];
+    let mut cwd = [0u8; MAXPATH];
 
     loop {
-        let _ = write(Fd::STDERR, b"$ ");
+        if let Ok(path) = getcwd(&mut cwd) {
+            eprint!("{} ", path);
+        }
+        eprint!("$ ");
 
         let Some(line) = gets(&mut buf) else {
             break; // EOF
@@ -0,0 +1,18 @@
+#![no_std]
+#![no_main]
+
+use user::*;
+
+#[unsafe(no_mangle)]
+fn main(args: Args) {
+    if args.len() < 2 {
+        exit_with_msg("usage: umount directory...");
+    }
+
+    for dir in args.args_as_str() {
+        if let Err(e) = umount(dir) {
+            eprintln!("umount: {} ({})", e, dir);
+            break;
+        }
+    }
+}
@@ -1,7 +1,7 @@
 pub mod raw {
     use core::arch::asm;
 
-    use kernel::abi::{Stat, Syscall};
+    use kernel::abi::{Stat, StatFs, Syscall};
 
     #[inline(always)]
     fn syscall0(syscall: Syscall) -> isize {
@@ -58,6 +58,39 @@ pub mod raw {
         ret
     }
 
+    #[inline(always)]
+    fn syscall4(syscall: Syscall, a0: usize, a1: usize, a2: usize, a3: usize) -> isize {
+        let ret: isize;
+        unsafe {
+            asm!(
+                "ecall",
+                in("a7") syscall as usize,
+                inlateout("a0") a0 as isize => ret,
+                in("a1") a1,
+                in("a2") a2,
+                in("a3") a3,
+            );
+        }
+        ret
+    }
+
+    #[inline(always)]
+    fn syscall5(syscall: Syscall, a0: usize, a1: usize, a2: usize, a3: usize, a4: usize) -> isize {
+        let ret: isize;
+        unsafe {
+            asm!(
+                "ecall",
+                in("a7") syscall as usize,
+                inlateout("a0") a0 as isize => ret,
+                in("a1") a1,
+                in("a2") a2,
+                in("a3") a3,
+                in("a4") a4,
+            );
+        }
+        ret
+    }
+
     pub fn fork() -> isize {
         syscall0(Syscall::Fork)
     }
@@ -103,6 +136,22 @@ pub mod raw {
         syscall1(Syscall::Dup, fd)
     }
 
+    pub fn dup2(old: usize, new: usize) -> isize {
+        syscall2(Syscall::Dup2, old, new)
+    }
+
+    pub fn dup3(old: usize, new: usize, flags: usize) -> isize {
+        syscall3(Syscall::Dup3, old, new, flags)
+    }
+
+    pub fn fcntl(fd: usize, cmd: usize, arg: usize) -> isize {
+        syscall3(Syscall::Fcntl, fd, cmd, arg)
+    }
+
+    pub fn flock(fd: usize, op: usize) -> isize {
+        syscall2(Syscall::Flock, fd, op)
+    }
+
     pub fn getpid() -> isize {
         syscall0(Syscall::Getpid)
     }
@@ -131,6 +180,10 @@ pub mod raw {
         syscall3(Syscall::Mknod, path as usize, major, minor)
     }
 
+    pub fn mkfifo(path: *const u8) -> isize {
+        syscall1(Syscall::Mkfifo, path as usize)
+    }
+
     pub fn unlink(path: *const u8) -> isize {
         syscall1(Syscall::Unlink, path as usize)
     }
@@ -142,9 +195,110 @@ pub mod raw {
     pub fn mkdir(path: *const u8) -> isize {
         syscall1(Syscall::Mkdir, path as usize)
     }
+
+    pub fn fsync(fd: usize) -> isize {
+        syscall1(Syscall::Fsync, fd)
+    }
+
+    pub fn fdatasync(fd: usize) -> isize {
+        syscall1(Syscall::Fdatasync, fd)
+    }
+
+    pub fn ftruncate(fd: usize, length: usize) -> isize {
+        syscall2(Syscall::Ftruncate, fd, length)
+    }
+
+    pub fn truncate(path: *const u8, length: usize) -> isize {
+        syscall2(Syscall::Truncate, path as usize, length)
+    }
+
+    pub fn mount(fstype: *const u8, dev: usize, path: *const u8) -> isize {
+        syscall3(Syscall::Mount, fstype as usize, dev, path as usize)
+    }
+
+    pub fn umount(path: *const u8) -> isize {
+        syscall1(Syscall::Umount, path as usize)
+    }
+
+    pub fn statfs(path: *const u8, statfs: *mut StatFs) -> isize {
+        syscall2(Syscall::Statfs, path as usize, statfs as usize)
+    }
+
+    pub fn socket(domain: usize, r#type: usize, protocol: usize) -> isize {
+        syscall3(Syscall::Socket, domain, r#type, protocol)
+    }
+
+    pub fn bind(fd: usize, path: *const u8) -> isize {
+        syscall2(Syscall::Bind, fd, path as usize)
+    }
+
+    pub fn listen(fd: usize, backlog: usize) -> isize {
+        syscall2(Syscall::Listen, fd, backlog)
+    }
+
+    pub fn accept(fd: usize) -> isize {
+        syscall1(Syscall::Accept, fd)
+    }
+
+    pub fn connect(fd: usize, path: *const u8) -> isize {
+        syscall2(Syscall::Connect, fd, path as usize)
+    }
+
+    pub fn sendfd(fd: usize, sent: usize) -> isize {
+        syscall2(Syscall::Sendfd, fd, sent)
+    }
+
+    pub fn recvfd(fd: usize) -> isize {
+        syscall1(Syscall::Recvfd, fd)
+    }
+
+    pub fn getcwd(buf: *mut u8, size: usize) -> isize {
+        syscall2(Syscall::Getcwd, buf as usize, size)
+    }
+
+    pub fn openat(dir: usize, path: *const u8, flags: usize) -> isize {
+        syscall3(Syscall::Openat, dir, path as usize, flags)
+    }
+
+    pub fn mkdirat(dir: usize, path: *const u8) -> isize {
+        syscall2(Syscall::Mkdirat, dir, path as usize)
+    }
+
+    pub fn unlinkat(dir: usize, path: *const u8, flags: usize) -> isize {
+        syscall3(Syscall::Unlinkat, dir, path as usize, flags)
+    }
+
+    pub fn linkat(
+        old_dir: usize,
+        old: *const u8,
+        new_dir: usize,
+        new: *const u8,
+        flags: usize,
+    ) -> isize {
+        syscall5(
+            Syscall::Linkat,
+            old_dir,
+            old as usize,
+            new_dir,
+            new as usize,
+            flags,
+        )
+    }
+
+    pub fn fstatat(dir: usize, path: *const u8, stat: *mut Stat, flags: usize) -> isize {
+        syscall4(Syscall::Fstatat, dir, path as usize, stat as usize, flags)
+    }
+
+    pub fn fchdir(fd: usize) -> isize {
+        syscall1(Syscall::Fchdir, fd)
+    }
+
+    pub fn chroot(path: *const u8) -> isize {
+        syscall1(Syscall::Chroot, path as usize)
+    }
 }
 
-use kernel::abi::{MAXPATH, Stat, SysError};
+use kernel::abi::{At, MAXPATH, Stat, StatFs, SysError};
 
 /// A file descriptor returned by or passed to syscalls.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -154,6 +308,8 @@ impl Fd {
     pub const STDIN: Fd = Fd(0);
     pub const STDOUT: Fd = Fd(1);
     pub const STDERR: Fd = Fd(2);
+    /// The current directory, as the directory argument of the `*at` syscalls
+    pub const CWD: Fd = Fd(At::FDCWD);
 
     /// Returns the raw file descriptor number.
     pub fn as_raw(&self) -> usize {
@@ -238,6 +394,9 @@ pub fn read(fd: Fd, buf: &mut [u8]) -> Result<usize, SysError> {
     check(raw::read(fd.as_raw(), buf.as_mut_ptr(), buf.len()))
 }
 
+/// Writes `buf` to `fd`. A write to a disk file is atomic as long as it fits in one log
+/// transaction, about half the log of the disk; larger writes are split into several transactions,
+/// so a crash may leave only the first part of them written.
 pub fn write(fd: Fd, buf: &[u8]) -> Result<usize, SysError> {
     check(raw::write(fd.as_raw(), buf.as_ptr(), buf.len()))
 }
@@ -291,6 +450,28 @@ pub fn dup(fd: Fd) -> Result<Fd, SysError> {
     check(raw::dup(fd.as_raw())).map(Fd)
 }
 
+/// Makes `new` refer to the same file as `old`, closing `new` first if it is open.
+pub fn dup2(old: Fd, new: Fd) -> Result<Fd, SysError> {
+    check(raw::dup2(old.as_raw(), new.as_raw())).map(Fd)
+}
+
+/// Like `dup2`, but `flags` may set `OpenFlag::CLOEXEC` on `new`, and `old` must differ from it.
+pub fn dup3(old: Fd, new: Fd, flags: usize) -> Result<Fd, SysError> {
+    check(raw::dup3(old.as_raw(), new.as_raw(), flags)).map(Fd)
+}
+
+/// Performs the `Fcntl` command `cmd` on `fd`. Returns the new descriptor for `Fcntl::DUPFD`
+/// and the requested flags for the `GET` commands.
+pub fn fcntl(fd: Fd, cmd: usize, arg: usize) -> Result<usize, SysError> {
+    check(raw::fcntl(fd.as_raw(), cmd, arg))
+}
+
+/// Takes or releases an advisory lock on the file behind `fd`, see `LockFlag`.
+/// The lock is released when the last descriptor sharing the open file is closed.
+pub fn flock(fd: Fd, op: usize) -> Result<(), SysError> {
+    check_unit(raw::flock(fd.as_raw(), op))
+}
+
 pub fn getpid() -> usize {
     raw::getpid() as usize
 }
@@ -321,6 +502,12 @@ pub fn mknod(path: &str, major: usize, minor: usize) -> Result<(), SysError> {
     check_unit(raw::mknod(cpath.as_ptr(), major, minor))
 }
 
+/// Creates a named pipe. Opening it for reading waits for a writer, and the other way around.
+pub fn mkfifo(path: &str) -> Result<(), SysError> {
+    let cpath = validate_path(path)?;
+    check_unit(raw::mkfifo(cpath.as_ptr()))
+}
+
 pub fn unlink(path: &str) -> Result<(), SysError> {
     let cpath = validate_path(path)?;
     check_unit(raw::unlink(cpath.as_ptr()))
@@ -336,3 +523,144 @@ pub fn mkdir(path: &str) -> Result<(), SysError> {
     let cpath = validate_path(path)?;
     check_unit(raw::mkdir(cpath.as_ptr()))
 }
+
+/// Waits until all finished writes are on disk.
+/// Without it, changes are committed within a few ticks and a crash may lose the most recent ones.
+pub fn fsync(fd: Fd) -> Result<(), SysError> {
+    check_unit(raw::fsync(fd.as_raw()))
+}
+
+pub fn fdatasync(fd: Fd) -> Result<(), SysError> {
+    check_unit(raw::fdatasync(fd.as_raw()))
+}
+
+/// Sets the size of a file open for writing. Growing it leaves a hole that reads as zeros and
+/// takes no disk space until written.
+pub fn ftruncate(fd: Fd, length: usize) -> Result<(), SysError> {
+    check_unit(raw::ftruncate(fd.as_raw(), length))
+}
+
+/// Sets the size of the file at `path`, like `ftruncate`.
+pub fn truncate(path: &str, length: usize) -> Result<(), SysError> {
+    let cpath = validate_path(path)?;
+    check_unit(raw::truncate(cpath.as_ptr(), length))
+}
+
+/// Mounts a file system of type `fstype` from virtio disk `dev` on the directory at `path`.
+/// Disk 1 is the root, mounted as `xv6fs`.
+pub fn mount(fstype: &str, dev: usize, path: &str) -> Result<(), SysError> {
+    let ctype = validate_path(fstype)?;
+    let cpath = validate_path(path)?;
+    check_unit(raw::mount(ctype.as_ptr(), dev, cpath.as_ptr()))
+}
+
+/// Unmounts the disk mounted on `path`. Fails while any of its files is open or used as a
+/// working directory.
+pub fn umount(path: &str) -> Result<(), SysError> {
+    let cpath = validate_path(path)?;
+    check_unit(raw::umount(cpath.as_ptr()))
+}
+
+/// Fills `statfs` with the space used by the file system `path` is on.
+pub fn statfs(path: &str, statfs: &mut StatFs) -> Result<(), SysError> {
+    let cpath = validate_path(path)?;
+    check_unit(raw::statfs(cpath.as_ptr(), statfs as *mut StatFs))
+}
+
+/// Creates an unconnected socket of `type` in `domain`, see `AddressFamily` and `SocketType`.
+pub fn socket(domain: usize, r#type: usize) -> Result<Fd, SysError> {
+    check(raw::socket(domain, r#type, 0)).map(Fd)
+}
+
+/// Binds socket `fd` to `path`, which must not exist yet.
+pub fn bind(fd: Fd, path: &str) -> Result<(), SysError> {
+    let cpath = validate_path(path)?;
+    check_unit(raw::bind(fd.as_raw(), cpath.as_ptr()))
+}
+
+/// Starts taking connections on bound socket `fd`, keeping up to `backlog` waiting.
+pub fn listen(fd: Fd, backlog: usize) -> Result<(), SysError> {
+    check_unit(raw::listen(fd.as_raw(), backlog))
+}
+
+/// Waits for a connection on listening socket `fd`, returning the server's end of it.
+pub fn accept(fd: Fd) -> Result<Fd, SysError> {
+    check(raw::accept(fd.as_raw())).map(Fd)
+}
+
+/// Connects socket `fd` to the socket listening on `path`.
+pub fn connect(fd: Fd, path: &str) -> Result<(), SysError> {
+    let cpath = validate_path(path)?;
+    check_unit(raw::connect(fd.as_raw(), cpath.as_ptr()))
+}
+
+/// Sends open file `sent` over connected socket `fd`. `sent` stays open here, and must not be a
+/// socket.
+pub fn sendfd(fd: Fd, sent: Fd) -> Result<(), SysError> {
+    check_unit(raw::sendfd(fd.as_raw(), sent.as_raw()))
+}
+
+/// Receives an open file sent over connected socket `fd`, as a new descriptor.
+pub fn recvfd(fd: Fd) -> Result<Fd, SysError> {
+    check(raw::recvfd(fd.as_raw())).map(Fd)
+}
+
+/// Writes the path of the current directory into `buf`, returning it.
+pub fn getcwd(buf: &mut [u8]) -> Result<&str, SysError> {
+    let n = check(raw::getcwd(buf.as_mut_ptr(), buf.len()))?;
+    core::str::from_utf8(&buf[..n]).map_err(|_| SysError::InvalidArgument)
+}
+
+/// Opens `path`, relative to directory `dir` unless absolute, like `open`.
+pub fn openat(dir: Fd, path: &str, flags: usize) -> Result<Fd, SysError> {
+    let cpath = validate_path(path)?;
+    check(raw::openat(dir.as_raw(), cpath.as_ptr(), flags)).map(Fd)
+}
+
+/// Creates directory `path`, relative to directory `dir` unless absolute.
+pub fn mkdirat(dir: Fd, path: &str) -> Result<(), SysError> {
+    let cpath = validate_path(path)?;
+    check_unit(raw::mkdirat(dir.as_raw(), cpath.as_ptr()))
+}
+
+/// Unlinks `path`, relative to directory `dir` unless absolute. With `At::REMOVEDIR` in `flags`
+/// it must be a directory, and otherwise it must not be one.
+pub fn unlinkat(dir: Fd, path: &str, flags: usize) -> Result<(), SysError> {
+    let cpath = validate_path(path)?;
+    check_unit(raw::unlinkat(dir.as_raw(), cpath.as_ptr(), flags))
+}
+
+/// Links `new` to `old`, each relative to its own directory unless absolute.
+pub fn linkat(old_dir: Fd, old: &str, new_dir: Fd, new: &str) -> Result<(), SysError> {
+    let cold = validate_path(old)?;
+    let cnew = validate_path(new)?;
+    check_unit(raw::linkat(
+        old_dir.as_raw(),
+        cold.as_ptr(),
+        new_dir.as_raw(),
+        cnew.as_ptr(),
+        0,
+    ))
+}
+
+/// Fills `stat` with the metadata of `path`, relative to directory `dir` unless absolute.
+pub fn fstatat(dir: Fd, path: &str, stat: &mut Stat) -> Result<(), SysError> {
+    let cpath = validate_path(path)?;
+    check_unit(raw::fstatat(
+        dir.as_raw(),
+        cpath.as_ptr(),
+        stat as *mut Stat,
+        0,
+    ))
+}
+
+/// Changes the current directory to directory `fd`.
+pub fn fchdir(fd: Fd) -> Result<(), SysError> {
+    check_unit(raw::fchdir(fd.as_raw()))
+}
+
+/// Makes `path` the root directory of this process and its future children.
+pub fn chroot(path: &str) -> Result<(), SysError> {
+    let cpath = validate_path(path)?;
+    check_unit(raw::chroot(cpath.as_ptr()))
+}